@@ -0,0 +1,56 @@
+//! Count the allocations of filling a channel
+//!
+//! The buffer is preallocated to the capacity, so the first fill of a channel
+//! allocates no more than filling it again, when the buffer is warmed up.
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use kv_mpsc::{sync_channel, unwrap_ok_or, Message};
+
+const CAP: usize = 100_000;
+
+/// the system allocator that counts allocations and reallocations
+struct Counting;
+
+static ALLOCS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let _count = ALLOCS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout);
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let _count = ALLOCS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+/// number of allocations made by `f`
+fn count(f: impl FnOnce()) -> usize {
+    let before = ALLOCS.load(Ordering::Relaxed);
+    f();
+    ALLOCS.load(Ordering::Relaxed) - before
+}
+
+fn main() {
+    let (tx, rx) = sync_channel::bounded(CAP);
+    let fill = || {
+        for key in 0..CAP {
+            unwrap_ok_or!(tx.try_send(Message::key_only(key)), err, panic!("{:?}", err));
+        }
+    };
+    let first = count(fill);
+    drop(rx.drain());
+    let second = count(fill);
+    println!("allocations of filling {} messages", CAP);
+    println!("  first fill: {}", first);
+    println!("  refill:     {}", second);
+}
@@ -102,6 +102,111 @@ fn with_conflict() {
     }
 }
 
+#[inline]
+fn std_mpsc_spsc() {
+    let (tx, rx) = std::sync::mpsc::sync_channel(CAP);
+    let handle = std::thread::spawn(move || {
+        for i in 0..SEND * THREADS {
+            let m = (i, 1, Option::None::<Arc<i32>>);
+            unwrap_ok_or!(tx.send(m), err, panic!("{:?}", err));
+        }
+    });
+    for _ in 0..SEND * THREADS {
+        let _drop = unwrap_ok_or!(rx.recv(), err, panic!("{:?}", err));
+    }
+    let _drop = handle.join();
+}
+
+/// one producer and one consumer, they rarely wait for each other, so most
+/// notifications are skipped as nobody is parked
+#[inline]
+fn spsc() {
+    let (tx, rx) = sync_channel::bounded(CAP);
+    let handle = std::thread::spawn(move || {
+        for i in 0..SEND * THREADS {
+            let m = Message::single_key(i, 1);
+            unwrap_ok_or!(tx.send(m), err, panic!("{:?}", err));
+        }
+    });
+    for _ in 0..SEND * THREADS {
+        let _drop = unwrap_ok_or!(rx.recv(), err, panic!("{:?}", err));
+    }
+    let _drop = handle.join();
+}
+
+#[inline]
+fn clone_heavy() {
+    let (tx, rx) = sync_channel::bounded(CAP);
+    let mut handles = vec![];
+    for thread in 0..THREADS {
+        let tx = tx.clone();
+        let handle = std::thread::spawn(move || {
+            for i in 0..SEND {
+                // a short-lived sender per message
+                let sender = tx.clone();
+                let m = Message::single_key(thread * SEND + i, 1);
+                unwrap_ok_or!(sender.send(m), err, panic!("{:?}", err));
+            }
+        });
+        handles.push(handle);
+    }
+    for _ in 0..SEND * THREADS {
+        let _drop = unwrap_ok_or!(rx.recv(), err, panic!("{:?}", err));
+    }
+
+    for handle in handles {
+        let _drop = handle.join();
+    }
+}
+
+#[inline]
+#[allow(dead_code)]
+fn unit_value() {
+    let (tx, rx) = sync_channel::bounded(CAP);
+    let mut handles = vec![];
+    for thread in 0..THREADS {
+        let tx = tx.clone();
+        let handle = std::thread::spawn(move || {
+            for i in 0..SEND {
+                let m = Message::single_key(thread * SEND + i, ());
+                unwrap_ok_or!(tx.send(m), err, panic!("{:?}", err));
+            }
+        });
+        handles.push(handle);
+    }
+    for _ in 0..SEND * THREADS {
+        let _drop = unwrap_ok_or!(rx.recv(), err, panic!("{:?}", err));
+    }
+
+    for handle in handles {
+        let _drop = handle.join();
+    }
+}
+
+#[inline]
+#[allow(dead_code)]
+fn key_only() {
+    let (tx, rx) = sync_channel::bounded(CAP);
+    let mut handles = vec![];
+    for thread in 0..THREADS {
+        let tx = tx.clone();
+        let handle = std::thread::spawn(move || {
+            for i in 0..SEND {
+                let m = Message::key_only(thread * SEND + i);
+                unwrap_ok_or!(tx.send(m), err, panic!("{:?}", err));
+            }
+        });
+        handles.push(handle);
+    }
+    for _ in 0..SEND * THREADS {
+        let _drop = unwrap_ok_or!(rx.recv_key_only(), err, panic!("{:?}", err));
+    }
+
+    for handle in handles {
+        let _drop = handle.join();
+    }
+}
+
 #[inline]
 #[cfg(feature = "async")]
 async fn tokio_mpsc() {
@@ -152,6 +257,33 @@ async fn async_no_conflict() {
     }
 }
 
+#[inline]
+#[cfg(feature = "async")]
+async fn async_recv_many() {
+    let (tx, rx) = async_channel::bounded(CAP);
+    let mut handles = vec![];
+    for thread in 0..THREADS {
+        let tx = tx.clone();
+        let handle = tokio::spawn(async move {
+            for i in 0..SEND {
+                let m = Message::single_key(thread * SEND + i, 1);
+                unwrap_ok_or!(tx.send(m).await, err, panic!("{:?}", err));
+            }
+        });
+        handles.push(handle);
+    }
+    let mut buf = Vec::with_capacity(CAP);
+    let mut count = 0;
+    while count < SEND * THREADS {
+        count += rx.recv_many(&mut buf, CAP).await as i32;
+        buf.clear();
+    }
+
+    for handle in handles {
+        let _drop = handle.await;
+    }
+}
+
 #[inline]
 #[cfg(feature = "async")]
 async fn async_with_conflict() {
@@ -178,10 +310,8 @@ async fn async_with_conflict() {
                 count += 1;
             }
             Err(e) => match e {
-                RecvError::AllConflict => {
-                    if !msgs.is_empty() {
-                        msgs.remove(0);
-                    }
+                RecvError::AllConflict if !msgs.is_empty() => {
+                    msgs.remove(0);
                 }
                 RecvError::Disconnected => {
                     panic!("unexpected disconnected");
@@ -204,6 +334,22 @@ pub fn send_recv(c: &mut Criterion) {
     group.bench_function("std mpsc", |b| b.iter(std_mpsc));
     group.bench_function("kv_mpsc no conflict", |b| b.iter(no_conflict));
     group.bench_function("kv_mpsc with conflict", |b| b.iter(with_conflict));
+    group.bench_function("kv_mpsc clone heavy", |b| b.iter(clone_heavy));
+    group.finish();
+}
+
+pub fn spsc_send_recv(c: &mut Criterion) {
+    let mut group = c.benchmark_group("sync spsc send_recv");
+    group.bench_function("std mpsc", |b| b.iter(std_mpsc_spsc));
+    group.bench_function("kv_mpsc", |b| b.iter(spsc));
+    group.finish();
+}
+
+#[allow(dead_code)]
+pub fn key_only_send_recv(c: &mut Criterion) {
+    let mut group = c.benchmark_group("sync key only send_recv");
+    group.bench_function("kv_mpsc single_key(k, ())", |b| b.iter(unit_value));
+    group.bench_function("kv_mpsc key_only(k)", |b| b.iter(key_only));
     group.finish();
 }
 
@@ -214,18 +360,21 @@ pub fn async_send_recv(c: &mut Criterion) {
         .worker_threads(8)
         .build()
         .unwrap();
-    // group.bench_function("tokio mpsc", |b| b.to_async(&rt).iter(tokio_mpsc));
-    // group.bench_function("async kv_mpsc no conflict", |b| {
-    //     b.to_async(&rt).iter(async_no_conflict)
-    // });
+    group.bench_function("tokio mpsc", |b| b.to_async(&rt).iter(tokio_mpsc));
     group.bench_function("async kv_mpsc with conflict", |b| {
         b.to_async(&rt).iter(async_with_conflict)
     });
+    group.bench_function("async kv_mpsc no conflict", |b| {
+        b.to_async(&rt).iter(async_no_conflict)
+    });
+    group.bench_function("async kv_mpsc recv_many", |b| {
+        b.to_async(&rt).iter(async_recv_many)
+    });
     group.finish();
 }
 
 #[cfg(not(feature = "async"))]
-criterion_group!(benches, send_recv);
+criterion_group!(benches, send_recv, key_only_send_recv, spsc_send_recv);
 #[cfg(feature = "async")]
 criterion_group!(benches, async_send_recv);
 criterion_main!(benches);
@@ -0,0 +1,312 @@
+//! The active keys, locked apart from the queue and sharded by key hash
+//!
+//! A key is active while a received message or a key guard holds it. Releasing
+//! keys only takes the locks of their shards, so dropping received messages
+//! never contends with senders for the queue lock, nor with releases of keys
+//! in other shards.
+//!
+//! Lock order: the queue state first, then a shard. No shard is locked while
+//! holding another one, popping a message checks and activates its keys one
+//! shard at a time with the queue locked, nothing locks the queue while holding
+//! a shard.
+
+use crate::buff::contain;
+use crate::completions::Feed;
+use crate::lock::{lock, Mutex, MutexGuard};
+use crate::message::Key;
+use crate::{unwrap_ok_or, unwrap_some_or};
+use std::borrow::Borrow;
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hash};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// number of shards
+pub(crate) const SHARDS: usize = 16;
+
+/// The state of an active key
+#[derive(Debug)]
+struct ActiveKey {
+    /// number of received messages and key guards that hold the key
+    count: usize,
+    /// is a queued message parked until the key is released
+    waited: bool,
+}
+
+/// A shard of the active keys, and the waited ones released since the queue
+/// took them
+#[derive(Debug)]
+pub(crate) struct ActiveTable<K: Key> {
+    /// active keys, a key is absent when nothing holds it
+    keys: HashMap<K, ActiveKey>,
+    /// released keys that queued messages wait for, taken by the queue
+    /// to make those messages ready again, with the order they are released
+    freed: Vec<(u64, K)>,
+    /// feed of released keys, if the receiver listens on it, the same one
+    /// in every shard
+    feed: Option<Arc<Feed<K>>>,
+}
+
+impl<K: Key> ActiveTable<K> {
+    /// is the key held by a received message or a key guard
+    pub(crate) fn is_active<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.keys.contains_key(key)
+    }
+}
+
+/// The active keys, sharded by key hash, each shard behind its own lock
+#[derive(Debug)]
+pub(crate) struct ActiveKeys<K: Key> {
+    /// the locked shards
+    pub(crate) shards: Box<[Mutex<ActiveTable<K>>]>,
+    /// hasher that picks the shard of a key
+    hasher: RandomState,
+    /// is any waited key released since the queue took them, so the queue
+    /// looks into the shards only then
+    freed: AtomicBool,
+    /// order of the next released waited key, so the queue makes the messages
+    /// that wait for them ready in the release order across shards
+    next_freed: AtomicU64,
+}
+
+impl<K: Key> ActiveKeys<K> {
+    /// new empty shards, preallocated to `cap` keys in all
+    pub(crate) fn new(cap: usize) -> Self {
+        let per_shard = cap / SHARDS;
+        ActiveKeys {
+            shards: (0..SHARDS)
+                .map(|_| {
+                    Mutex::new(ActiveTable {
+                        keys: HashMap::with_capacity(per_shard),
+                        freed: Vec::new(),
+                        feed: None,
+                    })
+                })
+                .collect(),
+            hasher: RandomState::new(),
+            freed: AtomicBool::new(false),
+            next_freed: AtomicU64::new(0),
+        }
+    }
+
+    /// index of the shard of `key`, the key's `Hash` runs before any lock
+    pub(crate) fn shard_of<Q>(&self, key: &Q) -> usize
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let [low, ..] = self.hasher.hash_one(key).to_le_bytes();
+        usize::from(low) % SHARDS
+    }
+
+    /// lock a shard, user code under it is contained, so it's never poisoned
+    pub(crate) fn lock(&self, shard: usize) -> MutexGuard<'_, ActiveTable<K>> {
+        lock(unwrap_some_or!(self.shards.get(shard), panic!("fatal error")))
+    }
+
+    /// run `f` with the shard of `key` locked
+    ///
+    /// `f` runs the key's `Eq`, a panic is resumed after unlocking the shard.
+    fn with_shard<Q, R>(&self, key: &Q, f: impl FnOnce(&mut ActiveTable<K>) -> R) -> R
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let mut table = self.lock(self.shard_of(key));
+        let done = contain(|| f(&mut table));
+        drop(table);
+        unwrap_ok_or!(done, payload, std::panic::resume_unwind(payload))
+    }
+
+    /// is the key held by a received message or a key guard
+    #[cfg(any(feature = "async", test))]
+    pub(crate) fn is_active<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.with_shard(key, |table| table.is_active(key))
+    }
+
+    /// activate a key once more, `waited` if a queued message is parked on it
+    pub(crate) fn activate(&self, key: K, waited: bool) {
+        let shard = self.shard_of(&key);
+        let mut table = self.lock(shard);
+        let done = contain(|| {
+            let state = table
+                .keys
+                .entry(key)
+                .or_insert(ActiveKey { count: 0, waited: false });
+            state.count =
+                unwrap_some_or!(state.count.checked_add(1), panic!("fatal error"));
+            state.waited |= waited;
+        });
+        drop(table);
+        unwrap_ok_or!(done, payload, std::panic::resume_unwind(payload));
+    }
+
+    /// activate all `keys` if none of them is active, or mark the first active
+    /// one as waited by a queued message and activate none, return whether
+    /// they are activated
+    pub(crate) fn try_activate(&self, keys: &[K]) -> bool {
+        for (i, key) in keys.iter().enumerate() {
+            let activated = contain(|| {
+                self.with_shard(key, |table| {
+                    if let Some(state) = table.keys.get_mut(key) {
+                        state.waited = true;
+                        return false;
+                    }
+                    let state = ActiveKey { count: 1, waited: false };
+                    let _none = table.keys.insert(key.clone(), state);
+                    true
+                })
+            });
+            if matches!(activated, Ok(true)) {
+                continue;
+            }
+            // nothing waits for the keys activated just now, they are dropped
+            for k in keys.iter().take(i) {
+                let _waited = self.deactivate(self.shard_of(k), k);
+            }
+            return unwrap_ok_or!(activated, payload, std::panic::resume_unwind(payload));
+        }
+        true
+    }
+
+    /// mark the key as waited by a queued message if it's active,
+    /// return whether it's active
+    pub(crate) fn wait_if_active<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.with_shard(key, |table| {
+            let state = unwrap_some_or!(table.keys.get_mut(key), return false);
+            state.waited = true;
+            true
+        })
+    }
+
+    /// release an active key once in its shard, return whether it's free now
+    /// and a queued message waits for it, and the feed of released keys
+    pub(crate) fn deactivate<Q>(
+        &self, shard: usize, key: &Q,
+    ) -> (bool, Option<Arc<Feed<K>>>)
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let mut table = self.lock(shard);
+        let done = contain(|| {
+            let state = unwrap_some_or!(table.keys.get_mut(key), return false);
+            state.count =
+                unwrap_some_or!(state.count.checked_sub(1), panic!("fatal error"));
+            if state.count != 0 {
+                return false;
+            }
+            let (freed, removed) =
+                unwrap_some_or!(table.keys.remove_entry(key), return false);
+            if removed.waited {
+                let order = self.next_freed.fetch_add(1, Ordering::Relaxed);
+                table.freed.push((order, freed));
+            }
+            removed.waited
+        });
+        let feed = table.feed.clone();
+        drop(table);
+        let waited = unwrap_ok_or!(done, payload, std::panic::resume_unwind(payload));
+        if waited {
+            // set after the key is put in its shard, the queue takes it then
+            self.freed.store(true, Ordering::Release);
+        }
+        (waited, feed)
+    }
+
+    /// take the waited keys released since the last call
+    pub(crate) fn take_freed(&self) -> Vec<K> {
+        // loaded first, the flag is mostly unset and a load is cheaper
+        if !self.freed.load(Ordering::Relaxed)
+            || !self.freed.swap(false, Ordering::Acquire)
+        {
+            return Vec::new();
+        }
+        let mut freed = Vec::new();
+        for shard in 0..self.shards.len() {
+            freed.append(&mut self.lock(shard).freed);
+        }
+        freed.sort_unstable_by_key(|&(order, _)| order);
+        freed.into_iter().map(|(_, key)| key).collect()
+    }
+
+    /// the active keys that match `pred`, looked up one shard at a time
+    pub(crate) fn keys_where(&self, mut pred: impl FnMut(&K) -> bool) -> Vec<K> {
+        let mut keys = Vec::new();
+        for shard in 0..self.shards.len() {
+            let table = self.lock(shard);
+            let done = contain(|| {
+                keys.extend(
+                    table
+                        .keys
+                        .keys()
+                        .filter(|&key| pred(key))
+                        .cloned(),
+                );
+            });
+            drop(table);
+            unwrap_ok_or!(done, payload, std::panic::resume_unwind(payload));
+        }
+        keys
+    }
+
+    /// the feed of released keys
+    pub(crate) fn feed(&self) -> Option<Arc<Feed<K>>> {
+        self.lock(0).feed.clone()
+    }
+
+    /// listen on released keys with `feed` in every shard, replacing the
+    /// previous one
+    pub(crate) fn set_feed(&self, feed: &Arc<Feed<K>>) {
+        for shard in 0..self.shards.len() {
+            self.lock(shard).feed = Some(Arc::clone(feed));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::ActiveKeys;
+
+    #[test]
+    fn test_try_activate_none_if_any_active() {
+        let active = ActiveKeys::new(16);
+        active.activate(3_u32, false);
+        assert!(!active.try_activate(&[1, 2, 3]));
+        // the keys activated before the active one are rolled back
+        assert!(!active.is_active(&1));
+        assert!(!active.is_active(&2));
+        // the active key is waited, its release frees it for the queue
+        assert!(active.deactivate(active.shard_of(&3), &3).0);
+        assert_eq!(active.take_freed(), vec![3]);
+        assert!(active.try_activate(&[1, 2, 3]));
+        assert!(active.is_active(&3));
+    }
+
+    #[test]
+    fn test_freed_in_release_order() {
+        // keys of different shards are taken in the order they are released
+        let active = ActiveKeys::new(16);
+        for key in 0_u32..64 {
+            active.activate(key, true);
+        }
+        for key in (0_u32..64).rev() {
+            let _waited = active.deactivate(active.shard_of(&key), &key);
+        }
+        assert_eq!(active.take_freed(), (0_u32..64).rev().collect::<Vec<_>>());
+        assert!(active.take_freed().is_empty());
+    }
+}
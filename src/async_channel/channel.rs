@@ -1,28 +1,62 @@
 //! Async mpsc channel that support key conflict resolution
 
 use super::shared::Shared;
-use super::Message;
-use crate::buff::{KeyedBuff, State};
-use crate::err::{RecvError, SendError};
+use super::{KeyLock, Message};
+use crate::active::ActiveKeys;
+use crate::buff::{SenderCount, State};
+use crate::completions::{Completions, Feed};
+use crate::err::{
+    RecvError, RecvFilterError, RecvOr, RecvTimeoutError, SendError, SendTimeoutError,
+    TryRecvError, TryReserveError, TrySendError,
+};
+use crate::lock::{lock, lock_or_recover, Mutex};
 use crate::message::Key;
-use crate::{unwrap_ok_or, unwrap_some_or};
+use crate::throttle::KeyThrottle;
+use crate::unwrap_ok_or;
+#[cfg(feature = "sink")]
+use crate::unwrap_some_or;
 #[cfg(feature = "event_listener")]
 use event_listener::Event;
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
 use std::fmt::Debug;
-use std::sync::{Arc, Mutex};
-#[cfg(not(feature = "event_listener"))]
+use std::future::Future;
+use std::panic::RefUnwindSafe;
+#[cfg(feature = "sink")]
+use std::panic::UnwindSafe;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll, Wake, Waker};
+use tokio::sync::futures::Notified;
+#[cfg(feature = "sink")]
+use tokio::sync::AcquireError;
 use tokio::sync::Notify;
+use tokio::sync::OwnedSemaphorePermit;
 use tokio::sync::Semaphore;
+use tokio::time::{Duration, Instant};
 
 /// A bounded sender that will wait when there is no empty buff slot
+///
+/// With the `sink` feature, it's a `futures_sink::Sink` too.
 #[derive(Debug)]
 pub struct BoundedSender<K: Key, V> {
     /// inner shared queue
     inner: Arc<Shared<K, V>>,
+    /// the buff slot reserved by `Sink::poll_ready`
+    #[cfg(feature = "sink")]
+    reservation: Reservation,
 }
 
-impl<K: Key, V: Debug> BoundedSender<K, V> {
+impl<K: Key, V> BoundedSender<K, V> {
+    /// new a sender that is already counted
+    fn new(inner: Arc<Shared<K, V>>) -> Self {
+        Self {
+            inner,
+            #[cfg(feature = "sink")]
+            reservation: Reservation::default(),
+        }
+    }
+
     /// send a message
     /// # Errors
     ///
@@ -33,77 +67,1054 @@ impl<K: Key, V: Debug> BoundedSender<K, V> {
     ) -> Result<(), SendError<Message<K, V>>> {
         self.inner.send(message).await
     }
+
+    /// send a message, wait at most `dur` for an empty buff slot
+    /// # Errors
+    ///
+    /// return `Err(SendTimeoutError::Timeout)` if no slot is freed in `dur`,
+    /// `Err(SendTimeoutError::Disconnected)` if channel is disconnected
+    #[inline]
+    pub async fn send_timeout(
+        &self, message: Message<K, V>, dur: Duration,
+    ) -> Result<(), SendTimeoutError<Message<K, V>>> {
+        // an unrepresentable deadline waits forever
+        self.inner
+            .send_deadline(message, Instant::now().checked_add(dur))
+            .await
+    }
+
+    /// send a message from outside of an async runtime, block the current
+    /// thread until there is an empty buff slot
+    /// # Errors
+    ///
+    /// return `Err` if channel is disconnected
+    /// # Panics
+    ///
+    /// Panics if called within an asynchronous execution context,
+    /// blocking a runtime thread may stall the receiver.
+    #[inline]
+    pub fn blocking_send(
+        &self, message: Message<K, V>,
+    ) -> Result<(), SendError<Message<K, V>>> {
+        assert!(
+            tokio::runtime::Handle::try_current().is_err(),
+            "`blocking_send` is called within an asynchronous execution context"
+        );
+        block_on(self.inner.send(message))
+    }
+
+    /// send a message without waiting, fail if the buffer is full
+    /// # Errors
+    ///
+    /// return `Err(TrySendError::Full)` if buffer is full,
+    /// `Err(TrySendError::Disconnected)` if channel is disconnected
+    #[inline]
+    pub fn try_send(
+        &self, message: Message<K, V>,
+    ) -> Result<(), TrySendError<Message<K, V>>> {
+        self.inner.try_send(message)
+    }
+
+    /// wait for an empty buff slot and reserve it, so a message can be
+    /// constructed and sent later without waiting
+    ///
+    /// The slot is given back if the permit is dropped without sending.
+    /// # Errors
+    ///
+    /// return `Err` if channel is disconnected
+    #[inline]
+    pub async fn reserve(&self) -> Result<SendPermit<'_, K, V>, SendError<()>> {
+        let permit = self.inner.reserve().await?;
+        Ok(SendPermit { inner: &self.inner, permit })
+    }
+
+    /// like [`BoundedSender::reserve`], but the permit owns the sender, so it can
+    /// be moved to another task, use a clone to keep sending here
+    /// # Errors
+    ///
+    /// return `Err` if channel is disconnected, the sender is dropped
+    #[inline]
+    pub async fn reserve_owned(self) -> Result<OwnedSendPermit<K, V>, SendError<()>> {
+        let permit = self.inner.reserve().await?;
+        Ok(OwnedSendPermit { sender: self, permit })
+    }
+
+    /// reserve an empty buff slot without waiting, the permit is the same
+    /// as the one of [`BoundedSender::reserve`]
+    /// # Errors
+    ///
+    /// return `Err(TryReserveError::Full)` if buffer is full,
+    /// `Err(TryReserveError::Disconnected)` if channel is disconnected
+    #[inline]
+    pub fn try_reserve(&self) -> Result<SendPermit<'_, K, V>, TryReserveError> {
+        let permit = self.inner.try_reserve()?;
+        Ok(SendPermit { inner: &self.inner, permit })
+    }
+
+    /// wait until the channel has room for at least `n` messages
+    ///
+    /// This is advisory, not a reservation: the room is not held for the caller,
+    /// so other senders may legitimately take some of it before the caller sends.
+    /// While waiting, the request queues fairly with pending `send`s, so senders
+    /// that start waiting later will not overtake it.
+    /// # Errors
+    ///
+    /// return `Err` if channel is disconnected
+    /// # Panics
+    ///
+    /// panic if `n` is greater than the capacity of channel
+    #[inline]
+    pub async fn wait_capacity(&self, n: usize) -> Result<(), SendError<()>> {
+        self.inner.wait_capacity(n).await
+    }
+
+    /// create a sender that allows at most `n` of its own messages in flight
+    /// for each key, other senders of the channel are not affected
+    ///
+    /// A message is in flight from the time it's sent until it's dropped,
+    /// whether it is still buffered or held by the receiver.
+    /// # Panics
+    ///
+    /// panic if `n` is zero
+    #[inline]
+    #[must_use]
+    pub fn with_key_throttle(&self, n: usize) -> ThrottledSender<K, V>
+    where
+        K: Send + Sync + 'static,
+    {
+        ThrottledSender { sender: self.clone(), throttle: Arc::new(KeyThrottle::new(n)) }
+    }
+
+    /// number of empty buff slots, i.e. messages that can be sent without waiting
+    ///
+    /// Slots reserved by permits are not empty, so it plus [`BoundedSender::len`]
+    /// is [`BoundedSender::max_capacity`] when nothing is reserved. It's a
+    /// snapshot as `len`.
+    #[inline]
+    #[must_use]
+    pub fn capacity(&self) -> usize {
+        self.inner.capacity()
+    }
+
+    /// capacity of the channel, given to [`bounded`]
+    #[inline]
+    #[must_use]
+    pub fn max_capacity(&self) -> usize {
+        self.inner.cap
+    }
+
+    /// number of queued messages, not including those held by the receiver
+    ///
+    /// It's a snapshot, other senders and the receiver may change it at once.
+    #[inline]
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.inner.len()
+    }
+
+    /// is no message queued, a snapshot as [`BoundedSender::len`]
+    #[inline]
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// is the buffer full, a snapshot as [`BoundedSender::len`]
+    #[inline]
+    #[must_use]
+    pub fn is_full(&self) -> bool {
+        let state = lock(&self.inner.state);
+        state.buff.is_full()
+    }
+
+    /// do both senders send to the same channel
+    #[inline]
+    #[must_use]
+    pub fn same_channel(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.inner, &other.inner)
+    }
+
+    /// is the receiver gone, checked without sending a message
+    ///
+    /// It's racy, the receiver may be dropped right after `false` is returned,
+    /// so `send` can still fail.
+    #[inline]
+    #[must_use]
+    pub fn is_disconnected(&self) -> bool {
+        let state = lock(&self.inner.state);
+        state.disconnected
+    }
+
+    /// is the channel closed by the receiver, i.e. it's dropped or `close` is called
+    ///
+    /// It's cheaper than [`BoundedSender::is_disconnected`], without locking
+    /// the channel, so it can be checked before building a costly message.
+    #[inline]
+    #[must_use]
+    pub fn is_closed(&self) -> bool {
+        self.inner.is_closed()
+    }
+
+    /// wait until the receiver is dropped or closes the channel, it resolves
+    /// immediately if it's already closed
+    #[inline]
+    pub async fn closed(&self) {
+        self.inner.closed().await;
+    }
+
+    /// is the receiver not dropped yet
+    ///
+    /// Unlike [`BoundedSender::is_disconnected`], a receiver that is closed
+    /// but still draining the buffer is alive.
+    #[inline]
+    #[must_use]
+    pub fn receiver_alive(&self) -> bool {
+        let state = lock(&self.inner.state);
+        !state.receiver_dropped
+    }
+}
+
+/// A sender that limits its in-flight messages per key,
+/// created by [`BoundedSender::with_key_throttle`]
+#[derive(Debug)]
+pub struct ThrottledSender<K: Key, V> {
+    /// the underlying sender
+    sender: BoundedSender<K, V>,
+    /// in-flight messages of this sender
+    throttle: Arc<KeyThrottle<K>>,
+}
+
+impl<K: Key + Send + Sync + 'static, V> ThrottledSender<K, V> {
+    /// send a message, wait while any key of it reaches the in-flight limit
+    /// or there is no empty buff slot
+    /// # Errors
+    ///
+    /// return `Err` if channel is disconnected
+    #[inline]
+    pub async fn send(
+        &self, mut message: Message<K, V>,
+    ) -> Result<(), SendError<Message<K, V>>> {
+        let keys = message.key().get_owned_keys();
+        self.throttle.acquire(&keys).await;
+        message.set_hook(self.throttle.release_hook(keys));
+        self.sender.send(message).await.map_err(|err| {
+            err.map(|mut unsent| {
+                if let Some(release) = unsent.take_hook() {
+                    release();
+                }
+                unsent
+            })
+        })
+    }
 }
 
 impl<K: Key, V> Clone for BoundedSender<K, V> {
     #[inline]
     fn clone(&self) -> Self {
-        let mut state = unwrap_ok_or!(self.inner.state.lock(), err, panic!("{:?}", err));
-        let n_senders = state.n_senders;
-        state.n_senders =
-            unwrap_some_or!(n_senders.checked_add(1), panic!("too many senders"));
-        drop(state);
-        Self { inner: Arc::clone(&self.inner) }
+        self.inner.senders.add();
+        Self::new(Arc::clone(&self.inner))
     }
 }
 
 impl<K: Key, V> Drop for BoundedSender<K, V> {
     #[inline]
     fn drop(&mut self) {
-        let mut state = unwrap_ok_or!(self.inner.state.lock(), err, panic!("{:?}", err));
-        let mut last_sender = false;
-        let n_senders = state.n_senders;
-        state.n_senders =
-            unwrap_some_or!(n_senders.checked_sub(1), panic!("too many senders"));
-        if state.n_senders == 0 {
-            last_sender = true;
-            state.disconnected = true;
-        }
-        drop(state);
-        if last_sender {
-            #[cfg(not(feature = "event_listener"))]
-            self.inner.notify_receiver.notify_one();
-            #[cfg(feature = "event_listener")]
-            self.inner.notify_receiver.notify(1);
+        self.inner.release_sender();
+    }
+}
+
+/// An unbounded sender created by [`unbounded`], sending never waits
+#[derive(Debug)]
+pub struct UnboundedSender<K: Key, V> {
+    /// inner shared queue
+    inner: Arc<Shared<K, V>>,
+}
+
+impl<K: Key, V> UnboundedSender<K, V> {
+    /// send a message without waiting, there is no capacity limit
+    /// # Errors
+    ///
+    /// return `Err` if channel is disconnected
+    #[inline]
+    pub fn send(&self, message: Message<K, V>) -> Result<(), SendError<Message<K, V>>> {
+        self.inner.send_unbounded(message)
+    }
+
+    /// do the senders belong to the same channel
+    #[inline]
+    #[must_use]
+    pub fn same_channel(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.inner, &other.inner)
+    }
+
+    /// is the channel closed by the receiver, i.e. it's dropped or `close` is called
+    #[inline]
+    #[must_use]
+    pub fn is_closed(&self) -> bool {
+        self.inner.is_closed()
+    }
+
+    /// wait until the receiver is dropped or closes the channel, it resolves
+    /// immediately if it's already closed
+    #[inline]
+    pub async fn closed(&self) {
+        self.inner.closed().await;
+    }
+}
+
+impl<K: Key, V> Clone for UnboundedSender<K, V> {
+    #[inline]
+    fn clone(&self) -> Self {
+        self.inner.senders.add();
+        Self { inner: Arc::clone(&self.inner) }
+    }
+}
+
+impl<K: Key, V> Drop for UnboundedSender<K, V> {
+    #[inline]
+    fn drop(&mut self) {
+        self.inner.release_sender();
+    }
+}
+
+/// a future acquiring a buff slot
+#[cfg(feature = "sink")]
+type AcquireSlot = Pin<
+    Box<dyn Future<Output = Result<OwnedSemaphorePermit, AcquireError>> + Send + Sync>,
+>;
+
+/// A buff slot reserved by `Sink::poll_ready`, the permit is given back
+/// when it's dropped without sending a message
+#[cfg(feature = "sink")]
+#[derive(Default)]
+struct Reservation {
+    /// the pending acquisition, kept between polls
+    acquiring: Option<AcquireSlot>,
+    /// the acquired slot
+    permit: Option<OwnedSemaphorePermit>,
+}
+
+// the acquisition is only polled by the owning sender, and tokio semaphores
+// are consistent after a panic
+#[cfg(feature = "sink")]
+impl UnwindSafe for Reservation {}
+#[cfg(feature = "sink")]
+impl RefUnwindSafe for Reservation {}
+
+#[cfg(feature = "sink")]
+impl Debug for Reservation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Reservation")
+            .field("acquiring", &self.acquiring.is_some())
+            .field("permit", &self.permit)
+            .finish()
+    }
+}
+
+/// Sending messages through `Sink`, e.g. `StreamExt::forward`, the messages are
+/// dropped if the channel is disconnected after a slot is reserved
+#[cfg(feature = "sink")]
+impl<K: Key, V> futures_sink::Sink<Message<K, V>> for BoundedSender<K, V> {
+    type Error = SendError<()>;
+
+    #[inline]
+    fn poll_ready(
+        self: Pin<&mut Self>, cx: &mut Context<'_>,
+    ) -> Poll<Result<(), Self::Error>> {
+        let this = self.get_mut();
+        let reservation = &mut this.reservation;
+        if reservation.permit.is_some() {
+            return Poll::Ready(Ok(()));
         }
+        let acquiring = reservation.acquiring.get_or_insert_with(|| {
+            Box::pin(Arc::clone(&this.inner.slots).acquire_owned())
+        });
+        let acquired = match acquiring.as_mut().poll(cx) {
+            Poll::Ready(acquired) => acquired,
+            Poll::Pending => return Poll::Pending,
+        };
+        reservation.acquiring = None;
+        // semaphore is closed when receiver is gone
+        let permit = unwrap_ok_or!(acquired, _, return Poll::Ready(Err(SendError(()))));
+        reservation.permit = Some(permit);
+        Poll::Ready(Ok(()))
+    }
+
+    #[inline]
+    fn start_send(self: Pin<&mut Self>, item: Message<K, V>) -> Result<(), Self::Error> {
+        let this = self.get_mut();
+        let permit = unwrap_some_or!(
+            this.reservation.permit.take(),
+            panic!("start_send without a successful poll_ready")
+        );
+        this.inner
+            .send_with_permit(item, permit)
+            .map_err(|_unsent| SendError(()))
+    }
+
+    #[inline]
+    fn poll_flush(
+        self: Pin<&mut Self>, _cx: &mut Context<'_>,
+    ) -> Poll<Result<(), Self::Error>> {
+        // a message is visible to the receiver once it's sent
+        Poll::Ready(Ok(()))
+    }
+
+    #[inline]
+    fn poll_close(
+        self: Pin<&mut Self>, _cx: &mut Context<'_>,
+    ) -> Poll<Result<(), Self::Error>> {
+        // the channel is disconnected when all senders are dropped, not closed
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// A reserved buff slot, created by [`BoundedSender::reserve`]
+///
+/// The slot is given back when it's dropped without sending a message.
+#[derive(Debug)]
+#[must_use = "the reserved slot is given back if the permit is dropped"]
+pub struct SendPermit<'a, K: Key, V> {
+    /// inner shared queue
+    inner: &'a Shared<K, V>,
+    /// the reserved slot
+    permit: OwnedSemaphorePermit,
+}
+
+impl<K: Key, V> SendPermit<'_, K, V> {
+    /// send a message into the reserved slot without waiting
+    /// # Errors
+    ///
+    /// return `Err` if channel is disconnected after the slot is reserved
+    #[inline]
+    pub fn send(self, message: Message<K, V>) -> Result<(), SendError<Message<K, V>>> {
+        self.inner
+            .send_with_permit(message, self.permit)
+    }
+}
+
+/// A reserved buff slot owning its sender, created by
+/// [`BoundedSender::reserve_owned`]
+///
+/// The slot is given back when it's dropped without sending a message,
+/// and so is the sender.
+#[derive(Debug)]
+#[must_use = "the reserved slot is given back if the permit is dropped"]
+pub struct OwnedSendPermit<K: Key, V> {
+    /// the sender that reserved the slot, still counted as a live sender
+    sender: BoundedSender<K, V>,
+    /// the reserved slot
+    permit: OwnedSemaphorePermit,
+}
+
+impl<K: Key, V> OwnedSendPermit<K, V> {
+    /// send a message into the reserved slot without waiting,
+    /// and get the sender back
+    /// # Errors
+    ///
+    /// return `Err` if channel is disconnected after the slot is reserved
+    #[inline]
+    pub fn send(
+        self, message: Message<K, V>,
+    ) -> Result<BoundedSender<K, V>, SendError<Message<K, V>>> {
+        let OwnedSendPermit { sender, permit } = self;
+        sender.inner.send_with_permit(message, permit)?;
+        Ok(sender)
     }
 }
 
 /// A sync receiver will wait when buff is empty
+///
+/// The receiver is the single consumer, it's `Send` but not `Sync`
+/// ```compile_fail
+/// fn assert_sync<T: Sync>() {}
+/// assert_sync::<kv_mpsc::async_channel::Receiver<u32, u32>>();
+/// ```
+/// so its futures are not `Send`, a receiving task is spawned with
+/// `tokio::task::spawn_local` or runs on its own thread, see [`crate::bridge`].
+/// A value that is not `Send` never crosses tasks
+/// ```compile_fail
+/// use std::rc::Rc;
+/// let (tx, _rx) = kv_mpsc::async_channel::bounded::<u32, Rc<u32>>(1);
+/// tokio::spawn(async move { drop(tx) });
+/// ```
 #[derive(Debug)]
 pub struct Receiver<K: Key, V> {
     /// shared FIFO queue
     inner: Arc<Shared<K, V>>,
+    /// how long `recv_wait` may wait on all conflict messages in debug builds
+    conflict_wait_limit: Cell<Option<Duration>>,
     /// remove the auto `Sync` implentation, so only one
     /// thread can access the receiver
     _marker: std::marker::PhantomData<RefCell<()>>,
 }
 
-impl<K: Key, V: Debug> Receiver<K, V> {
-    /// receive a message
+impl<K: Key, V> Receiver<K, V> {
+    /// faults injected into notifying the receiver
+    #[cfg(all(test, feature = "fault-injection"))]
+    pub(crate) fn faults(&self) -> &crate::fault::Faults {
+        &self.inner.faults
+    }
+
+    /// receive a message, the returned [`RecvFuture`] can be named and stored
+    /// # Errors
+    ///
+    /// return `Err` if channel is all sender gone
+    /// # Cancel safety
+    ///
+    /// It's cancel-safe, e.g. used in `tokio::select!`. Every poll checks the
+    /// buffer before waiting, and a message is removed only when it's returned,
+    /// so dropping the future loses no message and no wakeup, the next `recv`
+    /// finds what was sent in between.
+    #[inline]
+    pub fn recv(&self) -> RecvFuture<'_, K, V> {
+        RecvFuture { receiver: self }
+    }
+
+    /// receive a message from outside of an async runtime, block the current
+    /// thread until there is a message, like [`Receiver::recv`]
+    ///
+    /// It's woken by the same waker as `poll_recv`, so it works with either
+    /// notifier and needs no runtime handle.
+    /// # Errors
+    ///
+    /// return `Err` if channel is all sender gone
+    /// # Panics
+    ///
+    /// Panics if called within an asynchronous execution context,
+    /// blocking a runtime thread may stall the senders.
+    #[inline]
+    pub fn blocking_recv(&self) -> Result<Message<K, V>, RecvError> {
+        assert!(
+            tokio::runtime::Handle::try_current().is_err(),
+            "`blocking_recv` is called within an asynchronous execution context"
+        );
+        block_on(self.recv())
+    }
+
+    /// poll to receive a message, the primitive of [`Receiver::recv`] for
+    /// hand-written futures and state machines
+    ///
+    /// It returns `Pending` only when the buffer is empty and the channel is
+    /// connected, then the task of `cx` is woken when a message is sent, a key
+    /// is released or the last sender is dropped. Only the waker of the latest
+    /// call is kept, as there is a single receiver.
+    ///
+    /// It's cancel-safe: a message is removed from the buffer only when it's
+    /// returned in `Ready(Ok(_))`, so stopping polling after `Pending` loses
+    /// nothing, the registered waker is just woken in vain later.
+    /// # Errors
+    ///
+    /// return `Err` if channel is all sender gone
+    #[inline]
+    pub fn poll_recv(
+        &self, cx: &mut Context<'_>,
+    ) -> Poll<Result<Message<K, V>, RecvError>> {
+        self.inner.poll_recv(cx).map_ok(|mut msg| {
+            msg.set_shared(Arc::<Shared<K, V>>::clone(&self.inner));
+            msg
+        })
+    }
+
+    /// receive a message without waiting
+    /// # Errors
+    ///
+    /// return `Err(TryRecvError::Empty)` if buffer is empty, or the corresponding
+    /// error if channel is all sender gone or all messages conflict
+    #[inline]
+    pub fn try_recv(&self) -> Result<Message<K, V>, TryRecvError> {
+        self.inner.try_recv().map(|mut msg| {
+            msg.set_shared(Arc::<Shared<K, V>>::clone(&self.inner));
+            msg
+        })
+    }
+
+    /// receive a message, wait at most `dur` when buffer is empty
+    ///
+    /// Unlike wrapping [`Receiver::recv`] in `tokio::time::timeout`, conflict is
+    /// told apart from timeout, and the buffer is checked once more when the
+    /// deadline is reached, so a message sent right before it is returned.
+    /// # Errors
+    ///
+    /// return `Err(RecvTimeoutError::Timeout)` if no message is received in `dur`,
+    /// or the corresponding error if channel is all sender gone or all messages conflict
+    #[inline]
+    pub async fn recv_timeout(
+        &self, dur: Duration,
+    ) -> Result<Message<K, V>, RecvTimeoutError> {
+        // an unrepresentable deadline waits forever
+        let deadline = Instant::now().checked_add(dur);
+        match self.inner.recv_deadline(deadline).await? {
+            Some(mut msg) => {
+                msg.set_shared(Arc::<Shared<K, V>>::clone(&self.inner));
+                Ok(msg)
+            }
+            None => Err(RecvTimeoutError::Timeout),
+        }
+    }
+
+    /// receive a message like [`Receiver::recv`], but wait instead of returning
+    /// `AllConflict`, until a key is released or a message without conflict is sent
+    ///
+    /// It waits forever if the conflicting keys are held by the caller itself,
+    /// e.g. received messages that are only dropped after it returns. In debug
+    /// builds, [`Receiver::set_conflict_wait_limit`] turns such a wait into a panic.
     /// # Errors
     ///
     /// return `Err` if channel is all sender gone
+    /// # Panics
+    ///
+    /// Panics in debug builds if all queued messages conflict longer than the
+    /// limit set by `set_conflict_wait_limit`.
+    #[inline]
+    pub async fn recv_wait(&self) -> Result<Message<K, V>, RecvError> {
+        loop {
+            let limit = self.conflict_wait_limit.get();
+            // an unrepresentable deadline waits forever
+            let deadline = limit.and_then(|dur| Instant::now().checked_add(dur));
+            if let Some(mut msg) = self.inner.recv_wait(deadline).await? {
+                msg.set_shared(Arc::<Shared<K, V>>::clone(&self.inner));
+                return Ok(msg);
+            }
+            // an empty buffer is just idle
+            debug_assert!(
+                !self.inner.all_conflict(),
+                "`recv_wait` waits longer than {:?} while all messages conflict, \
+                 are their keys held by the caller?",
+                limit
+            );
+        }
+    }
+
+    /// let [`Receiver::recv_wait`] panic if all queued messages conflict for
+    /// longer than `limit`, a likely deadlock, `None` to wait forever
+    ///
+    /// It's a debugging aid, the limit is ignored in release builds.
+    #[inline]
+    pub fn set_conflict_wait_limit(&self, limit: Option<Duration>) {
+        self.conflict_wait_limit.set(limit);
+    }
+
+    /// receive a message, or give up waiting once `until` completes
+    ///
+    /// A buffered message is always preferred over `until`. It's cancel-safe,
+    /// a message is either returned or left in the channel.
+    /// # Errors
+    ///
+    /// return `Err(RecvOr::Cancelled)` if `until` completes while waiting,
+    /// or the corresponding error if channel is all sender gone or all messages conflict
+    #[inline]
+    pub async fn recv_or<F>(&self, until: F) -> Result<Message<K, V>, RecvOr>
+    where
+        F: Future<Output = ()>,
+    {
+        let biased = Biased { first: Box::pin(self.recv()), second: Box::pin(until) };
+        match biased.await {
+            Some(res) => res.map_err(RecvOr::from),
+            None => Err(RecvOr::Cancelled),
+        }
+    }
+
+    /// receive the front-most message that matches `pred` and doesn't conflict,
+    /// other messages are left in the channel, wait when buffer is empty
+    /// # Errors
+    ///
+    /// return `Err(RecvFilterError::NoMatch)` if no buffered message matches,
+    /// `Err(RecvFilterError::AllConflict)` if all matching messages conflict,
+    /// or `Err(RecvFilterError::Disconnected)` if channel is all sender gone
+    #[inline]
+    pub async fn recv_filter<P>(&self, pred: P) -> Result<Message<K, V>, RecvFilterError>
+    where
+        P: FnMut(&Message<K, V>) -> bool,
+    {
+        self.inner
+            .recv_filter(pred)
+            .await
+            .map(|mut msg| {
+                msg.set_shared(Arc::<Shared<K, V>>::clone(&self.inner));
+                msg
+            })
+    }
+
+    /// receive the front-most message with `key`, wait until such a message
+    /// is sent and the key is not active
+    ///
+    /// Other messages are left in the channel.
+    /// # Errors
+    ///
+    /// return `Err` if channel is all sender gone before a message with `key` is sent
     #[inline]
-    pub async fn recv(&self) -> Result<Message<K, V>, RecvError> {
-        self.inner.recv().await.map(|mut msg| {
+    pub async fn recv_key(&self, key: &K) -> Result<Message<K, V>, RecvError> {
+        self.inner.recv_key(key).await.map(|mut msg| {
             msg.set_shared(Arc::<Shared<K, V>>::clone(&self.inner));
             msg
         })
     }
 
-    /// print stats
-    #[cfg(feature = "profile")]
+    /// receive up to `limit` messages into `buf` at once, wait until at least
+    /// one is receivable, return the number of received messages
+    ///
+    /// Messages are taken under a single lock, in the order [`Receiver::recv`]
+    /// would return them, and none of them conflict with each other, so their
+    /// keys are all activated. Unlike `recv`, it waits instead of returning
+    /// `AllConflict`, so messages held from a previous batch must be dropped
+    /// before calling it again, or it may wait forever.
+    ///
+    /// Zero is returned if the channel is disconnected and drained, or `limit`
+    /// is zero. It's cancel-safe like `recv`, a batch is taken in a single poll.
+    #[inline]
+    pub async fn recv_many(&self, buf: &mut Vec<Message<K, V>>, limit: usize) -> usize {
+        if limit == 0 {
+            return 0;
+        }
+        let (msgs, panicked) =
+            unwrap_ok_or!(self.inner.recv_many(limit).await, _, return 0);
+        let received = msgs.len();
+        buf.extend(msgs.into_iter().map(|mut msg| {
+            msg.set_shared(Arc::<Shared<K, V>>::clone(&self.inner));
+            msg
+        }));
+        if let Some(payload) = panicked {
+            std::panic::resume_unwind(payload);
+        }
+        received
+    }
+
+    /// wait until `key` is not held by any received message or key lock
+    ///
+    /// It resolves at once if the key is not active, even if it has never
+    /// been. A key activated again before the task is polled is waited for again.
+    #[inline]
+    pub fn key_released(&self, key: K) -> KeyReleasedFuture<'_, K, V> {
+        KeyReleasedFuture { inner: &self.inner, key, notified: None }
+    }
+
+    /// turn into a stream of messages grouped by key, see [`GroupedStream`]
+    #[cfg(feature = "stream")]
+    #[inline]
+    #[must_use]
+    pub fn into_grouped_stream(self) -> GroupedStream<K, V> {
+        GroupedStream { receiver: self }
+    }
+
+    /// look at the message [`Receiver::recv`] would receive, without removing it
+    /// or activating its keys, wait when buffer is empty
+    ///
+    /// A lock can't be held across `.await`, so a copy is returned, it's not
+    /// attached to the channel and dropping it releases nothing.
+    /// # Errors
+    ///
+    /// return `Err` if channel is all sender gone or all messages conflict
+    #[inline]
+    pub async fn peek(&self) -> Result<Message<K, V>, RecvError>
+    where
+        V: Clone,
+    {
+        self.inner.peek().await
+    }
+
+    /// receive the front-most message even if its keys conflict, and activate them
+    ///
+    /// The keys are active as usual once received, so the message is held
+    /// like one received by [`Receiver::recv`], and a key may be active more
+    /// than once. It's intended for maintenance paths, e.g. discarding a
+    /// message that can't be processed.
+    /// # Errors
+    ///
+    /// return `Err` if channel is all sender gone
+    #[inline]
+    pub async fn recv_any(&self) -> Result<Message<K, V>, RecvError> {
+        self.inner.recv_any().await.map(|mut msg| {
+            msg.set_shared(Arc::<Shared<K, V>>::clone(&self.inner));
+            msg
+        })
+    }
+
+    /// receive the front-most message, ignoring and not updating the active keys
+    ///
+    /// The message is neither checked for conflict nor activates its keys, so
+    /// dropping it releases nothing, and a following message with the same key
+    /// can be received while it's alive. This is intended for observational
+    /// consumers, mixing it with [`Receiver::recv`] gives up the ordering and
+    /// conflict guarantees for the messages it consumes.
+    /// # Errors
+    ///
+    /// return `Err` if channel is all sender gone
+    #[inline]
+    pub async fn recv_unguarded(&self) -> Result<Message<K, V>, RecvError> {
+        self.inner.recv_unguarded().await
+    }
+
+    /// take all buffered messages at once in FIFO order, regardless of conflict
+    ///
+    /// Like [`Receiver::recv_unguarded`], the messages don't activate their keys,
+    /// so dropping them releases nothing, it's intended for shutdown when
+    /// nothing is processed concurrently anymore.
+    #[inline]
+    #[must_use]
+    pub fn drain(&self) -> Vec<Message<K, V>> {
+        self.inner.drain()
+    }
+
+    /// does `sender` send to this receiver
+    #[inline]
+    #[must_use]
+    pub fn same_channel(&self, sender: &BoundedSender<K, V>) -> bool {
+        Arc::ptr_eq(&self.inner, &sender.inner)
+    }
+
+    /// number of empty buff slots, like [`BoundedSender::capacity`],
+    /// always zero for an unbounded channel
+    #[inline]
+    #[must_use]
+    pub fn capacity(&self) -> usize {
+        self.inner.capacity()
+    }
+
+    /// capacity of the channel, zero for an unbounded channel
+    #[inline]
+    #[must_use]
+    pub fn max_capacity(&self) -> usize {
+        self.inner.cap
+    }
+
+    /// number of queued messages, not including those held by the receiver
+    ///
+    /// It's a snapshot, senders may change it at once.
+    #[inline]
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.inner.len()
+    }
+
+    /// is no message queued, a snapshot as [`Receiver::len`]
+    #[inline]
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// keys held by received messages or key locks that queued messages
+    /// wait for, i.e. why `recv` returns `AllConflict`, in no particular order
+    ///
+    /// It's a snapshot taken under the lock, and may be stale immediately.
+    #[inline]
+    #[must_use]
+    pub fn blocking_keys(&self) -> Vec<K> {
+        self.inner.blocking_keys()
+    }
+
+    /// number of live senders, throttled senders included
+    ///
+    /// It's a snapshot, senders may be cloned or dropped right after it's returned.
+    #[inline]
+    #[must_use]
+    pub fn sender_count(&self) -> usize {
+        self.inner.senders.get()
+    }
+
+    /// close the channel for sending, while buffered messages can still be received
+    ///
+    /// All following and blocked `send`s fail with `SendError`, and receiving
+    /// returns `Disconnected` once the buffer is drained. Dropping the receiver
+    /// closes the channel too, but the buffered messages are abandoned.
+    #[inline]
+    pub fn close(&self) {
+        // called in drop too, never panic, the flag is consistent even if poisoned
+        let mut state = lock_or_recover(&self.inner.state);
+        state.disconnected = true;
+        self.inner
+            .close_flag
+            .store(true, Ordering::Release);
+        drop(state);
+        // pending senders and capacity waiters will fail to acquire
+        // permits immediately, then return Err
+        self.inner.slots.close();
+        self.inner.receiver_closed.notify_waiters();
+    }
+
+    /// is the channel disconnected, i.e. all senders are gone or the channel is closed,
+    /// buffered messages may still be received
+    #[inline]
+    #[must_use]
+    pub fn is_disconnected(&self) -> bool {
+        self.remaining_after_disconnect().is_some()
+    }
+
+    /// number of buffered messages left to drain, `None` if the channel is
+    /// still connected
+    #[inline]
+    #[must_use]
+    pub fn remaining_after_disconnect(&self) -> Option<usize> {
+        self.inner.remaining_after_disconnect()
+    }
+
+    /// receive a message and keep only its key guard, the value is dropped,
+    /// handy when the channel is used as a keyed permit queue with `V = ()`
+    /// # Errors
+    ///
+    /// return `Err` if channel is all sender gone
+    #[inline]
+    pub async fn recv_key_only(&self) -> Result<KeyLock<K, V>, RecvError> {
+        self.recv().await.map(Message::into_key_lock)
+    }
+
+    /// listen on the keys released by received messages and key guards,
+    /// at most `cap` completions are buffered, see [`Completions`]
+    ///
+    /// Only one listener is served, a new one replaces the previous.
+    /// # Panics
+    ///
+    /// panic if `cap` is zero
+    #[inline]
+    #[must_use]
+    pub fn completions(&self, cap: usize) -> Completions<K> {
+        let feed = Arc::new(Feed::new(cap));
+        self.inner.active.set_feed(&feed);
+        Completions::new(feed)
+    }
+
+    /// activate `key` without any message, no message with the key will be
+    /// received until the returned guard is dropped
+    ///
+    /// The key is reference-counted, so it stays active while either the guard
+    /// or a received message with the key is alive.
+    #[inline]
+    pub fn lock_key(&self, key: K) -> KeyLock<K, V> {
+        self.inner.lock_key(key.clone());
+        KeyLock::new(key, Arc::<Shared<K, V>>::clone(&self.inner))
+    }
+
+    /// receive up to `n` messages, wait until `n` messages are received
+    /// or `dur` has elapsed, whichever comes first
+    ///
+    /// Conflict rules apply to each message as usual, so the batch ends early
+    /// when all remaining messages are conflict, an empty `Vec` means the
+    /// deadline is reached before any message is receivable.
+    /// # Errors
+    ///
+    /// return `Err` only if no message is received, messages received before
+    /// the channel is disconnected or conflict are returned as `Ok`
+    #[inline]
+    pub async fn recv_batch_timeout(
+        &self, n: usize, dur: Duration,
+    ) -> Result<Vec<Message<K, V>>, RecvError> {
+        let deadline = Instant::now().checked_add(dur);
+        let mut batch = Vec::new();
+        while batch.len() < n {
+            match self.inner.recv_deadline(deadline).await {
+                Ok(Some(mut msg)) => {
+                    msg.set_shared(Arc::<Shared<K, V>>::clone(&self.inner));
+                    batch.push(msg);
+                }
+                Ok(None) => break,
+                Err(err) => {
+                    if batch.is_empty() {
+                        return Err(err);
+                    }
+                    break;
+                }
+            }
+        }
+        Ok(batch)
+    }
+
+    /// statistics of receiving since the channel is created
+    ///
+    /// The counters are updated with relaxed ordering, they are eventually
+    /// consistent with concurrent receiving.
     #[inline]
-    #[allow(unsafe_code)]
-    pub fn print_stats(&self) {
-        // it's safe because there is only one receiver and sender whill not modify these
-        unsafe {
-            println!(
-                "wait count {}, try_recv cost time {:?}",
-                *self.inner.wait_count.get(),
-                *self.inner.try_recv_cost.get(),
-            )
+    #[must_use]
+    pub fn stats(&self) -> RecvStats {
+        let inner = &self.inner;
+        let state = lock(&inner.state);
+        let expired_count = state.buff.expired_count();
+        drop(state);
+        RecvStats {
+            wait_count: inner.wait_count.load(Ordering::Relaxed),
+            try_recv_time: Duration::from_nanos(
+                inner.try_recv_nanos.load(Ordering::Relaxed),
+            ),
+            recv_count: inner.recv_count.load(Ordering::Relaxed),
+            expired_count,
+        }
+    }
+}
+
+/// Statistics of a [`Receiver`], returned by [`Receiver::stats`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct RecvStats {
+    /// number of times the receiver waits for a message to be sent,
+    /// or a key to be released
+    pub wait_count: u64,
+    /// time spent trying to take a message out of the buff,
+    /// it's only measured with the `profile` feature, and zero otherwise
+    pub try_recv_time: Duration,
+    /// number of messages received, peeked and drained ones are not counted
+    pub recv_count: u64,
+    /// number of messages dropped instead of received, as their deadlines set
+    /// by [`Message::with_deadline`](crate::Message::with_deadline) are reached
+    pub expired_count: u64,
+}
+
+// the marker only opts out of `Sync`, the receiver has no interior
+// mutability outside the mutex protected state
+impl<K: Key + RefUnwindSafe, V: RefUnwindSafe> RefUnwindSafe for Receiver<K, V> {}
+
+/// A [`futures_core::Stream`] of messages grouped by key, created by
+/// [`Receiver::into_grouped_stream`]
+///
+/// Each item is a message received as [`Receiver::recv`] does, with the
+/// queued messages right behind it that have the same sole key, in FIFO order.
+/// All of them hold the key, so it's released when the whole group is dropped.
+/// The key is `None` for a message with several keys or none, which is always
+/// yielded alone.
+///
+/// Unlike `recv`, it waits while all messages conflict, and it ends when the
+/// channel is disconnected and drained.
+#[cfg(feature = "stream")]
+#[derive(Debug)]
+pub struct GroupedStream<K: Key, V> {
+    /// the consumed receiver
+    receiver: Receiver<K, V>,
+}
+
+#[cfg(feature = "stream")]
+impl<K: Key, V> GroupedStream<K, V> {
+    /// get the receiver back
+    #[inline]
+    #[must_use]
+    pub fn into_inner(self) -> Receiver<K, V> {
+        self.receiver
+    }
+}
+
+#[cfg(feature = "stream")]
+impl<K: Key, V> futures_core::Stream for GroupedStream<K, V> {
+    type Item = (Option<K>, Vec<Message<K, V>>);
+
+    #[inline]
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let inner = &self.receiver.inner;
+        match inner.poll_recv_group(cx) {
+            Poll::Ready(Ok((key, (msgs, panicked)))) => {
+                let group: Vec<_> = msgs
+                    .into_iter()
+                    .map(|mut msg| {
+                        msg.set_shared(Arc::<Shared<K, V>>::clone(inner));
+                        msg
+                    })
+                    .collect();
+                if let Some(payload) = panicked {
+                    // the keys are released by dropping the group
+                    drop(group);
+                    std::panic::resume_unwind(payload);
+                }
+                Poll::Ready(Some((key, group)))
+            }
+            Poll::Ready(Err(_)) => Poll::Ready(None),
+            Poll::Pending => Poll::Pending,
         }
     }
 }
@@ -111,14 +1122,101 @@ impl<K: Key, V: Debug> Receiver<K, V> {
 impl<K: Key, V> Drop for Receiver<K, V> {
     #[inline]
     fn drop(&mut self) {
-        let mut state =
-            unwrap_ok_or!(self.inner.state.lock(), err, panic!("lock err {:?}", err));
-        state.disconnected = true;
+        let mut state = lock_or_recover(&self.inner.state);
+        state.receiver_dropped = true;
         drop(state);
-        // pending senders will get a permit immediately
-        // and check the `state.disconnected`, then return Err
-        // strictly speaking, add one permit is enough
-        self.inner.slots.add_permits(1);
+        self.close();
+    }
+}
+
+/// The future of [`Receiver::recv`]
+///
+/// Each poll is a [`Receiver::poll_recv`], the task waker is registered
+/// whenever it's pending, and the latest one replaces the previous. It's
+/// `Unpin` and holds nothing but a reference, so it can be stored in a
+/// hand-written state machine and polled again after being moved.
+#[derive(Debug)]
+#[must_use = "futures do nothing unless you `.await` or poll them"]
+pub struct RecvFuture<'a, K: Key, V> {
+    /// the polled receiver
+    receiver: &'a Receiver<K, V>,
+}
+
+impl<K: Key, V> Future for RecvFuture<'_, K, V> {
+    type Output = Result<Message<K, V>, RecvError>;
+
+    #[inline]
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.receiver.poll_recv(cx)
+    }
+}
+
+/// The future of [`Receiver::key_released`]
+#[must_use = "futures do nothing unless you `.await` or poll them"]
+pub struct KeyReleasedFuture<'a, K: Key, V> {
+    /// the shared queue
+    inner: &'a Shared<K, V>,
+    /// the waited key
+    key: K,
+    /// the pending notification of a key release
+    notified: Option<Pin<Box<Notified<'a>>>>,
+}
+
+impl<K: Key, V> Debug for KeyReleasedFuture<'_, K, V> {
+    #[inline]
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("KeyReleasedFuture")
+            .field("key", &self.key)
+            .finish_non_exhaustive()
+    }
+}
+
+// the key is never pinned, the notification is boxed
+impl<K: Key, V> Unpin for KeyReleasedFuture<'_, K, V> {}
+
+impl<K: Key, V> Future for KeyReleasedFuture<'_, K, V> {
+    type Output = ();
+
+    #[inline]
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let this = self.get_mut();
+        let inner = this.inner;
+        loop {
+            // listen before checking, `notify_waiters` stores no permit
+            let notified = this
+                .notified
+                .get_or_insert_with(|| Box::pin(inner.key_released.notified()));
+            let _enabled = notified.as_mut().enable();
+            // rechecked after every notification, the key may be activated again
+            if !inner.is_key_active(&this.key) {
+                this.notified = None;
+                return Poll::Ready(());
+            }
+            match notified.as_mut().poll(cx) {
+                Poll::Ready(()) => this.notified = None,
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+/// A future that polls `first` before `second`, resolves to `None`
+/// if `second` completes first
+struct Biased<A, B> {
+    /// the preferred future
+    first: Pin<Box<A>>,
+    /// the fallback future
+    second: Pin<Box<B>>,
+}
+
+impl<A: Future, B: Future<Output = ()>> Future for Biased<A, B> {
+    type Output = Option<A::Output>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        if let Poll::Ready(out) = self.first.as_mut().poll(cx) {
+            return Poll::Ready(Some(out));
+        }
+        self.second.as_mut().poll(cx).map(|()| None)
     }
 }
 
@@ -131,23 +1229,99 @@ impl<K: Key, V> Drop for Receiver<K, V> {
 #[doc(alias = "channel")]
 pub fn bounded<K: Key, V>(cap: usize) -> (BoundedSender<K, V>, Receiver<K, V>) {
     assert!(cap > 0, "The capacity of channel must be greater than 0");
+    let (inner, r) = shared(cap, false);
+    let s = BoundedSender::new(inner);
+    (s, r)
+}
+
+/// An async channel with capacity > 0 that honors message priorities
+///
+/// The receiver takes the highest priority message set by
+/// [`Message::with_priority`](crate::Message::with_priority) among the ones
+/// without conflict, the front-most one on a tie. Every receive scans all
+/// of them, while [`bounded`] takes the front one right away.
+/// # Panics
+///
+/// panic is capicity less than zero
+#[inline]
+#[must_use]
+pub fn bounded_prioritized<K: Key, V>(
+    cap: usize,
+) -> (BoundedSender<K, V>, Receiver<K, V>) {
+    assert!(cap > 0, "The capacity of channel must be greater than 0");
+    let (inner, r) = shared(cap, true);
+    (BoundedSender::new(inner), r)
+}
+
+/// An async channel without capacity limit, sending never waits
+///
+/// Key conflicts are the same as a bounded channel, while a slow receiver
+/// lets the buffer grow without bound.
+#[inline]
+#[must_use]
+pub fn unbounded<K: Key, V>() -> (UnboundedSender<K, V>, Receiver<K, V>) {
+    let (inner, r) = shared(0, false);
+    (UnboundedSender { inner }, r)
+}
+
+/// the queue shared by a new sender and the receiver, `cap` slots are
+/// given to senders, an unbounded channel uses none
+fn shared<K: Key, V>(
+    cap: usize, prioritized: bool,
+) -> (Arc<Shared<K, V>>, Receiver<K, V>) {
+    let active = Arc::new(ActiveKeys::new(cap));
     let inner = Arc::new(Shared {
-        state: Mutex::new(State {
-            buff: KeyedBuff::new(cap),
-            n_senders: 1,
-            disconnected: false,
-        }),
+        state: Mutex::new(State::new(cap, prioritized, Arc::clone(&active))),
+        active,
+        senders: SenderCount::new(1),
+        cap,
         slots: Arc::new(Semaphore::new(cap)),
         #[cfg(not(feature = "event_listener"))]
         notify_receiver: Notify::new(),
         #[cfg(feature = "event_listener")]
         notify_receiver: Event::new(),
-        #[cfg(feature = "profile")]
-        try_recv_cost: std::cell::UnsafeCell::new(tokio::time::Duration::new(0, 0)),
-        #[cfg(feature = "profile")]
-        wait_count: std::cell::UnsafeCell::new(0),
+        close_flag: AtomicBool::new(false),
+        receiver_closed: Notify::new(),
+        key_released: Notify::new(),
+        recv_waker: Mutex::new(None),
+        try_recv_nanos: AtomicU64::new(0),
+        wait_count: AtomicU64::new(0),
+        recv_count: AtomicU64::new(0),
+        next_seq: AtomicU64::new(0),
+        #[cfg(feature = "fault-injection")]
+        faults: crate::fault::Faults::default(),
     });
-    let s = BoundedSender { inner: Arc::<Shared<K, V>>::clone(&inner) };
-    let r = Receiver { inner, _marker: std::marker::PhantomData };
-    (s, r)
+    let r = Receiver {
+        inner: Arc::<Shared<K, V>>::clone(&inner),
+        conflict_wait_limit: Cell::new(None),
+        _marker: std::marker::PhantomData,
+    };
+    (inner, r)
+}
+
+/// A waker unparking the thread blocked in [`block_on`]
+struct ThreadWaker(std::thread::Thread);
+
+impl Wake for ThreadWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+/// run `future` to completion on the current thread, park it while pending
+fn block_on<F: Future>(future: F) -> F::Output {
+    let waker = Waker::from(Arc::new(ThreadWaker(std::thread::current())));
+    let mut cx = Context::from_waker(&waker);
+    let mut future = Box::pin(future);
+    loop {
+        match future.as_mut().poll(&mut cx) {
+            Poll::Ready(output) => return output,
+            // a wakeup before parking leaves the token, so it isn't lost
+            Poll::Pending => std::thread::park(),
+        }
+    }
 }
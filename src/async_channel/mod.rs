@@ -56,7 +56,13 @@
 //! }
 //! ```
 
-pub use channel::{bounded, BoundedSender, Receiver};
+#[cfg(feature = "stream")]
+pub use channel::GroupedStream;
+pub use channel::{
+    bounded, bounded_prioritized, unbounded, BoundedSender, KeyReleasedFuture,
+    OwnedSendPermit, Receiver, RecvFuture, RecvStats, SendPermit, ThrottledSender,
+    UnboundedSender,
+};
 mod channel;
 mod shared;
 mod store_message;
@@ -67,15 +73,320 @@ type StoredMessage<K, V> = store_message::StoredMessage<K, V, shared::Shared<K,
 /// the real messge type send/recv in async channel
 type Message<K, V> = crate::message::Message<K, V, shared::Shared<K, V>>;
 
+/// the key lock of async channel
+type KeyLock<K, V> = crate::KeyLock<K, shared::Shared<K, V>>;
+
 #[cfg(test)]
+#[allow(clippy::result_large_err)] // a failed send hands the whole message back
 mod test {
-    use super::channel::bounded;
-    use crate::{unwrap_ok_or, unwrap_some_or, Message, RecvError, SendError};
+    use super::channel::{bounded, bounded_prioritized, unbounded, RecvStats};
+    use crate::{
+        unwrap_ok_or, unwrap_some_or, CompletionKind, Message, RecvError,
+        RecvFilterError, RecvOr, RecvTimeoutError, RedactedKey, SendError, TryRecvError,
+        TryReserveError, TrySendError,
+    };
+    use std::future::Future;
+    use std::panic::{catch_unwind, AssertUnwindSafe, RefUnwindSafe, UnwindSafe};
+    use std::pin::Pin;
+    use std::task::{Context, Poll, Wake, Waker};
     use std::{
         collections::HashSet,
         iter::FromIterator,
-        sync::{atomic::AtomicBool, Arc},
+        sync::{
+            atomic::{AtomicBool, Ordering::SeqCst},
+            Arc,
+        },
     };
+    use tokio::sync::oneshot;
+    use tokio::time::{Duration, Instant};
+
+    /// a waker that records whether it's woken
+    struct WokenFlag(AtomicBool);
+
+    impl Wake for WokenFlag {
+        fn wake(self: Arc<Self>) {
+            self.0.store(true, SeqCst);
+        }
+    }
+
+    impl WokenFlag {
+        /// take the flag, and reset it
+        fn take(&self) -> bool {
+            self.0.swap(false, SeqCst)
+        }
+    }
+
+    /// assert unwind safety at compile time
+    fn assert_unwind_safe<T: UnwindSafe + RefUnwindSafe>() {}
+
+    /// a key that deliberately implements no `Debug`
+    #[derive(Clone, PartialEq, Eq, Hash)]
+    struct CustomerId(u64);
+
+    #[tokio::test]
+    async fn test_redacted_key() {
+        let (tx, rx) = bounded(10);
+        let key = RedactedKey::new(CustomerId(1));
+        assert_eq!(
+            tx.send(Message::single_key(key.clone(), 1))
+                .await,
+            Ok(())
+        );
+        let msg = unwrap_ok_or!(rx.recv().await, err, panic!("{:?}", err));
+        assert_eq!(format!("{:?}", msg.get_single_key()), "Some(<redacted>)");
+        drop(msg);
+        let lock = rx.lock_key(key.clone());
+        assert!(!format!("{:?}", lock).contains('1'));
+        drop(lock);
+        drop(rx);
+        let res = tx.send(Message::single_key(key, 2)).await;
+        assert!(res.is_err());
+        assert!(format!("{:?}", res).contains("<redacted>"));
+    }
+
+    #[test]
+    fn test_unwind_safe() {
+        assert_unwind_safe::<super::Message<usize, usize>>();
+        assert_unwind_safe::<super::KeyLock<usize, usize>>();
+        assert_unwind_safe::<super::BoundedSender<usize, usize>>();
+        assert_unwind_safe::<super::ThrottledSender<usize, usize>>();
+        assert_unwind_safe::<super::Receiver<usize, usize>>();
+    }
+
+    #[tokio::test]
+    async fn test_catch_unwind_handler() {
+        let (tx, rx) = bounded(10);
+        for i in 1..=2 {
+            let _drop = tx.send(Message::single_key(0, i)).await;
+        }
+        let msg = unwrap_ok_or!(rx.recv().await, err, panic!("{:?}", err));
+        let res = catch_unwind(move || {
+            assert_eq!(msg.get_value(), &1);
+            panic!("handler panics while holding the message");
+        });
+        assert!(res.is_err());
+        // the key is released while unwinding
+        let msg2 = unwrap_ok_or!(rx.recv().await, err, panic!("{:?}", err));
+        assert_eq!(msg2.get_value(), &2);
+    }
+
+    /// a key whose `Hash`/`Clone` panic when armed, compared by id
+    #[derive(Debug)]
+    struct PanicKey {
+        /// identity of the key
+        id: usize,
+        /// `Hash` panics when set
+        hash_bomb: Arc<AtomicBool>,
+        /// `Clone` panics when set
+        clone_bomb: Arc<AtomicBool>,
+    }
+
+    impl PanicKey {
+        fn new(id: usize) -> Self {
+            PanicKey {
+                id,
+                hash_bomb: Arc::new(AtomicBool::new(false)),
+                clone_bomb: Arc::new(AtomicBool::new(false)),
+            }
+        }
+    }
+
+    impl Clone for PanicKey {
+        fn clone(&self) -> Self {
+            assert!(!self.clone_bomb.load(SeqCst), "clone panics");
+            PanicKey {
+                id: self.id,
+                hash_bomb: Arc::clone(&self.hash_bomb),
+                clone_bomb: Arc::clone(&self.clone_bomb),
+            }
+        }
+    }
+
+    impl PartialEq for PanicKey {
+        fn eq(&self, other: &Self) -> bool {
+            self.id == other.id
+        }
+    }
+
+    impl Eq for PanicKey {}
+
+    impl std::hash::Hash for PanicKey {
+        fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+            assert!(!self.hash_bomb.load(SeqCst), "hash panics");
+            self.id.hash(state);
+        }
+    }
+
+    /// a future that catches the panic of the inner future
+    struct CatchUnwind<F>(Pin<Box<F>>);
+
+    impl<F: Future> Future for CatchUnwind<F> {
+        type Output = std::thread::Result<F::Output>;
+
+        fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+            let inner = self.0.as_mut();
+            match catch_unwind(AssertUnwindSafe(|| inner.poll(cx))) {
+                Ok(Poll::Pending) => Poll::Pending,
+                Ok(Poll::Ready(output)) => Poll::Ready(Ok(output)),
+                Err(payload) => Poll::Ready(Err(payload)),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_catch_unwind_send() {
+        let (tx, rx) = bounded(10);
+        let key = PanicKey::new(1);
+        let bomb = Arc::clone(&key.hash_bomb);
+        let msg = Message::single_key(key.clone(), 1);
+        bomb.store(true, SeqCst);
+        assert!(CatchUnwind(Box::pin(tx.send(msg)))
+            .await
+            .is_err());
+        bomb.store(false, SeqCst);
+        // the state lock is not poisoned, the message is never queued
+        assert_eq!(rx.remaining_after_disconnect(), None);
+        assert_eq!(tx.send(Message::single_key(key, 2)).await, Ok(()));
+        drop(tx);
+        let msg2 = unwrap_ok_or!(rx.recv().await, err, panic!("{:?}", err));
+        assert_eq!(msg2.get_value(), &2);
+        assert_eq!(rx.remaining_after_disconnect(), Some(0));
+    }
+
+    #[tokio::test]
+    async fn test_catch_unwind_recv() {
+        let (tx, rx) = bounded(10);
+        let key = PanicKey::new(1);
+        let bomb = Arc::clone(&key.clone_bomb);
+        let _sent = tx.send(Message::single_key(key, 1)).await;
+        bomb.store(true, SeqCst);
+        assert!(CatchUnwind(Box::pin(rx.recv())).await.is_err());
+        bomb.store(false, SeqCst);
+        // the message is left in buffer
+        let msg = unwrap_ok_or!(rx.recv().await, err, panic!("{:?}", err));
+        assert_eq!(msg.get_value(), &1);
+        drop(tx);
+        assert_eq!(rx.recv().await, Err(RecvError::Disconnected));
+    }
+
+    #[tokio::test]
+    async fn test_catch_unwind_lock_key() {
+        let (tx, rx) = bounded(10);
+        let key = PanicKey::new(1);
+        let bomb = Arc::clone(&key.hash_bomb);
+        bomb.store(true, SeqCst);
+        assert!(catch_unwind(|| rx.lock_key(key.clone())).is_err());
+        bomb.store(false, SeqCst);
+        // the key is not locked
+        let _sent = tx
+            .send(Message::single_key(key.clone(), 1))
+            .await;
+        let msg = unwrap_ok_or!(rx.recv().await, err, panic!("{:?}", err));
+        drop(msg);
+        // a lock dropped while unwinding releases the key
+        assert!(catch_unwind(|| {
+            let _lock = rx.lock_key(key.clone());
+            panic!("panics while holding the lock");
+        })
+        .is_err());
+        let _sent2 = tx.send(Message::single_key(key, 2)).await;
+        let msg2 = unwrap_ok_or!(rx.recv().await, err, panic!("{:?}", err));
+        assert_eq!(msg2.get_value(), &2);
+    }
+
+    #[tokio::test]
+    async fn test_catch_unwind_recv_or() {
+        let (tx, rx) = bounded(10);
+        let until = async { panic!("until panics") };
+        assert!(CatchUnwind(Box::pin(rx.recv_or(until)))
+            .await
+            .is_err());
+        // the receiver is not broken by the dropped recv
+        let _sent = tx.send(Message::single_key(1, 1)).await;
+        let msg = unwrap_ok_or!(rx.recv_or(async {}).await, err, panic!("{:?}", err));
+        assert_eq!(msg.get_value(), &1);
+        drop(tx);
+        drop(msg);
+        assert_eq!(rx.recv().await, Err(RecvError::Disconnected));
+    }
+
+    #[tokio::test]
+    async fn test_completions() {
+        let (tx, rx) = bounded(1);
+        let completions = rx.completions(8);
+        let handle = tokio::spawn(async move {
+            let _sent = tx.send(Message::single_key(1, 1)).await;
+            let _sent2 = tx.send(Message::single_key(1, 2)).await;
+        });
+        let msg = unwrap_ok_or!(rx.recv().await, err, panic!("{:?}", err));
+        drop(msg);
+        assert_eq!(completions.next().await, (vec![1], CompletionKind::Dropped));
+        let msg2 = unwrap_ok_or!(rx.recv().await, err, panic!("{:?}", err));
+        let _drop = handle.await;
+        let waiter = tokio::spawn(async move { completions.next().await });
+        drop(msg2);
+        let completion = unwrap_ok_or!(waiter.await, err, panic!("{:?}", err));
+        assert_eq!(completion, (vec![1], CompletionKind::Dropped));
+        assert_eq!(rx.recv().await, Err(RecvError::Disconnected));
+    }
+
+    #[cfg(feature = "fault-injection")]
+    #[tokio::test]
+    async fn test_fault_spurious_wakeups() {
+        let (tx, rx) = bounded(1);
+        rx.faults().spurious_wakeups(100);
+        let handle = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            tx.send(Message::single_key(0, 0)).await
+        });
+        // the recv loop rechecks the buffer after each wakeup
+        let msg = unwrap_ok_or!(rx.recv().await, err, panic!("{:?}", err));
+        assert_eq!(msg, Message::single_key(0, 0));
+        assert_eq!(unwrap_ok_or!(handle.await, err, panic!("{:?}", err)), Ok(()));
+    }
+
+    #[cfg(feature = "fault-injection")]
+    #[tokio::test]
+    async fn test_fault_delayed_notifications() {
+        let (tx, rx) = bounded(1);
+        rx.faults()
+            .delay_notifications(Duration::from_millis(50));
+        let handle = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            tx.send(Message::single_key(0, 0)).await
+        });
+        let msg = unwrap_ok_or!(rx.recv().await, err, panic!("{:?}", err));
+        assert_eq!(msg, Message::single_key(0, 0));
+        assert_eq!(unwrap_ok_or!(handle.await, err, panic!("{:?}", err)), Ok(()));
+    }
+
+    #[cfg(feature = "fault-injection")]
+    #[tokio::test]
+    async fn test_fault_lost_notification() {
+        let (tx, rx) = bounded(10);
+        rx.faults().drop_notifications(1);
+        let flag = Arc::new(WokenFlag(AtomicBool::new(false)));
+        let waker = Waker::from(Arc::clone(&flag));
+        let mut cx = Context::from_waker(&waker);
+        let mut recv = Box::pin(rx.recv());
+        assert!(recv.as_mut().poll(&mut cx).is_pending());
+        let sender = tx.clone();
+        let handle = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            sender.send(Message::single_key(0, 0)).await
+        });
+        assert_eq!(unwrap_ok_or!(handle.await, err, panic!("{:?}", err)), Ok(()));
+        // by design a lost notification is not survived, the receiver
+        // isn't woken until the next one
+        assert!(!flag.take());
+        let _sent = tx.send(Message::single_key(1, 1)).await;
+        assert!(flag.take());
+        let msg = match recv.as_mut().poll(&mut cx) {
+            Poll::Ready(res) => unwrap_ok_or!(res, err, panic!("{:?}", err)),
+            Poll::Pending => panic!("a sent message is pending"),
+        };
+        assert_eq!(msg, Message::single_key(0, 0));
+    }
 
     #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
     async fn test_sender_close() {
@@ -99,6 +410,1068 @@ mod test {
         assert_eq!(tx.send(msg).await, Err(SendError(Message::single_key(1, 1))));
     }
 
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_recv_wait() {
+        let (tx, rx) = bounded(4);
+        assert!(tx.send(Message::single_key(1, 1)).await.is_ok());
+        assert!(tx.send(Message::single_key(1, 2)).await.is_ok());
+        let held = rx.recv_wait().await;
+        assert_eq!(held, Ok(Message::single_key(1, 1)));
+        // woken by a key release
+        let release = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            drop(held);
+        });
+        let second = rx.recv_wait().await;
+        assert_eq!(second, Ok(Message::single_key(1, 2)));
+        assert!(release.await.is_ok());
+        // woken by a message without conflict, key 1 is still held
+        assert!(tx.send(Message::single_key(1, 3)).await.is_ok());
+        let sender = tx.clone();
+        let send = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            sender
+                .send(Message::single_key(2, 4))
+                .await
+                .is_ok()
+        });
+        assert_eq!(rx.recv_wait().await, Ok(Message::single_key(2, 4)));
+        assert!(matches!(send.await, Ok(true)));
+        drop(second);
+        drop(tx);
+        assert_eq!(rx.recv_wait().await, Ok(Message::single_key(1, 3)));
+        assert_eq!(rx.recv_wait().await, Err(RecvError::Disconnected));
+    }
+
+    #[cfg(debug_assertions)]
+    #[tokio::test]
+    async fn test_recv_wait_limit() {
+        let (tx, rx) = bounded(4);
+        assert!(tx.send(Message::single_key(1, 1)).await.is_ok());
+        assert!(tx.send(Message::single_key(1, 2)).await.is_ok());
+        rx.set_conflict_wait_limit(Some(Duration::from_millis(20)));
+        let held = rx.recv_wait().await;
+        // the caller holds the key it waits for, the receiver is not `Sync`,
+        // so it waits on its own runtime
+        let waited = std::thread::spawn(move || {
+            let rt = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build();
+            let rt = unwrap_ok_or!(rt, err, panic!("{:?}", err));
+            let _second = rt.block_on(rx.recv_wait());
+        });
+        assert!(waited.join().is_err(), "a deadlock is missed");
+        drop(held);
+        // an idle channel is not a deadlock
+        let (_tx2, rx2) = bounded::<u32, u32>(1);
+        rx2.set_conflict_wait_limit(Some(Duration::from_millis(10)));
+        let idle = tokio::time::timeout(Duration::from_millis(50), rx2.recv_wait());
+        assert!(idle.await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_stats() {
+        let (tx, rx) = bounded(10);
+        assert_eq!(rx.stats(), RecvStats::default());
+        for key in 1..=4 {
+            assert!(tx
+                .send(Message::single_key(key, key))
+                .await
+                .is_ok());
+        }
+        // a peeked message is not received
+        assert!(rx.peek().await.is_ok());
+        assert_eq!(rx.stats().recv_count, 0);
+        assert!(rx.recv().await.is_ok());
+        assert!(rx.try_recv().is_ok());
+        assert_eq!(rx.stats().recv_count, 2);
+        let mut buf = Vec::new();
+        assert_eq!(rx.recv_many(&mut buf, 10).await, 2);
+        let stats = rx.stats();
+        assert_eq!(stats.recv_count, 4);
+        assert_eq!(stats.wait_count, 0);
+        #[cfg(not(feature = "profile"))]
+        assert_eq!(stats.try_recv_time, Duration::ZERO);
+
+        // the receiver waits for a message sent later
+        let sender = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            tx.send(Message::single_key(5, 5)).await.is_ok()
+        });
+        assert!(rx.recv().await.is_ok());
+        assert!(sender.await.unwrap_or(false));
+        let later = rx.stats();
+        assert_eq!(later.recv_count, 5);
+        assert!(later.wait_count > stats.wait_count);
+        #[cfg(feature = "profile")]
+        assert!(later.try_recv_time > stats.try_recv_time);
+    }
+
+    #[tokio::test]
+    async fn test_key_released() {
+        let (tx, rx) = bounded(2);
+        // never active
+        rx.key_released(1).await;
+        assert!(tx.send(Message::single_key(1, 1)).await.is_ok());
+        // a queued message doesn't hold its key
+        rx.key_released(1).await;
+        let held = rx.recv().await;
+        let waker = Arc::new(WokenFlag(AtomicBool::new(false)));
+        let task_waker = Waker::from(Arc::clone(&waker));
+        let mut cx = Context::from_waker(&task_waker);
+        let mut released = rx.key_released(1);
+        assert!(Pin::new(&mut released)
+            .poll(&mut cx)
+            .is_pending());
+        // activated again before the task observes the release
+        drop(held);
+        let lock = rx.lock_key(1);
+        assert!(waker.take());
+        assert!(Pin::new(&mut released)
+            .poll(&mut cx)
+            .is_pending());
+        drop(lock);
+        assert!(waker.take());
+        assert!(Pin::new(&mut released).poll(&mut cx).is_ready());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_key_released_other_task() {
+        let (tx, rx) = bounded(2);
+        assert!(tx.send(Message::single_key(1, 1)).await.is_ok());
+        let held = unwrap_ok_or!(rx.recv().await, err, panic!("{:?}", err));
+        let release = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            drop(held);
+        });
+        let waited = tokio::time::timeout(Duration::from_secs(1), rx.key_released(1));
+        assert!(waited.await.is_ok());
+        assert!(release.await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_recv_future() {
+        /// a state machine that stores the in-flight receive
+        struct Pending<'a> {
+            /// the stored future
+            recv: super::RecvFuture<'a, u32, u32>,
+        }
+        let (tx, rx) = bounded(1);
+        let waker = Arc::new(WokenFlag(AtomicBool::new(false)));
+        let task_waker = Waker::from(Arc::clone(&waker));
+        let mut cx = Context::from_waker(&task_waker);
+        let mut state = Pending { recv: rx.recv() };
+        assert!(Pin::new(&mut state.recv)
+            .poll(&mut cx)
+            .is_pending());
+        // moved between polls, it's `Unpin`
+        let mut moved = state;
+        assert!(tx.send(Message::single_key(1, 1)).await.is_ok());
+        assert!(waker.take());
+        let polled = Pin::new(&mut moved.recv).poll(&mut cx);
+        assert_eq!(polled, Poll::Ready(Ok(Message::single_key(1, 1))));
+        drop(tx);
+        assert_eq!(rx.recv().await, Err(RecvError::Disconnected));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_recv_cancel_safe() {
+        let (tx, rx) = bounded(1);
+        for i in 0..5000_u32 {
+            let sender = tx.clone();
+            let send = tokio::spawn(async move {
+                tokio::task::yield_now().await;
+                sender
+                    .send(Message::single_key(i, i))
+                    .await
+                    .is_ok()
+            });
+            // cancelled before, while or after the message is sent
+            let wait = Duration::from_micros(u64::from(i % 50));
+            let first = tokio::time::timeout(wait, rx.recv()).await;
+            let received = unwrap_ok_or!(first, _, {
+                let retry = tokio::time::timeout(Duration::from_secs(1), rx.recv());
+                unwrap_ok_or!(retry.await, _, panic!("wakeup lost at {}", i))
+            });
+            assert_eq!(received, Ok(Message::single_key(i, i)));
+            assert!(matches!(send.await, Ok(true)));
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_recv_cancel_by_poll() {
+        let (tx, rx) = bounded(4);
+        let waker = Waker::from(Arc::new(WokenFlag(AtomicBool::new(false))));
+        let mut cx = Context::from_waker(&waker);
+        for i in 0..2000_u32 {
+            // register a waker, then drop the future while a send races it
+            let sender = tx.clone();
+            let send = std::thread::spawn(move || {
+                sender
+                    .blocking_send(Message::single_key(i, i))
+                    .is_ok()
+            });
+            let mut recv = Box::pin(rx.recv());
+            let polled = recv.as_mut().poll(&mut cx);
+            drop(recv);
+            assert!(unwrap_ok_or!(send.join(), err, panic!("{:?}", err)));
+            if let Poll::Ready(received) = polled {
+                assert_eq!(received, Ok(Message::single_key(i, i)));
+                continue;
+            }
+            let retry = tokio::time::timeout(Duration::from_secs(1), rx.recv());
+            let received = unwrap_ok_or!(retry.await, _, panic!("wakeup lost at {}", i));
+            assert_eq!(received, Ok(Message::single_key(i, i)));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_recv_many() {
+        let (tx, rx) = bounded(10);
+        for (key, value) in [(1, 1), (1, 2), (2, 3), (3, 4), (4, 5), (2, 6)] {
+            assert!(tx
+                .send(Message::single_key(key, value))
+                .await
+                .is_ok());
+        }
+        let mut buf = Vec::new();
+        // messages in a batch don't conflict with each other
+        assert_eq!(rx.recv_many(&mut buf, 3).await, 3);
+        let values: Vec<_> = buf.iter().map(|msg| *msg.get_value()).collect();
+        assert_eq!(values, vec![1, 3, 4]);
+        assert_eq!(rx.recv_many(&mut buf, 10).await, 1);
+        assert_eq!(buf.last(), Some(&Message::single_key(4, 5)));
+        assert_eq!(rx.recv_many(&mut buf, 0).await, 0);
+        assert_eq!(rx.recv().await, Err(RecvError::AllConflict));
+        buf.clear();
+        assert_eq!(rx.recv_many(&mut buf, 10).await, 2);
+        assert_eq!(buf, vec![Message::single_key(1, 2), Message::single_key(2, 6)]);
+        buf.clear();
+        drop(tx);
+        assert_eq!(rx.recv_many(&mut buf, 10).await, 0);
+        assert!(buf.is_empty());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_recv_many_wait_conflict() {
+        let (tx, rx) = bounded(2);
+        assert!(tx.send(Message::single_key(1, 1)).await.is_ok());
+        let held = rx.recv().await;
+        assert!(tx.send(Message::single_key(1, 2)).await.is_ok());
+        let release = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            drop(held);
+        });
+        // waits for the key instead of returning all conflict
+        let mut buf = Vec::new();
+        assert_eq!(rx.recv_many(&mut buf, 2).await, 1);
+        assert_eq!(buf, vec![Message::single_key(1, 2)]);
+        assert!(release.await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_capacity() {
+        let (tx, rx) = bounded(3);
+        let consistent = |len: usize| {
+            assert_eq!(tx.len(), len);
+            assert_eq!(rx.len(), len);
+            assert_eq!(tx.capacity(), rx.capacity());
+            assert_eq!(rx.len() + rx.capacity(), rx.max_capacity());
+        };
+        assert_eq!(tx.max_capacity(), 3);
+        assert_eq!(rx.max_capacity(), 3);
+        consistent(0);
+        assert!(rx.is_empty());
+        assert!(tx.send(Message::single_key(1, 1)).await.is_ok());
+        assert!(tx.send(Message::single_key(1, 2)).await.is_ok());
+        consistent(2);
+        // a held message gives its slot back
+        let held = rx.recv().await;
+        consistent(1);
+        assert_eq!(rx.recv().await, Err(RecvError::AllConflict));
+        consistent(1);
+        drop(held);
+        let received = rx.recv().await;
+        consistent(0);
+        drop(received);
+        // a reserved slot is neither queued nor empty
+        let permit = tx
+            .reserve()
+            .await
+            .unwrap_or_else(|err| panic!("{:?}", err));
+        assert_eq!(tx.capacity(), 2);
+        assert_eq!(tx.len(), 0);
+        drop(permit);
+        consistent(0);
+
+        let (_unbounded_tx, unbounded_rx) = unbounded::<u32, u32>();
+        assert_eq!(unbounded_rx.max_capacity(), 0);
+        assert_eq!(unbounded_rx.capacity(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_sender_occupancy() {
+        let (tx, rx) = bounded(2);
+        assert_eq!(tx.capacity(), 2);
+        assert!(tx.is_empty());
+        assert!(tx.send(Message::single_key(1, 1)).await.is_ok());
+        assert!(tx.send(Message::single_key(1, 2)).await.is_ok());
+        assert_eq!(tx.len(), 2);
+        assert!(tx.is_full());
+        // a received message is held by the receiver, not queued
+        let msg = rx.recv().await;
+        assert_eq!(tx.len(), 1);
+        assert!(!tx.is_full());
+        drop(msg);
+        assert_eq!(tx.len(), 1);
+    }
+
+    #[test]
+    fn test_same_channel() {
+        let (tx, rx) = bounded::<u32, u32>(1);
+        let (tx2, rx2) = bounded::<u32, u32>(1);
+        assert!(tx.same_channel(&tx.clone()));
+        assert!(!tx.same_channel(&tx2));
+        assert!(rx.same_channel(&tx));
+        assert!(rx2.same_channel(&tx2));
+        assert!(!rx.same_channel(&tx2));
+    }
+
+    #[test]
+    fn test_sender_is_disconnected() {
+        let (tx, rx) = bounded::<u32, u32>(1);
+        let tx2 = tx.clone();
+        let tx3 = tx.clone();
+        drop(tx);
+        drop(tx2);
+        assert!(!tx3.is_disconnected());
+        drop(rx);
+        assert!(tx3.is_disconnected());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_receiver_close_and_drain() {
+        let (tx, rx) = bounded(2);
+        assert!(tx.send(Message::single_key(1, 1)).await.is_ok());
+        assert!(tx.send(Message::single_key(2, 2)).await.is_ok());
+        let sender = tx.clone();
+        let blocked =
+            tokio::spawn(async move { sender.send(Message::single_key(3, 3)).await });
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        rx.close();
+        // the blocked sender is woken up
+        let sent = unwrap_ok_or!(blocked.await, err, panic!("{:?}", err));
+        assert_eq!(sent, Err(SendError(Message::single_key(3, 3))));
+        assert_eq!(
+            tx.send(Message::single_key(4, 4)).await,
+            Err(SendError(Message::single_key(4, 4)))
+        );
+        assert!(tx.is_disconnected());
+        // buffered messages are drained though senders are alive
+        assert_eq!(rx.recv().await, Ok(Message::single_key(1, 1)));
+        assert_eq!(rx.recv().await, Ok(Message::single_key(2, 2)));
+        assert_eq!(rx.recv().await, Err(RecvError::Disconnected));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_drain() {
+        let (tx, rx) = bounded(3);
+        assert!(tx.send(Message::single_key(1, 1)).await.is_ok());
+        let held = rx.recv().await;
+        assert_eq!(held, Ok(Message::single_key(1, 1)));
+        assert!(tx
+            .send(Message::multiple_keys(vec![1, 2], 2))
+            .await
+            .is_ok());
+        assert!(tx.send(Message::single_key(2, 3)).await.is_ok());
+        assert!(tx.send(Message::single_key(3, 4)).await.is_ok());
+        let sender = tx.clone();
+        let blocked =
+            tokio::spawn(async move { sender.send(Message::single_key(4, 5)).await });
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        // conflicts are ignored, in FIFO order
+        let drained = rx.drain();
+        let values: Vec<_> = drained.iter().map(|m| *m.get_value()).collect();
+        assert_eq!(values, vec![2, 3, 4]);
+        // the blocked sender is woken up by the freed capacity
+        assert_eq!(unwrap_ok_or!(blocked.await, err, panic!("{:?}", err)), Ok(()));
+        assert_eq!(rx.drain(), vec![Message::single_key(4, 5)]);
+        // dropping drained messages releases nothing, key 1 is still held
+        drop(drained);
+        assert!(tx.send(Message::single_key(1, 6)).await.is_ok());
+        let conflict = rx
+            .recv_batch_timeout(1, Duration::from_millis(10))
+            .await;
+        assert_eq!(conflict, Err(RecvError::AllConflict));
+        drop(held);
+        assert_eq!(rx.recv().await, Ok(Message::single_key(1, 6)));
+    }
+
+    #[tokio::test]
+    async fn test_recv_any() {
+        let (tx, rx) = bounded(10);
+        for (k, v) in [(1, 1), (1, 2), (2, 3), (1, 4)] {
+            assert!(tx.send(Message::single_key(k, v)).await.is_ok());
+        }
+        let first = rx.recv().await;
+        assert_eq!(first, Ok(Message::single_key(1, 1)));
+        // the front-most message is taken though key 1 is active
+        let second = rx.recv_any().await;
+        assert_eq!(second, Ok(Message::single_key(1, 2)));
+        assert_eq!(rx.recv().await, Ok(Message::single_key(2, 3)));
+        assert_eq!(rx.recv().await, Err(RecvError::AllConflict));
+        // key 1 is active until both messages are dropped
+        drop(first);
+        assert_eq!(rx.recv().await, Err(RecvError::AllConflict));
+        drop(second);
+        assert_eq!(rx.recv().await, Ok(Message::single_key(1, 4)));
+        drop(tx);
+        assert_eq!(rx.recv_any().await, Err(RecvError::Disconnected));
+    }
+
+    #[tokio::test]
+    async fn test_peek() {
+        let (tx, rx) = bounded(10);
+        for (k, v) in [(1, 1), (1, 2), (2, 3)] {
+            assert!(tx.send(Message::single_key(k, v)).await.is_ok());
+        }
+        let peeked = rx.peek().await;
+        assert_eq!(peeked, Ok(Message::single_key(1, 1)));
+        // dropping the copy releases nothing, and the same message is received
+        drop(peeked);
+        let first = rx.recv().await;
+        assert_eq!(first, Ok(Message::single_key(1, 1)));
+        assert_eq!(rx.peek().await, Ok(Message::single_key(2, 3)));
+        assert_eq!(rx.recv().await, Ok(Message::single_key(2, 3)));
+        assert_eq!(rx.peek().await, Err(RecvError::AllConflict));
+        drop(first);
+        assert_eq!(rx.peek().await, Ok(Message::single_key(1, 2)));
+        assert_eq!(rx.recv().await, Ok(Message::single_key(1, 2)));
+        drop(tx);
+        assert_eq!(rx.peek().await, Err(RecvError::Disconnected));
+    }
+
+    #[tokio::test]
+    async fn test_recv_filter() {
+        let (tx, rx) = bounded(10);
+        for (k, v) in [(1, 1), (2, 10), (1, 20), (3, 2)] {
+            assert!(tx.send(Message::single_key(k, v)).await.is_ok());
+        }
+        let large = |m: &super::Message<u32, u32>| *m.get_value() >= 10;
+        let first = rx.recv_filter(large).await;
+        assert_eq!(first, Ok(Message::single_key(2, 10)));
+        let held = rx.recv().await;
+        assert_eq!(held, Ok(Message::single_key(1, 1)));
+        // the matching message is queued, but key 1 is active
+        assert_eq!(rx.recv_filter(large).await, Err(RecvFilterError::AllConflict));
+        let none = rx.recv_filter(|m| *m.get_value() > 100).await;
+        assert_eq!(none, Err(RecvFilterError::NoMatch));
+        drop(held);
+        assert_eq!(rx.recv_filter(large).await, Ok(Message::single_key(1, 20)));
+        // unmatched messages are left in order
+        assert_eq!(rx.recv().await, Ok(Message::single_key(3, 2)));
+        drop(tx);
+        assert_eq!(rx.recv_filter(large).await, Err(RecvFilterError::Disconnected));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_recv_key() {
+        let (tx, rx) = bounded(10);
+        for (k, v) in [(1, 1), (1, 2), (2, 3)] {
+            assert!(tx.send(Message::single_key(k, v)).await.is_ok());
+        }
+        let held = rx.recv().await;
+        assert_eq!(held, Ok(Message::single_key(1, 1)));
+        let release = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            drop(held);
+        });
+        // receivable once the outstanding message is dropped
+        assert_eq!(rx.recv_key(&1).await, Ok(Message::single_key(1, 2)));
+        unwrap_ok_or!(release.await, err, panic!("{:?}", err));
+        // a message sent later is waited for
+        let sender = tx.clone();
+        let send = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            sender
+                .send(Message::multiple_keys(vec![3, 4], 4))
+                .await
+        });
+        assert_eq!(rx.recv_key(&4).await, Ok(Message::multiple_keys(vec![3, 4], 4)));
+        assert_eq!(unwrap_ok_or!(send.await, err, panic!("{:?}", err)), Ok(()));
+        drop(tx);
+        assert_eq!(rx.recv_key(&5).await, Err(RecvError::Disconnected));
+        assert_eq!(rx.recv().await, Ok(Message::single_key(2, 3)));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_sender_count() {
+        let (tx, rx) = bounded::<u32, u32>(4);
+        assert_eq!(rx.sender_count(), 1);
+        let handles: Vec<_> = (0..4)
+            .map(|i| {
+                let sender = tx.clone();
+                tokio::spawn(async move {
+                    let clones: Vec<_> = (0..8).map(|_| sender.clone()).collect();
+                    assert!(sender
+                        .send(Message::single_key(i, i))
+                        .await
+                        .is_ok());
+                    drop(clones);
+                })
+            })
+            .collect();
+        for handle in handles {
+            assert!(handle.await.is_ok());
+        }
+        assert_eq!(rx.sender_count(), 1);
+        assert!(tx.receiver_alive());
+        drop(tx);
+        assert_eq!(rx.sender_count(), 0);
+        assert!(rx.is_disconnected());
+    }
+
+    #[tokio::test]
+    async fn test_receiver_alive() {
+        let (tx, rx) = bounded::<u32, u32>(1);
+        rx.close();
+        assert!(tx.is_disconnected());
+        assert!(tx.receiver_alive());
+        drop(rx);
+        assert!(!tx.receiver_alive());
+    }
+
+    #[cfg(feature = "stream")]
+    #[tokio::test]
+    async fn test_grouped_stream() {
+        use futures_util::StreamExt;
+        let (tx, rx) = bounded(10);
+        for (key, value) in [(1, 1), (1, 2), (2, 3), (1, 4), (2, 5), (2, 6)] {
+            assert!(tx
+                .send(Message::single_key(key, value))
+                .await
+                .is_ok());
+        }
+        assert!(tx
+            .send(Message::multiple_keys([3, 4], 7))
+            .await
+            .is_ok());
+        drop(tx);
+        let values = |group: &[super::Message<u32, u32>]| -> Vec<u32> {
+            group
+                .iter()
+                .map(|msg| *msg.get_value())
+                .collect()
+        };
+        let mut stream = rx.into_grouped_stream();
+        let ones = unwrap_some_or!(stream.next().await, panic!("stream ended"));
+        assert_eq!(ones.0, Some(1));
+        assert_eq!(values(&ones.1), vec![1, 2]);
+        let twos = unwrap_some_or!(stream.next().await, panic!("stream ended"));
+        assert_eq!(twos.0, Some(2));
+        assert_eq!(values(&twos.1), vec![3]);
+        // (1, 4) waits for the first group, (2, 5) for the second
+        let multi = unwrap_some_or!(stream.next().await, panic!("stream ended"));
+        assert_eq!(multi.0, None);
+        assert_eq!(values(&multi.1), vec![7]);
+        drop(twos);
+        let more_twos = unwrap_some_or!(stream.next().await, panic!("stream ended"));
+        assert_eq!(more_twos.0, Some(2));
+        assert_eq!(values(&more_twos.1), vec![5, 6]);
+        // a key is held until the whole group is dropped
+        let (_, mut held) = ones;
+        drop(held.pop());
+        let release = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            drop(held);
+        });
+        let last_one = unwrap_some_or!(stream.next().await, panic!("stream ended"));
+        assert_eq!(values(&last_one.1), vec![4]);
+        assert!(release.await.is_ok());
+        assert!(stream.next().await.is_none());
+        assert!(stream.into_inner().is_disconnected());
+    }
+
+    #[cfg(feature = "sink")]
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_sink_send_all() {
+        use futures_util::{stream, SinkExt};
+        let (mut tx, rx) = bounded::<u32, u32>(2);
+        let probe = tx.clone();
+        let sending = tokio::spawn(async move {
+            let mut msgs = stream::iter((0..4096).map(|i| Ok(Message::single_key(i, i))));
+            tx.send_all(&mut msgs).await
+        });
+        // back-pressure, the sink waits for slots
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(probe.len(), 2);
+        assert!(!sending.is_finished());
+        for i in 0..4096 {
+            let msg = unwrap_ok_or!(rx.recv().await, err, panic!("{:?}", err));
+            assert_eq!(msg.get_single_key(), Some(&i));
+        }
+        assert!(matches!(sending.await, Ok(Ok(()))));
+        assert!(probe.is_empty());
+    }
+
+    #[cfg(feature = "sink")]
+    #[tokio::test]
+    async fn test_sink_reservation_released() {
+        use futures_util::{future, SinkExt};
+        let (mut tx, rx) = bounded::<u32, u32>(1);
+        let other = tx.clone();
+        assert!(future::poll_fn(|cx| tx.poll_ready_unpin(cx))
+            .await
+            .is_ok());
+        // the only slot is reserved
+        let blocked = tokio::time::timeout(
+            Duration::from_millis(20),
+            other.send(Message::single_key(1, 1)),
+        );
+        assert!(blocked.await.is_err());
+        // dropping the sender gives the slot back
+        drop(tx);
+        assert!(other
+            .send(Message::single_key(2, 2))
+            .await
+            .is_ok());
+        let msg = unwrap_ok_or!(rx.recv().await, err, panic!("{:?}", err));
+        assert_eq!(msg.get_single_key(), Some(&2));
+        drop(rx);
+        // a closed channel fails to reserve
+        let mut closed = other.clone();
+        assert!(future::poll_fn(|cx| closed.poll_ready_unpin(cx))
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_try_send() {
+        let (tx, rx) = bounded::<u32, u32>(2);
+        assert!(tx.try_send(Message::single_key(1, 1)).is_ok());
+        assert!(tx.try_send(Message::single_key(2, 2)).is_ok());
+        // exactly full, the message is given back intact
+        assert_eq!(
+            tx.try_send(Message::single_key(3, 3)),
+            Err(TrySendError::Full(Message::single_key(3, 3)))
+        );
+        assert_eq!(tx.len(), 2);
+        let msg = unwrap_ok_or!(rx.recv().await, err, panic!("{:?}", err));
+        assert_eq!(msg.get_single_key(), Some(&1));
+        assert!(tx.try_send(Message::single_key(3, 3)).is_ok());
+        drop(rx);
+        assert_eq!(
+            tx.try_send(Message::single_key(4, 4)),
+            Err(TrySendError::Disconnected(Message::single_key(4, 4)))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_try_recv() {
+        let (tx, rx) = bounded::<u32, u32>(2);
+        assert_eq!(rx.try_recv().err(), Some(TryRecvError::Empty));
+        assert!(tx.send(Message::single_key(1, 1)).await.is_ok());
+        assert!(tx.send(Message::single_key(1, 2)).await.is_ok());
+        let first = unwrap_ok_or!(rx.try_recv(), err, panic!("{:?}", err));
+        assert_eq!(first, Message::single_key(1, 1));
+        // not empty, but blocked by the active key
+        assert_eq!(rx.try_recv().err(), Some(TryRecvError::AllConflict));
+        // the received message releases its key when dropped
+        drop(first);
+        let second = unwrap_ok_or!(rx.try_recv(), err, panic!("{:?}", err));
+        assert_eq!(second, Message::single_key(1, 2));
+        drop(tx);
+        assert_eq!(rx.try_recv().err(), Some(TryRecvError::Disconnected));
+    }
+
+    #[tokio::test]
+    async fn test_recv_timeout() {
+        let (tx, rx) = bounded::<u32, u32>(2);
+        let dur = Duration::from_millis(20);
+        assert_eq!(rx.recv_timeout(dur).await.err(), Some(RecvTimeoutError::Timeout));
+        assert!(tx.send(Message::single_key(1, 1)).await.is_ok());
+        assert!(tx.send(Message::single_key(1, 2)).await.is_ok());
+        let first = unwrap_ok_or!(rx.recv_timeout(dur).await, err, panic!("{:?}", err));
+        assert_eq!(first, Message::single_key(1, 1));
+        // conflict is not reported as timeout
+        assert_eq!(rx.recv_timeout(dur).await.err(), Some(RecvTimeoutError::AllConflict));
+        drop(first);
+        let second = unwrap_ok_or!(rx.recv_timeout(dur).await, err, panic!("{:?}", err));
+        assert_eq!(second, Message::single_key(1, 2));
+        drop(tx);
+        assert_eq!(
+            rx.recv_timeout(dur).await.err(),
+            Some(RecvTimeoutError::Disconnected)
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_recv_timeout_racing_send() {
+        let (tx, rx) = bounded::<u32, u32>(8);
+        let sending = tokio::spawn(async move {
+            for i in 0..200 {
+                tokio::time::sleep(Duration::from_micros(500)).await;
+                assert!(tx.send(Message::single_key(i, i)).await.is_ok());
+            }
+        });
+        // timeouts racing the sends never lose a message
+        let mut next = 0;
+        loop {
+            match rx
+                .recv_timeout(Duration::from_micros(300))
+                .await
+            {
+                Ok(msg) => {
+                    assert_eq!(msg.get_single_key(), Some(&next));
+                    next += 1;
+                }
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(err) => {
+                    assert_eq!(err, RecvTimeoutError::Disconnected);
+                    break;
+                }
+            }
+        }
+        assert_eq!(next, 200);
+        assert!(sending.await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_poll_recv() {
+        let (tx, rx) = bounded::<u32, u32>(2);
+        let flag = Arc::new(WokenFlag(AtomicBool::new(false)));
+        let waker = Waker::from(Arc::clone(&flag));
+        let mut cx = Context::from_waker(&waker);
+        assert!(rx.poll_recv(&mut cx).is_pending());
+        assert!(!flag.take());
+        // a sent message wakes the registered task
+        assert!(tx.send(Message::single_key(1, 1)).await.is_ok());
+        assert!(flag.take());
+        let held = match rx.poll_recv(&mut cx) {
+            Poll::Ready(res) => unwrap_ok_or!(res, err, panic!("{:?}", err)),
+            Poll::Pending => panic!("a sent message is pending"),
+        };
+        assert_eq!(held, Message::single_key(1, 1));
+        assert!(tx.send(Message::single_key(1, 2)).await.is_ok());
+        assert_eq!(rx.poll_recv(&mut cx), Poll::Ready(Err(RecvError::AllConflict)));
+        drop(held);
+        assert!(matches!(rx.poll_recv(&mut cx), Poll::Ready(Ok(_))));
+        // the last sender gone wakes the task too
+        assert!(rx.poll_recv(&mut cx).is_pending());
+        let _woken = flag.take();
+        drop(tx);
+        assert!(flag.take());
+        assert_eq!(rx.poll_recv(&mut cx), Poll::Ready(Err(RecvError::Disconnected)));
+    }
+
+    #[tokio::test]
+    async fn test_reserve() {
+        let (tx, rx) = bounded::<u32, u32>(2);
+        let other = tx.clone();
+        let first = tx
+            .reserve()
+            .await
+            .unwrap_or_else(|err| panic!("{:?}", err));
+        let second = tx
+            .reserve()
+            .await
+            .unwrap_or_else(|err| panic!("{:?}", err));
+        // all slots are reserved, other senders wait
+        let blocked = tokio::time::timeout(
+            Duration::from_millis(20),
+            other.send(Message::single_key(1, 1)),
+        );
+        assert!(blocked.await.is_err());
+        assert!(first.send(Message::single_key(2, 2)).is_ok());
+        // a permit dropped unsent gives the slot back
+        drop(second);
+        assert!(other
+            .send(Message::single_key(3, 3))
+            .await
+            .is_ok());
+        let msg = unwrap_ok_or!(rx.recv().await, err, panic!("{:?}", err));
+        assert_eq!(msg.get_single_key(), Some(&2));
+        drop(msg);
+        let reserved = tx
+            .reserve()
+            .await
+            .unwrap_or_else(|err| panic!("{:?}", err));
+        // disconnected after the reservation
+        rx.close();
+        assert_eq!(
+            reserved.send(Message::single_key(4, 4)),
+            Err(SendError(Message::single_key(4, 4)))
+        );
+        assert!(tx.reserve().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_try_reserve() {
+        let (tx, rx) = bounded::<u32, u32>(1);
+        let permit = tx
+            .try_reserve()
+            .unwrap_or_else(|err| panic!("{:?}", err));
+        assert_eq!(tx.try_reserve().err(), Some(TryReserveError::Full));
+        // the slot is given back when the permit is dropped
+        drop(permit);
+        let reserved = tx
+            .try_reserve()
+            .unwrap_or_else(|err| panic!("{:?}", err));
+        assert!(reserved.send(Message::single_key(1, 1)).is_ok());
+        assert_eq!(tx.try_reserve().err(), Some(TryReserveError::Full));
+        drop(rx);
+        assert_eq!(tx.try_reserve().err(), Some(TryReserveError::Disconnected));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_reserve_owned() {
+        let (tx, rx) = bounded::<u32, u32>(1);
+        let permit = tx
+            .clone()
+            .reserve_owned()
+            .await
+            .unwrap_or_else(|err| panic!("{:?}", err));
+        assert_eq!(rx.sender_count(), 2);
+        // the permit outlives the borrow of any sender
+        let handle = tokio::spawn(async move {
+            let sender = permit
+                .send(Message::single_key(1, 1))
+                .unwrap_or_else(|err| panic!("{:?}", err));
+            sender.is_full()
+        });
+        assert!(matches!(handle.await, Ok(true)));
+        assert_eq!(rx.sender_count(), 1);
+        let msg = unwrap_ok_or!(rx.recv().await, err, panic!("{:?}", err));
+        assert_eq!(msg.get_single_key(), Some(&1));
+        // an unused permit gives back both the slot and the sender
+        let unused = tx
+            .clone()
+            .reserve_owned()
+            .await
+            .unwrap_or_else(|err| panic!("{:?}", err));
+        assert!(tx.try_reserve().is_err());
+        drop(unused);
+        assert_eq!(rx.sender_count(), 1);
+        assert!(tx.try_reserve().is_ok());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_blocking_send() {
+        let (tx, rx) = bounded(1);
+        let producer = std::thread::spawn(move || {
+            // blocked on a full buffer until the consumer receives
+            (0..5).all(|i| {
+                tx.blocking_send(Message::single_key(i, i))
+                    .is_ok()
+            })
+        });
+        for i in 0..5 {
+            assert_eq!(rx.recv().await, Ok(Message::single_key(i, i)));
+        }
+        let sent = tokio::task::spawn_blocking(move || producer.join()).await;
+        assert!(matches!(sent, Ok(Ok(true))));
+        assert_eq!(rx.recv().await, Err(RecvError::Disconnected));
+    }
+
+    #[test]
+    fn test_blocking_send_disconnected() {
+        let (tx, rx) = bounded(1);
+        assert!(tx
+            .blocking_send(Message::single_key(1, 1))
+            .is_ok());
+        let sender = tx.clone();
+        let blocked =
+            std::thread::spawn(move || sender.blocking_send(Message::single_key(2, 2)));
+        std::thread::sleep(Duration::from_millis(20));
+        // the blocked thread is woken up by closing
+        drop(rx);
+        let sent = unwrap_ok_or!(blocked.join(), err, panic!("{:?}", err));
+        let err = unwrap_some_or!(sent.err(), panic!("it's closed"));
+        assert_eq!(err.as_inner(), &Message::single_key(2, 2));
+        assert_eq!(err.into_inner().into_value(), 2);
+        drop(tx);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_blocking_recv() {
+        let (tx, rx) = bounded::<i32, i32>(1);
+        let consumer = std::thread::spawn(move || {
+            let mut received = Vec::new();
+            loop {
+                match rx.blocking_recv() {
+                    Ok(msg) => received.push(msg.get_single_key().copied()),
+                    Err(err) => return (received, err),
+                }
+            }
+        });
+        let mut senders = Vec::new();
+        for i in 0..4 {
+            let sender = tx.clone();
+            senders.push(tokio::spawn(async move {
+                sender
+                    .send(Message::single_key(i, i))
+                    .await
+                    .is_ok()
+            }));
+        }
+        drop(tx);
+        for sender in senders {
+            assert!(matches!(sender.await, Ok(true)));
+        }
+        let joined = tokio::task::spawn_blocking(move || consumer.join()).await;
+        let received = unwrap_ok_or!(joined, err, panic!("{:?}", err));
+        let (mut received, err) = unwrap_ok_or!(received, err, panic!("{:?}", err));
+        // held messages are dropped at once, their keys never conflict
+        received.sort_unstable();
+        assert_eq!(received, vec![Some(0), Some(1), Some(2), Some(3)]);
+        assert_eq!(err, RecvError::Disconnected);
+    }
+
+    #[tokio::test]
+    async fn test_blocking_recv_in_runtime() {
+        let (_tx, rx) = bounded::<u32, u32>(1);
+        let received = catch_unwind(AssertUnwindSafe(|| rx.blocking_recv()));
+        assert!(received.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_blocking_send_in_runtime() {
+        let (tx, _rx) = bounded(1);
+        let sent = catch_unwind(AssertUnwindSafe(|| {
+            tx.blocking_send(Message::single_key(1, 1))
+        }));
+        assert!(sent.is_err());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    #[allow(clippy::unwrap_used)]
+    async fn test_unbounded() {
+        let (tx, rx) = unbounded();
+        let mut senders = Vec::new();
+        for task in 0..8_u32 {
+            let sender = tx.clone();
+            senders.push(tokio::spawn(async move {
+                // never waits for the receiver
+                (0..100_u32).all(|i| {
+                    sender
+                        .send(Message::single_key(task, i))
+                        .is_ok()
+                })
+            }));
+        }
+        for sender in senders {
+            assert!(matches!(sender.await, Ok(true)));
+        }
+        assert_eq!(rx.sender_count(), 1);
+        drop(tx);
+        let mut received = Vec::new();
+        let mut held = Vec::new();
+        loop {
+            match rx.recv().await {
+                Ok(msg) => {
+                    received.push((*msg.get_single_key().unwrap(), *msg.get_value()));
+                    held.push(msg);
+                }
+                // a message of every task is held
+                Err(RecvError::AllConflict) => {
+                    assert_eq!(held.len(), 8);
+                    held.clear();
+                }
+                Err(RecvError::Disconnected) => break,
+            }
+        }
+        assert_eq!(held.len(), 8);
+        assert_eq!(received.len(), 800);
+        // messages with the same key are received in order
+        for task in 0..8_u32 {
+            let values: Vec<_> = received
+                .iter()
+                .filter(|&&(key, _)| key == task)
+                .map(|&(_, value)| value)
+                .collect();
+            assert_eq!(values, (0..100).collect::<Vec<_>>());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_unbounded_closed() {
+        let (tx, rx) = unbounded();
+        assert!(tx.send(Message::single_key(1, 1)).is_ok());
+        assert!(tx.same_channel(&tx.clone()));
+        assert!(!tx.is_closed());
+        rx.close();
+        assert!(tx.is_closed());
+        tx.closed().await;
+        assert_eq!(
+            tx.send(Message::single_key(2, 2)),
+            Err(SendError(Message::single_key(2, 2)))
+        );
+        assert_eq!(rx.recv().await, Ok(Message::single_key(1, 1)));
+        assert_eq!(rx.recv().await, Err(RecvError::Disconnected));
+    }
+
+    #[tokio::test]
+    async fn test_receiver_close_with_permits() {
+        let (tx, rx) = bounded(3);
+        assert!(tx.send(Message::single_key(1, 1)).await.is_ok());
+        let permit = tx
+            .reserve()
+            .await
+            .unwrap_or_else(|err| panic!("{:?}", err));
+        rx.close();
+        // a slot reserved before closing can't be used either
+        assert_eq!(
+            permit.send(Message::single_key(2, 2)),
+            Err(SendError(Message::single_key(2, 2)))
+        );
+        assert!(matches!(tx.try_reserve(), Err(TryReserveError::Disconnected)));
+        // closed with live senders, the buffered message is still received
+        assert!(tx.is_closed());
+        assert_eq!(rx.remaining_after_disconnect(), Some(1));
+        assert_eq!(rx.try_recv(), Ok(Message::single_key(1, 1)));
+        assert_eq!(rx.remaining_after_disconnect(), Some(0));
+        assert_eq!(rx.try_recv(), Err(TryRecvError::Disconnected));
+        assert_eq!(rx.recv().await, Err(RecvError::Disconnected));
+    }
+
+    #[tokio::test]
+    async fn test_sender_is_closed() {
+        let (tx, rx) = bounded::<u32, u32>(1);
+        assert!(!tx.is_closed());
+        // set without any send in flight
+        drop(rx);
+        assert!(tx.is_closed());
+        assert!(tx
+            .send(Message::single_key(1, 1))
+            .await
+            .is_err());
+
+        let (tx2, rx2) = bounded::<u32, u32>(1);
+        rx2.close();
+        assert!(tx2.is_closed());
+        // not set when all other senders are gone
+        let (tx3, rx3) = bounded::<u32, u32>(1);
+        drop(tx3.clone());
+        assert!(!tx3.is_closed());
+        drop(rx3);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_sender_closed() {
+        let (tx, rx) = bounded::<u32, u32>(1);
+        let sender = tx.clone();
+        let closed = tokio::spawn(async move { sender.closed().await });
+        // not resolved while the receiver is alive, even with a message sent
+        assert!(tx.send(Message::single_key(1, 1)).await.is_ok());
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(!closed.is_finished());
+        drop(rx);
+        let resolved = tokio::time::timeout(Duration::from_secs(1), closed).await;
+        assert!(matches!(resolved, Ok(Ok(()))));
+        // resolved immediately once closed
+        tx.closed().await;
+    }
+
     #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
     #[allow(clippy::unwrap_used)]
     async fn test_conflict_msg_sequence_correct() {
@@ -211,7 +1584,6 @@ mod test {
 
     #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
     async fn test_conflict_single_key_send_recv() {
-        use std::sync::atomic::Ordering::SeqCst;
         // the test case is as follow
         // tx send 10 k1 msgs
         // rx recv a k1 msg, then recv will return `AllConflict`
@@ -315,4 +1687,539 @@ mod test {
             );
         }
     }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_recv_batch_timeout_deadline() {
+        let (tx, rx) = bounded(10);
+        for i in 0..2 {
+            let _drop = tx.send(Message::single_key(i, i)).await;
+        }
+        let dur = Duration::from_millis(100);
+        let start = Instant::now();
+        let batch =
+            unwrap_ok_or!(rx.recv_batch_timeout(5, dur).await, err, panic!("{:?}", err));
+        assert!(start.elapsed() >= dur);
+        assert_eq!(batch, vec![Message::single_key(0, 0), Message::single_key(1, 1)]);
+        drop(batch);
+        let empty =
+            unwrap_ok_or!(rx.recv_batch_timeout(5, dur).await, err, panic!("{:?}", err));
+        assert!(empty.is_empty());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_recv_batch_timeout_n_reached() {
+        let (tx, rx) = bounded(10);
+        for i in 0..5 {
+            let _drop = tx.send(Message::single_key(i, i)).await;
+        }
+        let dur = Duration::from_secs(10);
+        let start = Instant::now();
+        let batch =
+            unwrap_ok_or!(rx.recv_batch_timeout(3, dur).await, err, panic!("{:?}", err));
+        assert!(start.elapsed() < dur);
+        assert_eq!(batch.len(), 3);
+        // the batch ends when remaining messages conflict with the batch
+        let _drop = tx.send(Message::single_key(3, 5)).await;
+        let batch2 =
+            unwrap_ok_or!(rx.recv_batch_timeout(3, dur).await, err, panic!("{:?}", err));
+        assert!(start.elapsed() < dur);
+        assert_eq!(batch2, vec![Message::single_key(3, 3), Message::single_key(4, 4)]);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_recv_batch_timeout_disconnect() {
+        let (tx, rx) = bounded(10);
+        let handle = tokio::spawn(async move {
+            for i in 0..2 {
+                let _drop = tx.send(Message::single_key(i, i)).await;
+            }
+        });
+        let dur = Duration::from_secs(10);
+        let start = Instant::now();
+        let batch =
+            unwrap_ok_or!(rx.recv_batch_timeout(5, dur).await, err, panic!("{:?}", err));
+        assert!(start.elapsed() < dur);
+        assert_eq!(batch.len(), 2);
+        assert_eq!(rx.recv_batch_timeout(5, dur).await, Err(RecvError::Disconnected));
+        let _drop = handle.await;
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_wait_capacity() {
+        let cap = 4;
+        let (tx, rx) = bounded(cap);
+        for i in 0..cap {
+            let _drop = tx.send(Message::single_key(i, i)).await;
+        }
+        let ready = Arc::new(AtomicBool::new(false));
+        let waiter_ready = Arc::<AtomicBool>::clone(&ready);
+        let waiter = tx.clone();
+        let handle = tokio::spawn(async move {
+            let res = waiter.wait_capacity(2).await;
+            waiter_ready.store(true, SeqCst);
+            res
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!ready.load(SeqCst));
+        let _drop = rx.recv().await;
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!ready.load(SeqCst));
+        let _drop1 = rx.recv().await;
+        let res = unwrap_ok_or!(
+            tokio::time::timeout(Duration::from_secs(10), handle).await,
+            err,
+            panic!("{:?}", err)
+        );
+        assert_eq!(unwrap_ok_or!(res, err, panic!("{:?}", err)), Ok(()));
+        assert!(ready.load(SeqCst));
+        // it's not a reservation, the slots are still available
+        assert_eq!(tx.wait_capacity(2).await, Ok(()));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_wait_capacity_disconnect() {
+        let (tx, rx) = bounded(1);
+        let _drop = tx.send(Message::single_key(1, 1)).await;
+        let handle = tokio::spawn(async move { tx.wait_capacity(1).await });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        drop(rx);
+        let res = unwrap_ok_or!(handle.await, err, panic!("{:?}", err));
+        assert_eq!(res, Err(SendError(())));
+    }
+
+    #[tokio::test]
+    async fn test_recv_unguarded() {
+        let (tx, rx) = bounded(10);
+        for i in 0..3 {
+            let _drop = tx.send(Message::single_key(1, i)).await;
+        }
+        // an unguarded message holds no key
+        let msg1 = unwrap_ok_or!(rx.recv_unguarded().await, err, panic!("{:?}", err));
+        assert_eq!(msg1, Message::single_key(1, 0));
+        let msg2 = unwrap_ok_or!(rx.recv().await, err, panic!("{:?}", err));
+        assert_eq!(msg2, Message::single_key(1, 1));
+        // it ignores the active key of a guarded message
+        let msg3 = unwrap_ok_or!(rx.recv_unguarded().await, err, panic!("{:?}", err));
+        assert_eq!(msg3, Message::single_key(1, 2));
+        drop(tx);
+        assert_eq!(rx.recv_unguarded().await, Err(RecvError::Disconnected));
+        drop(msg1);
+        drop(msg2);
+        drop(msg3);
+    }
+
+    #[tokio::test]
+    async fn test_lock_key_queued_message() {
+        let (tx, rx) = bounded(10);
+        for i in 1..=2 {
+            let _drop = tx.send(Message::single_key(i, i)).await;
+        }
+        // a queued message gives up the locked key
+        let lock = rx.lock_key(1);
+        assert_eq!(lock.get_single_key(), Some(&1));
+        let msg = unwrap_ok_or!(rx.recv().await, err, panic!("{:?}", err));
+        assert_eq!(msg, Message::single_key(2, 2));
+        assert_eq!(rx.recv().await, Err(RecvError::AllConflict));
+        drop(lock);
+        let msg2 = unwrap_ok_or!(rx.recv().await, err, panic!("{:?}", err));
+        assert_eq!(msg2, Message::single_key(1, 1));
+        drop(msg);
+        drop(msg2);
+    }
+
+    #[tokio::test]
+    async fn test_lock_key_refcount() {
+        let (tx, rx) = bounded(10);
+        for i in 0..3 {
+            let _drop = tx.send(Message::single_key(1, i)).await;
+        }
+        // the message is dropped while the lock is held
+        let msg = unwrap_ok_or!(rx.recv().await, err, panic!("{:?}", err));
+        let lock = rx.lock_key(1);
+        drop(msg);
+        assert_eq!(rx.recv().await, Err(RecvError::AllConflict));
+        drop(lock);
+        let msg2 = unwrap_ok_or!(rx.recv().await, err, panic!("{:?}", err));
+        assert_eq!(msg2, Message::single_key(1, 1));
+
+        // the lock is dropped while the message is held
+        let lock2 = rx.lock_key(1);
+        drop(lock2);
+        assert_eq!(rx.recv().await, Err(RecvError::AllConflict));
+        drop(msg2);
+
+        // locks of the same key are counted separately
+        let lock3 = rx.lock_key(1);
+        let lock4 = rx.lock_key(1);
+        drop(lock3);
+        assert_eq!(rx.recv().await, Err(RecvError::AllConflict));
+        drop(lock4);
+        let msg3 = unwrap_ok_or!(rx.recv().await, err, panic!("{:?}", err));
+        assert_eq!(msg3, Message::single_key(1, 2));
+    }
+
+    #[tokio::test]
+    async fn test_remaining_after_disconnect() {
+        let (tx, rx) = bounded(10);
+        for i in 0..2 {
+            let _drop = tx.send(Message::single_key(i, i)).await;
+        }
+        assert!(!rx.is_disconnected());
+        assert_eq!(rx.remaining_after_disconnect(), None);
+        drop(tx);
+        assert!(rx.is_disconnected());
+        assert_eq!(rx.remaining_after_disconnect(), Some(2));
+        let msg = unwrap_ok_or!(rx.recv().await, err, panic!("{:?}", err));
+        assert_eq!(rx.remaining_after_disconnect(), Some(1));
+        let msg2 = unwrap_ok_or!(rx.recv().await, err, panic!("{:?}", err));
+        assert_eq!(rx.remaining_after_disconnect(), Some(0));
+        assert_eq!(rx.recv().await, Err(RecvError::Disconnected));
+        assert!(rx.is_disconnected());
+        drop(msg);
+        drop(msg2);
+    }
+
+    #[tokio::test]
+    async fn test_recv_or_cancel_empty() {
+        let (_tx, rx) = bounded::<usize, usize>(10);
+        let (cancel, cancelled) = oneshot::channel::<()>();
+        let handle = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            let _drop = cancel.send(());
+        });
+        let until = async move {
+            drop(cancelled.await);
+        };
+        assert_eq!(rx.recv_or(until).await, Err(RecvOr::Cancelled));
+        let _drop = handle.await;
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_recv_or_cancel_race() {
+        for i in 0..100 {
+            let (tx, rx) = bounded(10);
+            let (cancel, cancelled) = oneshot::channel::<()>();
+            let handle = tokio::spawn(async move {
+                let _drop = tx.send(Message::single_key(i, i)).await;
+            });
+            let handle2 = tokio::spawn(async move {
+                let _drop = cancel.send(());
+            });
+            let until = async move {
+                drop(cancelled.await);
+            };
+            match rx.recv_or(until).await {
+                Ok(msg) => assert_eq!(msg.get_value(), &i),
+                Err(err) => {
+                    assert_eq!(err, RecvOr::Cancelled);
+                    let _drop = handle.await;
+                    // the message is left in the channel
+                    assert_eq!(rx.recv().await, Ok(Message::single_key(i, i)));
+                }
+            }
+            let _drop = handle2.await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_recv_or_delivery() {
+        let (tx, rx) = bounded(10);
+        for _ in 0..2 {
+            let _drop = tx.send(Message::single_key(1, 1)).await;
+        }
+        // buffered messages are preferred over cancellation
+        let msg = unwrap_ok_or!(rx.recv_or(async {}).await, err, panic!("{:?}", err));
+        assert_eq!(rx.recv_or(async {}).await, Err(RecvOr::AllConflict));
+        drop(msg);
+        drop(tx);
+        let msg2 = unwrap_ok_or!(rx.recv_or(async {}).await, err, panic!("{:?}", err));
+        assert_eq!(msg2, Message::single_key(1, 1));
+        drop(msg2);
+        assert_eq!(rx.recv_or(async {}).await, Err(RecvOr::Disconnected));
+    }
+
+    #[tokio::test]
+    async fn test_recv_key_only() {
+        let (tx, rx) = bounded(10);
+        let _drop = tx.send(Message::key_only(1)).await;
+        let _sent = tx.send(Message::keys_only(vec![1, 2])).await;
+        let lock = unwrap_ok_or!(rx.recv_key_only().await, err, panic!("{:?}", err));
+        assert_eq!(lock.get_single_key(), Some(&1));
+        // the guard keeps the key active
+        assert_eq!(rx.recv().await, Err(RecvError::AllConflict));
+        drop(lock);
+        let lock2 = unwrap_ok_or!(rx.recv_key_only().await, err, panic!("{:?}", err));
+        assert!(lock2.is_multiple());
+        assert_eq!(lock2.get_key_set(), Some(&HashSet::from_iter(vec![1, 2])));
+        let _sent2 = tx.send(Message::key_only(2)).await;
+        assert_eq!(rx.recv().await, Err(RecvError::AllConflict));
+        drop(lock2);
+        assert_eq!(rx.recv().await, Ok(Message::key_only(2)));
+    }
+
+    #[tokio::test]
+    async fn test_into_value() {
+        let (tx, rx) = bounded(10);
+        let _sent = tx
+            .send(Message::single_key(1, String::from("first")))
+            .await;
+        let _sent2 = tx
+            .send(Message::multiple_keys(vec![1, 2], String::from("second")))
+            .await;
+        let msg = unwrap_ok_or!(rx.recv().await, err, panic!("{:?}", err));
+        assert_eq!(rx.recv().await, Err(RecvError::AllConflict));
+        // the key is released while the value is still alive
+        let value = msg.into_value();
+        let msg2 = unwrap_ok_or!(rx.recv().await, err, panic!("{:?}", err));
+        assert_eq!(value, "first");
+        assert_eq!(msg2.into_value(), "second");
+        let _sent3 = tx
+            .send(Message::single_key(2, String::from("third")))
+            .await;
+        assert_eq!(rx.recv().await, Ok(Message::single_key(2, String::from("third"))));
+    }
+
+    #[tokio::test]
+    async fn test_queue_latency() {
+        let (tx, rx) = bounded(10);
+        let msg = Message::single_key(1, 1);
+        assert_eq!(msg.enqueued_at(), None);
+        assert!(tx.send(msg).await.is_ok());
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        let received = unwrap_ok_or!(rx.recv().await, err, panic!("{:?}", err));
+        let latency = unwrap_some_or!(received.queue_latency(), panic!("it's sent"));
+        assert!(latency >= Duration::from_millis(20));
+        // so is a message sent without a buff slot
+        let (tx2, rx2) = unbounded();
+        tx2.send(Message::single_key(1, 1))
+            .unwrap_or_else(|_| unreachable!());
+        let received2 = unwrap_ok_or!(rx2.recv().await, err, panic!("{:?}", err));
+        assert!(received2.enqueued_at().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_send_timeout() {
+        let (tx, rx) = bounded(1);
+        let dur = Duration::from_millis(10);
+        assert!(tx
+            .send_timeout(Message::single_key(1, 1), dur)
+            .await
+            .is_ok());
+        let start = Instant::now();
+        let sent = tx
+            .send_timeout(Message::single_key(2, 2), dur)
+            .await;
+        assert!(start.elapsed() >= dur);
+        let err = unwrap_some_or!(sent.err(), panic!("it's full"));
+        assert!(err.is_timeout() && !err.is_disconnected());
+        assert_eq!(err.to_string(), "timed out sending on a full channel");
+        // the message comes back unchanged
+        assert_eq!(err.into_inner(), Message::single_key(2, 2));
+        drop(rx);
+        let closed = tx
+            .send_timeout(Message::single_key(3, 3), dur)
+            .await;
+        let closed_err = unwrap_some_or!(closed.err(), panic!("it's closed"));
+        assert!(closed_err.is_disconnected());
+        let boxed: Box<dyn std::error::Error> = Box::new(closed_err);
+        assert_eq!(boxed.to_string(), "sending on a closed channel");
+    }
+
+    #[tokio::test]
+    async fn test_blocking_keys() {
+        let (tx, rx) = bounded(10);
+        for (key, value) in [(1, 0), (2, 1), (1, 2)] {
+            assert!(tx
+                .send(Message::single_key(key, value))
+                .await
+                .is_ok());
+        }
+        let first = unwrap_ok_or!(rx.recv().await, err, panic!("{:?}", err));
+        let second = unwrap_ok_or!(rx.recv().await, err, panic!("{:?}", err));
+        assert_eq!(rx.try_recv().err(), Some(TryRecvError::AllConflict));
+        assert_eq!(rx.blocking_keys(), vec![1]);
+        drop(second);
+        assert_eq!(rx.blocking_keys(), vec![1]);
+        drop(first);
+        assert!(rx.blocking_keys().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_split() {
+        let (tx, rx) = bounded(10);
+        assert!(tx
+            .send(Message::single_key(1, String::from("first")))
+            .await
+            .is_ok());
+        assert!(tx
+            .send(Message::single_key(1, String::from("second")))
+            .await
+            .is_ok());
+        let first = unwrap_ok_or!(rx.recv().await, err, panic!("{:?}", err));
+        let (guard, value) = first.split();
+        assert_eq!(guard.get_single_key(), Some(&1));
+        assert_eq!(rx.try_recv().err(), Some(TryRecvError::AllConflict));
+        drop(guard);
+        // the value outlives the guard
+        let second = unwrap_ok_or!(rx.recv().await, err, panic!("{:?}", err));
+        assert_eq!(value, "first");
+        assert_eq!(second.get_value(), "second");
+    }
+
+    #[tokio::test]
+    async fn test_ttl() {
+        let (tx, rx) = bounded(2);
+        let expiring = Message::single_key(1, 0).with_ttl(Duration::from_millis(10));
+        assert!(tx.send(expiring).await.is_ok());
+        assert!(tx.send(Message::single_key(2, 1)).await.is_ok());
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        let held = unwrap_ok_or!(rx.recv().await, err, panic!("{:?}", err));
+        assert_eq!(held.get_value(), &1);
+        assert_eq!(rx.stats().expired_count, 1);
+        // an expired message waiting for a key is dropped too, with its permit
+        let pending = Message::single_key(2, 2).with_ttl(Duration::from_millis(10));
+        assert!(tx.send(pending).await.is_ok());
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert_eq!(rx.try_recv().err(), Some(TryRecvError::Empty));
+        assert_eq!(rx.stats().expired_count, 2);
+        assert_eq!(tx.capacity(), 2);
+        drop(held);
+    }
+
+    #[tokio::test]
+    async fn test_ttl_every_path() {
+        // an expired message in front, and a live one with the same key behind it
+        let queued = || {
+            let (tx, rx) = bounded(4);
+            let expired = Message::single_key(1, 0).with_ttl(Duration::ZERO);
+            assert!(tx.try_send(expired).is_ok());
+            assert!(tx.try_send(Message::single_key(1, 1)).is_ok());
+            (tx, rx)
+        };
+        let (_tx1, rx1) = queued();
+        let filtered =
+            unwrap_ok_or!(rx1.recv_filter(|_| true).await, err, panic!("{:?}", err));
+        assert_eq!((filtered.get_value(), rx1.stats().expired_count), (&1, 1));
+        let (_tx2, rx2) = queued();
+        let keyed = unwrap_ok_or!(rx2.recv_key(&1).await, err, panic!("{:?}", err));
+        assert_eq!((keyed.get_value(), rx2.stats().expired_count), (&1, 1));
+        let (_tx3, rx3) = queued();
+        let peeked = unwrap_ok_or!(rx3.peek().await, err, panic!("{:?}", err));
+        assert_eq!((peeked.get_value(), rx3.stats().expired_count), (&1, 1));
+        let (_tx4, rx4) = queued();
+        let any = unwrap_ok_or!(rx4.recv_any().await, err, panic!("{:?}", err));
+        assert_eq!((any.get_value(), rx4.stats().expired_count), (&1, 1));
+        let (_tx5, rx5) = queued();
+        let unguarded =
+            unwrap_ok_or!(rx5.recv_unguarded().await, err, panic!("{:?}", err));
+        assert_eq!((unguarded.get_value(), rx5.stats().expired_count), (&1, 1));
+        let (_tx6, rx6) = queued();
+        assert_eq!(rx6.drain(), vec![Message::single_key(1, 1)]);
+        assert_eq!(rx6.stats().expired_count, 1);
+        // a ttl too long to represent never expires
+        let msg: super::Message<i32, i32> =
+            Message::single_key(1, 2).with_ttl(Duration::MAX);
+        assert_eq!(msg.deadline(), None);
+    }
+
+    #[tokio::test]
+    async fn test_priority() {
+        let (tx, rx) = bounded_prioritized(10);
+        for (key, value, priority) in [(1, 0, 0), (2, 1, 5), (1, 2, 9), (3, 3, 5)] {
+            let msg = Message::single_key(key, value).with_priority(priority);
+            assert!(tx.send(msg).await.is_ok());
+        }
+        let second = unwrap_ok_or!(rx.recv().await, err, panic!("{:?}", err));
+        assert_eq!(second.get_value(), &1);
+        let fourth = unwrap_ok_or!(rx.recv().await, err, panic!("{:?}", err));
+        assert_eq!(fourth.get_value(), &3);
+        let first = unwrap_ok_or!(rx.recv().await, err, panic!("{:?}", err));
+        assert_eq!(first.get_value(), &0);
+        // the highest priority one waits for the key
+        assert_eq!(rx.try_recv().err(), Some(TryRecvError::AllConflict));
+        drop(first);
+        let third = unwrap_ok_or!(rx.recv().await, err, panic!("{:?}", err));
+        assert_eq!(third.get_value(), &2);
+    }
+
+    #[tokio::test]
+    async fn test_seq() {
+        let (tx, rx) = bounded(10);
+        for (key, value) in [(1, 0), (1, 1), (2, 2)] {
+            assert!(tx
+                .send(Message::single_key(key, value))
+                .await
+                .is_ok());
+        }
+        let (tx2, rx2) = unbounded();
+        tx2.send(Message::single_key(1, 0))
+            .unwrap_or_else(|_| unreachable!());
+        let first = unwrap_ok_or!(rx.recv().await, err, panic!("{:?}", err));
+        assert_eq!(first.seq(), Some(0));
+        // key 1 is active, the second message is skipped
+        let third = unwrap_ok_or!(rx.recv().await, err, panic!("{:?}", err));
+        assert_eq!(third.seq(), Some(2));
+        drop(first);
+        let second = unwrap_ok_or!(rx.recv().await, err, panic!("{:?}", err));
+        assert_eq!(second.seq(), Some(1));
+        // every channel counts on its own
+        let other = unwrap_ok_or!(rx2.recv().await, err, panic!("{:?}", err));
+        assert_eq!(other.seq(), Some(0));
+    }
+
+    #[tokio::test]
+    async fn test_into_parts() {
+        let (tx, rx) = bounded(10);
+        let _sent = tx.send(Message::single_key(1, 1)).await;
+        let _sent2 = tx
+            .send(Message::multiple_keys(vec![1, 2], 2))
+            .await;
+        let msg = unwrap_ok_or!(rx.recv().await, err, panic!("{:?}", err));
+        let (keys, value) = msg.into_parts();
+        assert_eq!((keys, value), (crate::KeySet::Single(1), 1));
+        let msg2 = unwrap_ok_or!(rx.recv().await, err, panic!("{:?}", err));
+        let (keys2, value2) = msg2.into_parts();
+        assert_eq!(keys2, crate::KeySet::multiple(vec![1, 2]));
+        assert_eq!(value2, 2);
+        // the keys are released
+        let _sent3 = tx.send(Message::single_key(2, 3)).await;
+        assert_eq!(rx.recv().await, Ok(Message::single_key(2, 3)));
+    }
+
+    #[test]
+    fn test_key_only_layout() {
+        use std::mem::size_of;
+        // a `()` value takes no space in the stored tuple
+        assert_eq!(
+            size_of::<super::StoredMessage<usize, ()>>() + size_of::<usize>(),
+            size_of::<super::StoredMessage<usize, usize>>()
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_key_throttle() {
+        let (tx, rx) = bounded(10);
+        let throttled = tx.with_key_throttle(1);
+        assert_eq!(throttled.send(Message::single_key(1, 1)).await, Ok(()));
+        let sent = Arc::new(AtomicBool::new(false));
+        let throttled_sent = Arc::<AtomicBool>::clone(&sent);
+        let handle = tokio::spawn(async move {
+            let res = throttled.send(Message::single_key(1, 2)).await;
+            throttled_sent.store(true, SeqCst);
+            res
+        });
+        // the unthrottled sender proceeds freely
+        assert_eq!(tx.send(Message::single_key(1, 3)).await, Ok(()));
+        assert_eq!(tx.send(Message::single_key(1, 4)).await, Ok(()));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!sent.load(SeqCst));
+        let msg = unwrap_ok_or!(rx.recv().await, err, panic!("{:?}", err));
+        assert_eq!(msg, Message::single_key(1, 1));
+        // the first message is still held by receiver
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!sent.load(SeqCst));
+        drop(msg);
+        assert_eq!(unwrap_ok_or!(handle.await, err, panic!("{:?}", err)), Ok(()));
+        assert!(sent.load(SeqCst));
+    }
 }
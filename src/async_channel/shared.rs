@@ -2,35 +2,47 @@
 
 //! A FIFO queue shared by sender and receiver
 
-use tokio::sync::Semaphore;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore, TryAcquireError};
 
 use super::{Message, StoredMessage};
-use crate::buff::State;
-use crate::err::{RecvError, SendError};
+use crate::active::ActiveKeys;
+use crate::buff::{contain, KeyedBuff, SenderCount, State};
+use crate::completions::CompletionKind;
+use crate::err::{
+    RecvError, RecvFilterError, SendError, SendTimeoutError, TryRecvError,
+    TryReserveError, TrySendError,
+};
+use crate::lock::{lock, lock_or_recover, Mutex};
 use crate::message::{DeactivateKeys, Key};
-use crate::unwrap_ok_or;
+use crate::{unwrap_ok_or, unwrap_some_or};
 #[cfg(feature = "event_listener")]
 use event_listener::Event;
-use std::fmt::Debug;
-use std::sync::{Arc, Mutex};
-#[cfg(not(feature = "event_listener"))]
+use std::any::Any;
+use std::panic::RefUnwindSafe;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll, Waker};
 use tokio::sync::Notify;
-#[cfg(feature = "profile")]
-use tokio::time::Duration;
+use tokio::time::{timeout_at, Instant};
 
-// it's safe here because all operations on rc will
-// protect by the Mutex
-#[allow(unsafe_code)]
-unsafe impl<K: Key, V> Send for Shared<K, V> {}
-#[cfg(not(feature = "profile"))]
-#[allow(unsafe_code)]
-unsafe impl<K: Key, V> Sync for Shared<K, V> {}
+/// messages received in a batch, with the panic that stopped the batch
+pub(crate) type Batch<K, V> = (Vec<Message<K, V>>, Option<Box<dyn Any + Send>>);
+
+/// a group of messages with their sole key, see [`KeyedBuff::pop_group_front`]
+#[cfg(feature = "stream")]
+pub(crate) type Group<K, V> = (Option<K>, Batch<K, V>);
 
 /// shared state between senders and receiver
 #[derive(Debug)]
 pub struct Shared<K: Key, V> {
     /// the queue state
     pub(crate) state: Mutex<State<StoredMessage<K, V>>>,
+    /// keys held by received messages and key locks, locked after the state
+    pub(crate) active: Arc<ActiveKeys<K>>,
+    /// number of live senders
+    pub(crate) senders: SenderCount,
+    /// number of buff slots, zero for an unbounded channel
+    pub(crate) cap: usize,
     /// semaphore that representes buffer resources
     pub(crate) slots: Arc<Semaphore>,
     /// notify receiver when send a message
@@ -39,87 +51,692 @@ pub struct Shared<K: Key, V> {
     /// notify receiver when send a message
     #[cfg(feature = "event_listener")]
     pub(crate) notify_receiver: Event,
-    /// try_recv time cost
-    #[cfg(feature = "profile")]
-    pub(crate) try_recv_cost: std::cell::UnsafeCell<Duration>,
-    /// recv wait count
-    #[cfg(feature = "profile")]
-    pub(crate) wait_count: std::cell::UnsafeCell<usize>,
+    /// set when the receiver closes the channel, it mirrors `state.disconnected`
+    /// for senders, which can't observe a disconnection by senders
+    pub(crate) close_flag: AtomicBool,
+    /// notify senders waiting for the receiver to close the channel
+    pub(crate) receiver_closed: Notify,
+    /// notify tasks waiting for a key release
+    pub(crate) key_released: Notify,
+    /// waker of the receiver task polling `poll_recv`
+    pub(crate) recv_waker: Mutex<Option<Waker>>,
+    /// `try_recv` time cost in nanoseconds, only measured with `profile`
+    pub(crate) try_recv_nanos: AtomicU64,
+    /// number of times the receiver waits for a notification
+    pub(crate) wait_count: AtomicU64,
+    /// number of messages received
+    pub(crate) recv_count: AtomicU64,
+    /// sequence number of the next message, only taken under the state lock
+    pub(crate) next_seq: AtomicU64,
+    /// faults injected into notifying receiver
+    #[cfg(feature = "fault-injection")]
+    pub(crate) faults: crate::fault::Faults,
 }
 
-#[cfg(feature = "profile")]
-#[allow(unsafe_code)]
-unsafe impl<K: Key, V> Sync for Shared<K, V> {}
+/// the shared state is reached from senders on any thread, the auto traits
+/// must hold without any unsafe claim
+const _: fn() = || {
+    /// compiles only if `T` is `Send` and `Sync`
+    fn assert_send_sync<T: Send + Sync>() {}
+    /// checked for any key and value a sender may move across threads
+    fn assert_shared<K: Key + Send, V: Send>() {
+        assert_send_sync::<Shared<K, V>>();
+    }
+    assert_shared::<u32, String>();
+};
+
+// tokio primitives never run user code, so they are consistent after a panic,
+// the stats counters are only statistics
+impl<K: Key + RefUnwindSafe, V: RefUnwindSafe> RefUnwindSafe for Shared<K, V> {}
 
 impl<K: Key, V> DeactivateKeys for Shared<K, V> {
     type Key = K;
-    fn release_key<'a, I: IntoIterator<Item = &'a Self::Key>>(&'a self, keys: I) {
-        let mut state = unwrap_ok_or!(self.state.lock(), err, panic!("{:?}", err));
-        for k in keys {
-            state.buff.deactivate_key(k);
+    fn release_key(&self, keys: &[&K], kind: CompletionKind) {
+        // only the shards of the keys are locked, one at a time, so releasing
+        // never contends with sending, nor with releasing keys of other shards
+        let released = contain(|| {
+            let mut waited_any = false;
+            let mut feed = None;
+            for k in keys {
+                let (waited, listened) = self
+                    .active
+                    .deactivate(self.active.shard_of(*k), *k);
+                waited_any |= waited;
+                feed = listened;
+            }
+            if keys.is_empty() {
+                feed = self.active.feed();
+            }
+            (waited_any, feed)
+        });
+        // wake up the receiver if a parked message waits for the keys,
+        // a notification before it waits is stored as a permit
+        if matches!(released, Ok((true, _))) {
+            self.wake_receiver();
+        }
+        self.key_released.notify_waiters();
+        // emitted without the lock, cloning keys may panic too
+        let emitted = released.and_then(|(_, feed)| {
+            contain(|| {
+                if let Some(feed) = feed {
+                    feed.push((keys.iter().map(|k| (*k).clone()).collect(), kind));
+                }
+            })
+        });
+        if let Err(payload) = emitted {
+            if !std::thread::panicking() {
+                std::panic::resume_unwind(payload);
+            }
         }
     }
 }
 
-impl<K: Key, V: Debug> Shared<K, V> {
+impl<K: Key, V> Shared<K, V> {
     /// send a message
     pub(crate) async fn send(
         &self, message: Message<K, V>,
     ) -> Result<(), SendError<Message<K, V>>> {
         let slots = Arc::clone(&self.slots);
-        let permit = unwrap_ok_or!(slots.acquire_owned().await, err, panic!("{:?}", err));
-        let mut state = unwrap_ok_or!(self.state.lock(), err, panic!("{:?}", err));
+        let acquired = slots.acquire_owned().await;
+        // semaphore is closed when receiver is gone
+        let permit = unwrap_ok_or!(acquired, _, return Err(SendError(message)));
+        self.send_with_permit(message, permit)
+    }
+
+    /// send a message, wait until `deadline` for an empty buff slot
+    pub(crate) async fn send_deadline(
+        &self, message: Message<K, V>, deadline: Option<Instant>,
+    ) -> Result<(), SendTimeoutError<Message<K, V>>> {
+        let acquire = Arc::clone(&self.slots).acquire_owned();
+        let acquired = match deadline {
+            Some(deadline) => {
+                let timed = timeout_at(deadline, acquire).await;
+                unwrap_ok_or!(timed, _, return Err(SendTimeoutError::Timeout(message)))
+            }
+            None => acquire.await,
+        };
+        // semaphore is closed when receiver is gone
+        let permit = unwrap_ok_or!(
+            acquired,
+            _,
+            return Err(SendTimeoutError::Disconnected(message))
+        );
+        self.send_with_permit(message, permit)
+            .map_err(SendTimeoutError::from)
+    }
+
+    /// wait until the receiver is dropped or closes the channel
+    pub(crate) async fn closed(&self) {
+        let mut notified = Box::pin(self.receiver_closed.notified());
+        // registered before checking, `notify_waiters` stores no permit
+        let _enabled = notified.as_mut().enable();
+        if !self.is_closed() {
+            notified.await;
+        }
+    }
+
+    /// number of empty buff slots, a reserved slot is not empty
+    pub(crate) fn capacity(&self) -> usize {
+        self.slots.available_permits()
+    }
+
+    /// active keys that queued messages wait for
+    pub(crate) fn blocking_keys(&self) -> Vec<K> {
+        let state = lock(&self.state);
+        let keys = contain(|| state.buff.blocking_keys());
+        drop(state);
+        unwrap_ok_or!(keys, payload, std::panic::resume_unwind(payload))
+    }
+
+    /// number of queued messages
+    pub(crate) fn len(&self) -> usize {
+        let state = lock(&self.state);
+        state.buff.len()
+    }
+
+    /// is the channel closed by the receiver, checked without locking
+    pub(crate) fn is_closed(&self) -> bool {
+        self.close_flag.load(Ordering::Acquire)
+    }
+
+    /// wait for an empty buff slot and hold it, the slot is given back
+    /// when the permit is dropped
+    pub(crate) async fn reserve(&self) -> Result<OwnedSemaphorePermit, SendError<()>> {
+        let acquired = Arc::clone(&self.slots).acquire_owned().await;
+        // semaphore is closed when receiver is gone
+        let permit = unwrap_ok_or!(acquired, _, return Err(SendError(())));
+        // a sender is alive, so only the receiver disconnects the channel
+        if self.is_closed() {
+            return Err(SendError(()));
+        }
+        Ok(permit)
+    }
+
+    /// hold an empty buff slot without waiting, the slot is given back
+    /// when the permit is dropped
+    pub(crate) fn try_reserve(&self) -> Result<OwnedSemaphorePermit, TryReserveError> {
+        let permit = match Arc::clone(&self.slots).try_acquire_owned() {
+            Ok(permit) => permit,
+            Err(TryAcquireError::NoPermits) => return Err(TryReserveError::Full),
+            // semaphore is closed when receiver is gone
+            Err(TryAcquireError::Closed) => return Err(TryReserveError::Disconnected),
+        };
+        // a sender is alive, so only the receiver disconnects the channel
+        if self.is_closed() {
+            return Err(TryReserveError::Disconnected);
+        }
+        Ok(permit)
+    }
+
+    /// send a message without waiting for an empty buff slot
+    pub(crate) fn try_send(
+        &self, message: Message<K, V>,
+    ) -> Result<(), TrySendError<Message<K, V>>> {
+        let permit = match self.try_reserve() {
+            Ok(permit) => permit,
+            Err(TryReserveError::Full) => return Err(TrySendError::Full(message)),
+            Err(TryReserveError::Disconnected) => {
+                return Err(TrySendError::Disconnected(message))
+            }
+        };
+        self.send_with_permit(message, permit)
+            .map_err(TrySendError::from)
+    }
+
+    /// send a message into the buff slot held by `permit`
+    pub(crate) fn send_with_permit(
+        &self, message: Message<K, V>, permit: OwnedSemaphorePermit,
+    ) -> Result<(), SendError<Message<K, V>>> {
+        self.push(message, Some(permit))
+    }
+
+    /// send a message without holding a buff slot, for unbounded senders
+    pub(crate) fn send_unbounded(
+        &self, message: Message<K, V>,
+    ) -> Result<(), SendError<Message<K, V>>> {
+        self.push(message, None)
+    }
+
+    /// push a message with the buff slot it holds, and wake up the receiver
+    fn push(
+        &self, mut message: Message<K, V>, permit: Option<OwnedSemaphorePermit>,
+    ) -> Result<(), SendError<Message<K, V>>> {
+        // fail fast without locking, the flag is rechecked under the lock
+        // since the receiver may close the channel in between
+        if self.is_closed() {
+            return Err(SendError(message));
+        }
+        let mut state = lock(&self.state);
         if state.disconnected {
             return Err(SendError(message));
         }
-        state.buff.push_back((message, permit));
+        message.stamp_enqueued(self.next_seq.fetch_add(1, Ordering::Relaxed));
+        let pushed = state.buff.push_back((message, permit));
         drop(state);
+        if let Err((unsent, payload)) = pushed {
+            // the message may run user code in drop too
+            drop(unsent);
+            std::panic::resume_unwind(payload);
+        }
+        self.wake_receiver();
+        Ok(())
+    }
+
+    /// release a dropped sender, the last one disconnects the channel
+    pub(crate) fn release_sender(&self) {
+        if self.senders.remove() {
+            // the flag is set under the lock, so a receiver either sees it
+            // or is already waiting for the following notification,
+            // never panic in drop, the flag is consistent even if poisoned
+            let mut state = lock_or_recover(&self.state);
+            state.disconnected = true;
+            drop(state);
+            self.wake_receiver();
+        }
+    }
+
+    /// wake up the receiver waiting for a message
+    pub(crate) fn wake_receiver(&self) {
+        #[cfg(feature = "fault-injection")]
+        if !self.faults.before_notify() {
+            return;
+        }
         #[cfg(not(feature = "event_listener"))]
         self.notify_receiver.notify_one();
         #[cfg(feature = "event_listener")]
         self.notify_receiver.notify(1);
+        // called in drop too, never panic, the slot is consistent even if poisoned
+        let mut slot = lock_or_recover(&self.recv_waker);
+        let waker = slot.take();
+        drop(slot);
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+
+    /// store the waker of the receiver task, to be woken by `wake_receiver`
+    fn register_receiver(&self, waker: &Waker) {
+        let mut slot = lock(&self.recv_waker);
+        let registered = slot
+            .as_ref()
+            .is_some_and(|w| w.will_wake(waker));
+        if !registered {
+            *slot = Some(waker.clone());
+        }
+    }
+
+    /// is the key held by a received message or a key lock
+    pub(crate) fn is_key_active(&self, key: &K) -> bool {
+        self.active.is_active(key)
+    }
+
+    /// number of queued messages if the queue is disconnected
+    pub(crate) fn remaining_after_disconnect(&self) -> Option<usize> {
+        let state = lock(&self.state);
+        state.remaining_after_disconnect()
+    }
+
+    /// activate a key without any message, a queued message with the key
+    /// is parked when it's found ready
+    pub(crate) fn lock_key(&self, key: K) {
+        self.active.activate(key, false);
+    }
+
+    /// wait until there are at least `n` available buffer slots
+    pub(crate) async fn wait_capacity(&self, n: usize) -> Result<(), SendError<()>> {
+        let cap = self.cap;
+        let disconnected = {
+            let state = lock(&self.state);
+            state.disconnected
+        };
+        assert!(n <= cap, "wait for {} slots in a channel with capacity {}", n, cap);
+        if disconnected {
+            return Err(SendError(()));
+        }
+        let n = unwrap_ok_or!(u32::try_from(n), err, panic!("{:?}", err));
+        // release the permits immediately, it's just a probe
+        let acquired = self.slots.acquire_many(n).await;
+        drop(unwrap_ok_or!(acquired, _, return Err(SendError(()))));
         Ok(())
     }
 
-    /// try recv, return None if buff is empty
-    fn try_recv(&self) -> Result<Option<Message<K, V>>, RecvError> {
+    /// add the time elapsed since `start` to the `try_recv` cost
+    #[cfg(feature = "profile")]
+    fn add_try_recv_cost(&self, start: std::time::Instant) {
+        let ns = u64::try_from(start.elapsed().as_nanos()).unwrap_or(u64::MAX);
+        let _cost = self
+            .try_recv_nanos
+            .fetch_add(ns, Ordering::Relaxed);
+    }
+
+    /// count `n` received messages
+    fn count_recv(&self, n: usize) {
+        let n = u64::try_from(n).unwrap_or(u64::MAX);
+        let _count = self.recv_count.fetch_add(n, Ordering::Relaxed);
+    }
+
+    /// try recv what `pop` takes from buff, return None if buff is empty
+    fn try_recv_with<R, E, F>(&self, pop: &mut F) -> Result<Option<R>, E>
+    where
+        E: From<RecvError>,
+        F: FnMut(&mut KeyedBuff<StoredMessage<K, V>>) -> Result<R, E>,
+    {
+        let taken = self.try_take_with(pop)?;
+        if taken.is_some() {
+            self.count_recv(1);
+        }
+        Ok(taken)
+    }
+
+    /// like `try_recv_with`, but what `pop` takes is not counted as received
+    fn try_take_with<R, E, F>(&self, pop: &mut F) -> Result<Option<R>, E>
+    where
+        E: From<RecvError>,
+        F: FnMut(&mut KeyedBuff<StoredMessage<K, V>>) -> Result<R, E>,
+    {
         #[cfg(feature = "profile")]
         use std::time::Instant;
         #[cfg(feature = "profile")]
         let start = Instant::now();
-        let mut state = unwrap_ok_or!(self.state.lock(), err, panic!("{:?}", err));
+        let mut state = lock(&self.state);
         // buffer is empty, wait sender to send
         if state.buff.is_empty() && !state.disconnected {
             #[cfg(feature = "profile")]
-            {
-                let cost = self.try_recv_cost.get();
-                // only one receiver, it's safe
-                #[allow(unsafe_code)]
-                unsafe {
-                    (*cost) += start.elapsed();
-                }
+            self.add_try_recv_cost(start);
+            return Ok(None);
+        }
+
+        if state.buff.is_empty() && state.disconnected {
+            return Err(RecvError::Disconnected.into());
+        }
+
+        let popped = contain(|| pop(&mut state.buff));
+        let expired = state.buff.take_expired();
+        let exhausted = !expired.is_empty() && state.buff.is_empty();
+        let disconnected = state.disconnected;
+        drop(state);
+        // dropping the permits frees the slots for senders
+        drop(expired);
+        let popped = unwrap_ok_or!(popped, payload, std::panic::resume_unwind(payload));
+        // the queued messages are all expired, wait for the next one
+        if popped.is_err() && exhausted {
+            if disconnected {
+                return Err(RecvError::Disconnected.into());
             }
             return Ok(None);
         }
+        let msg = popped?;
+        #[cfg(feature = "profile")]
+        self.add_try_recv_cost(start);
+        Ok(Some(msg))
+    }
+
+    /// poll to recv a message, the task is woken when a message is sent,
+    /// a key is released or the channel is disconnected
+    pub(crate) fn poll_recv(
+        &self, cx: &mut Context<'_>,
+    ) -> Poll<Result<Message<K, V>, RecvError>> {
+        let mut pop = KeyedBuff::pop_unconflict_front;
+        // the permit is released after unlocking
+        if let Some((msg, _permit)) = self.try_recv_with(&mut pop)? {
+            return Poll::Ready(Ok(msg));
+        }
+        self.register_receiver(cx.waker());
+        // a message sent before the registration doesn't wake the task
+        if let Some((msg, _permit)) = self.try_recv_with(&mut pop)? {
+            return Poll::Ready(Ok(msg));
+        }
+        let _waits = self.wait_count.fetch_add(1, Ordering::Relaxed);
+        #[cfg(feature = "fault-injection")]
+        if self.faults.take_spurious() {
+            cx.waker().wake_by_ref();
+        }
+        Poll::Pending
+    }
 
+    /// poll to recv a group of messages with the same sole key, like `poll_recv`
+    /// but it stays pending while all messages conflict
+    #[cfg(feature = "stream")]
+    pub(crate) fn poll_recv_group(
+        &self, cx: &mut Context<'_>,
+    ) -> Poll<Result<Group<K, V>, RecvError>> {
+        if let Some(group) = self.try_recv_group()? {
+            return Poll::Ready(Ok(group));
+        }
+        self.register_receiver(cx.waker());
+        // a message sent or a key released before the registration
+        // doesn't wake the task
+        if let Some(group) = self.try_recv_group()? {
+            return Poll::Ready(Ok(group));
+        }
+        let _waits = self.wait_count.fetch_add(1, Ordering::Relaxed);
+        Poll::Pending
+    }
+
+    /// try recv a group of messages, return None if none is receivable
+    #[cfg(feature = "stream")]
+    fn try_recv_group(&self) -> Result<Option<Group<K, V>>, RecvError> {
+        let mut state = lock(&self.state);
         if state.buff.is_empty() && state.disconnected {
             return Err(RecvError::Disconnected);
         }
+        let mut stored = Vec::new();
+        let mut key = None;
+        let popped = contain(|| {
+            state
+                .buff
+                .pop_group_front(&mut stored, &mut key)
+        });
+        let expired = state.buff.take_expired();
+        drop(state);
+        drop(expired);
+        if stored.is_empty() {
+            if let Err(payload) = popped {
+                std::panic::resume_unwind(payload);
+            }
+            // empty, or all conflict until a key is released
+            return Ok(None);
+        }
+        self.count_recv(stored.len());
+        // the permits are released after unlocking
+        let msgs = stored
+            .into_iter()
+            .map(|(msg, _permit)| msg)
+            .collect();
+        Ok(Some((key, (msgs, popped.err()))))
+    }
 
-        let (msg, _permit) = state.buff.pop_unconflict_front()?;
-        #[cfg(feature = "profile")]
-        {
-            let cost = self.try_recv_cost.get();
-            #[allow(unsafe_code)]
-            unsafe {
-                (*cost) += start.elapsed();
+    /// recv a message without waiting
+    pub(crate) fn try_recv(&self) -> Result<Message<K, V>, TryRecvError> {
+        let stored = self.try_recv_with(&mut KeyedBuff::pop_unconflict_front)?;
+        // the permit is released after unlocking
+        stored
+            .map(|(msg, _permit)| msg)
+            .ok_or(TryRecvError::Empty)
+    }
+
+    /// recv a message, wait until `deadline` when buffer is empty,
+    /// return `None` if the deadline is reached
+    pub(crate) async fn recv_deadline(
+        &self, deadline: Option<Instant>,
+    ) -> Result<Option<Message<K, V>>, RecvError> {
+        let stored = self
+            .recv_with(deadline, KeyedBuff::pop_unconflict_front)
+            .await?;
+        // the permit is released after unlocking
+        Ok(stored.map(|(msg, _permit)| msg))
+    }
+
+    /// recv a message like `recv_deadline`, but wait while all messages conflict
+    /// until a key is released, return `None` if the deadline is reached
+    pub(crate) async fn recv_wait(
+        &self, deadline: Option<Instant>,
+    ) -> Result<Option<Message<K, V>>, RecvError> {
+        let mut pop = KeyedBuff::pop_unconflict_front;
+        let stored = self
+            .wait_for(deadline, |shared| match shared.try_recv_with(&mut pop) {
+                // a key release wakes up the receiver if a message gets ready
+                Err(RecvError::AllConflict) => Ok(None),
+                Ok(stored) => Ok(stored),
+                Err(err) => Err(err),
+            })
+            .await?;
+        // the permit is released after unlocking
+        Ok(stored.map(|(msg, _permit)| msg))
+    }
+
+    /// are there queued messages, and all of them conflict
+    pub(crate) fn all_conflict(&self) -> bool {
+        let mut state = lock(&self.state);
+        !state.buff.is_empty() && !state.buff.has_ready()
+    }
+
+    /// recv the front-most message without activating its keys
+    pub(crate) async fn recv_unguarded(&self) -> Result<Message<K, V>, RecvError> {
+        let stored = self
+            .recv_with(None, KeyedBuff::pop_front_unguarded)
+            .await?;
+        let (msg, _permit) = unwrap_some_or!(
+            stored,
+            unreachable!("recv without deadline never times out")
+        );
+        Ok(msg)
+    }
+
+    /// recv the front-most message regardless of conflict, and activate its keys
+    pub(crate) async fn recv_any(&self) -> Result<Message<K, V>, RecvError> {
+        let stored = self
+            .recv_with(None, KeyedBuff::pop_front_any)
+            .await?;
+        let (msg, _permit) = unwrap_some_or!(
+            stored,
+            unreachable!("recv without deadline never times out")
+        );
+        Ok(msg)
+    }
+
+    /// wait for a receivable message like `recv`, and return a detached copy of it
+    pub(crate) async fn peek(&self) -> Result<Message<K, V>, RecvError>
+    where
+        V: Clone,
+    {
+        let mut peek = |buff: &mut KeyedBuff<StoredMessage<K, V>>| {
+            buff.purge_expired();
+            if !buff.has_ready() {
+                return Err(RecvError::AllConflict);
+            }
+            let front = buff
+                .front_ready()
+                .ok_or(RecvError::AllConflict)?;
+            Ok(front.0.clone_detached())
+        };
+        let msg = self
+            .wait_for(None, |shared| shared.try_take_with(&mut peek))
+            .await?;
+        Ok(unwrap_some_or!(msg, unreachable!("recv without deadline never times out")))
+    }
+
+    /// recv the front-most message that matches `pred` without conflict,
+    /// wait when buffer is empty
+    pub(crate) async fn recv_filter<P>(
+        &self, mut pred: P,
+    ) -> Result<Message<K, V>, RecvFilterError>
+    where
+        P: FnMut(&Message<K, V>) -> bool,
+    {
+        let pop = |buff: &mut KeyedBuff<StoredMessage<K, V>>| {
+            buff.pop_unconflict_where(|stored: &StoredMessage<K, V>| pred(&stored.0))
+        };
+        let stored = self.recv_with(None, pop).await?;
+        let (msg, _permit) = unwrap_some_or!(
+            stored,
+            unreachable!("recv without deadline never times out")
+        );
+        Ok(msg)
+    }
+
+    /// recv the front-most message with `key`, wait until it's receivable
+    pub(crate) async fn recv_key(&self, key: &K) -> Result<Message<K, V>, RecvError> {
+        let msg = self
+            .wait_for(None, |shared| shared.try_recv_key(key))
+            .await?;
+        Ok(unwrap_some_or!(msg, unreachable!("recv without deadline never times out")))
+    }
+
+    /// try recv the front-most message with `key`, return None if it's not receivable
+    fn try_recv_key(&self, key: &K) -> Result<Option<Message<K, V>>, RecvError> {
+        let mut state = lock(&self.state);
+        let popped = contain(|| {
+            state
+                .buff
+                .pop_unconflict_where(|stored: &StoredMessage<K, V>| {
+                    stored.0.key().contains(key)
+                })
+        });
+        let expired = state.buff.take_expired();
+        let disconnected = state.disconnected;
+        drop(state);
+        // dropping the permits frees the slots for senders
+        drop(expired);
+        match unwrap_ok_or!(popped, payload, std::panic::resume_unwind(payload)) {
+            Ok((msg, _permit)) => {
+                self.count_recv(1);
+                Ok(Some(msg))
             }
+            Err(RecvFilterError::NoMatch) if disconnected => Err(RecvError::Disconnected),
+            // a message with the key may be sent, or released later
+            Err(_) => Ok(None),
         }
-        Ok(Some(msg))
     }
 
-    /// recv a message
-    pub(crate) async fn recv(&self) -> Result<Message<K, V>, RecvError> {
+    /// recv up to `limit` unconflict messages under one lock, wait until
+    /// at least one is receivable
+    pub(crate) async fn recv_many(&self, limit: usize) -> Result<Batch<K, V>, RecvError> {
+        let batch = self
+            .wait_for(None, |shared| shared.try_recv_many(limit))
+            .await?;
+        Ok(unwrap_some_or!(batch, unreachable!("recv without deadline never times out")))
+    }
+
+    /// try recv up to `limit` unconflict messages, return None if none is receivable
+    fn try_recv_many(&self, limit: usize) -> Result<Option<Batch<K, V>>, RecvError> {
+        let mut state = lock(&self.state);
+        if state.buff.is_empty() && state.disconnected {
+            return Err(RecvError::Disconnected);
+        }
+        let mut stored = Vec::new();
+        // a popped message activates its keys, so the following ones
+        // never conflict with it
+        let popped = contain(|| {
+            while stored.len() < limit {
+                let next = state.buff.pop_unconflict_front();
+                stored.push(unwrap_ok_or!(next, _, break));
+            }
+        });
+        let expired = state.buff.take_expired();
+        drop(state);
+        drop(expired);
+        if stored.is_empty() {
+            if let Err(payload) = popped {
+                std::panic::resume_unwind(payload);
+            }
+            // empty, or all conflict until a key is released
+            return Ok(None);
+        }
+        self.count_recv(stored.len());
+        // the permits are released after unlocking, popped messages are
+        // still returned with a panic, their keys are active
+        let msgs = stored
+            .into_iter()
+            .map(|(msg, _permit)| msg)
+            .collect();
+        Ok(Some((msgs, popped.err())))
+    }
+
+    /// take all queued messages in FIFO order without activating their keys
+    pub(crate) fn drain(&self) -> Vec<Message<K, V>> {
+        let mut state = lock(&self.state);
+        let mut drained = Vec::with_capacity(state.buff.len());
+        let popped = contain(|| {
+            while let Ok(stored) = state.buff.pop_front_unguarded() {
+                drained.push(stored);
+            }
+        });
+        let expired = state.buff.take_expired();
+        drop(state);
+        drop(expired);
+        if let Err(payload) = popped {
+            drop(drained);
+            std::panic::resume_unwind(payload);
+        }
+        // dropping the permits frees the slots for senders
+        drained
+            .into_iter()
+            .map(|(msg, _permit)| msg)
+            .collect()
+    }
+
+    /// recv what `pop` takes from buff, wait until `deadline` when buffer is empty,
+    /// return `None` if the deadline is reached
+    async fn recv_with<R, E, F>(
+        &self, deadline: Option<Instant>, mut pop: F,
+    ) -> Result<Option<R>, E>
+    where
+        E: From<RecvError>,
+        F: FnMut(&mut KeyedBuff<StoredMessage<K, V>>) -> Result<R, E>,
+    {
+        self.wait_for(deadline, |shared| shared.try_recv_with(&mut pop))
+            .await
+    }
+
+    /// retry `attempt` on every notification until it returns `Some` or `Err`,
+    /// or `deadline` is reached
+    async fn wait_for<R, E, F>(
+        &self, deadline: Option<Instant>, mut attempt: F,
+    ) -> Result<Option<R>, E>
+    where
+        F: FnMut(&Self) -> Result<Option<R>, E>,
+    {
         // for notify
         // use loop, consider
         // senders push x values, call x times `notify_one`, only a single permit is stored
@@ -136,23 +753,29 @@ impl<K: Key, V: Debug> Shared<K, V> {
         loop {
             #[cfg(feature = "event_listener")]
             let listener = self.notify_receiver.listen();
-            if let Some(msg) = self.try_recv()? {
+            if let Some(msg) = attempt(self)? {
                 #[cfg(feature = "event_listener")]
                 let _drop = listener.discard();
-                return Ok(msg);
+                return Ok(Some(msg));
             }
-            #[cfg(feature = "profile")]
-            {
-                let count = self.wait_count.get();
-                #[allow(unsafe_code)]
-                unsafe {
-                    (*count) += 1;
-                }
+            let _waits = self.wait_count.fetch_add(1, Ordering::Relaxed);
+            #[cfg(feature = "fault-injection")]
+            if self.faults.take_spurious() {
+                continue;
             }
             #[cfg(not(feature = "event_listener"))]
-            self.notify_receiver.notified().await;
+            let notified = self.notify_receiver.notified();
             #[cfg(feature = "event_listener")]
-            listener.await;
+            let notified = listener;
+            match deadline {
+                Some(deadline) => {
+                    if timeout_at(deadline, notified).await.is_err() {
+                        // a message may arrive right before the deadline
+                        return attempt(self);
+                    }
+                }
+                None => notified.await,
+            }
         }
     }
 }
@@ -1,6 +1,6 @@
 //! message store in async channel buffer
 
-use std::collections::HashMap;
+use std::time::Instant;
 
 use tokio::sync::OwnedSemaphorePermit;
 
@@ -9,22 +9,27 @@ use crate::{
     message::{DeactivateKeys, Key},
 };
 
-/// the message type stored in buffer
-pub(super) type StoredMessage<K, V, T> = (crate::Message<K, V, T>, OwnedSemaphorePermit);
+/// the message type stored in buffer, with the buff slot it holds,
+/// a message sent by an unbounded sender holds no slot
+pub(super) type StoredMessage<K, V, T> =
+    (crate::Message<K, V, T>, Option<OwnedSemaphorePermit>);
 
 impl<K: Key, V, T: DeactivateKeys<Key = K>> BuffMessage for StoredMessage<K, V, T> {
     type Key = K;
 
-    /// is the message's key disjoint with an set of keys
-    fn conflict_keys(
-        &self, other: &HashMap<Self::Key, usize>,
-    ) -> Option<Vec<&Self::Key>> {
-        self.0.key.conflict_keys(other)
-    }
-
     /// collect all keys to an owned vector
     /// applicable to both key types
     fn get_owned_keys(&self) -> Vec<Self::Key> {
-        self.0.key.get_owned_keys()
+        self.0.key().get_owned_keys()
+    }
+
+    /// priority of the message, the slot doesn't matter
+    fn pop_priority(&self) -> u8 {
+        self.0.priority()
+    }
+
+    /// deadline of the message, an expired one frees its slot too
+    fn expiry(&self) -> Option<Instant> {
+        self.0.deadline()
     }
 }
@@ -29,7 +29,11 @@ async fn async_no_conflict() {
     for handle in handles {
         let _drop = handle.await;
     }
-    rx.print_stats();
+    let stats = rx.stats();
+    println!(
+        "wait count {}, try_recv cost time {:?}",
+        stats.wait_count, stats.try_recv_time
+    );
 }
 
 fn main() {
@@ -0,0 +1,297 @@
+//! Pump messages between a sync channel and an async channel
+//!
+//! A pump runs on its own thread, forwards every message of the upstream
+//! channel to the downstream one with the same key(s), and returns the number
+//! of forwarded messages when it stops. A full downstream channel blocks the
+//! pump, which in turn fills the upstream channel, so backpressure holds
+//! end-to-end.
+//!
+//! The pump stops when the upstream channel is disconnected and drained, which
+//! drops the downstream sender, or when the downstream receiver is gone, which
+//! drops the upstream receiver. The latter is only noticed on the next message
+//! to forward.
+//!
+//! While all upstream messages conflict, the pump waits like `recv_wait` until
+//! a key is released or a message without conflict is sent upstream.
+
+use crate::message::{DeactivateKeys, Key};
+use crate::{async_channel, sync_channel, unwrap_ok_or, KeyLock, Message};
+use std::thread::{self, JoinHandle};
+use tokio::runtime::{Builder, Runtime};
+
+/// When the upstream key(s) of a forwarded message are released
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Release {
+    /// release the upstream key(s) once the forwarded message is dropped
+    /// downstream, so no upstream message with the key(s) is forwarded until
+    /// the downstream consumer is done with it
+    HoldThrough,
+    /// release the upstream key(s) once the message is enqueued downstream,
+    /// conflicts are then resolved by the downstream channel alone
+    OnForward,
+}
+
+/// a runtime to drive one side of the pump
+fn pump_runtime() -> Runtime {
+    unwrap_ok_or!(Builder::new_current_thread().build(), err, panic!("{:?}", err))
+}
+
+/// split a received message into the forwarded message and the upstream guard,
+/// which is attached to the forwarded message with [`Release::HoldThrough`]
+fn forward<K, V, T, U>(
+    msg: Message<K, V, T>, release: Release,
+) -> (Message<K, V, U>, Option<KeyLock<K, T>>)
+where
+    K: Key + Send + Sync + 'static,
+    T: DeactivateKeys<Key = K> + Send + Sync + 'static,
+    U: DeactivateKeys<Key = K>,
+{
+    let (lock, value) = msg.split();
+    let mut forwarded = Message::from_key_set(lock.key.clone(), value);
+    match release {
+        Release::HoldThrough => {
+            // releasing the guard wakes up the pump waiting upstream
+            forwarded.set_hook(Box::new(move || drop(lock)));
+            (forwarded, None)
+        }
+        Release::OnForward => (forwarded, Some(lock)),
+    }
+}
+
+/// spawn a thread that pumps messages from a sync receiver to an async sender
+///
+/// The thread drives the async sender by itself, so no runtime is required.
+#[inline]
+#[must_use]
+pub fn sync_to_async<K, V>(
+    rx: sync_channel::Receiver<K, V>, tx: async_channel::BoundedSender<K, V>,
+    release: Release,
+) -> JoinHandle<usize>
+where
+    K: Key + Send + Sync + 'static,
+    V: Send + 'static,
+{
+    thread::spawn(move || {
+        let runtime = pump_runtime();
+        let mut count = 0_usize;
+        // only fails when upstream is disconnected and drained
+        while let Ok(msg) = rx.recv_wait() {
+            let (forwarded, lock) = forward(msg, release);
+            if runtime.block_on(tx.send(forwarded)).is_err() {
+                return count;
+            }
+            drop(lock);
+            count = count.wrapping_add(1);
+        }
+        count
+    })
+}
+
+/// spawn a thread that pumps messages from an async receiver to a sync sender
+///
+/// The thread drives the async receiver by itself, so no runtime is required.
+#[inline]
+#[must_use]
+pub fn async_to_sync<K, V>(
+    rx: async_channel::Receiver<K, V>, tx: sync_channel::BoundedSender<K, V>,
+    release: Release,
+) -> JoinHandle<usize>
+where
+    K: Key + Send + Sync + 'static,
+    V: Send + 'static,
+{
+    thread::spawn(move || {
+        let runtime = pump_runtime();
+        let mut count = 0_usize;
+        // only fails when upstream is disconnected and drained
+        while let Ok(msg) = runtime.block_on(rx.recv_wait()) {
+            let (forwarded, lock) = forward(msg, release);
+            if tx.send(forwarded).is_err() {
+                return count;
+            }
+            drop(lock);
+            count = count.wrapping_add(1);
+        }
+        count
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::{async_to_sync, sync_to_async, Release};
+    use crate::{async_channel, sync_channel, unwrap_ok_or, Message, RecvError};
+    use std::collections::HashSet;
+    use std::thread;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_sync_to_async() {
+        let (up_tx, up_rx) = sync_channel::bounded(2);
+        let (down_tx, down_rx) = async_channel::bounded(2);
+        let pump = sync_to_async(up_rx, down_tx, Release::OnForward);
+        let producer = thread::spawn(move || {
+            for i in 0..10 {
+                let _sent = up_tx.send(Message::multiple_keys(vec![i, i + 1], i));
+            }
+        });
+        for i in 0..10 {
+            let msg = unwrap_ok_or!(down_rx.recv().await, err, panic!("{:?}", err));
+            assert_eq!(*msg, i);
+            assert_eq!(msg.get_key_set(), Some(&HashSet::from([i, i + 1])));
+        }
+        // disconnection propagates downstream
+        assert_eq!(down_rx.recv().await, Err(RecvError::Disconnected));
+        let _drop = producer.join();
+        assert_eq!(unwrap_ok_or!(pump.join(), err, panic!("{:?}", err)), 10);
+    }
+
+    #[test]
+    fn test_async_to_sync() {
+        let runtime = super::pump_runtime();
+        let (up_tx, up_rx) = async_channel::bounded(2);
+        let (down_tx, down_rx) = sync_channel::bounded(2);
+        let pump = async_to_sync(up_rx, down_tx, Release::OnForward);
+        let producer = thread::spawn(move || {
+            for i in 0..10 {
+                let _sent = runtime.block_on(up_tx.send(Message::single_key(i, i)));
+            }
+        });
+        for i in 0..10 {
+            let msg = unwrap_ok_or!(down_rx.recv(), err, panic!("{:?}", err));
+            assert_eq!(msg.get_single_key(), Some(&i));
+        }
+        assert_eq!(down_rx.recv(), Err(RecvError::Disconnected));
+        let _drop = producer.join();
+        assert_eq!(unwrap_ok_or!(pump.join(), err, panic!("{:?}", err)), 10);
+    }
+
+    #[test]
+    fn test_downstream_disconnect() {
+        let (up_tx, up_rx) = sync_channel::bounded(2);
+        let (down_tx, down_rx) = async_channel::bounded::<usize, usize>(2);
+        let pump = sync_to_async(up_rx, down_tx, Release::HoldThrough);
+        drop(down_rx);
+        // noticed on the next message, then the upstream receiver is gone
+        assert_eq!(up_tx.send(Message::single_key(1, 1)), Ok(()));
+        assert_eq!(unwrap_ok_or!(pump.join(), err, panic!("{:?}", err)), 0);
+        assert!(up_tx.send(Message::single_key(1, 2)).is_err());
+    }
+
+    /// the conflicting traffic sent upstream before the pump starts
+    const TRAFFIC: [(usize, usize); 3] = [(1, 1), (1, 2), (2, 3)];
+
+    /// pump `TRAFFIC` from async to sync, hold (k1, 1) downstream, and return
+    /// whether (k1, 2) is forwarded meanwhile
+    fn async_to_sync_forwarded_while_held(release: Release) -> bool {
+        let runtime = super::pump_runtime();
+        let (up_tx, up_rx) = async_channel::bounded(10);
+        let (down_tx, down_rx) = sync_channel::bounded(10);
+        for (key, value) in TRAFFIC {
+            let _sent = runtime.block_on(up_tx.send(Message::single_key(key, value)));
+        }
+        let pump = async_to_sync(up_rx, down_tx, release);
+        let held = unwrap_ok_or!(down_rx.recv(), err, panic!("{:?}", err));
+        assert_eq!(*held, 1);
+        let other = unwrap_ok_or!(down_rx.recv(), err, panic!("{:?}", err));
+        assert_eq!(*other, 3);
+        // buffered but conflicting, or not forwarded at all
+        let probe = down_rx.recv_batch_timeout(1, Duration::from_millis(50));
+        let forwarded = matches!(probe, Err(RecvError::AllConflict));
+        assert!(forwarded || matches!(probe, Ok(ref batch) if batch.is_empty()));
+        drop(held);
+        let next = unwrap_ok_or!(down_rx.recv(), err, panic!("{:?}", err));
+        assert_eq!(*next, 2);
+        drop(up_tx);
+        assert_eq!(down_rx.recv(), Err(RecvError::Disconnected));
+        assert_eq!(unwrap_ok_or!(pump.join(), err, panic!("{:?}", err)), 3);
+        forwarded
+    }
+
+    /// pump `TRAFFIC` from sync to async, hold (k1, 1) downstream, and return
+    /// whether (k1, 2) is forwarded meanwhile
+    async fn sync_to_async_forwarded_while_held(release: Release) -> bool {
+        let (up_tx, up_rx) = sync_channel::bounded(10);
+        let (down_tx, down_rx) = async_channel::bounded(10);
+        for (key, value) in TRAFFIC {
+            let _sent = up_tx.send(Message::single_key(key, value));
+        }
+        let pump = sync_to_async(up_rx, down_tx, release);
+        let held = unwrap_ok_or!(down_rx.recv().await, err, panic!("{:?}", err));
+        assert_eq!(*held, 1);
+        let other = unwrap_ok_or!(down_rx.recv().await, err, panic!("{:?}", err));
+        assert_eq!(*other, 3);
+        let probe = down_rx
+            .recv_batch_timeout(1, Duration::from_millis(50))
+            .await;
+        let forwarded = matches!(probe, Err(RecvError::AllConflict));
+        assert!(forwarded || matches!(probe, Ok(ref batch) if batch.is_empty()));
+        drop(held);
+        let next = unwrap_ok_or!(down_rx.recv().await, err, panic!("{:?}", err));
+        assert_eq!(*next, 2);
+        drop(up_tx);
+        assert_eq!(down_rx.recv().await, Err(RecvError::Disconnected));
+        assert_eq!(unwrap_ok_or!(pump.join(), err, panic!("{:?}", err)), 3);
+        forwarded
+    }
+
+    #[test]
+    fn test_async_to_sync_release() {
+        // (k1, 2) stays upstream until the held message is dropped downstream
+        assert!(!async_to_sync_forwarded_while_held(Release::HoldThrough));
+        // (k1, 2) is forwarded at once, and conflicts downstream
+        assert!(async_to_sync_forwarded_while_held(Release::OnForward));
+    }
+
+    #[tokio::test]
+    async fn test_sync_to_async_release() {
+        assert!(!sync_to_async_forwarded_while_held(Release::HoldThrough).await);
+        assert!(sync_to_async_forwarded_while_held(Release::OnForward).await);
+    }
+
+    #[test]
+    fn test_async_to_sync_wake_on_send() {
+        let runtime = super::pump_runtime();
+        let (up_tx, up_rx) = async_channel::bounded(10);
+        let (down_tx, down_rx) = sync_channel::bounded(10);
+        for value in 1..=2 {
+            let _sent = runtime.block_on(up_tx.send(Message::single_key(1, value)));
+        }
+        let pump = async_to_sync(up_rx, down_tx, Release::HoldThrough);
+        let held = unwrap_ok_or!(down_rx.recv(), err, panic!("{:?}", err));
+        assert_eq!(*held, 1);
+        // the pump stalls on (k1, 2) until a message without conflict is sent
+        thread::sleep(Duration::from_millis(50));
+        let _sent = runtime.block_on(up_tx.send(Message::single_key(2, 3)));
+        let other = down_rx.recv_timeout(Duration::from_millis(500));
+        assert_eq!(other.map(|msg| *msg), Ok(3));
+        drop(held);
+        let next = unwrap_ok_or!(down_rx.recv(), err, panic!("{:?}", err));
+        assert_eq!(*next, 2);
+        drop(up_tx);
+        assert_eq!(unwrap_ok_or!(pump.join(), err, panic!("{:?}", err)), 3);
+    }
+
+    #[tokio::test]
+    async fn test_sync_to_async_wake_on_send() {
+        let (up_tx, up_rx) = sync_channel::bounded(10);
+        let (down_tx, down_rx) = async_channel::bounded(10);
+        for value in 1..=2 {
+            let _sent = up_tx.send(Message::single_key(1, value));
+        }
+        let pump = sync_to_async(up_rx, down_tx, Release::HoldThrough);
+        let held = unwrap_ok_or!(down_rx.recv().await, err, panic!("{:?}", err));
+        assert_eq!(*held, 1);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let _sent = up_tx.send(Message::single_key(2, 3));
+        let other = down_rx
+            .recv_timeout(Duration::from_millis(500))
+            .await;
+        assert_eq!(other.map(|msg| *msg), Ok(3));
+        drop(held);
+        let next = unwrap_ok_or!(down_rx.recv().await, err, panic!("{:?}", err));
+        assert_eq!(*next, 2);
+        drop(up_tx);
+        assert_eq!(unwrap_ok_or!(pump.join(), err, panic!("{:?}", err)), 3);
+    }
+}
@@ -1,12 +1,20 @@
 //! A FIFO queue shared by sender and receiver
 
-use crate::err::RecvError;
+use crate::active::ActiveKeys;
+use crate::err::{RecvError, RecvFilterError};
 use crate::message::Key;
-use crate::{unwrap_ok_or, unwrap_some_or};
+use crate::unwrap_some_or;
+use std::any::Any;
 use std::borrow::Borrow;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, VecDeque};
 use std::fmt::Debug;
 use std::hash::Hash;
+#[cfg(feature = "stream")]
+use std::ops::Bound;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
 
 #[cfg(feature = "list")]
 use std::collections::LinkedList;
@@ -14,123 +22,576 @@ use std::collections::LinkedList;
 /// actual buffer type
 type BuffType<T> = LinkedList<T>;
 #[cfg(not(feature = "list"))]
-use std::collections::VecDeque;
-use std::rc::Rc;
-#[cfg(not(feature = "list"))]
 /// actual buffer type
 type BuffType<T> = VecDeque<T>;
 
+/// A queued message
+#[derive(Debug)]
+struct Entry<T> {
+    /// the message
+    msg: T,
+    /// number of keys that an older queued message has too
+    blocked: usize,
+    /// is it at the head of all its keys but waiting for an active one
+    parked: bool,
+}
+
 /// A fixed size buff
+///
+/// Active keys live in their own sharded table, see [`ActiveKeys`]. The buff only
+/// orders messages that share a key: a message is ready when it's the oldest
+/// queued one for each of its keys, and it's received once none of them is
+/// active. A ready message found with an active key is parked until the key
+/// is released, so each receive looks at no more than a few of them.
 #[derive(Debug)]
 pub(crate) struct KeyedBuff<T: BuffMessage> {
-    /// FIFO queue buff, store msgs that without conflitc
-    ready: BuffType<T>,
-    /// msgs that conflict with that key
-    pending_on_key: HashMap<<T as BuffMessage>::Key, Vec<Rc<T>>>,
+    /// all queued messages, ordered by id, which is the enqueue order
+    entries: BTreeMap<u64, Entry<T>>,
+    /// FIFO queue of ids of messages that without conflict
+    ready: BuffType<u64>,
+    /// ids of queued messages with each key in FIFO order,
+    /// a key is absent when no queued message has it
+    keys: HashMap<<T as BuffMessage>::Key, VecDeque<u64>>,
+    /// keys held by received messages and key guards
+    active: Arc<ActiveKeys<<T as BuffMessage>::Key>>,
+    /// id of next pushed message
+    next_id: u64,
     /// capacity of buff
     cap: usize,
-    /// size of buff now
-    size: usize,
+    /// pop the highest priority ready message instead of the front one
+    prioritized: bool,
+    /// number of queued messages with a deadline, no need to look for
+    /// expired messages without any
+    expiring: usize,
+    /// expired messages removed from the queue, taken by the caller to drop
+    /// them after unlocking
+    expired: Vec<T>,
+    /// number of expired messages ever removed
+    expired_count: u64,
 }
 
 impl<T: BuffMessage> KeyedBuff<T> {
-    /// new a buff with cap
-    pub(crate) fn new(cap: usize) -> Self {
+    /// new a buff with cap and the table of active keys, a prioritized buff
+    /// scans all ready messages for the highest priority one
+    ///
+    /// The ready queue and key map are preallocated to `cap`, so filling
+    /// the buff the first time does not reallocate them.
+    pub(crate) fn new(
+        cap: usize, prioritized: bool, active: Arc<ActiveKeys<<T as BuffMessage>::Key>>,
+    ) -> Self {
         KeyedBuff {
-            ready: BuffType::with_capacity(cap),
-            pending_on_key: HashMap::with_capacity(cap),
+            entries: BTreeMap::new(),
+            #[cfg(not(feature = "list"))]
+            ready: VecDeque::with_capacity(cap),
+            #[cfg(feature = "list")]
+            ready: LinkedList::new(),
+            keys: HashMap::with_capacity(cap),
+            active,
+            next_id: 0,
             cap,
-            size: 0,
+            prioritized,
+            expiring: 0,
+            expired: Vec::new(),
+            expired_count: 0,
         }
     }
 
     /// push back to buff
-    pub(crate) fn push_back(&mut self, m: T) {
-        let size = unwrap_some_or!(self.size.checked_add(1), panic!("fatal error"));
-        self.size = size;
-        let keys = m.get_owned_keys();
-        let mut pending = false;
-        let msg = Rc::new(m);
-        for k in keys {
-            if let Some(pendings) = self.pending_on_key.get_mut(&k) {
-                pending = true;
-                pendings.push(Rc::clone(&msg));
-            } else {
-                let _drop = self.pending_on_key.insert(k, vec![]);
+    ///
+    /// A message waits behind the older queued messages that share a key
+    /// with it, so messages that share a key are received in FIFO order.
+    /// Only the queue is locked, whether its keys are active is checked when
+    /// it's popped.
+    ///
+    /// The key's `Clone`/`Hash`/`Eq` may panic, the registered keys are rolled
+    /// back and the message is returned with the panic, so that callers can drop
+    /// the message and resume the panic after unlocking the state.
+    pub(crate) fn push_back(&mut self, m: T) -> Result<(), (T, Box<dyn Any + Send>)> {
+        let id = self.next_id;
+        let next_id = unwrap_some_or!(id.checked_add(1), panic!("fatal error"));
+        let mut registered: usize = 0;
+        let mut blocked: usize = 0;
+        let mut keys = Vec::new();
+        let pushed = contain(|| {
+            keys = m.get_owned_keys();
+            for k in &keys {
+                let queued = self.keys.entry(k.clone()).or_default();
+                if !queued.is_empty() {
+                    blocked =
+                        unwrap_some_or!(blocked.checked_add(1), panic!("fatal error"));
+                }
+                queued.push_back(id);
+                registered =
+                    unwrap_some_or!(registered.checked_add(1), panic!("fatal error"));
             }
+        });
+        if let Err(payload) = pushed {
+            for k in keys.iter().take(registered) {
+                let queued = unwrap_some_or!(self.keys.get_mut(k), panic!("fatal error"));
+                let _popped = queued.pop_back();
+                if queued.is_empty() {
+                    let _removed = self.keys.remove(k);
+                }
+            }
+            return Err((m, payload));
+        }
+        self.next_id = next_id;
+        if m.expiry().is_some() {
+            self.expiring =
+                unwrap_some_or!(self.expiring.checked_add(1), panic!("fatal error"));
         }
-        if !pending {
-            let inner_msg = unwrap_ok_or!(
-                Rc::try_unwrap(msg),
-                _,
-                panic!("there should be only on ref")
-            );
-            self.ready.push_back(inner_msg);
+        let _drop = self
+            .entries
+            .insert(id, Entry { msg: m, blocked, parked: false });
+        if blocked == 0 {
+            self.ready.push_back(id);
         }
+        Ok(())
     }
 
-    /// pop an unconflict message as front as possible
+    /// pop an unconflict message as front as possible, and activate its keys
+    ///
+    /// A prioritized buff pops the highest priority one, the front-most of them
+    /// on a tie. Expired messages are removed first, see [`KeyedBuff::take_expired`].
     pub(crate) fn pop_unconflict_front(&mut self) -> Result<T, RecvError> {
-        if self.ready.is_empty() && self.size != 0 {
-            Err(RecvError::AllConflict)
-        } else {
-            #[cfg(not(feature = "list"))]
-            let msg = unwrap_some_or!(self.ready.pop_front(), panic!("fatal error"));
-            #[cfg(feature = "list")]
-            let msg = self.buff.remove(index);
-            let size = unwrap_some_or!(self.size.checked_sub(1), panic!("fatal error"));
-            self.size = size;
-            Ok(msg)
+        self.purge_expired();
+        if self.prioritized {
+            let id =
+                unwrap_some_or!(self.next_ready(), return Err(RecvError::AllConflict));
+            return Ok(self.pop_ready(id));
+        }
+        // a FIFO buff activates the keys of the front message as it checks them,
+        // so each key takes its shard once
+        self.unpark_freed();
+        while let Some(&id) = self.ready.front() {
+            let entry = unwrap_some_or!(self.entries.get(&id), panic!("fatal error"));
+            let keys = entry.msg.get_owned_keys();
+            if self.active.try_activate(&keys) {
+                let msg = self.take_ready(id);
+                for k in keys {
+                    if self.pass_on(&k, id, true) {
+                        let _active = self.active.wait_if_active(&k);
+                    }
+                }
+                return Ok(msg);
+            }
+            self.park(id);
         }
+        Err(RecvError::AllConflict)
     }
 
-    /// remove an active key
-    pub(crate) fn deactivate_key<Q>(&mut self, key: &Q)
+    /// remove all expired messages, conflicting or not, they never get the
+    /// keys they are pending on
+    pub(crate) fn purge_expired(&mut self) {
+        if self.expiring == 0 {
+            return;
+        }
+        let now = Instant::now();
+        let expired: Vec<u64> = self
+            .entries
+            .iter()
+            .filter(|&(_, entry)| entry.msg.expiry().is_some_and(|d| d <= now))
+            .map(|(&id, _)| id)
+            .collect();
+        for id in expired {
+            let msg = self.remove_queued(id);
+            self.expired.push(msg);
+            self.expired_count =
+                unwrap_some_or!(self.expired_count.checked_add(1), panic!("fatal error"));
+        }
+    }
+
+    /// take the expired messages removed by the last pops, dropping them
+    /// may run user code, so it's done after unlocking
+    pub(crate) fn take_expired(&mut self) -> Vec<T> {
+        std::mem::take(&mut self.expired)
+    }
+
+    /// number of expired messages ever removed
+    pub(crate) fn expired_count(&self) -> u64 {
+        self.expired_count
+    }
+
+    /// remove a queued entry
+    fn take_entry(&mut self, id: u64) -> Entry<T> {
+        let entry = unwrap_some_or!(self.entries.remove(&id), panic!("fatal error"));
+        if entry.msg.expiry().is_some() {
+            self.expiring =
+                unwrap_some_or!(self.expiring.checked_sub(1), panic!("fatal error"));
+        }
+        entry
+    }
+
+    /// park the ready messages with an active key, so the ones left are
+    /// receivable, then return the id of the ready message to pop next
+    ///
+    /// Parked messages whose keys are released since are made ready again
+    /// first. A FIFO buff only parks the front ones, a prioritized buff
+    /// parks all of them.
+    fn next_ready(&mut self) -> Option<u64> {
+        self.unpark_freed();
+        if !self.prioritized {
+            while let Some(&id) = self.ready.front() {
+                if !self.park_if_active(id) {
+                    return Some(id);
+                }
+            }
+            return None;
+        }
+        let ids: Vec<u64> = self.ready.iter().copied().collect();
+        for id in ids {
+            let _parked = self.park_if_active(id);
+        }
+        self.front_ready_id()
+    }
+
+    /// id of the ready message to pop next, without looking at active keys
+    fn front_ready_id(&self) -> Option<u64> {
+        if !self.prioritized {
+            return self.ready.front().copied();
+        }
+        let entries = &self.entries;
+        let priority = |id: &u64| {
+            entries
+                .get(id)
+                .map_or(0, |entry| entry.msg.pop_priority())
+        };
+        // `max_by_key` takes the last maximum, the reversed scan makes it the
+        // front-most one
+        self.ready
+            .iter()
+            .copied()
+            .rev()
+            .max_by_key(priority)
+    }
+
+    /// park a ready message until its active key is released,
+    /// return false if none of its keys is active
+    fn park_if_active(&mut self, id: u64) -> bool {
+        let entry = unwrap_some_or!(self.entries.get(&id), panic!("fatal error"));
+        let keys = entry.msg.get_owned_keys();
+        // the release of the first active key wakes the receiver, the other
+        // active keys are checked again then
+        if !keys
+            .iter()
+            .any(|k| self.active.wait_if_active(k))
+        {
+            return false;
+        }
+        self.park(id);
+        true
+    }
+
+    /// park a ready message until the active key it waits for is released
+    fn park(&mut self, id: u64) {
+        self.remove_ready(id);
+        let entry = unwrap_some_or!(self.entries.get_mut(&id), panic!("fatal error"));
+        entry.parked = true;
+    }
+
+    /// make the messages parked on the keys released since ready again
+    fn unpark_freed(&mut self) {
+        for key in self.active.take_freed() {
+            self.unpark(&key);
+        }
+    }
+
+    /// make the message parked at the head of a released key ready again,
+    /// it's checked again before it's popped
+    fn unpark<Q>(&mut self, key: &Q)
     where
         <T as BuffMessage>::Key: Borrow<Q>,
         Q: Hash + Eq + ?Sized,
     {
-        if let Some(pending_msgs) = self.pending_on_key.get_mut(key) {
-            if !pending_msgs.is_empty() {
-                let first = pending_msgs.remove(0);
-                if Rc::strong_count(&first) == 1 {
-                    let msg = unwrap_ok_or!(
-                        Rc::try_unwrap(first),
-                        _,
-                        panic!("there should be only on ref")
-                    );
-                    self.ready.push_back(msg);
-                }
-            }
-            if pending_msgs.is_empty() {
-                let _drop = self.pending_on_key.remove(key);
+        let id = *unwrap_some_or!(self.keys.get(key).and_then(VecDeque::front), return);
+        let entry = unwrap_some_or!(self.entries.get_mut(&id), panic!("fatal error"));
+        if entry.parked {
+            entry.parked = false;
+            self.ready.push_back(id);
+        }
+    }
+
+    /// pop an unconflict message as front as possible, with the queued messages
+    /// right behind it that have the same sole key, and activate their keys
+    ///
+    /// The sole key is stored in `key`, it's left `None` for a message with
+    /// several keys or none. Messages are pushed into `group` one by one, so
+    /// they are kept if the key's `Clone`/`Eq` panics halfway.
+    #[cfg(feature = "stream")]
+    pub(crate) fn pop_group_front(
+        &mut self, group: &mut Vec<T>, key: &mut Option<<T as BuffMessage>::Key>,
+    ) -> Result<(), RecvError> {
+        self.purge_expired();
+        let mut last =
+            unwrap_some_or!(self.next_ready(), return Err(RecvError::AllConflict));
+        let first = self.pop_ready(last);
+        let mut keys = first.get_owned_keys();
+        group.push(first);
+        let sole = match keys.pop() {
+            Some(sole) if keys.is_empty() => key.insert(sole),
+            _ => return Ok(()),
+        };
+        // a message with the sole key only, and next in line for it, is parked
+        // on the key popped right before it, and is popped as if the key was
+        // released to it
+        while let Some((&id, entry)) = self
+            .entries
+            .range((Bound::Excluded(last), Bound::Unbounded))
+            .next()
+        {
+            if !entry.parked || entry.msg.get_owned_keys() != std::slice::from_ref(sole) {
+                break;
             }
+            let next = self.pop_ready(id);
+            group.push(next);
+            last = id;
+        }
+        Ok(())
+    }
+
+    /// pop an unconflict message that matches `pred` as front as possible,
+    /// and activate its keys
+    ///
+    /// Tell whether a matching message is queued but conflicts, or there is none.
+    /// Expired messages are removed first.
+    pub(crate) fn pop_unconflict_where<P>(
+        &mut self, mut pred: P,
+    ) -> Result<T, RecvFilterError>
+    where
+        P: FnMut(&T) -> bool,
+    {
+        self.purge_expired();
+        self.park_all();
+        let entries = &self.entries;
+        let found = self.ready.iter().copied().find(|id| {
+            entries
+                .get(id)
+                .is_some_and(|entry| pred(&entry.msg))
+        });
+        if let Some(id) = found {
+            return Ok(self.pop_ready(id));
+        }
+        if self
+            .entries
+            .values()
+            .any(|entry| pred(&entry.msg))
+        {
+            Err(RecvFilterError::AllConflict)
+        } else {
+            Err(RecvFilterError::NoMatch)
+        }
+    }
+
+    /// park all ready messages with an active key, like `next_ready` on
+    /// a prioritized buff
+    fn park_all(&mut self) {
+        self.unpark_freed();
+        let ids: Vec<u64> = self.ready.iter().copied().collect();
+        for id in ids {
+            let _parked = self.park_if_active(id);
+        }
+    }
+
+    /// pop a ready message, or one parked right after its keys are activated
+    /// by the message popped before it, and activate its keys
+    fn pop_ready(&mut self, id: u64) -> T {
+        // clone keys before any change, a panic in `Clone` leaves buffer untouched
+        let keys = unwrap_some_or!(self.entries.get(&id), panic!("fatal error"))
+            .msg
+            .get_owned_keys();
+        let msg = self.take_ready(id);
+        for k in keys {
+            let parked = self.pass_on(&k, id, true);
+            self.active.activate(k, parked);
         }
+        msg
     }
 
-    /// is buffer full
+    /// take a ready or parked message out, its keys are left to pass on
+    fn take_ready(&mut self, id: u64) -> T {
+        let entry = self.take_entry(id);
+        if !entry.parked {
+            self.remove_ready(id);
+        }
+        entry.msg
+    }
+
+    /// pop the front-most message regardless of conflict,
+    /// its keys are not activated, expired messages are removed first
+    pub(crate) fn pop_front_unguarded(&mut self) -> Result<T, RecvError> {
+        self.purge_expired();
+        let id = *unwrap_some_or!(
+            self.entries.keys().next(),
+            return Err(RecvError::AllConflict)
+        );
+        Ok(self.remove_queued(id))
+    }
+
+    /// the message that `pop_unconflict_front` pops next, callers purge expired
+    /// messages first and look for ready ones by `has_ready`
+    pub(crate) fn front_ready(&self) -> Option<&T> {
+        let id = self.front_ready_id()?;
+        self.entries.get(&id).map(|entry| &entry.msg)
+    }
+
+    /// pop the front-most message regardless of conflict, and activate its keys,
+    /// expired messages are removed first
+    pub(crate) fn pop_front_any(&mut self) -> Result<T, RecvError> {
+        self.purge_expired();
+        let id = *unwrap_some_or!(
+            self.entries.keys().next(),
+            return Err(RecvError::AllConflict)
+        );
+        // clone keys before any change, a panic in `Clone` leaves buffer untouched
+        let keys = unwrap_some_or!(self.entries.get(&id), panic!("fatal error"))
+            .msg
+            .get_owned_keys();
+        let msg = self.remove_queued(id);
+        // the messages next in line for the keys are parked when they are found
+        // ready with the keys active
+        for k in keys {
+            self.active.activate(k, false);
+        }
+        Ok(msg)
+    }
+
+    /// remove a queued message, and pass its keys on to the messages behind it
+    fn remove_queued(&mut self, id: u64) -> T {
+        // clone keys before any change, a panic in `Clone` leaves buffer untouched
+        let keys = unwrap_some_or!(self.entries.get(&id), panic!("fatal error"))
+            .msg
+            .get_owned_keys();
+        let entry = self.take_entry(id);
+        if entry.blocked == 0 && !entry.parked {
+            self.remove_ready(id);
+        }
+        for k in keys {
+            let _parked = self.pass_on(&k, id, false);
+        }
+        entry.msg
+    }
+
+    /// remove id from the queue of a key, the next message in line may get
+    /// ready, it's parked at once if the key is `activated` for the removed one,
+    /// return whether it's parked
+    fn pass_on<Q>(&mut self, key: &Q, id: u64, activated: bool) -> bool
+    where
+        <T as BuffMessage>::Key: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let queued = unwrap_some_or!(self.keys.get_mut(key), panic!("fatal error"));
+        if queued.front() != Some(&id) {
+            queued.retain(|queued| *queued != id);
+            return false;
+        }
+        let _passed = queued.pop_front();
+        let next = unwrap_some_or!(queued.front().copied(), {
+            let _drop = self.keys.remove(key);
+            return false;
+        });
+        let entry = unwrap_some_or!(self.entries.get_mut(&next), panic!("fatal error"));
+        entry.blocked =
+            unwrap_some_or!(entry.blocked.checked_sub(1), panic!("fatal error"));
+        if entry.blocked != 0 {
+            return false;
+        }
+        if !activated {
+            self.ready.push_back(next);
+        }
+        entry.parked = activated;
+        activated
+    }
+
+    /// remove id from the ready queue
+    fn remove_ready(&mut self, id: u64) {
+        if self.ready.front() == Some(&id) {
+            let _drop = self.ready.pop_front();
+            return;
+        }
+        let index = unwrap_some_or!(
+            self.ready.iter().position(|r| *r == id),
+            panic!("fatal error")
+        );
+        #[cfg(not(feature = "list"))]
+        let _drop = self.ready.remove(index);
+        // `LinkedList::remove` is unstable
+        #[cfg(feature = "list")]
+        {
+            let mut tail = self.ready.split_off(index);
+            let _drop = tail.pop_front();
+            self.ready.append(&mut tail);
+        }
+    }
+
+    /// active keys that queued messages are pending on
+    pub(crate) fn blocking_keys(&self) -> Vec<<T as BuffMessage>::Key> {
+        self.active
+            .keys_where(|key| self.keys.contains_key(key))
+    }
+
+    /// capacity of buffer
+    pub(crate) fn cap(&self) -> usize {
+        self.cap
+    }
+
+    /// is buffer full, a zero capacity buffer still holds a message in handoff
     pub(crate) fn is_full(&self) -> bool {
-        self.size == self.cap
+        self.entries.len() >= self.cap.max(1)
+    }
+
+    /// id of next pushed message
+    pub(crate) fn next_id(&self) -> u64 {
+        self.next_id
+    }
+
+    /// is the message with `id` still queued
+    pub(crate) fn is_queued(&self, id: u64) -> bool {
+        self.entries.contains_key(&id)
+    }
+
+    /// take back a queued message, and pass its keys on
+    pub(crate) fn remove(&mut self, id: u64) -> T {
+        self.remove_queued(id)
+    }
+
+    /// number of queued messages
+    pub(crate) fn len(&self) -> usize {
+        self.entries.len()
     }
 
     /// is buffer empty
     pub(crate) fn is_empty(&self) -> bool {
-        self.size == 0
+        self.entries.is_empty()
+    }
+
+    /// is any queued message without conflict, the ones with an active key
+    /// are parked, so a released key wakes up the receiver
+    pub(crate) fn has_ready(&mut self) -> bool {
+        self.next_ready().is_some()
     }
 }
 
+/// run user code (the key's `Clone`/`Hash`/`Eq`) while the state is locked,
+/// a panic is caught and returned, callers resume it after unlocking,
+/// so the state lock is never poisoned
+pub(crate) fn contain<R, F: FnOnce() -> R>(f: F) -> Result<R, Box<dyn Any + Send>> {
+    catch_unwind(AssertUnwindSafe(f))
+}
+
 /// A trait that represents keyed message stored in buffer
 pub(crate) trait BuffMessage {
     /// key type
     type Key: Key;
 
-    /// is the message's key disjoint with an set of keys
-    fn conflict_keys(&self, other: &HashMap<Self::Key, usize>)
-        -> Option<Vec<&Self::Key>>;
-
     /// collect all keys to an owned vector
     /// applicable to both key types
     fn get_owned_keys(&self) -> Vec<Self::Key>;
+
+    /// priority of the message, only a prioritized buff looks at it
+    fn pop_priority(&self) -> u8;
+
+    /// after which the message is removed instead of popped
+    fn expiry(&self) -> Option<Instant>;
 }
 
 /// The state of queue
@@ -138,9 +599,242 @@ pub(crate) trait BuffMessage {
 pub(crate) struct State<T: BuffMessage> {
     /// queue buffer
     pub(crate) buff: KeyedBuff<T>,
-    /// n senders of the queue
-    pub(crate) n_senders: usize,
     /// is the queue disconnected
     /// all sender gone or receiver closed
     pub(crate) disconnected: bool,
+    /// is the receiver dropped, unlike `disconnected` it's not set by closing
+    pub(crate) receiver_dropped: bool,
+}
+
+impl<T: BuffMessage> State<T> {
+    /// new a connected state, with the table of active keys
+    pub(crate) fn new(
+        cap: usize, prioritized: bool, active: Arc<ActiveKeys<<T as BuffMessage>::Key>>,
+    ) -> Self {
+        State {
+            buff: KeyedBuff::new(cap, prioritized, active),
+            disconnected: false,
+            receiver_dropped: false,
+        }
+    }
+
+    /// number of queued messages if the queue is disconnected
+    pub(crate) fn remaining_after_disconnect(&self) -> Option<usize> {
+        self.disconnected.then(|| self.buff.len())
+    }
+}
+
+/// The number of live senders, kept out of the state lock so that cloning
+/// and dropping senders don't contend with sending and receiving
+#[derive(Debug)]
+pub(crate) struct SenderCount(AtomicUsize);
+
+impl SenderCount {
+    /// new a count of `n` senders
+    pub(crate) fn new(n: usize) -> Self {
+        SenderCount(AtomicUsize::new(n))
+    }
+
+    /// number of live senders
+    pub(crate) fn get(&self) -> usize {
+        self.0.load(Ordering::Acquire)
+    }
+
+    /// count a cloned sender
+    pub(crate) fn add(&self) {
+        // a sender is cloned from a live one, so the count never revives from zero
+        let prev = self.0.fetch_add(1, Ordering::Relaxed);
+        if prev == usize::MAX {
+            // wrapped around, restore it before panicking
+            self.0.store(usize::MAX, Ordering::Relaxed);
+            panic!("too many senders");
+        }
+    }
+
+    /// count a sender revived from a weak one, fail if no sender is alive,
+    /// so the channel is or is about to be disconnected
+    pub(crate) fn try_add(&self) -> bool {
+        self.0
+            .fetch_update(Ordering::Acquire, Ordering::Relaxed, |n| {
+                (n != 0).then(|| n.checked_add(1)).flatten()
+            })
+            .is_ok()
+    }
+
+    /// count a dropped sender, return true if it's the last one,
+    /// then the caller disconnects the queue
+    pub(crate) fn remove(&self) -> bool {
+        let prev = self.0.fetch_sub(1, Ordering::AcqRel);
+        assert!(prev != 0, "fatal error");
+        prev == 1
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{BuffMessage, KeyedBuff};
+    use crate::active::ActiveKeys;
+    use crate::err::{RecvError, RecvFilterError};
+    use std::sync::Arc;
+    use std::time::Instant;
+
+    /// a message with plain keys, identified by its value
+    struct Msg(Vec<u32>, u32);
+
+    impl BuffMessage for Msg {
+        type Key = u32;
+
+        fn get_owned_keys(&self) -> Vec<u32> {
+            self.0.clone()
+        }
+
+        fn pop_priority(&self) -> u8 {
+            0
+        }
+
+        fn expiry(&self) -> Option<Instant> {
+            None
+        }
+    }
+
+    /// a buff with messages pushed in order
+    fn buff_of(msgs: &[(&[u32], u32)]) -> KeyedBuff<Msg> {
+        let mut buff = KeyedBuff::new(16, false, Arc::new(ActiveKeys::new(16)));
+        for &(keys, value) in msgs {
+            assert!(buff
+                .push_back(Msg(keys.to_vec(), value))
+                .is_ok());
+        }
+        buff
+    }
+
+    /// release a key once, as dropping a received message does
+    fn release(buff: &KeyedBuff<Msg>, key: u32) {
+        let _freed = buff
+            .active
+            .deactivate(buff.active.shard_of(&key), &key);
+    }
+
+    /// is the key held by a received message or a key guard
+    fn is_active(buff: &KeyedBuff<Msg>, key: u32) -> bool {
+        buff.active.is_active(&key)
+    }
+
+    /// pop the next message, and take its value
+    fn pop(buff: &mut KeyedBuff<Msg>) -> Result<u32, RecvError> {
+        buff.pop_unconflict_front().map(|msg| msg.1)
+    }
+
+    #[test]
+    fn test_fifo_without_conflict() {
+        let mut buff = buff_of(&[(&[1], 0), (&[2], 1), (&[3], 2)]);
+        assert_eq!(pop(&mut buff), Ok(0));
+        assert_eq!(pop(&mut buff), Ok(1));
+        assert_eq!(pop(&mut buff), Ok(2));
+        assert_eq!(pop(&mut buff), Err(RecvError::AllConflict));
+        assert!(buff.is_empty());
+    }
+
+    #[test]
+    fn test_pop_conflict_release() {
+        let mut buff = buff_of(&[(&[1], 0), (&[1], 1), (&[2], 2)]);
+        assert_eq!(pop(&mut buff), Ok(0));
+        // the front message is pending on key 1, the one behind it is ready
+        assert_eq!(pop(&mut buff), Ok(2));
+        assert_eq!(pop(&mut buff), Err(RecvError::AllConflict));
+        // a message pushed while others conflict is still found
+        assert!(buff.push_back(Msg(vec![3], 3)).is_ok());
+        assert_eq!(pop(&mut buff), Ok(3));
+        assert_eq!(pop(&mut buff), Err(RecvError::AllConflict));
+        release(&buff, 1);
+        assert_eq!(pop(&mut buff), Ok(1));
+        assert!(buff.is_empty());
+    }
+
+    #[test]
+    fn test_pop_middle_then_release() {
+        let mut buff = buff_of(&[(&[1], 0), (&[2], 1), (&[1], 2), (&[2], 3)]);
+        let middle = buff.pop_unconflict_where(|msg| msg.1 == 1);
+        assert_eq!(middle.map(|msg| msg.1), Ok(1));
+        assert_eq!(pop(&mut buff), Ok(0));
+        assert_eq!(pop(&mut buff), Err(RecvError::AllConflict));
+        let pending = buff.pop_unconflict_where(|msg| msg.1 == 3);
+        assert_eq!(pending.map(|msg| msg.1), Err(RecvFilterError::AllConflict));
+        release(&buff, 2);
+        assert_eq!(pop(&mut buff), Ok(3));
+        release(&buff, 1);
+        assert_eq!(pop(&mut buff), Ok(2));
+        assert_eq!(buff.len(), 0);
+    }
+
+    #[test]
+    fn test_release_order() {
+        let mut buff = buff_of(&[(&[1], 0), (&[2], 1), (&[1], 2), (&[2], 3)]);
+        assert_eq!(pop(&mut buff), Ok(0));
+        assert_eq!(pop(&mut buff), Ok(1));
+        // messages are ready in the order their keys are released
+        release(&buff, 2);
+        release(&buff, 1);
+        assert_eq!(pop(&mut buff), Ok(3));
+        assert_eq!(pop(&mut buff), Ok(2));
+    }
+
+    #[test]
+    fn test_multiple_keys() {
+        let mut buff = buff_of(&[(&[1], 0), (&[2], 1), (&[1, 2], 2), (&[3], 3)]);
+        assert_eq!(pop(&mut buff), Ok(0));
+        assert_eq!(pop(&mut buff), Ok(1));
+        assert_eq!(pop(&mut buff), Ok(3));
+        release(&buff, 1);
+        // still pending on key 2
+        assert_eq!(pop(&mut buff), Err(RecvError::AllConflict));
+        release(&buff, 2);
+        assert_eq!(pop(&mut buff), Ok(2));
+    }
+
+    #[test]
+    fn test_activate_owned_key() {
+        let mut buff = buff_of(&[(&[1], 0), (&[1], 1)]);
+        // the owner gives up the key, and waits in front of the others
+        buff.active.activate(1, false);
+        assert!(is_active(&buff, 1));
+        assert_eq!(pop(&mut buff), Err(RecvError::AllConflict));
+        release(&buff, 1);
+        assert!(!is_active(&buff, 1));
+        assert_eq!(pop(&mut buff), Ok(0));
+        release(&buff, 1);
+        assert_eq!(pop(&mut buff), Ok(1));
+        release(&buff, 1);
+        assert!(!is_active(&buff, 1));
+    }
+
+    #[test]
+    #[cfg(not(feature = "list"))]
+    fn test_preallocated() {
+        let mut buff = buff_of(&[]);
+        let ready = buff.ready.capacity();
+        let keys = buff.keys.capacity();
+        assert!(ready >= 16 && keys >= 16);
+        for value in 0..16 {
+            assert!(buff.push_back(Msg(vec![value], value)).is_ok());
+        }
+        assert_eq!(buff.ready.capacity(), ready);
+        assert_eq!(buff.keys.capacity(), keys);
+    }
+
+    #[test]
+    fn test_remove_queued() {
+        let mut buff = buff_of(&[(&[1], 0), (&[1], 1), (&[1], 2)]);
+        let id = buff.next_id();
+        assert!(buff.push_back(Msg(vec![1], 3)).is_ok());
+        assert!(buff.is_queued(id));
+        assert_eq!(buff.remove(id).1, 3);
+        assert!(!buff.is_queued(id));
+        // the front owner is taken, the next one gets the key
+        assert_eq!(buff.pop_front_unguarded().map(|msg| msg.1), Ok(0));
+        assert_eq!(pop(&mut buff), Ok(1));
+        assert_eq!(pop(&mut buff), Err(RecvError::AllConflict));
+        release(&buff, 1);
+        assert_eq!(pop(&mut buff), Ok(2));
+    }
 }
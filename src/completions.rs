@@ -0,0 +1,119 @@
+//! A lossy feed of released keys
+
+use crate::message::Key;
+use crate::unwrap_ok_or;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+#[cfg(feature = "async")]
+use tokio::sync::Notify;
+
+/// How the keys of a completion are released
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum CompletionKind {
+    /// a received message is dropped
+    Dropped,
+    /// a key guard, from `lock_key` or `recv_key_only`, is dropped
+    Unlocked,
+    /// the keys are released explicitly, by `Message::release_keys` or
+    /// `KeyLock::release`
+    Released,
+}
+
+/// released keys and how they are released
+pub type Completion<K> = (Vec<K>, CompletionKind);
+
+/// the ring buffer shared by the channel and the listener
+#[derive(Debug)]
+pub(crate) struct Feed<K: Key> {
+    /// buffered completions and the number of dropped ones
+    ring: Mutex<(VecDeque<Completion<K>>, u64)>,
+    /// max buffered completions
+    cap: usize,
+    /// notify async listener when a completion is pushed
+    #[cfg(feature = "async")]
+    notify: Notify,
+}
+
+impl<K: Key> Feed<K> {
+    /// new a feed that buffers at most `cap` completions
+    pub(crate) fn new(cap: usize) -> Self {
+        assert!(cap > 0, "The capacity of completions must be greater than 0");
+        Feed {
+            ring: Mutex::new((VecDeque::with_capacity(cap), 0)),
+            cap,
+            #[cfg(feature = "async")]
+            notify: Notify::new(),
+        }
+    }
+
+    /// push a completion, the oldest one is dropped if the feed is full
+    pub(crate) fn push(&self, completion: Completion<K>) {
+        // called in drop, must not panic
+        let mut ring = unwrap_ok_or!(self.ring.lock(), _, return);
+        if ring.0.len() >= self.cap {
+            let _drop = ring.0.pop_front();
+            ring.1 = ring.1.saturating_add(1);
+        }
+        ring.0.push_back(completion);
+        drop(ring);
+        #[cfg(feature = "async")]
+        self.notify.notify_one();
+    }
+}
+
+/// The listener of completions, created by `Receiver::completions`
+///
+/// A completion is emitted when the keys of a received message or a key guard
+/// are released, i.e. when processing is finished rather than when the message
+/// is delivered. The feed is bounded and lossy, the oldest completion is dropped
+/// when it's full, so a slow listener never backs up the channel.
+#[derive(Debug)]
+pub struct Completions<K: Key> {
+    /// the shared feed
+    feed: Arc<Feed<K>>,
+}
+
+impl<K: Key> Completions<K> {
+    /// new a listener of the feed
+    pub(crate) fn new(feed: Arc<Feed<K>>) -> Self {
+        Completions { feed }
+    }
+
+    /// take the oldest buffered completion
+    #[inline]
+    #[must_use]
+    pub fn try_next(&self) -> Option<Completion<K>> {
+        let mut ring = unwrap_ok_or!(self.feed.ring.lock(), err, panic!("{:?}", err));
+        ring.0.pop_front()
+    }
+
+    /// take all buffered completions, oldest first
+    #[inline]
+    #[must_use]
+    pub fn drain(&self) -> Vec<Completion<K>> {
+        let mut ring = unwrap_ok_or!(self.feed.ring.lock(), err, panic!("{:?}", err));
+        ring.0.drain(..).collect()
+    }
+
+    /// number of completions dropped because the feed was full
+    #[inline]
+    #[must_use]
+    pub fn lost(&self) -> u64 {
+        unwrap_ok_or!(self.feed.ring.lock(), err, panic!("{:?}", err)).1
+    }
+
+    /// wait for the oldest completion
+    #[cfg(feature = "async")]
+    #[inline]
+    pub async fn next(&self) -> Completion<K> {
+        loop {
+            // a permit is stored if pushed in between
+            let notified = self.feed.notify.notified();
+            if let Some(completion) = self.try_next() {
+                return completion;
+            }
+            notified.await;
+        }
+    }
+}
@@ -1,5 +1,8 @@
 //! Errors used by ``kv_mpsc`` when Send and Receive
 
+use std::error::Error;
+use std::fmt::{self, Debug, Display, Formatter};
+
 /// Error occurs only when channel is disconnected or
 /// all messages are conflict
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
@@ -12,8 +15,449 @@ pub enum RecvError {
     AllConflict,
 }
 
+impl RecvError {
+    /// is it returned as all senders are gone
+    ///
+    /// ```
+    /// use kv_mpsc::RecvError;
+    ///
+    /// assert!(RecvError::Disconnected.is_disconnected());
+    /// assert!(!RecvError::AllConflict.is_disconnected());
+    /// ```
+    #[inline]
+    #[must_use]
+    pub fn is_disconnected(self) -> bool {
+        matches!(self, RecvError::Disconnected)
+    }
+
+    /// is it returned as all buffered messages conflict
+    ///
+    /// ```
+    /// use kv_mpsc::RecvError;
+    ///
+    /// assert!(RecvError::AllConflict.is_conflict());
+    /// assert!(!RecvError::Disconnected.is_conflict());
+    /// ```
+    #[inline]
+    #[must_use]
+    pub fn is_conflict(self) -> bool {
+        matches!(self, RecvError::AllConflict)
+    }
+}
+
+impl Display for RecvError {
+    #[inline]
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match *self {
+            RecvError::Disconnected => f.write_str("receiving on a disconnected channel"),
+            RecvError::AllConflict => {
+                f.write_str("all buffered messages conflict with active keys")
+            }
+        }
+    }
+}
+
+impl Error for RecvError {}
+
 /// Error occurs only when channel is disconnected
+///
+/// The unsent message is taken back by [`SendError::into_inner`].
+///
+/// ```
+/// use kv_mpsc::sync_channel::bounded;
+/// use kv_mpsc::Message;
+///
+/// let (tx, rx) = bounded(1);
+/// drop(rx);
+/// let err = tx.send(Message::single_key(1, "value")).unwrap_err();
+/// assert_eq!(err.to_string(), "sending on a closed channel");
+/// assert_eq!(err.into_inner().into_value(), "value");
+/// ```
 #[derive(Debug, PartialEq, Eq)]
 #[non_exhaustive]
 #[doc(alias = "closed")]
 pub struct SendError<T>(pub T);
+
+impl<T> SendError<T> {
+    /// is the receiver closed, it's always true, as the only reason a send
+    /// fails
+    ///
+    /// ```
+    /// use kv_mpsc::sync_channel::bounded;
+    /// use kv_mpsc::Message;
+    ///
+    /// let (tx, rx) = bounded(1);
+    /// drop(rx);
+    /// let err = tx.send(Message::single_key(1, ())).unwrap_err();
+    /// assert!(err.is_disconnected());
+    /// ```
+    #[inline]
+    #[doc(alias = "is_closed")]
+    // kept as a method, in case more reasons are added
+    #[allow(clippy::unused_self)]
+    pub fn is_disconnected(&self) -> bool {
+        true
+    }
+
+    /// take back the unsent message
+    #[inline]
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+
+    /// the unsent message
+    #[inline]
+    pub fn as_inner(&self) -> &T {
+        &self.0
+    }
+
+    /// transform the unsent message
+    #[inline]
+    pub fn map<U, F>(self, f: F) -> SendError<U>
+    where
+        F: FnOnce(T) -> U,
+    {
+        SendError(f(self.0))
+    }
+}
+
+/// printable without `T: Debug`, the unsent message is not shown
+impl<T> Display for SendError<T> {
+    #[inline]
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str("sending on a closed channel")
+    }
+}
+
+impl<T: Debug> Error for SendError<T> {}
+
+/// Error returned by `try_send`, the unsent message is given back
+#[derive(Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum TrySendError<T> {
+    /// The buffer is full
+    Full(T),
+    /// The receiver is closed
+    #[doc(alias = "closed")]
+    Disconnected(T),
+}
+
+impl<T> TrySendError<T> {
+    /// is the buffer full
+    #[inline]
+    pub fn is_full(&self) -> bool {
+        matches!(*self, TrySendError::Full(_))
+    }
+
+    /// is the receiver closed
+    #[inline]
+    #[doc(alias = "is_closed")]
+    pub fn is_disconnected(&self) -> bool {
+        matches!(*self, TrySendError::Disconnected(_))
+    }
+
+    /// take back the unsent message
+    #[inline]
+    pub fn into_inner(self) -> T {
+        match self {
+            TrySendError::Full(unsent) | TrySendError::Disconnected(unsent) => unsent,
+        }
+    }
+}
+
+impl<T> From<SendError<T>> for TrySendError<T> {
+    #[inline]
+    fn from(err: SendError<T>) -> Self {
+        TrySendError::Disconnected(err.into_inner())
+    }
+}
+
+/// printable without `T: Debug`, the unsent message is not shown
+impl<T> Display for TrySendError<T> {
+    #[inline]
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match *self {
+            TrySendError::Full(_) => f.write_str("sending on a full channel"),
+            TrySendError::Disconnected(_) => f.write_str("sending on a closed channel"),
+        }
+    }
+}
+
+impl<T: Debug> Error for TrySendError<T> {}
+
+/// Error returned by `send_timeout`, the unsent message is given back
+#[derive(Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum SendTimeoutError<T> {
+    /// The buffer is still full at the timeout
+    Timeout(T),
+    /// The receiver is closed
+    #[doc(alias = "closed")]
+    Disconnected(T),
+}
+
+impl<T> SendTimeoutError<T> {
+    /// is the buffer still full at the timeout
+    #[inline]
+    pub fn is_timeout(&self) -> bool {
+        matches!(*self, SendTimeoutError::Timeout(_))
+    }
+
+    /// is the receiver closed
+    #[inline]
+    #[doc(alias = "is_closed")]
+    pub fn is_disconnected(&self) -> bool {
+        matches!(*self, SendTimeoutError::Disconnected(_))
+    }
+
+    /// take back the unsent message
+    #[inline]
+    pub fn into_inner(self) -> T {
+        match self {
+            SendTimeoutError::Timeout(unsent)
+            | SendTimeoutError::Disconnected(unsent) => unsent,
+        }
+    }
+}
+
+impl<T> From<SendError<T>> for SendTimeoutError<T> {
+    #[inline]
+    fn from(err: SendError<T>) -> Self {
+        SendTimeoutError::Disconnected(err.into_inner())
+    }
+}
+
+/// printable without `T: Debug`, the unsent message is not shown
+impl<T> Display for SendTimeoutError<T> {
+    #[inline]
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match *self {
+            SendTimeoutError::Timeout(_) => {
+                f.write_str("timed out sending on a full channel")
+            }
+            SendTimeoutError::Disconnected(_) => {
+                f.write_str("sending on a closed channel")
+            }
+        }
+    }
+}
+
+impl<T: Debug> Error for SendTimeoutError<T> {}
+
+/// Error returned by `try_reserve`, when a buff slot can't be reserved immediately
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[non_exhaustive]
+pub enum TryReserveError {
+    /// The buffer is full
+    Full,
+    /// The receiver is closed
+    #[doc(alias = "closed")]
+    Disconnected,
+}
+
+/// Error returned by `try_recv`, when a message can't be received immediately
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[non_exhaustive]
+pub enum TryRecvError {
+    /// The buffer is empty
+    Empty,
+    /// All senders are clodes
+    #[doc(alias = "closed")]
+    Disconnected,
+    /// All message's keys in buffer are conflict with active keys
+    AllConflict,
+}
+
+impl TryRecvError {
+    /// is it returned as the buffer is empty
+    ///
+    /// ```
+    /// use kv_mpsc::TryRecvError;
+    ///
+    /// assert!(TryRecvError::Empty.is_empty());
+    /// assert!(!TryRecvError::AllConflict.is_empty());
+    /// ```
+    #[inline]
+    #[must_use]
+    pub fn is_empty(self) -> bool {
+        matches!(self, TryRecvError::Empty)
+    }
+
+    /// is it returned as all senders are gone
+    ///
+    /// ```
+    /// use kv_mpsc::TryRecvError;
+    ///
+    /// assert!(TryRecvError::Disconnected.is_disconnected());
+    /// assert!(!TryRecvError::Empty.is_disconnected());
+    /// ```
+    #[inline]
+    #[must_use]
+    pub fn is_disconnected(self) -> bool {
+        matches!(self, TryRecvError::Disconnected)
+    }
+
+    /// is it returned as all buffered messages conflict
+    ///
+    /// ```
+    /// use kv_mpsc::TryRecvError;
+    ///
+    /// assert!(TryRecvError::AllConflict.is_conflict());
+    /// assert!(!TryRecvError::Empty.is_conflict());
+    /// ```
+    #[inline]
+    #[must_use]
+    pub fn is_conflict(self) -> bool {
+        matches!(self, TryRecvError::AllConflict)
+    }
+}
+
+impl From<RecvError> for TryRecvError {
+    #[inline]
+    fn from(err: RecvError) -> Self {
+        match err {
+            RecvError::Disconnected => Self::Disconnected,
+            RecvError::AllConflict => Self::AllConflict,
+        }
+    }
+}
+
+impl Display for TryRecvError {
+    #[inline]
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match *self {
+            TryRecvError::Empty => f.write_str("receiving on an empty channel"),
+            TryRecvError::Disconnected => Display::fmt(&RecvError::Disconnected, f),
+            TryRecvError::AllConflict => Display::fmt(&RecvError::AllConflict, f),
+        }
+    }
+}
+
+impl Error for TryRecvError {}
+
+/// Error returned by `recv_timeout`, when a message can't be received in time
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[non_exhaustive]
+pub enum RecvTimeoutError {
+    /// No message is received before the timeout
+    Timeout,
+    /// All senders are clodes
+    #[doc(alias = "closed")]
+    Disconnected,
+    /// All message's keys in buffer are conflict with active keys
+    AllConflict,
+}
+
+impl RecvTimeoutError {
+    /// is it returned as no message is received in time
+    ///
+    /// ```
+    /// use kv_mpsc::RecvTimeoutError;
+    ///
+    /// assert!(RecvTimeoutError::Timeout.is_timeout());
+    /// assert!(!RecvTimeoutError::AllConflict.is_timeout());
+    /// ```
+    #[inline]
+    #[must_use]
+    pub fn is_timeout(self) -> bool {
+        matches!(self, RecvTimeoutError::Timeout)
+    }
+
+    /// is it returned as all senders are gone
+    ///
+    /// ```
+    /// use kv_mpsc::RecvTimeoutError;
+    ///
+    /// assert!(RecvTimeoutError::Disconnected.is_disconnected());
+    /// assert!(!RecvTimeoutError::Timeout.is_disconnected());
+    /// ```
+    #[inline]
+    #[must_use]
+    pub fn is_disconnected(self) -> bool {
+        matches!(self, RecvTimeoutError::Disconnected)
+    }
+
+    /// is it returned as all buffered messages conflict
+    ///
+    /// ```
+    /// use kv_mpsc::RecvTimeoutError;
+    ///
+    /// assert!(RecvTimeoutError::AllConflict.is_conflict());
+    /// assert!(!RecvTimeoutError::Timeout.is_conflict());
+    /// ```
+    #[inline]
+    #[must_use]
+    pub fn is_conflict(self) -> bool {
+        matches!(self, RecvTimeoutError::AllConflict)
+    }
+}
+
+impl From<RecvError> for RecvTimeoutError {
+    #[inline]
+    fn from(err: RecvError) -> Self {
+        match err {
+            RecvError::Disconnected => Self::Disconnected,
+            RecvError::AllConflict => Self::AllConflict,
+        }
+    }
+}
+
+impl Display for RecvTimeoutError {
+    #[inline]
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match *self {
+            RecvTimeoutError::Timeout => f.write_str("timed out waiting on a channel"),
+            RecvTimeoutError::Disconnected => Display::fmt(&RecvError::Disconnected, f),
+            RecvTimeoutError::AllConflict => Display::fmt(&RecvError::AllConflict, f),
+        }
+    }
+}
+
+impl Error for RecvTimeoutError {}
+
+/// Error returned by `recv_filter`, when no matching message can be received
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[non_exhaustive]
+pub enum RecvFilterError {
+    /// No buffered message matches the predicate
+    NoMatch,
+    /// All senders are clodes
+    #[doc(alias = "closed")]
+    Disconnected,
+    /// Matching messages are buffered, but their keys conflict with active keys
+    AllConflict,
+}
+
+impl From<RecvError> for RecvFilterError {
+    #[inline]
+    fn from(err: RecvError) -> Self {
+        match err {
+            RecvError::Disconnected => Self::Disconnected,
+            RecvError::AllConflict => Self::AllConflict,
+        }
+    }
+}
+
+/// Error returned by `recv_or`, when the cancellation signal fires or
+/// a message can't be received
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[non_exhaustive]
+pub enum RecvOr {
+    /// The cancellation signal fires while waiting for a message
+    Cancelled,
+    /// All senders are clodes
+    #[doc(alias = "closed")]
+    Disconnected,
+    /// All message's keys in buffer are conflict with active keys
+    AllConflict,
+}
+
+impl From<RecvError> for RecvOr {
+    #[inline]
+    fn from(err: RecvError) -> Self {
+        match err {
+            RecvError::Disconnected => Self::Disconnected,
+            RecvError::AllConflict => Self::AllConflict,
+        }
+    }
+}
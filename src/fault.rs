@@ -0,0 +1,100 @@
+//! Fault injection for notifications, only built with the `fault-injection` feature
+//!
+//! Lost-wakeup races are rarely produced by real schedulers, the faults here
+//! make them deterministic for tests.
+
+use crate::lock::{lock, Mutex, MutexGuard};
+use crate::waiter::Waiter;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// faults injected into the notifications of a channel
+#[derive(Debug, Default)]
+pub(crate) struct Faults {
+    /// number of following notifications to drop
+    dropped: AtomicUsize,
+    /// delay of each notification in milliseconds
+    delay_ms: AtomicU64,
+    /// number of following waits that return spuriously
+    spurious: AtomicUsize,
+}
+
+impl Faults {
+    /// drop the next `n` notifications
+    #[cfg(test)]
+    pub(crate) fn drop_notifications(&self, n: usize) {
+        self.dropped.store(n, Ordering::SeqCst);
+    }
+
+    /// delay every notification by `dur`, the notifier sleeps before notifying
+    #[cfg(test)]
+    pub(crate) fn delay_notifications(&self, dur: Duration) {
+        let ms = u64::try_from(dur.as_millis()).unwrap_or(u64::MAX);
+        self.delay_ms.store(ms, Ordering::SeqCst);
+    }
+
+    /// make the next `n` waits return without any notification
+    #[cfg(test)]
+    pub(crate) fn spurious_wakeups(&self, n: usize) {
+        self.spurious.store(n, Ordering::SeqCst);
+    }
+
+    /// called before notifying, return false if the notification is dropped
+    pub(crate) fn before_notify(&self) -> bool {
+        if take_one(&self.dropped) {
+            return false;
+        }
+        let ms = self.delay_ms.load(Ordering::SeqCst);
+        if ms > 0 {
+            thread::sleep(Duration::from_millis(ms));
+        }
+        true
+    }
+
+    /// called before waiting, return true if the wait returns spuriously
+    pub(crate) fn take_spurious(&self) -> bool {
+        take_one(&self.spurious)
+    }
+}
+
+/// decrease a non-zero counter, return false if it's zero
+fn take_one(counter: &AtomicUsize) -> bool {
+    counter
+        .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+        .is_ok()
+}
+
+/// A waiter with injected faults
+#[derive(Debug, Default)]
+pub(crate) struct Faulty<W: Waiter> {
+    /// the real waiter
+    inner: W,
+    /// faults to inject
+    pub(crate) faults: Faults,
+}
+
+impl<W: Waiter> Waiter for Faulty<W> {
+    fn wait_until<'a, T>(
+        &self, mutex: &'a Mutex<T>, guard: MutexGuard<'a, T>, deadline: Option<Instant>,
+    ) -> MutexGuard<'a, T> {
+        if self.faults.take_spurious() {
+            // unlock and relock, as a real spurious wakeup does
+            drop(guard);
+            return lock(mutex);
+        }
+        self.inner.wait_until(mutex, guard, deadline)
+    }
+
+    fn notify_one(&self) {
+        if self.faults.before_notify() {
+            self.inner.notify_one();
+        }
+    }
+
+    fn notify_all(&self) {
+        if self.faults.before_notify() {
+            self.inner.notify_all();
+        }
+    }
+}
@@ -0,0 +1,118 @@
+//! A guard that keeps keys active without any message value
+
+use crate::completions::CompletionKind;
+use crate::message::{CompletionHook, DeactivateKeys, Key, KeySet};
+use std::collections::HashSet;
+use std::fmt::Debug;
+use std::panic::{RefUnwindSafe, UnwindSafe};
+use std::sync::Arc;
+
+/// An RAII guard of active key(s), returned by `Receiver::lock_key`,
+/// `Receiver::recv_key_only` and [`Message::forget`](crate::Message::forget)
+///
+/// No message with the key(s) will be received until the guard is dropped,
+/// or released by [`KeyLock::release`]. It's `Send` whenever the key is,
+/// so the key(s) can be released on another thread or task.
+#[must_use = "the key is released as soon as the lock is dropped"]
+pub struct KeyLock<K: Key, T: DeactivateKeys<Key = K>> {
+    /// the locked key(s)
+    pub(crate) key: KeySet<K>,
+    /// use to release the key(s)
+    pub(crate) shared: Option<Arc<T>>,
+    /// called once when the guard is dropped
+    pub(crate) hook: Option<CompletionHook>,
+    /// how the key(s) are reported when the guard is dropped
+    pub(crate) kind: CompletionKind,
+}
+
+impl<K: Key, T: DeactivateKeys<Key = K>> KeyLock<K, T> {
+    /// new a guard of an activated key
+    pub(crate) fn new(key: K, shared: Arc<T>) -> Self {
+        KeyLock {
+            key: KeySet::Single(key),
+            shared: Some(shared),
+            hook: None,
+            kind: CompletionKind::Unlocked,
+        }
+    }
+
+    /// new a guard of the key(s) of a message
+    pub(crate) fn from_parts(
+        key: KeySet<K>, shared: Option<Arc<T>>, hook: Option<CompletionHook>,
+    ) -> Self {
+        KeyLock { key, shared, hook, kind: CompletionKind::Dropped }
+    }
+
+    /// release the key(s) as dropping does, and take them out
+    pub(crate) fn into_key(mut self) -> KeySet<K> {
+        self.release_in_place(self.kind);
+        // nothing is left to release when the emptied guard is dropped
+        std::mem::replace(&mut self.key, KeySet::Multiple(HashSet::new()))
+    }
+
+    /// release the key(s) as `kind` and call the hook, at most once
+    pub(crate) fn release_in_place(&mut self, kind: CompletionKind) {
+        if let Some(shared) = self.shared.take() {
+            let keys: Vec<&K> = self.key.iter().collect();
+            shared.release_key(&keys, kind);
+        }
+        if let Some(hook) = self.hook.take() {
+            hook();
+        }
+    }
+
+    /// release the key(s) right away, reported as [`CompletionKind::Released`]
+    #[inline]
+    pub fn release(mut self) {
+        self.release_in_place(CompletionKind::Released);
+    }
+
+    /// is the guard holding multiple keys
+    #[inline]
+    pub fn is_multiple(&self) -> bool {
+        self.key.is_multiple()
+    }
+
+    /// return a ref to single key or None
+    #[inline]
+    pub fn get_single_key(&self) -> Option<&K> {
+        self.key.get_single_key()
+    }
+
+    /// return a ref to keyset
+    #[inline]
+    pub fn get_key_set(&self) -> Option<&HashSet<K>> {
+        self.key.get_key_set()
+    }
+}
+
+impl<K: Key, T: DeactivateKeys<Key = K>> Debug for KeyLock<K, T> {
+    #[inline]
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("KeyLock")
+            .field("key", &self.key)
+            .finish_non_exhaustive()
+    }
+}
+
+// the hook is created by the crate, same as `Message`
+impl<K, T> UnwindSafe for KeyLock<K, T>
+where
+    K: Key + UnwindSafe,
+    T: DeactivateKeys<Key = K> + RefUnwindSafe,
+{
+}
+
+impl<K, T> RefUnwindSafe for KeyLock<K, T>
+where
+    K: Key + RefUnwindSafe,
+    T: DeactivateKeys<Key = K> + RefUnwindSafe,
+{
+}
+
+impl<K: Key, T: DeactivateKeys<Key = K>> Drop for KeyLock<K, T> {
+    #[inline]
+    fn drop(&mut self) {
+        self.release_in_place(self.kind);
+    }
+}
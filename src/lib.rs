@@ -16,7 +16,6 @@
     non_ascii_idents,
     // non_exhaustive_omitted_patterns, unstable
     noop_method_call,
-    pointer_structural_match,
     rust_2021_incompatible_closure_captures,
     rust_2021_incompatible_or_patterns,
     rust_2021_prefixes_incompatible_syntax,
@@ -61,7 +60,7 @@
     clippy::indexing_slicing,
     // clippy::inline_asm_x86_att_syntax, stick to intel syntax
     clippy::inline_asm_x86_intel_syntax,
-    clippy::integer_arithmetic,
+    clippy::arithmetic_side_effects,
     // clippy::integer_division, required in the project
     clippy::let_underscore_must_use,
     clippy::lossy_float_literal,
@@ -88,7 +87,6 @@
     clippy::shadow_unrelated,
     clippy::str_to_string,
     clippy::string_add,
-    clippy::string_to_string,
     clippy::todo,
     clippy::unimplemented,
     clippy::unnecessary_self_imports,
@@ -103,8 +101,8 @@
 #![allow(
     clippy::panic, // allow debug_assert, panic in production code
     clippy::multiple_crate_versions, // caused by the dependency, can't be fixed
+    clippy::uninlined_format_args, // keep the positional `{:?}` used across the crate
 )]
-#![feature(linked_list_remove)]
 
 //! `kv_mpsc` is a mpsc channel that support key conflict resolution.
 //! //!
@@ -120,8 +118,9 @@
 //! The channel is a synchronous, bounded channel. The [`sync_channel::bounded`] function will
 //! return a `(BoundedSender, Receiver)` tuple where the storage for pending
 //! messages is a pre-allocated buffer of a fixed size. All sends will be
-//! **synchronous** by blocking until there is buffer space available. For simplicity
-//! of implementation, capacity of channel must be greater that zero.
+//! **synchronous** by blocking until there is buffer space available. A capacity of zero
+//! makes a rendezvous channel, where a send blocks until the receiver takes the message,
+//! the async channel still requires a capacity greater than zero.
 //!
 //! ## Key of message and conflict
 //! All messages in channel have single/multiple key(s), once a message is consumed
@@ -139,17 +138,54 @@
 //! subsequent recv calls will return an [`err::RecvError`].
 //! If the receiver closed, all sender's `send` invocation will return an [`err::SendError`].
 //!
+//! ## Unwind safety
+//!
+//! All public types are [`std::panic::UnwindSafe`] and [`std::panic::RefUnwindSafe`]
+//! whenever their key and value types are, so they can be used inside
+//! [`std::panic::catch_unwind`]. A message dropped while unwinding releases its
+//! key(s) as usual. User code that runs while the channel state is locked
+//! (`Clone`/`Hash`/`Eq` of the key) is contained: a panic in it rolls back the
+//! partial change, and is resumed after the state is unlocked, so the channel
+//! stays usable and no key is left active. `Hash` and `Eq` are expected to be
+//! consistent, as for any [`std::collections::HashMap`] key, and dropping any of
+//! the types never panics while unwinding.
+//!
 //! ## Async/ version
 //! [`async_channel`] is the async version based on tokio, both have the same interface.
+//! [`bridge`] pumps messages between a sync channel and an async one.
+//!
+//! The `async` feature adds [`async_channel`] next to [`sync_channel`] rather than
+//! replacing it, nothing channel specific is re-exported at the top level, so both
+//! are used in one build by their paths, `kv_mpsc::sync_channel::bounded` and
+//! `kv_mpsc::async_channel::bounded`. [`Message`] is the message type of both,
+//! the channel it's sent to is inferred.
 
 #[cfg(feature = "async")]
 pub mod async_channel;
 
+mod active;
+#[cfg(feature = "async")]
+pub mod bridge;
 mod buff;
+mod completions;
 mod err;
+#[cfg(feature = "fault-injection")]
+mod fault;
+mod key_lock;
+mod lock;
 mod message;
+mod message_builder;
+mod redacted_key;
 pub mod sync_channel;
+mod throttle;
 mod util;
+mod waiter;
+mod wave;
 
+pub use completions::{Completion, CompletionKind, Completions};
 pub use err::*;
-pub use message::Message;
+pub use key_lock::KeyLock;
+pub use message::{KeySet, Message};
+pub use message_builder::MessageBuilder;
+pub use redacted_key::RedactedKey;
+pub use wave::plan_waves;
@@ -0,0 +1,101 @@
+//! The lock of channel states, std's or `parking_lot`'s by the `parking_lot` feature
+//!
+//! User code under the lock is contained, so a std mutex is not poisoned in
+//! practice, the helpers here erase the `Result` of locking and waiting, and
+//! what notifying returns.
+
+#[cfg(not(feature = "parking_lot"))]
+use crate::unwrap_ok_or;
+#[cfg(all(feature = "parking_lot", not(feature = "event_listener")))]
+pub(crate) use parking_lot::Condvar;
+#[cfg(feature = "parking_lot")]
+pub(crate) use parking_lot::{Mutex, MutexGuard};
+#[cfg(all(not(feature = "parking_lot"), not(feature = "event_listener")))]
+pub(crate) use std::sync::Condvar;
+#[cfg(not(feature = "parking_lot"))]
+pub(crate) use std::sync::{Mutex, MutexGuard};
+#[cfg(not(feature = "event_listener"))]
+use std::time::Duration;
+
+/// lock a mutex, panic if it's poisoned
+#[cfg(not(feature = "parking_lot"))]
+pub(crate) fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    unwrap_ok_or!(mutex.lock(), err, panic!("{:?}", err))
+}
+
+/// lock a mutex, a `parking_lot` mutex is never poisoned
+#[cfg(feature = "parking_lot")]
+pub(crate) fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock()
+}
+
+/// lock a mutex even if it's poisoned, for drop that must not panic
+#[cfg(not(feature = "parking_lot"))]
+pub(crate) fn lock_or_recover<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    unwrap_ok_or!(mutex.lock(), err, err.into_inner())
+}
+
+/// lock a mutex for drop that must not panic, a `parking_lot` mutex is
+/// never poisoned
+#[cfg(feature = "parking_lot")]
+pub(crate) fn lock_or_recover<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock()
+}
+
+/// release `guard` and block until `cond` is notified, then lock again
+#[cfg(all(not(feature = "parking_lot"), not(feature = "event_listener")))]
+pub(crate) fn wait<'a, T>(cond: &Condvar, guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+    unwrap_ok_or!(cond.wait(guard), err, panic!("{:?}", err))
+}
+
+/// release `guard` and block until `cond` is notified, then lock again
+#[cfg(all(feature = "parking_lot", not(feature = "event_listener")))]
+pub(crate) fn wait<'a, T>(
+    cond: &Condvar, mut guard: MutexGuard<'a, T>,
+) -> MutexGuard<'a, T> {
+    cond.wait(&mut guard);
+    guard
+}
+
+/// release `guard` and block until `cond` is notified or `timeout` elapses,
+/// then lock again
+#[cfg(all(not(feature = "parking_lot"), not(feature = "event_listener")))]
+pub(crate) fn wait_timeout<'a, T>(
+    cond: &Condvar, guard: MutexGuard<'a, T>, timeout: Duration,
+) -> MutexGuard<'a, T> {
+    unwrap_ok_or!(cond.wait_timeout(guard, timeout), err, panic!("{:?}", err)).0
+}
+
+/// release `guard` and block until `cond` is notified or `timeout` elapses,
+/// then lock again
+#[cfg(all(feature = "parking_lot", not(feature = "event_listener")))]
+pub(crate) fn wait_timeout<'a, T>(
+    cond: &Condvar, mut guard: MutexGuard<'a, T>, timeout: Duration,
+) -> MutexGuard<'a, T> {
+    let _timed_out = cond.wait_for(&mut guard, timeout);
+    guard
+}
+
+/// wake up one thread blocked on `cond`
+#[cfg(all(not(feature = "parking_lot"), not(feature = "event_listener")))]
+pub(crate) fn notify_one(cond: &Condvar) {
+    cond.notify_one();
+}
+
+/// wake up one thread blocked on `cond`
+#[cfg(all(feature = "parking_lot", not(feature = "event_listener")))]
+pub(crate) fn notify_one(cond: &Condvar) {
+    let _woken = cond.notify_one();
+}
+
+/// wake up all threads blocked on `cond`
+#[cfg(all(not(feature = "parking_lot"), not(feature = "event_listener")))]
+pub(crate) fn notify_all(cond: &Condvar) {
+    cond.notify_all();
+}
+
+/// wake up all threads blocked on `cond`
+#[cfg(all(feature = "parking_lot", not(feature = "event_listener")))]
+pub(crate) fn notify_all(cond: &Condvar) {
+    let _woken = cond.notify_all();
+}
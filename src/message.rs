@@ -2,43 +2,177 @@
 
 // use crate::unwrap_ok_or;
 use crate::buff::BuffMessage;
-use std::collections::{HashMap, HashSet};
+use crate::completions::CompletionKind;
+use crate::key_lock::KeyLock;
+use std::borrow::Borrow;
+use std::cmp::Ordering;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{hash_set, HashSet};
 use std::fmt::Debug;
-use std::hash::Hash;
-use std::iter::FromIterator;
+use std::hash::{Hash, Hasher};
+use std::iter::{FromIterator, Once};
+use std::ops::Deref;
+use std::panic::{RefUnwindSafe, UnwindSafe};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 /// Trait bound for the message key
+///
+/// `Debug` is only used to print keys, wrap a key that must not be printed in
+/// [`crate::RedactedKey`].
 pub trait Key: Eq + Hash + Clone + Debug {}
 
 impl<T: Eq + Hash + Clone + Debug> Key for T {}
 
-/// Key of a message
+/// Key(s) of a message, built into one by [`Message::from_key_set`]
+///
+/// Two key sets conflict when they share any key, so a message is not received
+/// while another one that conflicts with it holds its keys. A single key and a
+/// set of just that key conflict, though they're not equal, and an empty set
+/// conflicts with nothing.
 #[derive(Debug, Clone, PartialEq, Eq)]
-pub(crate) enum KeySet<K: Key> {
+#[non_exhaustive]
+pub enum KeySet<K: Key> {
     /// single key
     Single(K),
     /// mutiple keys
     Multiple(HashSet<K>),
 }
 
+/// An iterator over the key(s) of a keyset, without cloning them
+// it lives on the stack briefly, boxing the larger variant would allocate
+#[allow(variant_size_differences)]
+#[derive(Debug, Clone)]
+pub(crate) enum KeyIter<'a, K> {
+    /// the single key
+    Single(Once<&'a K>),
+    /// the multiple keys
+    Multiple(hash_set::Iter<'a, K>),
+}
+
+impl<'a, K> Iterator for KeyIter<'a, K> {
+    type Item = &'a K;
+
+    fn next(&mut self) -> Option<&'a K> {
+        match *self {
+            Self::Single(ref mut once) => once.next(),
+            Self::Multiple(ref mut iter) => iter.next(),
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        match *self {
+            Self::Single(ref once) => once.size_hint(),
+            Self::Multiple(ref iter) => iter.size_hint(),
+        }
+    }
+}
+
+impl<K> ExactSizeIterator for KeyIter<'_, K> {}
+
 impl<K: Key> KeySet<K> {
-    /// collect all conflict keys with keys in other
-    pub(crate) fn conflict_keys(&self, other: &HashMap<K, usize>) -> Option<Vec<&K>> {
+    /// new a single key
+    #[inline]
+    pub fn single(key: K) -> Self {
+        Self::Single(key)
+    }
+
+    /// new a set of keys, duplicated keys are merged
+    #[inline]
+    pub fn multiple<I>(keys: I) -> Self
+    where
+        I: IntoIterator<Item = K>,
+    {
+        Self::Multiple(HashSet::from_iter(keys))
+    }
+
+    /// iterate over the key(s)
+    #[inline]
+    pub fn iter(&self) -> impl ExactSizeIterator<Item = &K> + '_ {
         match *self {
-            Self::Single(ref k) => other.contains_key(k).then(|| vec![k]),
+            Self::Single(ref k) => KeyIter::Single(std::iter::once(k)),
+            Self::Multiple(ref keys) => KeyIter::Multiple(keys.iter()),
+        }
+    }
+
+    /// hash the key(s), the keys of a set are combined in any order
+    fn hash_keys<H: Hasher>(&self, state: &mut H) {
+        match *self {
+            Self::Single(ref k) => {
+                state.write_u8(0);
+                k.hash(state);
+            }
             Self::Multiple(ref keys) => {
-                let mut ret = vec![];
-                for k in keys {
-                    if other.contains_key(k) {
-                        ret.push(k);
-                    }
-                }
-                (!ret.is_empty()).then(|| ret)
+                state.write_u8(1);
+                state.write_usize(keys.len());
+                // a fixed hasher per key, and a commutative combination
+                let combined = keys.iter().fold(0_u64, |acc, k| {
+                    let mut hasher = DefaultHasher::new();
+                    k.hash(&mut hasher);
+                    acc.wrapping_add(hasher.finish())
+                });
+                state.write_u64(combined);
             }
         }
     }
 
+    /// compare the key(s), a single key is less than any set, and sets are
+    /// compared as their sorted keys
+    fn cmp_keys(&self, other: &Self) -> Ordering
+    where
+        K: Ord,
+    {
+        match *self {
+            Self::Single(ref a) => match *other {
+                Self::Single(ref b) => a.cmp(b),
+                Self::Multiple(_) => Ordering::Less,
+            },
+            Self::Multiple(ref a) => match *other {
+                Self::Single(_) => Ordering::Greater,
+                Self::Multiple(ref b) => {
+                    let mut a: Vec<&K> = a.iter().collect();
+                    let mut b: Vec<&K> = b.iter().collect();
+                    a.sort_unstable();
+                    b.sort_unstable();
+                    a.cmp(&b)
+                }
+            },
+        }
+    }
+
+    /// number of keys
+    #[inline]
+    pub fn len(&self) -> usize {
+        match *self {
+            Self::Single(_) => 1,
+            Self::Multiple(ref keys) => keys.len(),
+        }
+    }
+
+    /// is it an empty set
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// do the two keysets share no key, messages with disjoint keysets
+    /// never conflict
+    #[inline]
+    pub fn is_disjoint(&self, other: &Self) -> bool {
+        !self.conflicts_with(other)
+    }
+
+    /// do the two keysets share any key, an empty keyset conflicts with nothing
+    pub(crate) fn conflicts_with(&self, other: &Self) -> bool {
+        match *other {
+            Self::Single(ref k) => self.contains(k),
+            Self::Multiple(ref keys) => match *self {
+                Self::Single(ref k) => keys.contains(k),
+                Self::Multiple(ref own) => !own.is_disjoint(keys),
+            },
+        }
+    }
+
     /// does it containes multiple keys
     pub(crate) fn is_multiple(&self) -> bool {
         !matches!(*self, Self::Single(_))
@@ -60,6 +194,19 @@ impl<K: Key> KeySet<K> {
         }
     }
 
+    /// does it contain `key`
+    #[inline]
+    pub fn contains<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        match *self {
+            Self::Single(ref k) => k.borrow() == key,
+            Self::Multiple(ref keys) => keys.contains(key),
+        }
+    }
+
     /// get mutiple keyset if the key is
     pub(crate) fn get_key_set(&self) -> Option<&HashSet<K>> {
         match *self {
@@ -69,19 +216,89 @@ impl<K: Key> KeySet<K> {
     }
 }
 ///  Message type in channel
+///
+/// The value is read through `Deref`, the keys stay active meanwhile
+/// ```
+/// use kv_mpsc::{sync_channel, Message};
+///
+/// let (tx, rx) = sync_channel::bounded(1);
+/// tx.send(Message::single_key(1, String::from("value"))).unwrap();
+/// let msg = rx.recv().unwrap();
+/// assert_eq!(msg.len(), 5);
+/// assert!(msg.starts_with("val"));
+/// ```
 pub struct Message<K: Key, V, T: DeactivateKeys<Key = K>> {
-    /// message key
-    pub(crate) key: KeySet<K>,
+    /// message key(s), released when the message is dropped
+    lock: KeyLock<K, T>,
     /// messasge value
-    pub(crate) value: V,
-    /// use to control the active keys
-    shared: Option<Arc<T>>,
+    value: V,
+    /// when the message is put into a channel, `None` if it's never sent
+    enqueued_at: Option<Instant>,
+    /// channel-global enqueue order, `None` if it's never sent
+    seq: Option<u64>,
+    /// receiving priority in a prioritized channel, higher goes first
+    priority: u8,
+    /// after which the message is dropped instead of received
+    deadline: Option<Instant>,
+}
+
+/// A callback invoked when a message is dropped
+pub(crate) type CompletionHook = Box<dyn FnOnce() + Send + Sync>;
+
+impl<K: Key, V, T: DeactivateKeys<Key = K>> Deref for Message<K, V, T> {
+    type Target = V;
+
+    #[inline]
+    fn deref(&self) -> &V {
+        &self.value
+    }
+}
+
+// no `Borrow<V>`, a message compares its keys too, so it's not equal to
+// its value as `Borrow` requires
+impl<K: Key, V, T: DeactivateKeys<Key = K>> AsRef<V> for Message<K, V, T> {
+    #[inline]
+    fn as_ref(&self) -> &V {
+        &self.value
+    }
 }
 
 impl<K: Key, V: PartialEq, T: DeactivateKeys<Key = K>> PartialEq for Message<K, V, T> {
     #[inline]
     fn eq(&self, other: &Self) -> bool {
-        self.key == other.key && self.value == other.value
+        self.lock.key == other.lock.key && self.value == other.value
+    }
+}
+
+impl<K: Key, V: Eq, T: DeactivateKeys<Key = K>> Eq for Message<K, V, T> {}
+
+// the channel a message is attached to is ignored, as `PartialEq` does
+impl<K: Key, V: Hash, T: DeactivateKeys<Key = K>> Hash for Message<K, V, T> {
+    #[inline]
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.lock.key.hash_keys(state);
+        self.value.hash(state);
+    }
+}
+
+// ordered by value first, so a `BinaryHeap` of messages pops the greatest value,
+// the key(s) break the tie
+impl<K: Key + Ord, V: PartialOrd, T: DeactivateKeys<Key = K>> PartialOrd
+    for Message<K, V, T>
+{
+    #[inline]
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        let value = self.value.partial_cmp(&other.value)?;
+        Some(value.then_with(|| self.lock.key.cmp_keys(&other.lock.key)))
+    }
+}
+
+impl<K: Key + Ord, V: Ord, T: DeactivateKeys<Key = K>> Ord for Message<K, V, T> {
+    #[inline]
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.value
+            .cmp(&other.value)
+            .then_with(|| self.lock.key.cmp_keys(&other.lock.key))
     }
 }
 
@@ -89,23 +306,28 @@ impl<K: Key + Debug, V: Debug, T: DeactivateKeys<Key = K>> Debug for Message<K,
     #[inline]
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("Message")
-            .field("key", &self.key)
+            .field("key", &self.lock.key)
             .field("value", &self.value)
-            .finish()
+            .finish_non_exhaustive()
     }
 }
 
-impl<K: Key, V, T: DeactivateKeys<Key = K>> Drop for Message<K, V, T> {
-    #[inline]
-    fn drop(&mut self) {
-        if let Some(shared) = self.shared.take() {
-            let keys = match self.key {
-                KeySet::Single(ref k) => vec![k],
-                KeySet::Multiple(ref keys) => keys.iter().collect::<Vec<&K>>(),
-            };
-            shared.release_key(keys);
-        }
-    }
+// the hook is created by the crate, it only touches the mutex protected
+// state, which is never observed broken after a panic
+impl<K, V, T> UnwindSafe for Message<K, V, T>
+where
+    K: Key + UnwindSafe,
+    V: UnwindSafe,
+    T: DeactivateKeys<Key = K> + RefUnwindSafe,
+{
+}
+
+impl<K, V, T> RefUnwindSafe for Message<K, V, T>
+where
+    K: Key + RefUnwindSafe,
+    V: RefUnwindSafe,
+    T: DeactivateKeys<Key = K> + RefUnwindSafe,
+{
 }
 
 impl<K: Key, V, T: DeactivateKeys<Key = K>> Message<K, V, T> {
@@ -115,37 +337,228 @@ impl<K: Key, V, T: DeactivateKeys<Key = K>> Message<K, V, T> {
     where
         I: IntoIterator<Item = K>,
     {
-        Message { key: KeySet::Multiple(HashSet::from_iter(keys)), value, shared: None }
+        Self::from_key_set(KeySet::Multiple(HashSet::from_iter(keys)), value)
     }
 
     /// new a single key message
     #[inline]
     pub fn single_key(key: K, value: V) -> Self {
-        Message { key: KeySet::Single(key), value, shared: None }
+        Self::from_key_set(KeySet::Single(key), value)
+    }
+
+    /// new a message with a keyset, e.g. the one taken by [`Message::into_parts`]
+    #[inline]
+    pub fn from_key_set(key: KeySet<K>, value: V) -> Self {
+        Message {
+            lock: KeyLock::from_parts(key, None, None),
+            value,
+            enqueued_at: None,
+            seq: None,
+            priority: 0,
+            deadline: None,
+        }
+    }
+
+    /// drop the value, and hand over the key(s) to a guard
+    pub(crate) fn into_key_lock(mut self) -> KeyLock<K, T> {
+        self.lock.kind = CompletionKind::Unlocked;
+        self.lock
+    }
+
+    /// drop the value, and keep the key(s) active until the returned guard
+    /// is dropped or released
+    ///
+    /// Unlike dropping the message, which releases the key(s) along with the
+    /// value, it lets another thread or task release them later, e.g. once a
+    /// commit is confirmed. The release is reported as a dropped message.
+    #[inline]
+    pub fn forget(self) -> KeyLock<K, T> {
+        let Message { lock, value, .. } = self;
+        drop(value);
+        lock
+    }
+
+    /// split into the guard of the key(s) and the value
+    ///
+    /// The key(s) are released once when the guard is dropped, while the value
+    /// is free to outlive it. Leaking the guard with [`std::mem::forget`] leaves
+    /// the key(s) active, the same as leaking the message.
+    #[inline]
+    pub fn split(self) -> (KeyLock<K, T>, V) {
+        (self.lock, self.value)
+    }
+
+    /// clone the key(s), value, priority and deadline into a message not
+    /// attached to any channel
+    ///
+    /// The copy takes no part in conflict tracking: it holds no key, and
+    /// dropping it releases nothing, the original keeps its key(s) active.
+    /// There is no `Clone`, as a clone attached to the channel would release
+    /// the key(s) twice.
+    #[inline]
+    #[must_use]
+    pub fn clone_detached(&self) -> Self
+    where
+        V: Clone,
+    {
+        Self::from_key_set(self.key().clone(), self.value.clone())
+            .with_priority(self.priority)
+            .with_deadline_opt(self.deadline)
+    }
+
+    /// message key(s)
+    pub(crate) fn key(&self) -> &KeySet<K> {
+        &self.lock.key
+    }
+
+    /// stamp the time and order the message is put into a channel
+    #[inline]
+    pub(crate) fn stamp_enqueued(&mut self, seq: u64) {
+        self.enqueued_at = Some(Instant::now());
+        self.seq = Some(seq);
+    }
+
+    /// when the message was put into a channel, `None` for a message that is
+    /// never sent, or a detached copy
+    #[inline]
+    pub fn enqueued_at(&self) -> Option<Instant> {
+        self.enqueued_at
+    }
+
+    /// time elapsed since the message was put into a channel, it's the
+    /// queueing delay if called right after receiving
+    #[inline]
+    pub fn queue_latency(&self) -> Option<Duration> {
+        self.enqueued_at.map(|at| at.elapsed())
+    }
+
+    /// set the receiving priority, the default is 0
+    ///
+    /// A channel made by `bounded_prioritized` hands out the highest priority
+    /// message among the ones without conflict, in FIFO order on a tie,
+    /// other channels ignore it.
+    #[inline]
+    #[must_use]
+    pub fn with_priority(mut self, priority: u8) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    /// receiving priority of the message
+    #[inline]
+    pub fn priority(&self) -> u8 {
+        self.priority
+    }
+
+    /// expire the message `ttl` after now, see [`Message::with_deadline`],
+    /// a `ttl` too long to represent leaves the message without a deadline
+    #[inline]
+    #[must_use]
+    pub fn with_ttl(self, ttl: Duration) -> Self {
+        self.with_deadline_opt(Instant::now().checked_add(ttl))
+    }
+
+    /// expire the message at `deadline`
+    ///
+    /// An expired message is never received, the receiver drops it instead,
+    /// along with its buff slot, even if it's waiting for a key. It's counted
+    /// in the receiver's expired count.
+    #[inline]
+    #[must_use]
+    pub fn with_deadline(self, deadline: Instant) -> Self {
+        self.with_deadline_opt(Some(deadline))
+    }
+
+    /// set or clear the deadline
+    fn with_deadline_opt(mut self, deadline: Option<Instant>) -> Self {
+        self.deadline = deadline;
+        self
+    }
+
+    /// when the message expires, `None` if it never does
+    #[inline]
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    /// is the deadline reached
+    #[inline]
+    pub fn is_expired(&self) -> bool {
+        self.deadline
+            .is_some_and(|d| d <= Instant::now())
+    }
+
+    /// sequence number assigned when the message is put into a channel, it
+    /// follows the enqueue order of all senders, `None` for a message that is
+    /// never sent, or a detached copy
+    #[inline]
+    pub fn seq(&self) -> Option<u64> {
+        self.seq
     }
 
     /// set the share queue
     #[inline]
     pub(crate) fn set_shared(&mut self, shared: Arc<T>) {
-        self.shared = Some(shared);
+        self.lock.shared = Some(shared);
+    }
+
+    /// set the hook called when the message is dropped
+    #[inline]
+    pub(crate) fn set_hook(&mut self, hook: CompletionHook) {
+        self.lock.hook = Some(hook);
+    }
+
+    /// take the hook out, so it will not be called on drop
+    #[inline]
+    pub(crate) fn take_hook(&mut self) -> Option<CompletionHook> {
+        self.lock.hook.take()
     }
 
     /// is the message's keyset containes multiple keys
     #[inline]
     pub fn is_multiple(&self) -> bool {
-        self.key.is_multiple()
+        self.key().is_multiple()
     }
 
     /// return a ref to single key or None
     #[inline]
     pub fn get_single_key(&self) -> Option<&K> {
-        self.key.get_single_key()
+        self.key().get_single_key()
     }
 
     /// return a ref to keyset
     #[inline]
     pub fn get_key_set(&self) -> Option<&HashSet<K>> {
-        self.key.get_key_set()
+        self.key().get_key_set()
+    }
+
+    /// does the message have `key`, whether it has a single key or not
+    #[inline]
+    pub fn contains_key<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.key().contains(key)
+    }
+
+    /// iterate over the key(s), whether the message has a single key or not
+    #[inline]
+    pub fn keys(&self) -> impl ExactSizeIterator<Item = &K> + '_ {
+        self.key().iter()
+    }
+
+    /// number of keys, zero for a message built with no key
+    #[inline]
+    pub fn keys_len(&self) -> usize {
+        self.key().len()
+    }
+
+    /// do the two messages share any key, which is exactly when the channel
+    /// keeps one of them from being received while the other is active
+    #[inline]
+    pub fn conflicts_with(&self, other: &Self) -> bool {
+        self.key().conflicts_with(other.key())
     }
 
     /// get message value
@@ -153,20 +566,107 @@ impl<K: Key, V, T: DeactivateKeys<Key = K>> Message<K, V, T> {
     pub fn get_value(&self) -> &V {
         &self.value
     }
+
+    /// release the key(s) now, as if the message is dropped, but keep the value
+    ///
+    /// Messages with the key(s) may be received right after it, dropping the
+    /// message later releases nothing. It's a no-op if the key(s) are already
+    /// released, or the message is not received from a channel. The completion
+    /// is reported as [`CompletionKind::Released`].
+    #[inline]
+    pub fn release_keys(&mut self) {
+        self.lock
+            .release_in_place(CompletionKind::Released);
+    }
+
+    /// transform the value, the key(s) stay active until the new message
+    /// is dropped
+    #[inline]
+    pub fn map<U, F>(self, f: F) -> Message<K, U, T>
+    where
+        F: FnOnce(V) -> U,
+    {
+        let Message { lock, value, enqueued_at, seq, priority, deadline } = self;
+        Message { lock, value: f(value), enqueued_at, seq, priority, deadline }
+    }
+
+    /// transform the value by reference, the key(s) stay active until the
+    /// new message is dropped
+    ///
+    /// # Errors
+    ///
+    /// return the message untouched along with the error of `f`
+    #[inline]
+    pub fn try_map<U, E, F>(self, f: F) -> Result<Message<K, U, T>, (Self, E)>
+    where
+        F: FnOnce(&V) -> Result<U, E>,
+    {
+        match f(&self.value) {
+            Ok(value) => Ok(Message {
+                lock: self.lock,
+                value,
+                enqueued_at: self.enqueued_at,
+                seq: self.seq,
+                priority: self.priority,
+                deadline: self.deadline,
+            }),
+            Err(err) => Err((self, err)),
+        }
+    }
+
+    /// take the value, the key(s) are released before it's returned,
+    /// as if the message is dropped
+    #[inline]
+    pub fn into_value(self) -> V {
+        let Message { lock, value, .. } = self;
+        drop(lock);
+        value
+    }
+
+    /// take the key(s) and value, the key(s) are released before they're
+    /// returned, as if the message is dropped
+    #[inline]
+    pub fn into_parts(self) -> (KeySet<K>, V) {
+        (self.lock.into_key(), self.value)
+    }
 }
 
-impl<K: Key, V, T: DeactivateKeys<Key = K>> BuffMessage for Message<K, V, T> {
-    type Key = K;
+// constructors of key-only messages, they can't be inferred in the generic impl
+#[allow(clippy::multiple_inherent_impl)]
+impl<K: Key, S: DeactivateKeys<Key = K>> Message<K, (), S> {
+    /// new a single key message without value
+    #[inline]
+    pub fn key_only(key: K) -> Self {
+        Self::single_key(key, ())
+    }
 
-    /// collect all conflict keys with keys in other
-    fn conflict_keys(&self, other: &HashMap<Self::Key, usize>) -> Option<Vec<&K>> {
-        self.key.conflict_keys(other)
+    /// new a message without value
+    #[inline]
+    pub fn keys_only<I>(keys: I) -> Self
+    where
+        I: IntoIterator<Item = K>,
+    {
+        Self::multiple_keys(keys, ())
     }
+}
+
+impl<K: Key, V, T: DeactivateKeys<Key = K>> BuffMessage for Message<K, V, T> {
+    type Key = K;
 
     /// collect all keys to an owned vector
     /// applicable to both key types
     fn get_owned_keys(&self) -> Vec<Self::Key> {
-        self.key.get_owned_keys()
+        self.key().get_owned_keys()
+    }
+
+    /// priority set by `with_priority`
+    fn pop_priority(&self) -> u8 {
+        self.priority
+    }
+
+    /// deadline set by `with_deadline`
+    fn expiry(&self) -> Option<Instant> {
+        self.deadline
     }
 }
 
@@ -177,5 +677,5 @@ pub trait DeactivateKeys {
     type Key: Key;
 
     /// release all keys
-    fn release_key<'a, I: IntoIterator<Item = &'a Self::Key>>(&'a self, keys: I);
+    fn release_key(&self, keys: &[&Self::Key], kind: CompletionKind);
 }
@@ -0,0 +1,83 @@
+//! A builder of a message whose keys are added one by one
+
+use crate::message::{DeactivateKeys, Key, KeySet, Message};
+use crate::unwrap_some_or;
+use std::collections::HashSet;
+
+/// A builder of a [`Message`] whose keys are added one by one
+///
+/// ```
+/// use kv_mpsc::{sync_channel, MessageBuilder};
+///
+/// let (tx, rx) = sync_channel::bounded(1);
+/// let msg = MessageBuilder::new().key(1).keys(vec![2, 3]).value("value").build();
+/// tx.send(msg).unwrap();
+/// assert_eq!(rx.recv().unwrap().keys_len(), 3);
+/// ```
+#[derive(Debug, Clone)]
+#[must_use]
+pub struct MessageBuilder<K: Key, V> {
+    /// the keys added so far
+    keys: HashSet<K>,
+    /// the value, required by `build`
+    value: Option<V>,
+}
+
+impl<K: Key, V> Default for MessageBuilder<K, V> {
+    #[inline]
+    fn default() -> Self {
+        MessageBuilder { keys: HashSet::new(), value: None }
+    }
+}
+
+impl<K: Key, V> MessageBuilder<K, V> {
+    /// new a builder without any key or value
+    #[inline]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// add a key, a key added twice is kept once
+    #[inline]
+    pub fn key(mut self, key: K) -> Self {
+        let _new = self.keys.insert(key);
+        self
+    }
+
+    /// add keys
+    #[inline]
+    pub fn keys<I>(mut self, keys: I) -> Self
+    where
+        I: IntoIterator<Item = K>,
+    {
+        self.keys.extend(keys);
+        self
+    }
+
+    /// set the value, replacing the one set before
+    #[inline]
+    pub fn value(mut self, value: V) -> Self {
+        self.value = Some(value);
+        self
+    }
+
+    /// build the message, it has a single key if exactly one distinct key is
+    /// added, and a set of keys otherwise, an empty set conflicts with nothing
+    ///
+    /// # Panics
+    ///
+    /// Panic if no value is set
+    #[inline]
+    #[must_use]
+    pub fn build<T: DeactivateKeys<Key = K>>(self) -> Message<K, V, T> {
+        let value =
+            unwrap_some_or!(self.value, panic!("The value of a message is not set"));
+        let key = if self.keys.len() == 1 {
+            let sole = self.keys.into_iter().next();
+            KeySet::Single(unwrap_some_or!(sole, unreachable!("a key is added")))
+        } else {
+            KeySet::Multiple(self.keys)
+        };
+        Message::from_key_set(key, value)
+    }
+}
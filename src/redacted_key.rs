@@ -0,0 +1,60 @@
+//! A key wrapper that never prints the wrapped key
+
+use std::fmt::Debug;
+
+/// A key wrapper whose `Debug` prints `<redacted>`
+///
+/// [`crate::Message`] keys must implement `Debug`, which is only used by panic
+/// messages and `Debug` of the channel types. Wrap a key that must not be
+/// printed (e.g. it carries personal data), `Eq`, `Hash` and `Clone` are
+/// forwarded to the inner key.
+///
+/// ```
+/// use kv_mpsc::sync_channel::bounded;
+/// use kv_mpsc::{Message, RedactedKey};
+///
+/// #[derive(Clone, PartialEq, Eq, Hash)]
+/// struct CustomerId(u64);
+///
+/// let (tx, rx) = bounded(1);
+/// tx.send(Message::single_key(RedactedKey::new(CustomerId(1)), "order")).unwrap();
+/// let msg = rx.recv().unwrap();
+/// assert!(format!("{:?}", msg).contains("<redacted>"));
+/// assert_eq!(msg.get_single_key().map(|k| k.get().0), Some(1));
+/// ```
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct RedactedKey<K>(K);
+
+impl<K> RedactedKey<K> {
+    /// wrap a key
+    #[inline]
+    pub fn new(key: K) -> Self {
+        RedactedKey(key)
+    }
+
+    /// return a ref to the inner key
+    #[inline]
+    pub fn get(&self) -> &K {
+        &self.0
+    }
+
+    /// unwrap the inner key
+    #[inline]
+    pub fn into_inner(self) -> K {
+        self.0
+    }
+}
+
+impl<K> From<K> for RedactedKey<K> {
+    #[inline]
+    fn from(key: K) -> Self {
+        RedactedKey(key)
+    }
+}
+
+impl<K> Debug for RedactedKey<K> {
+    #[inline]
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("<redacted>")
+    }
+}
@@ -1,15 +1,25 @@
 //! Sync mpsc channel that support key conflict resolution
 
 use super::shared::Shared;
-use super::Message;
-use crate::buff::KeyedBuff;
+use super::{KeyLock, Message};
 use crate::buff::State;
-use crate::err::{RecvError, SendError};
+use crate::completions::{Completions, Feed};
+use crate::err::{
+    RecvError, RecvFilterError, RecvOr, RecvTimeoutError, SendError, SendTimeoutError,
+    TryRecvError, TrySendError,
+};
+use crate::lock::{lock, lock_or_recover, MutexGuard};
 use crate::message::Key;
-use crate::{unwrap_ok_or, unwrap_some_or};
+use crate::throttle::KeyThrottle;
+use crate::unwrap_some_or;
+use crate::waiter::Waiter;
 use std::cell::RefCell;
 use std::fmt::Debug;
-use std::sync::{Arc, Condvar, Mutex};
+use std::ops::Deref;
+use std::panic::RefUnwindSafe;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Weak};
+use std::time::{Duration, Instant};
 
 /// A bounded sender that will block when there no empty buff slot
 #[derive(Debug)]
@@ -27,16 +37,158 @@ impl<K: Key, V> BoundedSender<K, V> {
     pub fn send(&self, message: Message<K, V>) -> Result<(), SendError<Message<K, V>>> {
         self.inner.send(message)
     }
+
+    /// send a message without waiting, fail if the buffer is full
+    /// # Errors
+    ///
+    /// return `Err(TrySendError::Full)` if buffer is full,
+    /// `Err(TrySendError::Disconnected)` if channel is disconnected
+    #[inline]
+    pub fn try_send(
+        &self, message: Message<K, V>,
+    ) -> Result<(), TrySendError<Message<K, V>>> {
+        self.inner.try_send(message)
+    }
+
+    /// send a message, block at most `dur` when the buffer is full
+    /// # Errors
+    ///
+    /// return `Err(SendTimeoutError::Timeout)` if no slot is freed in `dur`,
+    /// `Err(SendTimeoutError::Disconnected)` if channel is disconnected
+    #[inline]
+    pub fn send_timeout(
+        &self, message: Message<K, V>, dur: Duration,
+    ) -> Result<(), SendTimeoutError<Message<K, V>>> {
+        // an unrepresentable deadline waits forever
+        self.inner
+            .send_deadline(message, Instant::now().checked_add(dur))
+    }
+
+    /// create a sender that allows at most `n` of its own messages in flight
+    /// for each key, other senders of the channel are not affected
+    ///
+    /// A message is in flight from the time it's sent until it's dropped,
+    /// whether it is still buffered or held by the receiver.
+    /// # Panics
+    ///
+    /// panic if `n` is zero
+    #[inline]
+    #[must_use]
+    pub fn with_key_throttle(&self, n: usize) -> ThrottledSender<K, V>
+    where
+        K: Send + Sync + 'static,
+    {
+        ThrottledSender { sender: self.clone(), throttle: Arc::new(KeyThrottle::new(n)) }
+    }
+
+    /// capacity of the channel
+    #[inline]
+    #[must_use]
+    pub fn capacity(&self) -> usize {
+        let state = lock(&self.inner.state);
+        state.buff.cap()
+    }
+
+    /// number of queued messages, not including those held by the receiver
+    ///
+    /// It's a snapshot, other senders and the receiver may change it at once.
+    #[inline]
+    #[must_use]
+    pub fn len(&self) -> usize {
+        let state = lock(&self.inner.state);
+        state.buff.len()
+    }
+
+    /// is no message queued, a snapshot as [`BoundedSender::len`]
+    #[inline]
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// is the buffer full, a snapshot as [`BoundedSender::len`]
+    #[inline]
+    #[must_use]
+    pub fn is_full(&self) -> bool {
+        let state = lock(&self.inner.state);
+        state.buff.is_full()
+    }
+
+    /// do both senders send to the same channel
+    #[inline]
+    #[must_use]
+    pub fn same_channel(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.inner, &other.inner)
+    }
+
+    /// is the receiver gone, checked without sending a message
+    ///
+    /// It's racy, the receiver may be dropped right after `false` is returned,
+    /// so `send` can still fail.
+    #[inline]
+    #[must_use]
+    pub fn is_disconnected(&self) -> bool {
+        let state = lock(&self.inner.state);
+        state.disconnected
+    }
+
+    /// create a [`WeakSender`] that doesn't keep the channel connected
+    #[inline]
+    #[must_use]
+    pub fn downgrade(&self) -> WeakSender<K, V> {
+        WeakSender { inner: Arc::downgrade(&self.inner) }
+    }
+
+    /// is the receiver not dropped yet
+    ///
+    /// Unlike [`BoundedSender::is_disconnected`], a receiver that is closed
+    /// but still draining the buffer is alive.
+    #[inline]
+    #[must_use]
+    pub fn receiver_alive(&self) -> bool {
+        let state = lock(&self.inner.state);
+        !state.receiver_dropped
+    }
+}
+
+/// A sender that limits its in-flight messages per key,
+/// created by [`BoundedSender::with_key_throttle`]
+#[derive(Debug)]
+pub struct ThrottledSender<K: Key, V> {
+    /// the underlying sender
+    sender: BoundedSender<K, V>,
+    /// in-flight messages of this sender
+    throttle: Arc<KeyThrottle<K>>,
+}
+
+impl<K: Key + Send + Sync + 'static, V> ThrottledSender<K, V> {
+    /// send a message, block while any key of it reaches the in-flight limit
+    /// or there no empty buff slot
+    /// # Errors
+    ///
+    /// return `Err` if channel is disconnected
+    #[inline]
+    pub fn send(
+        &self, mut message: Message<K, V>,
+    ) -> Result<(), SendError<Message<K, V>>> {
+        let keys = message.key().get_owned_keys();
+        self.throttle.acquire_blocking(&keys);
+        message.set_hook(self.throttle.release_hook(keys));
+        self.sender.send(message).map_err(|err| {
+            err.map(|mut unsent| {
+                if let Some(release) = unsent.take_hook() {
+                    release();
+                }
+                unsent
+            })
+        })
+    }
 }
 
 impl<K: Key, V> Clone for BoundedSender<K, V> {
     #[inline]
     fn clone(&self) -> Self {
-        let mut state = unwrap_ok_or!(self.inner.state.lock(), err, panic!("{:?}", err));
-        let n_senders = state.n_senders;
-        state.n_senders =
-            unwrap_some_or!(n_senders.checked_add(1), panic!("too many senders"));
-        drop(state);
+        self.inner.senders.add();
         Self { inner: Arc::clone(&self.inner) }
     }
 }
@@ -44,23 +196,101 @@ impl<K: Key, V> Clone for BoundedSender<K, V> {
 impl<K: Key, V> Drop for BoundedSender<K, V> {
     #[inline]
     fn drop(&mut self) {
-        let mut state = unwrap_ok_or!(self.inner.state.lock(), err, panic!("{:?}", err));
-        let mut last_sender = false;
-        let n_senders = state.n_senders;
-        state.n_senders =
-            unwrap_some_or!(n_senders.checked_sub(1), panic!("too many senders"));
-        if state.n_senders == 0 {
-            last_sender = true;
+        if self.inner.senders.remove() {
+            // the flag is set under the lock, so a receiver either sees it
+            // or is already waiting for the following notification,
+            // never panic in drop, the flag is consistent even if poisoned
+            let mut state = lock_or_recover(&self.inner.state);
             state.disconnected = true;
-        }
-        drop(state);
-        if last_sender {
+            drop(state);
             self.inner.fill.notify_one();
         }
     }
 }
 
+/// A sender that doesn't count as a live sender, created by [`BoundedSender::downgrade`]
+///
+/// The receiver sees `Disconnected` once all real senders are dropped,
+/// no matter how many weak senders exist.
+#[derive(Debug)]
+pub struct WeakSender<K: Key, V> {
+    /// inner shared queue, not kept alive by the weak sender
+    inner: Weak<Shared<K, V>>,
+}
+
+impl<K: Key, V> WeakSender<K, V> {
+    /// get a real sender back, `None` if the channel is disconnected
+    #[inline]
+    #[must_use]
+    pub fn upgrade(&self) -> Option<BoundedSender<K, V>> {
+        let inner = self.inner.upgrade()?;
+        // never revive a count that reached zero, the last sender is disconnecting
+        if !inner.senders.try_add() {
+            return None;
+        }
+        let sender = BoundedSender { inner };
+        // the receiver may be closed, then dropping the sender undoes the count
+        (!sender.is_disconnected()).then_some(sender)
+    }
+}
+
+impl<K: Key, V> Clone for WeakSender<K, V> {
+    #[inline]
+    fn clone(&self) -> Self {
+        Self { inner: Weak::clone(&self.inner) }
+    }
+}
+
+/// A token that cancels [`Receiver::recv_or`], created by [`Receiver::cancel_token`]
+///
+/// Cancellation is permanent, all clones share the same state.
+#[derive(Debug)]
+pub struct CancelToken<K: Key, V> {
+    /// is the token cancelled
+    cancelled: Arc<AtomicBool>,
+    /// shared FIFO queue, to wake up the receiver
+    inner: Arc<Shared<K, V>>,
+}
+
+impl<K: Key, V> CancelToken<K, V> {
+    /// cancel the token, and wake up the waiting receiver
+    #[inline]
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+        self.inner.wake_receiver();
+    }
+
+    /// is the token cancelled
+    #[inline]
+    #[must_use]
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+}
+
+impl<K: Key, V> Clone for CancelToken<K, V> {
+    #[inline]
+    fn clone(&self) -> Self {
+        Self {
+            cancelled: Arc::clone(&self.cancelled),
+            inner: Arc::<Shared<K, V>>::clone(&self.inner),
+        }
+    }
+}
+
 /// A sync receiver will block when buff is empty
+///
+/// The receiver is the single consumer, it's `Send` but not `Sync`
+/// ```compile_fail
+/// fn assert_sync<T: Sync>() {}
+/// assert_sync::<kv_mpsc::sync_channel::Receiver<u32, u32>>();
+/// ```
+/// and a value that is not `Send` never crosses threads
+/// ```compile_fail
+/// use std::rc::Rc;
+/// let (tx, _rx) = kv_mpsc::sync_channel::bounded::<u32, Rc<u32>>(1);
+/// std::thread::spawn(move || drop(tx));
+/// ```
 #[derive(Debug)]
 pub struct Receiver<K: Key, V> {
     /// shared FIFO queue
@@ -71,6 +301,22 @@ pub struct Receiver<K: Key, V> {
 }
 
 impl<K: Key, V> Receiver<K, V> {
+    /// receive a message like [`Receiver::recv`], but block instead of returning
+    /// `AllConflict`, until a key is released or a message without conflict is sent
+    ///
+    /// It blocks forever if the conflicting keys are held by the calling thread
+    /// itself, e.g. received messages that are only dropped after it returns.
+    /// # Errors
+    ///
+    /// return `Err` if channel is all sender gone
+    #[inline]
+    pub fn recv_wait(&self) -> Result<Message<K, V>, RecvError> {
+        self.inner.recv_ready().map(|mut msg| {
+            msg.set_shared(Arc::<Shared<K, V>>::clone(&self.inner));
+            msg
+        })
+    }
+
     /// receive a message
     /// # Errors
     ///
@@ -82,37 +328,474 @@ impl<K: Key, V> Receiver<K, V> {
             msg
         })
     }
+
+    /// receive a message without waiting
+    /// # Errors
+    ///
+    /// return `Err(TryRecvError::Empty)` if buffer is empty, or the corresponding
+    /// error if channel is all sender gone or all messages conflict
+    #[inline]
+    pub fn try_recv(&self) -> Result<Message<K, V>, TryRecvError> {
+        self.inner.try_recv().map(|mut msg| {
+            msg.set_shared(Arc::<Shared<K, V>>::clone(&self.inner));
+            msg
+        })
+    }
+
+    /// an iterator that receives all receivable messages without blocking,
+    /// see [`TryIter`]
+    #[inline]
+    #[must_use]
+    pub fn try_iter(&self) -> TryIter<'_, K, V> {
+        TryIter { rx: self }
+    }
+
+    /// block until `key` is not held by any received message or key lock,
+    /// at most `dur`, return whether it's released
+    ///
+    /// It returns `true` at once if the key is not active, even if it has
+    /// never been. A key activated again before this thread wakes up is
+    /// waited for again.
+    #[inline]
+    #[must_use]
+    pub fn wait_key_released(&self, key: &K, dur: Duration) -> bool {
+        // an unrepresentable deadline waits forever
+        let deadline = Instant::now().checked_add(dur);
+        self.inner.wait_key_released(key, deadline)
+    }
+
+    /// receive a message, block at most `dur` when buffer is empty
+    ///
+    /// The wait is bounded by a deadline fixed at the call, so spurious wakeups
+    /// don't extend it.
+    /// # Errors
+    ///
+    /// return `Err(RecvTimeoutError::Timeout)` if no message is received in `dur`,
+    /// or the corresponding error if channel is all sender gone or all messages conflict
+    #[inline]
+    pub fn recv_timeout(&self, dur: Duration) -> Result<Message<K, V>, RecvTimeoutError> {
+        // an unrepresentable deadline waits forever
+        self.recv_until(Instant::now().checked_add(dur))
+    }
+
+    /// receive a message, block until `deadline` when buffer is empty
+    /// # Errors
+    ///
+    /// return `Err(RecvTimeoutError::Timeout)` if no message is received before
+    /// `deadline`, or the corresponding error if channel is all sender gone or
+    /// all messages conflict
+    #[inline]
+    pub fn recv_deadline(
+        &self, deadline: Instant,
+    ) -> Result<Message<K, V>, RecvTimeoutError> {
+        self.recv_until(Some(deadline))
+    }
+
+    /// receive a message, block until `deadline` if any when buffer is empty
+    fn recv_until(
+        &self, deadline: Option<Instant>,
+    ) -> Result<Message<K, V>, RecvTimeoutError> {
+        match self.inner.recv_deadline(deadline)? {
+            Some(mut msg) => {
+                msg.set_shared(Arc::<Shared<K, V>>::clone(&self.inner));
+                Ok(msg)
+            }
+            None => Err(RecvTimeoutError::Timeout),
+        }
+    }
+
+    /// receive a message, or give up waiting once `token` is cancelled
+    ///
+    /// A buffered message is always preferred, so a cancelled token only
+    /// takes effect when the receiver would block, and no message is lost.
+    /// # Errors
+    ///
+    /// return `Err(RecvOr::Cancelled)` if `token` is cancelled while waiting,
+    /// or the corresponding error if channel is all sender gone or all messages conflict
+    #[inline]
+    pub fn recv_or(&self, token: &CancelToken<K, V>) -> Result<Message<K, V>, RecvOr> {
+        match self.inner.recv_or(&token.cancelled)? {
+            Some(mut msg) => {
+                msg.set_shared(Arc::<Shared<K, V>>::clone(&self.inner));
+                Ok(msg)
+            }
+            None => Err(RecvOr::Cancelled),
+        }
+    }
+
+    /// create a token that cancels [`Receiver::recv_or`]
+    #[inline]
+    #[must_use]
+    pub fn cancel_token(&self) -> CancelToken<K, V> {
+        CancelToken {
+            cancelled: Arc::new(AtomicBool::new(false)),
+            inner: Arc::<Shared<K, V>>::clone(&self.inner),
+        }
+    }
+
+    /// receive the front-most message that matches `pred` and doesn't conflict,
+    /// other messages are left in the channel, wait when buffer is empty
+    /// # Errors
+    ///
+    /// return `Err(RecvFilterError::NoMatch)` if no buffered message matches,
+    /// `Err(RecvFilterError::AllConflict)` if all matching messages conflict,
+    /// or `Err(RecvFilterError::Disconnected)` if channel is all sender gone
+    #[inline]
+    pub fn recv_filter<P>(&self, pred: P) -> Result<Message<K, V>, RecvFilterError>
+    where
+        P: FnMut(&Message<K, V>) -> bool,
+    {
+        self.inner.recv_filter(pred).map(|mut msg| {
+            msg.set_shared(Arc::<Shared<K, V>>::clone(&self.inner));
+            msg
+        })
+    }
+
+    /// receive the front-most message with `key`, wait until such a message
+    /// is sent and the key is not active
+    ///
+    /// Other messages are left in the channel.
+    /// # Errors
+    ///
+    /// return `Err` if channel is all sender gone before a message with `key` is sent
+    #[inline]
+    pub fn recv_key(&self, key: &K) -> Result<Message<K, V>, RecvError> {
+        self.inner.recv_key(key).map(|mut msg| {
+            msg.set_shared(Arc::<Shared<K, V>>::clone(&self.inner));
+            msg
+        })
+    }
+
+    /// look at the message [`Receiver::recv`] would receive, without removing it
+    /// or activating its keys, block when buffer is empty
+    ///
+    /// The channel is locked while the returned guard is alive, so drop it before
+    /// receiving or waiting for anything else.
+    /// # Errors
+    ///
+    /// return `Err` if channel is all sender gone or all messages conflict
+    #[inline]
+    pub fn peek(&self) -> Result<PeekedMessage<'_, K, V>, RecvError> {
+        let state = self.inner.peek()?;
+        Ok(PeekedMessage { state })
+    }
+
+    /// receive the front-most message even if its keys conflict, and activate them
+    ///
+    /// The keys are active as usual once received, so the message is held
+    /// like one received by [`Receiver::recv`], and a key may be active more
+    /// than once. It's intended for maintenance paths, e.g. discarding a
+    /// message that can't be processed.
+    /// # Errors
+    ///
+    /// return `Err` if channel is all sender gone
+    #[inline]
+    pub fn recv_any(&self) -> Result<Message<K, V>, RecvError> {
+        self.inner.recv_any().map(|mut msg| {
+            msg.set_shared(Arc::<Shared<K, V>>::clone(&self.inner));
+            msg
+        })
+    }
+
+    /// receive the front-most message, ignoring and not updating the active keys
+    ///
+    /// The message is neither checked for conflict nor activates its keys, so
+    /// dropping it releases nothing, and a following message with the same key
+    /// can be received while it's alive. This is intended for observational
+    /// consumers, mixing it with [`Receiver::recv`] gives up the ordering and
+    /// conflict guarantees for the messages it consumes.
+    /// # Errors
+    ///
+    /// return `Err` if channel is all sender gone
+    #[inline]
+    pub fn recv_unguarded(&self) -> Result<Message<K, V>, RecvError> {
+        self.inner.recv_unguarded()
+    }
+
+    /// take all buffered messages at once in FIFO order, regardless of conflict
+    ///
+    /// Like [`Receiver::recv_unguarded`], the messages don't activate their keys,
+    /// so dropping them releases nothing, it's intended for shutdown when
+    /// nothing is processed concurrently anymore.
+    #[inline]
+    #[must_use]
+    pub fn drain(&self) -> Vec<Message<K, V>> {
+        self.inner.drain()
+    }
+
+    /// does `sender` send to this receiver
+    #[inline]
+    #[must_use]
+    pub fn same_channel(&self, sender: &BoundedSender<K, V>) -> bool {
+        Arc::ptr_eq(&self.inner, &sender.inner)
+    }
+
+    /// number of live senders, throttled senders included
+    ///
+    /// It's a snapshot, senders may be cloned or dropped right after it's returned.
+    #[inline]
+    #[must_use]
+    pub fn sender_count(&self) -> usize {
+        self.inner.senders.get()
+    }
+
+    /// close the channel for sending, while buffered messages can still be received
+    ///
+    /// All following and blocked `send`s fail with `SendError`, and receiving
+    /// returns `Disconnected` once the buffer is drained. Dropping the receiver
+    /// closes the channel too, but the buffered messages are abandoned.
+    #[inline]
+    pub fn close(&self) {
+        // called in drop too, never panic, the flag is consistent even if poisoned
+        let mut state = lock_or_recover(&self.inner.state);
+        state.disconnected = true;
+        drop(state);
+        self.inner.empty.notify_all();
+    }
+
+    /// is the channel disconnected, i.e. all senders are gone or the channel is closed,
+    /// buffered messages may still be received
+    #[inline]
+    #[must_use]
+    pub fn is_disconnected(&self) -> bool {
+        self.remaining_after_disconnect().is_some()
+    }
+
+    /// number of buffered messages left to drain, `None` if the channel is
+    /// still connected
+    #[inline]
+    #[must_use]
+    pub fn remaining_after_disconnect(&self) -> Option<usize> {
+        self.inner.remaining_after_disconnect()
+    }
+
+    /// number of queued messages, not including received ones whose keys
+    /// are still active
+    ///
+    /// It's a snapshot taken under the lock, and may be stale immediately.
+    #[inline]
+    #[must_use]
+    pub fn len(&self) -> usize {
+        let state = lock(&self.inner.state);
+        state.buff.len()
+    }
+
+    /// is no message queued, a snapshot as [`Receiver::len`]
+    #[inline]
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// keys held by received messages or key locks that queued messages
+    /// wait for, i.e. why `recv` returns `AllConflict`, in no particular order
+    ///
+    /// It's a snapshot taken under the lock, and may be stale immediately.
+    #[inline]
+    #[must_use]
+    pub fn blocking_keys(&self) -> Vec<K> {
+        self.inner.blocking_keys()
+    }
+
+    /// number of messages dropped since the channel is created, as their
+    /// deadlines set by [`Message::with_deadline`](crate::Message::with_deadline)
+    /// are reached before they are received
+    #[inline]
+    #[must_use]
+    pub fn expired_count(&self) -> u64 {
+        self.inner.expired_count()
+    }
+
+    /// receive a message and keep only its key guard, the value is dropped,
+    /// handy when the channel is used as a keyed permit queue with `V = ()`
+    /// # Errors
+    ///
+    /// return `Err` if channel is all sender gone
+    #[inline]
+    pub fn recv_key_only(&self) -> Result<KeyLock<K, V>, RecvError> {
+        self.recv().map(Message::into_key_lock)
+    }
+
+    /// listen on the keys released by received messages and key guards,
+    /// at most `cap` completions are buffered, see [`Completions`]
+    ///
+    /// Only one listener is served, a new one replaces the previous.
+    /// # Panics
+    ///
+    /// panic if `cap` is zero
+    #[inline]
+    #[must_use]
+    pub fn completions(&self, cap: usize) -> Completions<K> {
+        let feed = Arc::new(Feed::new(cap));
+        self.inner.active.set_feed(&feed);
+        Completions::new(feed)
+    }
+
+    /// activate `key` without any message, no message with the key will be
+    /// received until the returned guard is dropped
+    ///
+    /// The key is reference-counted, so it stays active while either the guard
+    /// or a received message with the key is alive.
+    #[inline]
+    pub fn lock_key(&self, key: K) -> KeyLock<K, V> {
+        self.inner.lock_key(key.clone());
+        KeyLock::new(key, Arc::<Shared<K, V>>::clone(&self.inner))
+    }
+
+    /// receive up to `n` messages, block until `n` messages are received
+    /// or `dur` has elapsed, whichever comes first
+    ///
+    /// Conflict rules apply to each message as usual, so the batch ends early
+    /// when all remaining messages are conflict, an empty `Vec` means the
+    /// deadline is reached before any message is receivable.
+    /// # Errors
+    ///
+    /// return `Err` only if no message is received, messages received before
+    /// the channel is disconnected or conflict are returned as `Ok`
+    #[inline]
+    pub fn recv_batch_timeout(
+        &self, n: usize, dur: Duration,
+    ) -> Result<Vec<Message<K, V>>, RecvError> {
+        let deadline = Instant::now().checked_add(dur);
+        let mut batch = Vec::new();
+        while batch.len() < n {
+            match self.inner.recv_deadline(deadline) {
+                Ok(Some(mut msg)) => {
+                    msg.set_shared(Arc::<Shared<K, V>>::clone(&self.inner));
+                    batch.push(msg);
+                }
+                Ok(None) => break,
+                Err(err) => {
+                    if batch.is_empty() {
+                        return Err(err);
+                    }
+                    break;
+                }
+            }
+        }
+        Ok(batch)
+    }
 }
 
+// the marker only opts out of `Sync`, the receiver has no interior
+// mutability outside the mutex protected state
+impl<K: Key + RefUnwindSafe, V: RefUnwindSafe> RefUnwindSafe for Receiver<K, V> {}
+
 impl<K: Key, V> Drop for Receiver<K, V> {
     #[inline]
     fn drop(&mut self) {
-        let mut state =
-            unwrap_ok_or!(self.inner.state.lock(), err, panic!("lock err {:?}", err));
-        state.disconnected = true;
+        let mut state = lock_or_recover(&self.inner.state);
+        state.receiver_dropped = true;
         drop(state);
-        self.inner.empty.notify_all();
+        self.close();
+    }
+}
+
+/// A message left in the channel, created by [`Receiver::peek`]
+///
+/// It dereferences to the message, and holds the channel lock until dropped.
+#[derive(Debug)]
+pub struct PeekedMessage<'a, K: Key, V> {
+    /// the locked state, with the peeked message in front of ready ones
+    state: MutexGuard<'a, State<Message<K, V>>>,
+}
+
+impl<K: Key, V> Deref for PeekedMessage<'_, K, V> {
+    type Target = Message<K, V>;
+
+    #[inline]
+    fn deref(&self) -> &Self::Target {
+        let front = self.state.buff.front_ready();
+        unwrap_some_or!(front, unreachable!("the peeked message is locked in buff"))
+    }
+}
+
+/// An iterator over the messages receivable at the moment, created by
+/// [`Receiver::try_iter`]
+///
+/// It never blocks, and ends when the buffer is empty, all remaining messages
+/// conflict, or the channel is disconnected. The state is only locked inside
+/// each `next`, so it can be called again after releasing yielded messages.
+#[derive(Debug)]
+pub struct TryIter<'a, K: Key, V> {
+    /// the receiver to receive from
+    rx: &'a Receiver<K, V>,
+}
+
+impl<K: Key, V> Iterator for TryIter<'_, K, V> {
+    type Item = Message<K, V>;
+
+    #[inline]
+    fn next(&mut self) -> Option<Self::Item> {
+        self.rx.try_recv().ok()
     }
 }
 
-/// A sync channel with capacity > 0
-/// # Panics
+/// An owning iterator that receives messages until the channel is disconnected,
+/// created by `into_iter` on [`Receiver`]
 ///
-/// panic is capicity less than zero
+/// Unlike [`Receiver::recv`], it waits for a key release when all buffered
+/// messages conflict, so messages must be dropped before the next iteration,
+/// or by another thread, otherwise it blocks forever.
+#[derive(Debug)]
+pub struct IntoIter<K: Key, V> {
+    /// the receiver to receive from, the channel stays connected while it's alive
+    rx: Receiver<K, V>,
+}
+
+impl<K: Key, V> Iterator for IntoIter<K, V> {
+    type Item = Message<K, V>;
+
+    #[inline]
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut msg = self.rx.inner.recv_ready().ok()?;
+        msg.set_shared(Arc::<Shared<K, V>>::clone(&self.rx.inner));
+        Some(msg)
+    }
+}
+
+impl<K: Key, V> IntoIterator for Receiver<K, V> {
+    type IntoIter = IntoIter<K, V>;
+    type Item = Message<K, V>;
+
+    #[inline]
+    fn into_iter(self) -> Self::IntoIter {
+        IntoIter { rx: self }
+    }
+}
+
+/// A sync channel with capacity `cap`
+///
+/// A zero capacity makes a rendezvous channel, a `send` doesn't return until
+/// the receiver takes its message, and key conflicts still apply to it.
+/// `try_send` always fails with `Full` as nothing is buffered.
 #[inline]
 #[must_use]
 #[doc(alias = "channel")]
+#[doc(alias = "rendezvous")]
 pub fn bounded<K: Key, V>(cap: usize) -> (BoundedSender<K, V>, Receiver<K, V>) {
-    assert!(cap > 0, "The capacity of channel must be greater than 0");
-    let inner = Arc::new(Shared {
-        state: Mutex::new(State {
-            buff: KeyedBuff::new(cap),
-            n_senders: 1,
-            disconnected: false,
-        }),
-        fill: Condvar::new(),
-        empty: Condvar::new(),
-    });
+    channel(cap, false)
+}
+
+/// A sync channel with capacity `cap` that honors message priorities
+///
+/// The receiver takes the highest priority message set by
+/// [`Message::with_priority`](crate::Message::with_priority) among the ones
+/// without conflict, the front-most one on a tie. Every receive scans all
+/// of them, while [`bounded`] takes the front one right away.
+#[inline]
+#[must_use]
+pub fn bounded_prioritized<K: Key, V>(
+    cap: usize,
+) -> (BoundedSender<K, V>, Receiver<K, V>) {
+    channel(cap, true)
+}
+
+/// a sender and receiver sharing a new queue
+fn channel<K: Key, V>(
+    cap: usize, prioritized: bool,
+) -> (BoundedSender<K, V>, Receiver<K, V>) {
+    let inner = Arc::new(Shared::new(cap, prioritized));
     let s = BoundedSender { inner: Arc::<Shared<K, V>>::clone(&inner) };
     let r = Receiver { inner, _marker: std::marker::PhantomData };
     (s, r)
@@ -52,24 +52,232 @@
 
 mod channel;
 
-pub use channel::{bounded, BoundedSender, Receiver};
+pub use channel::{
+    bounded, bounded_prioritized, BoundedSender, CancelToken, IntoIter, PeekedMessage,
+    Receiver, ThrottledSender, TryIter, WeakSender,
+};
 mod shared;
 
 /// the real messge used in sync channel
 type Message<K, V> = crate::Message<K, V, shared::Shared<K, V>>;
 
+/// the key lock of sync channel
+type KeyLock<K, V> = crate::KeyLock<K, shared::Shared<K, V>>;
+
 #[cfg(test)]
+#[allow(clippy::result_large_err)] // a failed send hands the whole message back
 mod test {
 
-    use crate::sync_channel::bounded;
-    use crate::{unwrap_ok_or, unwrap_some_or, Message, RecvError, SendError};
+    use super::shared::Shared;
+    use crate::buff::SenderCount;
+    use crate::sync_channel::{bounded, bounded_prioritized, Receiver};
+    use crate::waiter::Waiter;
+    use crate::{
+        plan_waves, unwrap_ok_or, unwrap_some_or, CompletionKind, KeySet, Message,
+        MessageBuilder, RecvError, RecvFilterError, RecvOr, RecvTimeoutError,
+        RedactedKey, SendError, SendTimeoutError, TryRecvError, TrySendError,
+    };
+    use std::panic::{catch_unwind, RefUnwindSafe, UnwindSafe};
     use std::{
         collections::HashSet,
         iter::FromIterator,
-        sync::{atomic::AtomicBool, Arc},
+        sync::{
+            atomic::{AtomicBool, Ordering::SeqCst},
+            Arc,
+        },
         thread,
+        time::{Duration, Instant},
     };
 
+    /// assert unwind safety at compile time
+    fn assert_unwind_safe<T: UnwindSafe + RefUnwindSafe>() {}
+
+    /// a key that deliberately implements no `Debug`
+    #[derive(Clone, PartialEq, Eq, Hash)]
+    struct CustomerId(u64);
+
+    #[test]
+    fn test_redacted_key() {
+        let (tx, rx) = bounded(10);
+        let key = RedactedKey::new(CustomerId(1));
+        assert_eq!(tx.send(Message::single_key(key.clone(), 1)), Ok(()));
+        let msg = unwrap_ok_or!(rx.recv(), err, panic!("{:?}", err));
+        assert_eq!(format!("{:?}", msg.get_single_key()), "Some(<redacted>)");
+        drop(msg);
+        let lock = rx.lock_key(key.clone());
+        assert!(!format!("{:?}", lock).contains('1'));
+        drop(lock);
+        drop(rx);
+        let res = tx.send(Message::single_key(key, 2));
+        assert!(res.is_err());
+        assert!(format!("{:?}", res).contains("<redacted>"));
+    }
+
+    #[test]
+    fn test_unwind_safe() {
+        assert_unwind_safe::<super::Message<usize, usize>>();
+        assert_unwind_safe::<super::KeyLock<usize, usize>>();
+        assert_unwind_safe::<super::BoundedSender<usize, usize>>();
+        assert_unwind_safe::<super::ThrottledSender<usize, usize>>();
+        assert_unwind_safe::<Receiver<usize, usize>>();
+    }
+
+    #[test]
+    fn test_catch_unwind_handler() {
+        let (tx, rx) = bounded(10);
+        for i in 1..=2 {
+            let _drop = tx.send(Message::single_key(0, i));
+        }
+        let res = catch_unwind(|| {
+            let msg = unwrap_ok_or!(rx.recv(), err, panic!("{:?}", err));
+            assert_eq!(msg.get_value(), &1);
+            panic!("handler panics while holding the message");
+        });
+        assert!(res.is_err());
+        // the key is released while unwinding
+        let msg2 = unwrap_ok_or!(rx.recv(), err, panic!("{:?}", err));
+        assert_eq!(msg2.get_value(), &2);
+    }
+
+    /// a key whose `Hash`/`Clone` panic when armed, compared by id
+    #[derive(Debug)]
+    struct PanicKey {
+        /// identity of the key
+        id: usize,
+        /// `Hash` panics when set
+        hash_bomb: Arc<AtomicBool>,
+        /// `Clone` panics when set
+        clone_bomb: Arc<AtomicBool>,
+    }
+
+    impl PanicKey {
+        fn new(id: usize) -> Self {
+            PanicKey {
+                id,
+                hash_bomb: Arc::new(AtomicBool::new(false)),
+                clone_bomb: Arc::new(AtomicBool::new(false)),
+            }
+        }
+    }
+
+    impl Clone for PanicKey {
+        fn clone(&self) -> Self {
+            assert!(!self.clone_bomb.load(SeqCst), "clone panics");
+            PanicKey {
+                id: self.id,
+                hash_bomb: Arc::clone(&self.hash_bomb),
+                clone_bomb: Arc::clone(&self.clone_bomb),
+            }
+        }
+    }
+
+    impl PartialEq for PanicKey {
+        fn eq(&self, other: &Self) -> bool {
+            self.id == other.id
+        }
+    }
+
+    impl Eq for PanicKey {}
+
+    impl std::hash::Hash for PanicKey {
+        fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+            assert!(!self.hash_bomb.load(SeqCst), "hash panics");
+            self.id.hash(state);
+        }
+    }
+
+    #[test]
+    fn test_catch_unwind_send() {
+        let (tx, rx) = bounded(10);
+        let key = PanicKey::new(1);
+        let bomb = Arc::clone(&key.hash_bomb);
+        let msg = Message::single_key(key.clone(), 1);
+        bomb.store(true, SeqCst);
+        assert!(catch_unwind(|| tx.send(msg)).is_err());
+        bomb.store(false, SeqCst);
+        // the state lock is not poisoned, the message is never queued
+        assert_eq!(rx.remaining_after_disconnect(), None);
+        assert_eq!(tx.send(Message::single_key(key, 2)), Ok(()));
+        drop(tx);
+        let msg2 = unwrap_ok_or!(rx.recv(), err, panic!("{:?}", err));
+        assert_eq!(msg2.get_value(), &2);
+        assert_eq!(rx.remaining_after_disconnect(), Some(0));
+    }
+
+    #[test]
+    fn test_catch_unwind_send_rollback() {
+        let (tx, rx) = bounded(10);
+        let (a, b, c) = (PanicKey::new(1), PanicKey::new(2), PanicKey::new(3));
+        let bomb = Arc::clone(&c.hash_bomb);
+        let _sent = tx.send(Message::single_key(a.clone(), 1));
+        let held = unwrap_ok_or!(rx.recv(), err, panic!("{:?}", err));
+        let msg = Message::multiple_keys(vec![a.clone(), b.clone(), c], 2);
+        bomb.store(true, SeqCst);
+        assert!(catch_unwind(|| tx.send(msg)).is_err());
+        // keys registered before the panic are rolled back
+        let _sent2 = tx.send(Message::single_key(b, 3));
+        let msg3 = unwrap_ok_or!(rx.recv(), err, panic!("{:?}", err));
+        assert_eq!(msg3.get_value(), &3);
+        let _sent3 = tx.send(Message::single_key(a, 4));
+        drop(held);
+        let msg4 = unwrap_ok_or!(rx.recv(), err, panic!("{:?}", err));
+        assert_eq!(msg4.get_value(), &4);
+    }
+
+    #[test]
+    fn test_catch_unwind_recv() {
+        let (tx, rx) = bounded(10);
+        let key = PanicKey::new(1);
+        let bomb = Arc::clone(&key.clone_bomb);
+        let _sent = tx.send(Message::single_key(key, 1));
+        bomb.store(true, SeqCst);
+        assert!(catch_unwind(|| rx.recv()).is_err());
+        bomb.store(false, SeqCst);
+        // the message is left in buffer
+        let msg = unwrap_ok_or!(rx.recv(), err, panic!("{:?}", err));
+        assert_eq!(msg.get_value(), &1);
+        drop(tx);
+        assert_eq!(rx.recv(), Err(RecvError::Disconnected));
+    }
+
+    #[test]
+    fn test_catch_unwind_lock_key() {
+        let (tx, rx) = bounded(10);
+        let key = PanicKey::new(1);
+        let bomb = Arc::clone(&key.hash_bomb);
+        bomb.store(true, SeqCst);
+        assert!(catch_unwind(|| rx.lock_key(key.clone())).is_err());
+        bomb.store(false, SeqCst);
+        // the key is not locked
+        let _sent = tx.send(Message::single_key(key.clone(), 1));
+        let msg = unwrap_ok_or!(rx.recv(), err, panic!("{:?}", err));
+        drop(msg);
+        // a lock dropped while unwinding releases the key
+        assert!(catch_unwind(|| {
+            let _lock = rx.lock_key(key.clone());
+            panic!("panics while holding the lock");
+        })
+        .is_err());
+        let _sent2 = tx.send(Message::single_key(key, 2));
+        let msg2 = unwrap_ok_or!(rx.recv(), err, panic!("{:?}", err));
+        assert_eq!(msg2.get_value(), &2);
+    }
+
+    #[test]
+    fn test_catch_unwind_key_throttle() {
+        let (tx, rx) = bounded(10);
+        let throttled = tx.with_key_throttle(1);
+        let (a, b) = (PanicKey::new(1), PanicKey::new(2));
+        let bomb = Arc::clone(&b.hash_bomb);
+        let msg = Message::multiple_keys(vec![a.clone(), b], 1);
+        bomb.store(true, SeqCst);
+        assert!(catch_unwind(|| throttled.send(msg)).is_err());
+        // a counted key is rolled back, or this send blocks forever
+        assert_eq!(throttled.send(Message::single_key(a, 2)), Ok(()));
+        let msg2 = unwrap_ok_or!(rx.recv(), err, panic!("{:?}", err));
+        assert_eq!(msg2.get_value(), &2);
+    }
+
     #[test]
     fn test_sender_close() {
         let cap = 10;
@@ -92,6 +300,584 @@ mod test {
         assert_eq!(tx.send(msg), Err(SendError(Message::single_key(1, 1))));
     }
 
+    #[test]
+    fn test_try_recv() {
+        let (tx, rx) = bounded(10);
+        assert_eq!(rx.try_recv(), Err(TryRecvError::Empty));
+        assert!(tx.send(Message::single_key(1, 1)).is_ok());
+        assert!(tx.send(Message::single_key(1, 2)).is_ok());
+        assert!(tx.send(Message::single_key(2, 3)).is_ok());
+        let first = rx.try_recv();
+        assert_eq!(first, Ok(Message::single_key(1, 1)));
+        // key 1 is active, the conflict message is skipped
+        assert_eq!(rx.recv(), Ok(Message::single_key(2, 3)));
+        assert_eq!(rx.try_recv(), Err(TryRecvError::AllConflict));
+        drop(first);
+        assert_eq!(rx.try_recv(), Ok(Message::single_key(1, 2)));
+        drop(tx);
+        assert_eq!(rx.try_recv(), Err(TryRecvError::Disconnected));
+        assert_eq!(rx.recv(), Err(RecvError::Disconnected));
+    }
+
+    #[test]
+    fn test_recv_timeout_elapsed() {
+        let (tx, rx) = bounded::<usize, usize>(10);
+        let dur = Duration::from_millis(100);
+        let start = Instant::now();
+        assert_eq!(rx.recv_timeout(dur), Err(RecvTimeoutError::Timeout));
+        let elapsed = start.elapsed();
+        assert!(elapsed >= dur);
+        assert!(elapsed < dur + Duration::from_millis(100), "{:?}", elapsed);
+        drop(tx);
+    }
+
+    #[test]
+    fn test_recv_timeout() {
+        let (tx, rx) = bounded(10);
+        let sender = tx.clone();
+        let handle = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            sender.send(Message::single_key(1, 1))
+        });
+        let dur = Duration::from_secs(10);
+        let first = rx.recv_timeout(dur);
+        assert_eq!(first, Ok(Message::single_key(1, 1)));
+        assert_eq!(unwrap_ok_or!(handle.join(), err, panic!("{:?}", err)), Ok(()));
+        assert!(tx.send(Message::single_key(1, 2)).is_ok());
+        assert_eq!(rx.recv_timeout(dur), Err(RecvTimeoutError::AllConflict));
+        drop(first);
+        drop(tx);
+        assert_eq!(rx.recv_timeout(dur), Ok(Message::single_key(1, 2)));
+        assert_eq!(rx.recv_timeout(dur), Err(RecvTimeoutError::Disconnected));
+    }
+
+    #[test]
+    fn test_send_timeout_full() {
+        let (tx, rx) = bounded(1);
+        assert!(tx.send(Message::single_key(1, 1)).is_ok());
+        let dur = Duration::from_millis(100);
+        let start = Instant::now();
+        assert_eq!(
+            tx.send_timeout(Message::single_key(2, 2), dur),
+            Err(SendTimeoutError::Timeout(Message::single_key(2, 2)))
+        );
+        let elapsed = start.elapsed();
+        assert!(elapsed >= dur);
+        assert!(elapsed < dur + Duration::from_millis(100), "{:?}", elapsed);
+        drop(rx);
+        assert_eq!(
+            tx.send_timeout(Message::single_key(2, 2), dur),
+            Err(SendTimeoutError::Disconnected(Message::single_key(2, 2)))
+        );
+    }
+
+    #[test]
+    fn test_send_timeout_slot_freed() {
+        let (tx, rx) = bounded(1);
+        assert!(tx.send(Message::single_key(1, 1)).is_ok());
+        let handle = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            let first = rx.recv();
+            (first, rx.recv())
+        });
+        let sent = tx.send_timeout(Message::single_key(2, 2), Duration::from_secs(10));
+        assert_eq!(sent, Ok(()));
+        let (first, second) = unwrap_ok_or!(handle.join(), err, panic!("{:?}", err));
+        assert_eq!(first, Ok(Message::single_key(1, 1)));
+        assert_eq!(second, Ok(Message::single_key(2, 2)));
+    }
+
+    #[test]
+    fn test_try_iter() {
+        let (tx, rx) = bounded(10);
+        assert_eq!(rx.try_iter().count(), 0);
+        for (k, v) in [(1, 1), (2, 2), (1, 3), (3, 4)] {
+            assert!(tx.send(Message::single_key(k, v)).is_ok());
+        }
+        // stops at the first `AllConflict`, message 3 is skipped over
+        let first: Vec<_> = rx.try_iter().collect();
+        let values: Vec<_> = first.iter().map(|m| *m.get_value()).collect();
+        assert_eq!(values, vec![1, 2, 4]);
+        assert_eq!(rx.try_recv(), Err(TryRecvError::AllConflict));
+        assert_eq!(rx.try_iter().count(), 0);
+        drop(first);
+        let second: Vec<_> = rx.try_iter().collect();
+        assert_eq!(second, vec![Message::single_key(1, 3)]);
+        drop(tx);
+        assert_eq!(rx.try_iter().count(), 0);
+    }
+
+    #[test]
+    fn test_into_iter() {
+        let (tx, rx) = bounded(10);
+        for (k, v) in [(1, 1), (1, 2), (2, 3), (1, 4)] {
+            assert!(tx.send(Message::single_key(k, v)).is_ok());
+        }
+        drop(tx);
+        // conflict messages are waited for, not ending the iteration
+        let handle = thread::spawn(move || {
+            rx.into_iter()
+                .map(|msg| *msg.get_value())
+                .collect::<Vec<_>>()
+        });
+        let values = unwrap_ok_or!(handle.join(), err, panic!("{:?}", err));
+        assert_eq!(values, vec![1, 3, 2, 4]);
+    }
+
+    #[test]
+    fn test_into_iter_release_by_other_thread() {
+        let (tx, rx) = bounded(10);
+        assert!(tx.send(Message::single_key(1, 1)).is_ok());
+        assert!(tx.send(Message::single_key(1, 2)).is_ok());
+        let mut iter = rx.into_iter();
+        let first = unwrap_some_or!(iter.next(), panic!("no message"));
+        let handle = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            drop(first);
+        });
+        assert_eq!(iter.next(), Some(Message::single_key(1, 2)));
+        unwrap_ok_or!(handle.join(), err, panic!("{:?}", err));
+        // the channel is still connected while the iterator is alive
+        assert_eq!(tx.send(Message::single_key(2, 3)), Ok(()));
+        drop(tx);
+        assert_eq!(iter.next(), Some(Message::single_key(2, 3)));
+        assert_eq!(iter.next(), None);
+    }
+
+    #[test]
+    fn test_sender_occupancy() {
+        let (tx, rx) = bounded(2);
+        assert_eq!(tx.capacity(), 2);
+        assert!(tx.is_empty());
+        assert!(tx.send(Message::single_key(1, 1)).is_ok());
+        assert!(tx.send(Message::single_key(1, 2)).is_ok());
+        assert_eq!(tx.len(), 2);
+        assert!(tx.is_full());
+        // a received message is held by the receiver, not queued
+        let msg = rx.recv();
+        assert_eq!(tx.len(), 1);
+        assert!(!tx.is_full());
+        drop(msg);
+        assert_eq!(tx.len(), 1);
+    }
+
+    #[test]
+    fn test_receiver_len() {
+        let n = 5;
+        let (tx, rx) = bounded(n);
+        assert!(rx.is_empty());
+        for i in 0..n {
+            assert!(tx.send(Message::single_key(i, i)).is_ok());
+        }
+        assert_eq!(rx.len(), n);
+        let msg = rx.recv();
+        // the keys of the received message are still active
+        assert_eq!(rx.len(), n - 1);
+        drop(msg);
+        assert_eq!(rx.len(), n - 1);
+        assert!(!rx.is_empty());
+    }
+
+    #[test]
+    fn test_same_channel() {
+        let (tx, rx) = bounded::<u32, u32>(1);
+        let (tx2, rx2) = bounded::<u32, u32>(1);
+        assert!(tx.same_channel(&tx.clone()));
+        assert!(!tx.same_channel(&tx2));
+        assert!(rx.same_channel(&tx));
+        assert!(rx2.same_channel(&tx2));
+        assert!(!rx.same_channel(&tx2));
+    }
+
+    #[test]
+    fn test_sender_is_disconnected() {
+        let (tx, rx) = bounded::<u32, u32>(1);
+        let tx2 = tx.clone();
+        let tx3 = tx.clone();
+        drop(tx);
+        drop(tx2);
+        assert!(!tx3.is_disconnected());
+        drop(rx);
+        assert!(tx3.is_disconnected());
+    }
+
+    #[test]
+    fn test_receiver_close_and_drain() {
+        let (tx, rx) = bounded(2);
+        assert!(tx.send(Message::single_key(1, 1)).is_ok());
+        assert!(tx.send(Message::single_key(2, 2)).is_ok());
+        let sender = tx.clone();
+        let blocked = thread::spawn(move || sender.send(Message::single_key(3, 3)));
+        thread::sleep(Duration::from_millis(20));
+        rx.close();
+        // the blocked sender is woken up
+        let sent = unwrap_ok_or!(blocked.join(), err, panic!("{:?}", err));
+        assert_eq!(sent, Err(SendError(Message::single_key(3, 3))));
+        assert_eq!(
+            tx.send(Message::single_key(4, 4)),
+            Err(SendError(Message::single_key(4, 4)))
+        );
+        assert!(tx.is_disconnected());
+        // buffered messages are drained though senders are alive
+        assert_eq!(rx.recv(), Ok(Message::single_key(1, 1)));
+        assert_eq!(rx.recv(), Ok(Message::single_key(2, 2)));
+        assert_eq!(rx.recv(), Err(RecvError::Disconnected));
+    }
+
+    #[test]
+    fn test_rendezvous_send_blocks_until_recv() {
+        let (tx, rx) = bounded(0);
+        assert_eq!(tx.capacity(), 0);
+        let handle = thread::spawn(move || tx.send(Message::single_key(1, 1)));
+        thread::sleep(Duration::from_millis(50));
+        assert!(!handle.is_finished());
+        assert_eq!(rx.recv(), Ok(Message::single_key(1, 1)));
+        assert_eq!(unwrap_ok_or!(handle.join(), err, panic!("{:?}", err)), Ok(()));
+        assert_eq!(rx.recv(), Err(RecvError::Disconnected));
+    }
+
+    #[test]
+    fn test_rendezvous_pairing() {
+        let (tx, rx) = bounded(0);
+        let n = 8;
+        let handles: Vec<_> = (0..n)
+            .map(|i| {
+                let sender = tx.clone();
+                thread::spawn(move || sender.send(Message::single_key(i, i)))
+            })
+            .collect();
+        drop(tx);
+        // every send is paired with a recv, none is left over
+        let mut values: Vec<_> = rx
+            .into_iter()
+            .map(|msg| *msg.get_value())
+            .collect();
+        for handle in handles {
+            assert_eq!(unwrap_ok_or!(handle.join(), err, panic!("{:?}", err)), Ok(()));
+        }
+        values.sort_unstable();
+        assert_eq!(values, (0..n).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_rendezvous_conflict() {
+        let (tx, rx) = bounded(0);
+        let sender = tx.clone();
+        let handle = thread::spawn(move || sender.send(Message::single_key(1, 1)));
+        let first = rx.recv();
+        assert_eq!(first, Ok(Message::single_key(1, 1)));
+        assert_eq!(unwrap_ok_or!(handle.join(), err, panic!("{:?}", err)), Ok(()));
+        // the handoff waits while its key is active
+        let handle2 = thread::spawn(move || tx.send(Message::single_key(1, 2)));
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(rx.recv(), Err(RecvError::AllConflict));
+        assert!(!handle2.is_finished());
+        drop(first);
+        assert_eq!(rx.recv(), Ok(Message::single_key(1, 2)));
+        assert_eq!(unwrap_ok_or!(handle2.join(), err, panic!("{:?}", err)), Ok(()));
+    }
+
+    #[test]
+    fn test_rendezvous_take_back() {
+        let (tx, rx) = bounded(0);
+        assert_eq!(
+            tx.try_send(Message::single_key(1, 1)),
+            Err(TrySendError::Full(Message::single_key(1, 1)))
+        );
+        assert_eq!(
+            tx.send_timeout(Message::single_key(1, 1), Duration::from_millis(20)),
+            Err(SendTimeoutError::Timeout(Message::single_key(1, 1)))
+        );
+        // the timed out message is not left in the slot
+        assert!(tx.is_empty());
+        let handle = thread::spawn(move || tx.send(Message::single_key(2, 2)));
+        thread::sleep(Duration::from_millis(20));
+        drop(rx);
+        let sent = unwrap_ok_or!(handle.join(), err, panic!("{:?}", err));
+        assert_eq!(sent, Err(SendError(Message::single_key(2, 2))));
+    }
+
+    #[test]
+    fn test_drain() {
+        let (tx, rx) = bounded(3);
+        assert!(tx.send(Message::single_key(1, 1)).is_ok());
+        let held = rx.recv();
+        assert_eq!(held, Ok(Message::single_key(1, 1)));
+        assert!(tx
+            .send(Message::multiple_keys(vec![1, 2], 2))
+            .is_ok());
+        assert!(tx.send(Message::single_key(2, 3)).is_ok());
+        assert!(tx.send(Message::single_key(3, 4)).is_ok());
+        let sender = tx.clone();
+        let blocked = thread::spawn(move || sender.send(Message::single_key(4, 5)));
+        thread::sleep(Duration::from_millis(20));
+        // conflicts are ignored, in FIFO order
+        let drained = rx.drain();
+        let values: Vec<_> = drained.iter().map(|m| *m.get_value()).collect();
+        assert_eq!(values, vec![2, 3, 4]);
+        // the blocked sender is woken up by the freed capacity
+        assert_eq!(unwrap_ok_or!(blocked.join(), err, panic!("{:?}", err)), Ok(()));
+        assert_eq!(rx.drain(), vec![Message::single_key(4, 5)]);
+        // dropping drained messages releases nothing, key 1 is still held
+        drop(drained);
+        assert!(tx.send(Message::single_key(1, 6)).is_ok());
+        assert_eq!(rx.try_recv(), Err(TryRecvError::AllConflict));
+        drop(held);
+        assert_eq!(rx.try_recv(), Ok(Message::single_key(1, 6)));
+    }
+
+    #[test]
+    fn test_recv_any() {
+        let (tx, rx) = bounded(10);
+        for (k, v) in [(1, 1), (1, 2), (2, 3), (1, 4)] {
+            assert!(tx.send(Message::single_key(k, v)).is_ok());
+        }
+        let first = rx.recv();
+        assert_eq!(first, Ok(Message::single_key(1, 1)));
+        // the front-most message is taken though key 1 is active
+        let second = rx.recv_any();
+        assert_eq!(second, Ok(Message::single_key(1, 2)));
+        assert_eq!(rx.recv(), Ok(Message::single_key(2, 3)));
+        assert_eq!(rx.try_recv(), Err(TryRecvError::AllConflict));
+        // key 1 is active until both messages are dropped
+        drop(first);
+        assert_eq!(rx.try_recv(), Err(TryRecvError::AllConflict));
+        drop(second);
+        assert_eq!(rx.try_recv(), Ok(Message::single_key(1, 4)));
+        drop(tx);
+        assert_eq!(rx.recv_any(), Err(RecvError::Disconnected));
+    }
+
+    #[test]
+    fn test_peek() {
+        let (tx, rx) = bounded(10);
+        for (k, v) in [(1, 1), (1, 2), (2, 3)] {
+            assert!(tx.send(Message::single_key(k, v)).is_ok());
+        }
+        let peeked = unwrap_ok_or!(rx.peek(), err, panic!("{:?}", err));
+        assert_eq!(peeked.get_single_key(), Some(&1));
+        assert_eq!(peeked.get_value(), &1);
+        drop(peeked);
+        // peeking activates nothing, the same message is received
+        let first = rx.recv();
+        assert_eq!(first, Ok(Message::single_key(1, 1)));
+        let peeked2 = unwrap_ok_or!(rx.peek(), err, panic!("{:?}", err));
+        assert_eq!(peeked2.get_value(), &3);
+        drop(peeked2);
+        assert_eq!(rx.recv(), Ok(Message::single_key(2, 3)));
+        assert_eq!(rx.peek().map(|m| *m.get_value()), Err(RecvError::AllConflict));
+        drop(first);
+        assert_eq!(rx.peek().map(|m| *m.get_value()), Ok(2));
+        assert_eq!(rx.recv(), Ok(Message::single_key(1, 2)));
+        drop(tx);
+        assert_eq!(rx.peek().map(|m| *m.get_value()), Err(RecvError::Disconnected));
+    }
+
+    #[test]
+    fn test_recv_filter() {
+        let (tx, rx) = bounded(10);
+        for (k, v) in [(1, 1), (2, 10), (1, 20), (3, 2)] {
+            assert!(tx.send(Message::single_key(k, v)).is_ok());
+        }
+        let large = |m: &super::Message<u32, u32>| *m.get_value() >= 10;
+        let first = rx.recv_filter(large);
+        assert_eq!(first, Ok(Message::single_key(2, 10)));
+        let held = rx.recv();
+        assert_eq!(held, Ok(Message::single_key(1, 1)));
+        // the matching message is queued, but key 1 is active
+        assert_eq!(rx.recv_filter(large), Err(RecvFilterError::AllConflict));
+        assert_eq!(
+            rx.recv_filter(|m| *m.get_value() > 100),
+            Err(RecvFilterError::NoMatch)
+        );
+        drop(held);
+        assert_eq!(rx.recv_filter(large), Ok(Message::single_key(1, 20)));
+        // unmatched messages are left in order
+        assert_eq!(rx.recv(), Ok(Message::single_key(3, 2)));
+        drop(tx);
+        assert_eq!(rx.recv_filter(large), Err(RecvFilterError::Disconnected));
+    }
+
+    #[test]
+    fn test_recv_key() {
+        let (tx, rx) = bounded(10);
+        for (k, v) in [(1, 1), (1, 2), (2, 3)] {
+            assert!(tx.send(Message::single_key(k, v)).is_ok());
+        }
+        let held = rx.recv();
+        assert_eq!(held, Ok(Message::single_key(1, 1)));
+        let release = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            drop(held);
+        });
+        // receivable once the outstanding message is dropped
+        assert_eq!(rx.recv_key(&1), Ok(Message::single_key(1, 2)));
+        unwrap_ok_or!(release.join(), err, panic!("{:?}", err));
+        // a message sent later is waited for
+        let sender = tx.clone();
+        let handle2 = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            sender.send(Message::multiple_keys(vec![3, 4], 4))
+        });
+        assert_eq!(rx.recv_key(&4), Ok(Message::multiple_keys(vec![3, 4], 4)));
+        assert_eq!(unwrap_ok_or!(handle2.join(), err, panic!("{:?}", err)), Ok(()));
+        drop(tx);
+        assert_eq!(rx.recv_key(&5), Err(RecvError::Disconnected));
+        assert_eq!(rx.recv(), Ok(Message::single_key(2, 3)));
+    }
+
+    #[test]
+    fn test_sender_count() {
+        let (tx, rx) = bounded::<u32, u32>(4);
+        assert_eq!(rx.sender_count(), 1);
+        let handles: Vec<_> = (0..4)
+            .map(|i| {
+                let sender = tx.clone();
+                thread::spawn(move || {
+                    let clones: Vec<_> = (0..8).map(|_| sender.clone()).collect();
+                    assert!(sender.send(Message::single_key(i, i)).is_ok());
+                    drop(clones);
+                })
+            })
+            .collect();
+        for handle in handles {
+            assert!(handle.join().is_ok());
+        }
+        assert_eq!(rx.sender_count(), 1);
+        assert!(tx.receiver_alive());
+        drop(tx);
+        assert_eq!(rx.sender_count(), 0);
+        assert!(rx.is_disconnected());
+    }
+
+    #[test]
+    fn test_receiver_alive() {
+        let (tx, rx) = bounded::<u32, u32>(1);
+        rx.close();
+        assert!(tx.is_disconnected());
+        assert!(tx.receiver_alive());
+        drop(rx);
+        assert!(!tx.receiver_alive());
+    }
+
+    #[test]
+    fn test_weak_sender() {
+        let (tx, rx) = bounded::<u32, u32>(1);
+        let weak = tx.downgrade();
+        let cached = weak.clone();
+        assert_eq!(rx.sender_count(), 1);
+        let upgraded = weak.upgrade();
+        assert_eq!(rx.sender_count(), 2);
+        drop(upgraded);
+        drop(tx);
+        // weak senders don't keep the channel connected
+        assert!(rx.is_disconnected());
+        assert_eq!(rx.recv().err(), Some(RecvError::Disconnected));
+        assert!(cached.upgrade().is_none());
+        assert_eq!(rx.sender_count(), 0);
+        drop(rx);
+        assert!(weak.upgrade().is_none());
+    }
+
+    #[test]
+    fn test_upgrade_racing_last_drop() {
+        let (tx, rx) = bounded::<u32, u32>(1);
+        let handles: Vec<_> = (0..4)
+            .map(|_| {
+                let weak = tx.downgrade();
+                thread::spawn(move || {
+                    for _ in 0..1000 {
+                        let sender = unwrap_some_or!(weak.upgrade(), break);
+                        assert!(sender.receiver_alive());
+                    }
+                })
+            })
+            .collect();
+        drop(tx);
+        for handle in handles {
+            assert!(handle.join().is_ok());
+        }
+        assert_eq!(rx.sender_count(), 0);
+        assert_eq!(rx.recv(), Err(RecvError::Disconnected));
+    }
+
+    #[test]
+    fn test_recv_wait() {
+        let (tx, rx) = bounded(4);
+        assert!(tx.send(Message::single_key(1, 1)).is_ok());
+        assert!(tx.send(Message::single_key(1, 2)).is_ok());
+        let held = rx.recv_wait();
+        assert_eq!(held, Ok(Message::single_key(1, 1)));
+        // nothing is sent anymore, only the key release wakes it up
+        let release = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            drop(held);
+        });
+        let second = rx.recv_wait();
+        assert_eq!(second, Ok(Message::single_key(1, 2)));
+        assert!(release.join().is_ok());
+        // a key lock released by another thread
+        assert!(tx.send(Message::single_key(1, 3)).is_ok());
+        drop(second);
+        let lock = rx.lock_key(1);
+        let unlock = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            drop(lock);
+        });
+        assert_eq!(rx.recv_wait(), Ok(Message::single_key(1, 3)));
+        assert!(unlock.join().is_ok());
+        drop(tx);
+        assert_eq!(rx.recv_wait(), Err(RecvError::Disconnected));
+    }
+
+    #[test]
+    fn test_wait_key_released() {
+        let (tx, rx) = bounded(2);
+        // never active, even with no time to wait
+        assert!(rx.wait_key_released(&1, Duration::ZERO));
+        assert!(tx.send(Message::single_key(1, 1)).is_ok());
+        let held = unwrap_ok_or!(rx.recv(), err, panic!("{:?}", err));
+        assert!(!rx.wait_key_released(&1, Duration::from_millis(10)));
+        let release = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            drop(held);
+        });
+        assert!(rx.wait_key_released(&1, Duration::from_secs(1)));
+        assert!(release.join().is_ok());
+    }
+
+    #[test]
+    fn test_try_send_full() {
+        let (tx, rx) = bounded(1);
+        assert_eq!(tx.try_send(Message::single_key(1, 1)), Ok(()));
+        assert_eq!(
+            tx.try_send(Message::single_key(2, 2)),
+            Err(TrySendError::Full(Message::single_key(2, 2)))
+        );
+        assert_eq!(rx.recv(), Ok(Message::single_key(1, 1)));
+        assert_eq!(tx.try_send(Message::single_key(2, 2)), Ok(()));
+    }
+
+    #[test]
+    fn test_try_send_disconnected() {
+        let (tx, rx) = bounded(1);
+        drop(rx);
+        assert_eq!(
+            tx.try_send(Message::single_key(1, 1)),
+            Err(TrySendError::Disconnected(Message::single_key(1, 1)))
+        );
+    }
+
+    #[test]
+    fn test_try_send_wakes_receiver() {
+        let (tx, rx) = bounded(1);
+        let handle = thread::spawn(move || rx.recv());
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(tx.try_send(Message::single_key(1, 1)), Ok(()));
+        let received = unwrap_ok_or!(handle.join(), err, panic!("{:?}", err));
+        assert_eq!(received, Ok(Message::single_key(1, 1)));
+    }
+
     #[test]
     #[allow(clippy::unwrap_used)]
     fn test_conflict_msg_sequence_correct() {
@@ -202,9 +988,201 @@ mod test {
         }
     }
 
+    #[test]
+    fn test_deep_conflict_recv() {
+        // conflicting messages never enter the ready queue, so recv
+        // doesn't scan them however deep the buffer is
+        let (tx, rx) = bounded(10_001);
+        let _sent = tx.send(Message::single_key(0, 0));
+        let held = unwrap_ok_or!(rx.recv(), err, panic!("{:?}", err));
+        for i in 1..10_000 {
+            let key = usize::from(i == 9_000);
+            let _sent2 = tx.send(Message::single_key(key, i));
+        }
+        let msg = unwrap_ok_or!(rx.recv(), err, panic!("{:?}", err));
+        assert_eq!(msg.get_value(), &9_000);
+        assert_eq!(rx.recv(), Err(RecvError::AllConflict));
+        drop(held);
+        let msg2 = unwrap_ok_or!(rx.recv(), err, panic!("{:?}", err));
+        assert_eq!(msg2.get_value(), &1);
+    }
+
+    #[test]
+    fn test_hot_keys_stress() {
+        use std::collections::{HashMap, VecDeque};
+        // thousands of messages on a few hot keys, some with two of them
+        let keys = [0_u32, 1, 2, 3];
+        let (tx, rx) = bounded(2_000);
+        let pattern = keys
+            .iter()
+            .cycle()
+            .zip(keys.iter().rev().cycle())
+            .zip([false, false, true].iter().cycle());
+        for (value, ((&a, &b), &multi)) in (0_u32..2_000).zip(pattern) {
+            let msg = if multi {
+                Message::multiple_keys([a, b], value)
+            } else {
+                Message::single_key(a, value)
+            };
+            assert!(tx.send(msg).is_ok());
+        }
+        drop(tx);
+        let mut held = VecDeque::new();
+        let mut last: HashMap<u32, u32> = HashMap::new();
+        let mut received = Vec::new();
+        loop {
+            match rx.try_recv() {
+                Ok(msg) => {
+                    // messages sharing a key are received in FIFO order
+                    for key in msg.keys() {
+                        if let Some(prev) = last.insert(*key, *msg.get_value()) {
+                            assert!(prev < *msg.get_value());
+                        }
+                    }
+                    received.push(*msg.get_value());
+                    held.push_back(msg);
+                    if held.len() > 3 {
+                        drop(held.pop_front());
+                    }
+                }
+                Err(TryRecvError::AllConflict) => {
+                    // with nothing held, some message is always receivable
+                    assert!(!held.is_empty());
+                    drop(held.pop_front());
+                }
+                Err(err) => {
+                    assert_eq!(err, TryRecvError::Disconnected);
+                    break;
+                }
+            }
+        }
+        received.sort_unstable();
+        assert_eq!(received, (0..2_000).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_drop_while_sending() {
+        // received messages are dropped on another thread while senders keep
+        // pushing, releasing keys only takes the table of active keys
+        let (tx, rx) = bounded(8);
+        let senders: Vec<_> = (0_u32..4)
+            .map(|n| {
+                let tx = tx.clone();
+                thread::spawn(move || {
+                    for value in 0_u32..1_000 {
+                        let msg = Message::single_key(value % 4, n * 1_000 + value);
+                        assert!(tx.send(msg).is_ok());
+                    }
+                })
+            })
+            .collect();
+        drop(tx);
+        let (drop_tx, drop_rx) = std::sync::mpsc::channel();
+        let dropper = thread::spawn(move || drop_rx.into_iter().for_each(drop));
+        let mut received = Vec::new();
+        loop {
+            match rx.recv_wait() {
+                Ok(msg) => {
+                    received.push(*msg.get_value());
+                    assert!(drop_tx.send(msg).is_ok());
+                }
+                Err(err) => {
+                    assert_eq!(err, RecvError::Disconnected);
+                    break;
+                }
+            }
+        }
+        received.sort_unstable();
+        assert_eq!(received, (0..4_000).collect::<Vec<_>>());
+        drop(drop_tx);
+        for handle in senders {
+            assert!(handle.join().is_ok());
+        }
+        assert!(dropper.join().is_ok());
+    }
+
+    #[test]
+    fn test_lock_key_while_receiving() {
+        // keys are locked by the receiver and released on another thread while
+        // the sender pushes, the parked messages are received in order per key
+        let (tx, rx) = bounded(8);
+        let sender = thread::spawn(move || {
+            for value in 0_u32..2_000 {
+                assert!(tx
+                    .send(Message::single_key(value % 4, value))
+                    .is_ok());
+            }
+        });
+        let (lock_tx, lock_rx) = std::sync::mpsc::channel();
+        let releaser = thread::spawn(move || {
+            lock_rx
+                .into_iter()
+                .for_each(super::KeyLock::release);
+        });
+        let mut received = Vec::new();
+        loop {
+            match rx.recv_wait() {
+                Ok(msg) => {
+                    let value = *msg.get_value();
+                    received.push(value);
+                    drop(msg);
+                    // the next message with the key is parked until it's released
+                    assert!(lock_tx.send(rx.lock_key(value % 4)).is_ok());
+                }
+                Err(err) => {
+                    assert_eq!(err, RecvError::Disconnected);
+                    break;
+                }
+            }
+        }
+        drop(lock_tx);
+        assert!(sender.join().is_ok());
+        assert!(releaser.join().is_ok());
+        for key in 0..4 {
+            let values: Vec<_> = received
+                .iter()
+                .copied()
+                .filter(|v| v % 4 == key)
+                .collect();
+            let sent: Vec<_> = (0_u32..2_000)
+                .filter(|v| v % 4 == key)
+                .collect();
+            assert_eq!(values, sent);
+        }
+    }
+
+    #[test]
+    fn test_pop_middle_then_release() {
+        // messages are tracked by id, so popping from the middle of the buffer
+        // never shifts what a key release makes receivable
+        let (tx, rx) = bounded(10);
+        for (key, value) in [(1, 0), (2, 1), (1, 2), (3, 3), (2, 4), (4, 5)] {
+            assert!(tx.send(Message::single_key(key, value)).is_ok());
+        }
+        let third = unwrap_ok_or!(rx.recv_key(&3), err, panic!("{:?}", err));
+        assert_eq!(third.get_value(), &3);
+        let fifth = unwrap_ok_or!(rx.recv_filter(|msg| msg.get_value() == &5), err, {
+            panic!("{:?}", err)
+        });
+        let first = unwrap_ok_or!(rx.recv(), err, panic!("{:?}", err));
+        assert_eq!(first.get_value(), &0);
+        let second = unwrap_ok_or!(rx.recv(), err, panic!("{:?}", err));
+        assert_eq!(second.get_value(), &1);
+        assert_eq!(rx.recv(), Err(RecvError::AllConflict));
+        drop((third, fifth));
+        assert_eq!(rx.recv(), Err(RecvError::AllConflict));
+        drop(second);
+        let msg = unwrap_ok_or!(rx.recv(), err, panic!("{:?}", err));
+        assert_eq!(msg.get_value(), &4);
+        assert_eq!(rx.recv(), Err(RecvError::AllConflict));
+        drop(first);
+        let msg2 = unwrap_ok_or!(rx.recv(), err, panic!("{:?}", err));
+        assert_eq!(msg2.get_value(), &2);
+        assert_eq!(rx.try_recv(), Err(TryRecvError::Empty));
+    }
+
     #[test]
     fn test_conflict_single_key_send_recv() {
-        use std::sync::atomic::Ordering::SeqCst;
         // the test case is as follow
         // tx send 10 k1 msgs
         // rx recv a k1 msg, then recv will return `AllConflict`
@@ -308,4 +1286,1161 @@ mod test {
             );
         }
     }
+
+    #[test]
+    fn test_recv_batch_timeout_deadline() {
+        let (tx, rx) = bounded(10);
+        for i in 0..2 {
+            let _drop = tx.send(Message::single_key(i, i));
+        }
+        let dur = Duration::from_millis(100);
+        let start = Instant::now();
+        let batch =
+            unwrap_ok_or!(rx.recv_batch_timeout(5, dur), err, panic!("{:?}", err));
+        assert!(start.elapsed() >= dur);
+        assert_eq!(batch, vec![Message::single_key(0, 0), Message::single_key(1, 1)]);
+        drop(batch);
+        let empty =
+            unwrap_ok_or!(rx.recv_batch_timeout(5, dur), err, panic!("{:?}", err));
+        assert!(empty.is_empty());
+    }
+
+    #[test]
+    fn test_recv_batch_timeout_n_reached() {
+        let (tx, rx) = bounded(10);
+        for i in 0..5 {
+            let _drop = tx.send(Message::single_key(i, i));
+        }
+        let dur = Duration::from_secs(10);
+        let start = Instant::now();
+        let batch =
+            unwrap_ok_or!(rx.recv_batch_timeout(3, dur), err, panic!("{:?}", err));
+        assert!(start.elapsed() < dur);
+        assert_eq!(batch.len(), 3);
+        // the batch ends when remaining messages conflict with the batch
+        let _drop = tx.send(Message::single_key(3, 5));
+        let batch2 =
+            unwrap_ok_or!(rx.recv_batch_timeout(3, dur), err, panic!("{:?}", err));
+        assert!(start.elapsed() < dur);
+        assert_eq!(batch2, vec![Message::single_key(3, 3), Message::single_key(4, 4)]);
+    }
+
+    #[test]
+    fn test_recv_batch_timeout_disconnect() {
+        let (tx, rx) = bounded(10);
+        let handle = thread::spawn(move || {
+            for i in 0..2 {
+                let _drop = tx.send(Message::single_key(i, i));
+            }
+        });
+        let dur = Duration::from_secs(10);
+        let start = Instant::now();
+        let batch =
+            unwrap_ok_or!(rx.recv_batch_timeout(5, dur), err, panic!("{:?}", err));
+        assert!(start.elapsed() < dur);
+        assert_eq!(batch.len(), 2);
+        assert_eq!(rx.recv_batch_timeout(5, dur), Err(RecvError::Disconnected));
+        let _drop = handle.join();
+    }
+
+    #[test]
+    fn test_recv_unguarded() {
+        let (tx, rx) = bounded(10);
+        for i in 0..3 {
+            let _drop = tx.send(Message::single_key(1, i));
+        }
+        // an unguarded message holds no key
+        let msg1 = unwrap_ok_or!(rx.recv_unguarded(), err, panic!("{:?}", err));
+        assert_eq!(msg1, Message::single_key(1, 0));
+        let msg2 = unwrap_ok_or!(rx.recv(), err, panic!("{:?}", err));
+        assert_eq!(msg2, Message::single_key(1, 1));
+        // it ignores the active key of a guarded message
+        let msg3 = unwrap_ok_or!(rx.recv_unguarded(), err, panic!("{:?}", err));
+        assert_eq!(msg3, Message::single_key(1, 2));
+        drop(tx);
+        assert_eq!(rx.recv_unguarded(), Err(RecvError::Disconnected));
+        drop(msg1);
+        drop(msg2);
+        drop(msg3);
+    }
+
+    #[test]
+    fn test_lock_key_queued_message() {
+        let (tx, rx) = bounded(10);
+        for i in 1..=2 {
+            let _drop = tx.send(Message::single_key(i, i));
+        }
+        // a queued message gives up the locked key
+        let lock = rx.lock_key(1);
+        assert_eq!(lock.get_single_key(), Some(&1));
+        let msg = unwrap_ok_or!(rx.recv(), err, panic!("{:?}", err));
+        assert_eq!(msg, Message::single_key(2, 2));
+        assert_eq!(rx.recv(), Err(RecvError::AllConflict));
+        drop(lock);
+        let msg2 = unwrap_ok_or!(rx.recv(), err, panic!("{:?}", err));
+        assert_eq!(msg2, Message::single_key(1, 1));
+        drop(msg);
+        drop(msg2);
+    }
+
+    #[test]
+    fn test_lock_key_refcount() {
+        let (tx, rx) = bounded(10);
+        for i in 0..3 {
+            let _drop = tx.send(Message::single_key(1, i));
+        }
+        // the message is dropped while the lock is held
+        let msg = unwrap_ok_or!(rx.recv(), err, panic!("{:?}", err));
+        let lock = rx.lock_key(1);
+        drop(msg);
+        assert_eq!(rx.recv(), Err(RecvError::AllConflict));
+        drop(lock);
+        let msg2 = unwrap_ok_or!(rx.recv(), err, panic!("{:?}", err));
+        assert_eq!(msg2, Message::single_key(1, 1));
+
+        // the lock is dropped while the message is held
+        let lock2 = rx.lock_key(1);
+        drop(lock2);
+        assert_eq!(rx.recv(), Err(RecvError::AllConflict));
+        drop(msg2);
+
+        // locks of the same key are counted separately
+        let lock3 = rx.lock_key(1);
+        let lock4 = rx.lock_key(1);
+        drop(lock3);
+        assert_eq!(rx.recv(), Err(RecvError::AllConflict));
+        drop(lock4);
+        let msg3 = unwrap_ok_or!(rx.recv(), err, panic!("{:?}", err));
+        assert_eq!(msg3, Message::single_key(1, 2));
+    }
+
+    #[test]
+    #[should_panic(expected = "too many senders")]
+    fn test_sender_count_overflow() {
+        // pretend usize::MAX senders are alive
+        let senders = SenderCount::new(usize::MAX);
+        assert!(!senders.remove());
+        senders.add();
+        senders.add();
+    }
+
+    #[test]
+    fn test_spurious_wakeup() {
+        let shared = Arc::new(Shared::<usize, usize>::new(1, false));
+        let receiver = Arc::clone(&shared);
+        let handle = thread::spawn(move || receiver.recv());
+        let full = Message::single_key(0, 0);
+        let _drop = shared.send(full);
+        let handle2 = {
+            let sender = Arc::clone(&shared);
+            thread::spawn(move || sender.send(Message::single_key(1, 1)))
+        };
+        for _ in 0..10 {
+            shared.fill.notify_all();
+            shared.empty.notify_all();
+            thread::sleep(Duration::from_millis(10));
+        }
+        let msg = unwrap_ok_or!(handle.join(), err, panic!("{:?}", err));
+        assert_eq!(msg, Ok(Message::single_key(0, 0)));
+        assert_eq!(unwrap_ok_or!(handle2.join(), err, panic!("{:?}", err)), Ok(()));
+    }
+
+    #[cfg(feature = "fault-injection")]
+    #[test]
+    fn test_fault_spurious_wakeups() {
+        let shared = Arc::new(Shared::<usize, usize>::new(1, false));
+        shared.fill.faults.spurious_wakeups(100);
+        shared.empty.faults.spurious_wakeups(100);
+        // the recv loop rechecks the buffer after each wakeup
+        let receiver = Arc::clone(&shared);
+        let handle = thread::spawn(move || receiver.recv());
+        thread::sleep(Duration::from_millis(20));
+        let _sent = shared.send(Message::single_key(0, 0));
+        let msg = unwrap_ok_or!(handle.join(), err, panic!("{:?}", err));
+        assert_eq!(msg, Ok(Message::single_key(0, 0)));
+        // so does a sender waiting for a slot
+        let _sent2 = shared.send(Message::single_key(1, 1));
+        let sender = Arc::clone(&shared);
+        let handle2 = thread::spawn(move || sender.send(Message::single_key(2, 2)));
+        thread::sleep(Duration::from_millis(20));
+        assert!(!handle2.is_finished());
+        let msg2 = unwrap_ok_or!(shared.recv(), err, panic!("{:?}", err));
+        assert_eq!(msg2, Message::single_key(1, 1));
+        assert_eq!(unwrap_ok_or!(handle2.join(), err, panic!("{:?}", err)), Ok(()));
+    }
+
+    #[cfg(feature = "fault-injection")]
+    #[test]
+    fn test_fault_delayed_notifications() {
+        let shared = Arc::new(Shared::<usize, usize>::new(1, false));
+        shared
+            .fill
+            .faults
+            .delay_notifications(Duration::from_millis(50));
+        let receiver = Arc::clone(&shared);
+        let handle = thread::spawn(move || receiver.recv());
+        thread::sleep(Duration::from_millis(20));
+        let _sent = shared.send(Message::single_key(0, 0));
+        let msg = unwrap_ok_or!(handle.join(), err, panic!("{:?}", err));
+        assert_eq!(msg, Ok(Message::single_key(0, 0)));
+    }
+
+    #[cfg(feature = "fault-injection")]
+    #[test]
+    fn test_fault_lost_notification() {
+        let shared = Arc::new(Shared::<usize, usize>::new(10, false));
+        shared.fill.faults.drop_notifications(1);
+        let receiver = Arc::clone(&shared);
+        let handle = thread::spawn(move || receiver.recv());
+        thread::sleep(Duration::from_millis(20));
+        let _sent = shared.send(Message::single_key(0, 0));
+        // by design a lost notification is not survived, the receiver
+        // blocks until the next one
+        thread::sleep(Duration::from_millis(50));
+        assert!(!handle.is_finished());
+        let _sent2 = shared.send(Message::single_key(1, 1));
+        let msg = unwrap_ok_or!(handle.join(), err, panic!("{:?}", err));
+        assert_eq!(msg, Ok(Message::single_key(0, 0)));
+        // a receiver with a deadline recovers at the deadline
+        shared.fill.faults.drop_notifications(1);
+        let receiver2 = Arc::clone(&shared);
+        let handle2 = thread::spawn(move || {
+            let _msg = receiver2.recv();
+            let deadline = Instant::now() + Duration::from_millis(100);
+            receiver2.recv_deadline(Some(deadline))
+        });
+        thread::sleep(Duration::from_millis(20));
+        let _sent3 = shared.send(Message::single_key(2, 2));
+        let msg2 = unwrap_ok_or!(handle2.join(), err, panic!("{:?}", err));
+        assert_eq!(msg2, Ok(Some(Message::single_key(2, 2))));
+    }
+
+    #[test]
+    fn test_completions() {
+        // the conflict scenario in module doc
+        let (tx, rx) = bounded(1);
+        let completions = rx.completions(8);
+        let handle = thread::spawn(move || {
+            let _sent = tx.send(Message::single_key(1, 1));
+            let _sent2 = tx.send(Message::single_key(1, 2));
+        });
+        let msg = unwrap_ok_or!(rx.recv(), err, panic!("{:?}", err));
+        assert_eq!(completions.try_next(), None);
+        drop(msg);
+        let _drop = handle.join();
+        let msg2 = unwrap_ok_or!(rx.recv(), err, panic!("{:?}", err));
+        drop(msg2);
+        assert_eq!(rx.recv(), Err(RecvError::Disconnected));
+        let lock = rx.lock_key(2);
+        drop(lock);
+        let released = rx.lock_key(3);
+        released.release();
+        assert_eq!(
+            completions.drain(),
+            vec![
+                (vec![1], CompletionKind::Dropped),
+                (vec![1], CompletionKind::Dropped),
+                (vec![2], CompletionKind::Unlocked),
+                (vec![3], CompletionKind::Released),
+            ]
+        );
+        assert_eq!(completions.lost(), 0);
+    }
+
+    #[test]
+    fn test_completions_lossy() {
+        let (tx, rx) = bounded(10);
+        let completions = rx.completions(2);
+        for i in 0..3 {
+            let _sent = tx.send(Message::key_only(i));
+        }
+        for _ in 0..3 {
+            let lock = unwrap_ok_or!(rx.recv_key_only(), err, panic!("{:?}", err));
+            drop(lock);
+        }
+        // the oldest is dropped
+        assert_eq!(completions.try_next(), Some((vec![1], CompletionKind::Unlocked)));
+        assert_eq!(completions.try_next(), Some((vec![2], CompletionKind::Unlocked)));
+        assert_eq!(completions.try_next(), None);
+        assert_eq!(completions.lost(), 1);
+        // unguarded messages release nothing
+        let _sent = tx.send(Message::single_key(0, ()));
+        drop(unwrap_ok_or!(rx.recv_unguarded(), err, panic!("{:?}", err)));
+        assert_eq!(completions.try_next(), None);
+    }
+
+    #[test]
+    fn test_wakeup_on_disconnect() {
+        let (tx, rx) = bounded::<usize, usize>(1);
+        let handle = thread::spawn(move || rx.recv());
+        thread::sleep(Duration::from_millis(50));
+        drop(tx);
+        let res = unwrap_ok_or!(handle.join(), err, panic!("{:?}", err));
+        assert_eq!(res, Err(RecvError::Disconnected));
+
+        let (tx2, rx2) = bounded(1);
+        let _drop = tx2.send(Message::single_key(0, 0));
+        let handle2 = thread::spawn(move || tx2.send(Message::single_key(1, 1)));
+        thread::sleep(Duration::from_millis(50));
+        drop(rx2);
+        let res2 = unwrap_ok_or!(handle2.join(), err, panic!("{:?}", err));
+        let unsent = unwrap_some_or!(res2.err(), panic!("it's closed")).into_inner();
+        assert_eq!(unsent, Message::single_key(1, 1));
+    }
+
+    #[test]
+    fn test_remaining_after_disconnect() {
+        let (tx, rx) = bounded(10);
+        for i in 0..2 {
+            let _drop = tx.send(Message::single_key(i, i));
+        }
+        assert!(!rx.is_disconnected());
+        assert_eq!(rx.remaining_after_disconnect(), None);
+        drop(tx);
+        assert!(rx.is_disconnected());
+        assert_eq!(rx.remaining_after_disconnect(), Some(2));
+        let msg = unwrap_ok_or!(rx.recv(), err, panic!("{:?}", err));
+        assert_eq!(rx.remaining_after_disconnect(), Some(1));
+        let msg2 = unwrap_ok_or!(rx.recv(), err, panic!("{:?}", err));
+        assert_eq!(rx.remaining_after_disconnect(), Some(0));
+        assert_eq!(rx.recv(), Err(RecvError::Disconnected));
+        assert!(rx.is_disconnected());
+        drop(msg);
+        drop(msg2);
+    }
+
+    #[test]
+    fn test_conflicts_with() {
+        let single = |k: usize| super::Message::single_key(k, 0);
+        let multiple = |keys: &[usize]| super::Message::multiple_keys(keys.to_vec(), 0);
+        assert!(single(1).conflicts_with(&single(1)));
+        assert!(!single(1).conflicts_with(&single(2)));
+        assert!(single(1).conflicts_with(&multiple(&[1, 2])));
+        assert!(multiple(&[1, 2]).conflicts_with(&single(2)));
+        assert!(!multiple(&[1, 2]).conflicts_with(&single(3)));
+        assert!(multiple(&[1, 2]).conflicts_with(&multiple(&[2, 3])));
+        assert!(!multiple(&[1, 2]).conflicts_with(&multiple(&[3, 4])));
+        assert!(!multiple(&[]).conflicts_with(&multiple(&[])));
+        assert!(!multiple(&[]).conflicts_with(&single(1)));
+    }
+
+    /// a xorshift generator, enough for random keysets
+    struct XorShift(u64);
+
+    impl XorShift {
+        /// next number below `n`
+        fn below(&mut self, n: u64) -> usize {
+            self.0 ^= self.0.wrapping_shl(13);
+            self.0 ^= self.0.wrapping_shr(7);
+            self.0 ^= self.0.wrapping_shl(17);
+            let r = unwrap_some_or!(self.0.checked_rem(n), panic!("fatal error"));
+            unwrap_ok_or!(usize::try_from(r), err, panic!("{:?}", err))
+        }
+
+        /// a random single or multiple (maybe empty) keyset message
+        fn message(&mut self, value: usize) -> super::Message<usize, usize> {
+            if self.below(2) == 0 {
+                Message::single_key(self.below(5), value)
+            } else {
+                let n = self.below(4);
+                let keys = (0..n)
+                    .map(|_| self.below(5))
+                    .collect::<Vec<_>>();
+                Message::multiple_keys(keys, value)
+            }
+        }
+    }
+
+    #[test]
+    fn test_conflicts_with_matches_channel() {
+        let mut rng = XorShift(0x9E37_79B9_7F4A_7C15);
+        for _ in 0..1000 {
+            let (tx, rx) = bounded(2);
+            let (a, b) = (rng.message(0), rng.message(0));
+            let conflict = a.conflicts_with(&b);
+            assert_eq!(conflict, b.conflicts_with(&a));
+            let _drop = tx.send(a);
+            let _sent = tx.send(b);
+            let held = unwrap_ok_or!(rx.recv(), err, panic!("{:?}", err));
+            let next = rx.recv();
+            assert_eq!(next.is_err(), conflict, "{:?} {:?}", held, next);
+            if conflict {
+                assert_eq!(next, Err(RecvError::AllConflict));
+            }
+        }
+    }
+
+    #[test]
+    fn test_plan_waves() {
+        let msgs = vec![
+            Message::single_key(1, 0),
+            Message::single_key(2, 1),
+            Message::multiple_keys(vec![1, 2], 2),
+            Message::single_key(3, 3),
+            Message::single_key(1, 4),
+            Message::multiple_keys(vec![], 5),
+        ];
+        let waves = plan_waves::<usize, usize, Shared<usize, usize>>(msgs);
+        let values = waves
+            .iter()
+            .map(|wave| {
+                wave.iter()
+                    .map(|m| *m.get_value())
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(values, vec![vec![0, 1, 3, 5], vec![2], vec![4]]);
+        assert!(plan_waves::<usize, usize, Shared<usize, usize>>(vec![]).is_empty());
+    }
+
+    #[test]
+    fn test_plan_waves_random() {
+        let mut rng = XorShift(0x2545_F491_4F6C_DD1D);
+        for _ in 0..100 {
+            let msgs = (0..20)
+                .map(|i| rng.message(i))
+                .collect::<Vec<_>>();
+            let expected = msgs
+                .iter()
+                .map(|m| (*m.get_value(), m.key().get_owned_keys()))
+                .collect::<Vec<_>>();
+            let waves = plan_waves(msgs);
+            let wave_of = |v: usize| {
+                unwrap_some_or!(
+                    waves
+                        .iter()
+                        .position(|w| w.iter().any(|m| *m.get_value() == v)),
+                    panic!("message {} is lost", v)
+                )
+            };
+            // per-key order is preserved across waves
+            for (i, &(v1, ref keys1)) in expected.iter().enumerate() {
+                for &(v2, ref keys2) in unwrap_some_or!(expected.get(i..), panic!())
+                    .iter()
+                    .skip(1)
+                {
+                    if keys1.iter().any(|k| keys2.contains(k)) {
+                        assert!(wave_of(v1) < wave_of(v2));
+                    }
+                }
+            }
+            // each wave is consumed without conflict
+            let (tx, rx) = bounded(20);
+            for wave in waves {
+                let n = wave.len();
+                for m in wave {
+                    let _drop = tx.send(m);
+                }
+                let batch = (0..n)
+                    .map(|_| unwrap_ok_or!(rx.recv(), err, panic!("{:?}", err)))
+                    .collect::<Vec<_>>();
+                drop(batch);
+            }
+        }
+    }
+
+    #[test]
+    fn test_recv_or_cancel_empty() {
+        let (_tx, rx) = bounded::<usize, usize>(10);
+        let token = rx.cancel_token();
+        let canceller = token.clone();
+        let handle = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            canceller.cancel();
+        });
+        assert_eq!(rx.recv_or(&token), Err(RecvOr::Cancelled));
+        assert!(token.is_cancelled());
+        let _drop = handle.join();
+    }
+
+    #[test]
+    fn test_recv_or_cancel_race() {
+        for i in 0..100 {
+            let (tx, rx) = bounded(10);
+            let token = rx.cancel_token();
+            let canceller = token.clone();
+            let handle = thread::spawn(move || {
+                let _drop = tx.send(Message::single_key(i, i));
+            });
+            let handle2 = thread::spawn(move || canceller.cancel());
+            match rx.recv_or(&token) {
+                Ok(msg) => assert_eq!(msg.get_value(), &i),
+                Err(err) => {
+                    assert_eq!(err, RecvOr::Cancelled);
+                    let _drop = handle.join();
+                    // the message is left in the channel
+                    assert_eq!(rx.recv(), Ok(Message::single_key(i, i)));
+                }
+            }
+            let _drop = handle2.join();
+        }
+    }
+
+    #[test]
+    fn test_recv_or_delivery() {
+        let (tx, rx) = bounded(10);
+        let token = rx.cancel_token();
+        for _ in 0..2 {
+            let _drop = tx.send(Message::single_key(1, 1));
+        }
+        token.cancel();
+        // buffered messages are preferred over cancellation
+        let msg = unwrap_ok_or!(rx.recv_or(&token), err, panic!("{:?}", err));
+        assert_eq!(rx.recv_or(&token), Err(RecvOr::AllConflict));
+        drop(msg);
+        drop(tx);
+        let msg2 = unwrap_ok_or!(rx.recv_or(&token), err, panic!("{:?}", err));
+        assert_eq!(msg2, Message::single_key(1, 1));
+        drop(msg2);
+        assert_eq!(rx.recv_or(&token), Err(RecvOr::Disconnected));
+    }
+
+    #[test]
+    fn test_recv_key_only() {
+        let (tx, rx) = bounded(10);
+        let _drop = tx.send(Message::key_only(1));
+        let _sent = tx.send(Message::keys_only(vec![1, 2]));
+        let lock = unwrap_ok_or!(rx.recv_key_only(), err, panic!("{:?}", err));
+        assert_eq!(lock.get_single_key(), Some(&1));
+        // the guard keeps the key active
+        assert_eq!(rx.recv(), Err(RecvError::AllConflict));
+        drop(lock);
+        let lock2 = unwrap_ok_or!(rx.recv_key_only(), err, panic!("{:?}", err));
+        assert!(lock2.is_multiple());
+        assert_eq!(lock2.get_key_set(), Some(&HashSet::from_iter(vec![1, 2])));
+        let _sent2 = tx.send(Message::key_only(2));
+        assert_eq!(rx.recv(), Err(RecvError::AllConflict));
+        drop(lock2);
+        assert_eq!(rx.recv(), Ok(Message::key_only(2)));
+    }
+
+    #[test]
+    fn test_into_value() {
+        let (tx, rx) = bounded(10);
+        let _sent = tx.send(Message::single_key(1, String::from("first")));
+        let _sent2 = tx.send(Message::multiple_keys(vec![1, 2], String::from("second")));
+        let msg = unwrap_ok_or!(rx.recv(), err, panic!("{:?}", err));
+        assert_eq!(rx.recv(), Err(RecvError::AllConflict));
+        // the key is released while the value is still alive
+        let value = msg.into_value();
+        let msg2 = unwrap_ok_or!(rx.recv(), err, panic!("{:?}", err));
+        assert_eq!(value, "first");
+        assert_eq!(msg2.into_value(), "second");
+        let _sent3 = tx.send(Message::single_key(2, String::from("third")));
+        assert_eq!(rx.recv(), Ok(Message::single_key(2, String::from("third"))));
+    }
+
+    #[test]
+    fn test_key_set() {
+        let single = KeySet::single(1);
+        let multiple = KeySet::multiple(vec![1, 2, 2]);
+        assert_eq!(single.len(), 1);
+        assert_eq!(multiple.len(), 2);
+        assert!(multiple.contains(&2));
+        assert!(!single.contains(&2));
+        assert_eq!(
+            multiple.iter().copied().collect::<HashSet<_>>(),
+            HashSet::from_iter(vec![1, 2])
+        );
+        assert!(KeySet::<u32>::multiple(vec![]).is_empty());
+        // single and single
+        assert!(!single.is_disjoint(&KeySet::single(1)));
+        assert!(single.is_disjoint(&KeySet::single(2)));
+        // single and multiple, both ways
+        assert!(!single.is_disjoint(&multiple));
+        assert!(!multiple.is_disjoint(&single));
+        assert!(KeySet::single(3).is_disjoint(&multiple));
+        assert!(multiple.is_disjoint(&KeySet::single(3)));
+        // multiple and multiple
+        assert!(!multiple.is_disjoint(&KeySet::multiple(vec![2, 3])));
+        assert!(multiple.is_disjoint(&KeySet::multiple(vec![3, 4])));
+        // an empty set conflicts with nothing, not even itself
+        let empty = KeySet::multiple(vec![]);
+        assert!(empty.is_disjoint(&single));
+        assert!(empty.is_disjoint(&empty));
+        // a message built from a key set conflicts as the set does
+        let (tx, rx) = bounded(10);
+        let _sent = tx.send(Message::from_key_set(multiple, 1));
+        let _sent2 = tx.send(Message::from_key_set(KeySet::single(3), 2));
+        let _sent3 = tx.send(Message::from_key_set(single, 3));
+        let held = unwrap_ok_or!(rx.recv(), err, panic!("{:?}", err));
+        assert_eq!(rx.recv(), Ok(Message::single_key(3, 2)));
+        assert_eq!(rx.recv(), Err(RecvError::AllConflict));
+        drop(held);
+    }
+
+    #[test]
+    fn test_contains_key() {
+        let single: super::Message<String, ()> = Message::key_only(String::from("a"));
+        assert!(single.contains_key("a"));
+        assert!(!single.contains_key("b"));
+        let multiple: super::Message<String, ()> =
+            Message::keys_only(vec![String::from("a"), String::from("b")]);
+        assert!(multiple.contains_key("b"));
+        assert!(multiple.contains_key(&String::from("a")));
+        assert!(!multiple.contains_key("c"));
+        assert_eq!(multiple.keys_len(), 2);
+    }
+
+    #[test]
+    fn test_message_builder() {
+        let (tx, rx) = bounded(10);
+        let single = MessageBuilder::new()
+            .key(1)
+            .key(1)
+            .value(1)
+            .build();
+        assert!(!single.is_multiple());
+        assert!(tx.send(single).is_ok());
+        let multiple = MessageBuilder::new()
+            .key(2)
+            .keys(vec![3, 4])
+            .value(2)
+            .build();
+        assert!(tx.send(multiple).is_ok());
+        let none = MessageBuilder::new()
+            .keys(vec![])
+            .value(3)
+            .build();
+        assert!(tx.send(none).is_ok());
+        assert_eq!(rx.recv(), Ok(Message::single_key(1, 1)));
+        assert_eq!(rx.recv(), Ok(Message::multiple_keys(vec![2, 3, 4], 2)));
+        assert_eq!(rx.recv(), Ok(Message::multiple_keys(vec![], 3)));
+        let result = catch_unwind(|| {
+            let _msg: super::Message<u32, u32> = MessageBuilder::new().key(1).build();
+        });
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_queue_latency() {
+        let (tx, rx) = bounded(10);
+        let msg = Message::single_key(1, 1);
+        assert_eq!(msg.enqueued_at(), None);
+        assert_eq!(msg.queue_latency(), None);
+        let before = Instant::now();
+        assert!(tx.send(msg).is_ok());
+        thread::sleep(Duration::from_millis(20));
+        let received = unwrap_ok_or!(rx.recv(), err, panic!("{:?}", err));
+        let enqueued_at = unwrap_some_or!(received.enqueued_at(), panic!("it's sent"));
+        assert!(enqueued_at >= before);
+        let latency = unwrap_some_or!(received.queue_latency(), panic!("it's sent"));
+        assert!(latency >= Duration::from_millis(20));
+        // a detached copy is never sent
+        assert_eq!(received.clone_detached().enqueued_at(), None);
+        // the timestamp moves along with the keys
+        assert_eq!(received.map(|v| v + 1).enqueued_at(), Some(enqueued_at));
+    }
+
+    #[test]
+    fn test_error_display() {
+        let (tx, rx) = bounded::<u32, u32>(1);
+        drop(rx);
+        let sent = tx.send(Message::single_key(1, 1));
+        let err = unwrap_some_or!(sent.err(), panic!("it's closed"));
+        assert_eq!(err.to_string(), "sending on a closed channel");
+        let boxed: Box<dyn std::error::Error> = Box::new(err);
+        assert_eq!(boxed.to_string(), "sending on a closed channel");
+        let (closed_tx, closed_rx) = bounded::<u32, u32>(1);
+        drop(closed_tx);
+        let recv_err =
+            unwrap_some_or!(closed_rx.recv().err(), panic!("it's disconnected"));
+        let recv_boxed: Box<dyn std::error::Error> = Box::new(recv_err);
+        assert_eq!(recv_boxed.to_string(), "receiving on a disconnected channel");
+        assert_eq!(
+            RecvError::AllConflict.to_string(),
+            "all buffered messages conflict with active keys"
+        );
+    }
+
+    #[test]
+    fn test_send_error_inner() {
+        let (tx, rx) = bounded(1);
+        drop(rx);
+        let sent = tx.send(Message::single_key(1, 1));
+        let err = unwrap_some_or!(sent.err(), panic!("it's closed"));
+        assert_eq!(err.as_inner(), &Message::single_key(1, 1));
+        let err = err.map(Message::into_value);
+        assert_eq!(err, SendError(1));
+        assert_eq!(err.into_inner(), 1);
+    }
+
+    #[test]
+    fn test_try_send_error() {
+        let (tx, rx) = bounded(1);
+        assert!(tx.try_send(Message::single_key(1, 1)).is_ok());
+        let sent = tx.try_send(Message::single_key(2, 2));
+        let err = unwrap_some_or!(sent.err(), panic!("it's full"));
+        assert!(err.is_full() && !err.is_disconnected());
+        assert_eq!(err.to_string(), "sending on a full channel");
+        assert_eq!(err.into_inner().into_value(), 2);
+        drop(rx);
+        let resent = tx.try_send(Message::single_key(3, 3));
+        let closed = unwrap_some_or!(resent.err(), panic!("it's closed"));
+        assert!(closed.is_disconnected() && !closed.is_full());
+        let boxed: Box<dyn std::error::Error> = Box::new(closed);
+        assert_eq!(boxed.to_string(), "sending on a closed channel");
+        let converted: TrySendError<u32> = SendError(4).into();
+        assert_eq!(converted, TrySendError::Disconnected(4));
+        assert_eq!(converted.into_inner(), 4);
+    }
+
+    #[test]
+    fn test_try_recv_error() {
+        // a new variant fails to compile here, as a reminder to cover it
+        let describe = |kind: TryRecvError| match kind {
+            TryRecvError::Empty => "empty",
+            TryRecvError::Disconnected => "disconnected",
+            TryRecvError::AllConflict => "all conflict",
+        };
+        let (tx, rx) = bounded(10);
+        let empty = unwrap_some_or!(rx.try_recv().err(), panic!("it's empty"));
+        assert_eq!(describe(empty), "empty");
+        assert_eq!(empty.to_string(), "receiving on an empty channel");
+        assert!(tx.send(Message::single_key(1, 1)).is_ok());
+        assert!(tx.send(Message::single_key(1, 2)).is_ok());
+        let msg = unwrap_ok_or!(rx.try_recv(), err, panic!("{:?}", err));
+        let conflict = unwrap_some_or!(rx.try_recv().err(), panic!("it conflicts"));
+        assert_eq!(describe(conflict), "all conflict");
+        assert_eq!(conflict.to_string(), RecvError::AllConflict.to_string());
+        drop((msg, tx));
+        drop(rx.try_recv());
+        let disconnected =
+            unwrap_some_or!(rx.try_recv().err(), panic!("it's disconnected"));
+        assert_eq!(describe(disconnected), "disconnected");
+        let boxed: Box<dyn std::error::Error> = Box::new(disconnected);
+        assert_eq!(boxed.to_string(), "receiving on a disconnected channel");
+        for recv_err in [RecvError::Disconnected, RecvError::AllConflict] {
+            assert_eq!(TryRecvError::from(recv_err).to_string(), recv_err.to_string());
+        }
+    }
+
+    #[test]
+    fn test_recv_timeout_error() {
+        let (tx, rx) = bounded(10);
+        let deadline = Instant::now() + Duration::from_millis(10);
+        let timeout =
+            unwrap_some_or!(rx.recv_deadline(deadline).err(), panic!("it's empty"));
+        assert_eq!(timeout, RecvTimeoutError::Timeout);
+        assert!(Instant::now() >= deadline);
+        assert_eq!(timeout.to_string(), "timed out waiting on a channel");
+        assert!(tx.send(Message::single_key(1, 1)).is_ok());
+        assert!(tx.send(Message::single_key(1, 2)).is_ok());
+        let received = rx.recv_deadline(Instant::now());
+        let first = unwrap_ok_or!(received, err, panic!("{:?}", err));
+        let conflict = rx.recv_timeout(Duration::from_millis(10)).err();
+        assert_eq!(conflict, Some(RecvTimeoutError::AllConflict));
+        drop((first, tx));
+        let second =
+            unwrap_ok_or!(rx.recv_timeout(Duration::ZERO), err, panic!("{:?}", err));
+        assert_eq!(second.get_value(), &2);
+        let disconnected =
+            unwrap_some_or!(rx.recv_deadline(Instant::now()).err(), panic!("gone"));
+        assert_eq!(disconnected, RecvTimeoutError::Disconnected);
+        let boxed: Box<dyn std::error::Error> = Box::new(disconnected);
+        assert_eq!(boxed.to_string(), "receiving on a disconnected channel");
+    }
+
+    #[test]
+    fn test_send_timeout_error() {
+        let (tx, rx) = bounded(1);
+        assert!(tx
+            .send(Message::single_key(1, String::from("first")))
+            .is_ok());
+        let msg = Message::single_key(2, String::from("second"));
+        let sent = tx.send_timeout(msg, Duration::from_millis(10));
+        let err = unwrap_some_or!(sent.err(), panic!("it's full"));
+        assert!(err.is_timeout() && !err.is_disconnected());
+        // the message comes back unchanged
+        let unsent = err.into_inner();
+        assert_eq!(unsent.get_single_key(), Some(&2));
+        assert_eq!(unsent.get_value(), "second");
+        drop(rx);
+        let resent = tx.send_timeout(unsent, Duration::from_millis(10));
+        let closed = unwrap_some_or!(resent.err(), panic!("it's closed"));
+        assert!(closed.is_disconnected() && !closed.is_timeout());
+        assert_eq!(closed.to_string(), "sending on a closed channel");
+        let converted: SendTimeoutError<u32> = SendError(1).into();
+        assert_eq!(converted, SendTimeoutError::Disconnected(1));
+    }
+
+    #[test]
+    fn test_blocking_keys() {
+        let (tx, rx) = bounded(10);
+        for (key, value) in [(1, 0), (2, 1), (3, 2), (1, 3), (2, 4)] {
+            assert!(tx.send(Message::single_key(key, value)).is_ok());
+        }
+        assert!(rx.blocking_keys().is_empty());
+        let held: Vec<_> = (0..3)
+            .map(|_| unwrap_ok_or!(rx.recv(), err, panic!("{:?}", err)))
+            .collect();
+        assert_eq!(rx.recv(), Err(RecvError::AllConflict));
+        // key 3 is held, but nothing waits for it
+        let mut blocking = rx.blocking_keys();
+        blocking.sort_unstable();
+        assert_eq!(blocking, vec![1, 2]);
+        drop(held);
+        let lock = rx.lock_key(1);
+        let msg = unwrap_ok_or!(rx.recv(), err, panic!("{:?}", err));
+        assert_eq!(msg.get_value(), &4);
+        assert_eq!(rx.blocking_keys(), vec![1]);
+        drop(lock);
+        assert!(rx.blocking_keys().is_empty());
+    }
+
+    #[test]
+    fn test_forget() {
+        let (tx, rx) = bounded(10);
+        assert!(tx
+            .send(Message::single_key(1, String::from("first")))
+            .is_ok());
+        assert!(tx
+            .send(Message::single_key(1, String::from("second")))
+            .is_ok());
+        let first = unwrap_ok_or!(rx.recv(), err, panic!("{:?}", err));
+        let lock = first.forget();
+        assert_eq!(lock.get_single_key(), Some(&1));
+        // the value is gone, while the key is still active
+        assert_eq!(rx.try_recv(), Err(TryRecvError::AllConflict));
+        // confirmed on another thread
+        let handle = thread::spawn(move || lock.release());
+        assert!(handle.join().is_ok());
+        let second = unwrap_ok_or!(rx.recv(), err, panic!("{:?}", err));
+        assert_eq!(second.get_value(), "second");
+        // a dropped guard releases the key too
+        drop(second.forget());
+        assert_eq!(rx.try_recv(), Err(TryRecvError::Empty));
+    }
+
+    #[test]
+    fn test_ttl() {
+        let (tx, rx) = bounded(2);
+        let expiring = Message::single_key(1, 0).with_ttl(Duration::from_millis(10));
+        assert!(expiring.deadline().is_some());
+        assert!(!expiring.is_expired());
+        assert!(tx.send(expiring).is_ok());
+        assert!(tx.send(Message::single_key(2, 1)).is_ok());
+        thread::sleep(Duration::from_millis(20));
+        let held = unwrap_ok_or!(rx.recv(), err, panic!("{:?}", err));
+        assert_eq!(held.get_value(), &1);
+        assert_eq!(held.deadline(), None);
+        assert_eq!(rx.expired_count(), 1);
+        // an expired message waiting for a key is dropped too
+        let pending = Message::single_key(2, 2).with_deadline(Instant::now());
+        assert!(tx.send(pending).is_ok());
+        assert_eq!(rx.try_recv(), Err(TryRecvError::Empty));
+        assert_eq!(rx.expired_count(), 2);
+        // so its slot is free
+        assert!(tx.try_send(Message::single_key(3, 3)).is_ok());
+        let next = unwrap_ok_or!(rx.recv(), err, panic!("{:?}", err));
+        assert_eq!(next.get_value(), &3);
+    }
+
+    #[test]
+    fn test_ttl_every_path() {
+        /// receive a value in one way
+        type Path = fn(&Receiver<i32, i32>) -> Option<i32>;
+        // an expired message in front, and a live one with the same key behind it
+        let queued = || {
+            let (tx, rx) = bounded(4);
+            let expired = Message::single_key(1, 0).with_ttl(Duration::ZERO);
+            assert!(tx.send(expired).is_ok());
+            assert!(tx.send(Message::single_key(1, 1)).is_ok());
+            (tx, rx)
+        };
+        let paths: [Path; 5] = [
+            |rx| rx.recv_filter(|_| true).ok().map(|msg| *msg),
+            |rx| rx.recv_key(&1).ok().map(|msg| *msg),
+            |rx| rx.peek().ok().map(|msg| *msg.get_value()),
+            |rx| rx.recv_any().ok().map(|msg| *msg),
+            |rx| rx.recv_unguarded().ok().map(|msg| *msg),
+        ];
+        for path in paths {
+            let (_tx, rx) = queued();
+            assert_eq!(path(&rx), Some(1));
+            assert_eq!(rx.expired_count(), 1);
+        }
+        let (_tx, rx) = queued();
+        assert_eq!(rx.drain(), vec![Message::single_key(1, 1)]);
+        assert_eq!(rx.expired_count(), 1);
+        // a ttl too long to represent never expires
+        let msg: super::Message<i32, i32> =
+            Message::single_key(1, 2).with_ttl(Duration::MAX);
+        assert_eq!(msg.deadline(), None);
+    }
+
+    #[test]
+    fn test_priority() {
+        let (tx, rx) = bounded_prioritized(10);
+        assert!(tx.send(Message::single_key(1, 0)).is_ok());
+        assert!(tx
+            .send(Message::single_key(2, 1).with_priority(5))
+            .is_ok());
+        // conflicts with the first one, the priority doesn't let it jump ahead
+        assert!(tx
+            .send(Message::single_key(1, 2).with_priority(9))
+            .is_ok());
+        assert!(tx
+            .send(Message::single_key(3, 3).with_priority(5))
+            .is_ok());
+        let second = unwrap_ok_or!(rx.recv(), err, panic!("{:?}", err));
+        assert_eq!((second.get_value(), second.priority()), (&1, 5));
+        // a tie is broken in FIFO order
+        let fourth = unwrap_ok_or!(rx.recv(), err, panic!("{:?}", err));
+        assert_eq!(fourth.get_value(), &3);
+        let first = unwrap_ok_or!(rx.recv(), err, panic!("{:?}", err));
+        assert_eq!(first.get_value(), &0);
+        assert_eq!(rx.recv(), Err(RecvError::AllConflict));
+        drop(first);
+        let third = unwrap_ok_or!(rx.recv(), err, panic!("{:?}", err));
+        assert_eq!(third.get_value(), &2);
+        // a plain channel ignores priorities
+        let (plain_tx, plain_rx) = bounded(10);
+        assert!(plain_tx.send(Message::single_key(1, 0)).is_ok());
+        assert!(plain_tx
+            .send(Message::single_key(2, 1).with_priority(9))
+            .is_ok());
+        let front = unwrap_ok_or!(plain_rx.recv(), err, panic!("{:?}", err));
+        assert_eq!(front.get_value(), &0);
+    }
+
+    #[test]
+    fn test_seq() {
+        let (tx, rx) = bounded(10);
+        let msg = Message::single_key(1, 0);
+        assert_eq!(msg.seq(), None);
+        assert!(tx.send(msg).is_ok());
+        assert!(tx.send(Message::single_key(1, 1)).is_ok());
+        assert!(tx.send(Message::single_key(2, 2)).is_ok());
+        assert!(tx.send(Message::single_key(3, 3)).is_ok());
+        let first = unwrap_ok_or!(rx.recv(), err, panic!("{:?}", err));
+        assert_eq!(first.seq(), Some(0));
+        // key 1 is active, the second message is skipped
+        let third = unwrap_ok_or!(rx.recv(), err, panic!("{:?}", err));
+        assert_eq!(third.seq(), Some(2));
+        let fourth = unwrap_ok_or!(rx.recv(), err, panic!("{:?}", err));
+        assert_eq!(fourth.seq(), Some(3));
+        drop(first);
+        let second = unwrap_ok_or!(rx.recv(), err, panic!("{:?}", err));
+        assert_eq!(second.seq(), Some(1));
+        assert_eq!(second.clone_detached().seq(), None);
+        assert_eq!(second.map(|v| v + 1).seq(), Some(1));
+    }
+
+    #[test]
+    fn test_keys() {
+        let single: super::Message<u32, ()> = Message::key_only(1);
+        assert_eq!(single.keys().collect::<Vec<_>>(), vec![&1]);
+        assert_eq!(single.keys_len(), 1);
+        let multiple: super::Message<u32, ()> = Message::keys_only(vec![1, 2, 3]);
+        let keys: HashSet<u32> = multiple.keys().copied().collect();
+        assert_eq!(keys, HashSet::from_iter(vec![1, 2, 3]));
+        assert_eq!(multiple.keys().len(), 3);
+        assert_eq!(multiple.keys_len(), 3);
+        let none: super::Message<u32, ()> = Message::keys_only(vec![]);
+        assert_eq!(none.keys().next(), None);
+        assert_eq!(none.keys_len(), 0);
+    }
+
+    #[test]
+    fn test_deref() {
+        fn value_len(value: &str) -> usize {
+            value.len()
+        }
+        fn as_ref_len<M: AsRef<String>>(msg: &M) -> usize {
+            msg.as_ref().len()
+        }
+        let (tx, rx) = bounded(1);
+        let _sent = tx.send(Message::single_key(1, String::from("value")));
+        let msg = unwrap_ok_or!(rx.recv(), err, panic!("{:?}", err));
+        assert_eq!(value_len(&msg), 5);
+        assert_eq!(as_ref_len(&msg), 5);
+        assert_eq!(*msg, "value");
+        // all of them reach the same value
+        let derefed: &String = &msg;
+        assert!(std::ptr::eq(derefed, msg.get_value()));
+        assert!(std::ptr::eq(msg.as_ref(), msg.get_value()));
+    }
+
+    #[test]
+    fn test_message_heap() {
+        use std::collections::BinaryHeap;
+        let (tx, rx) = bounded(10);
+        for (key, value) in [(1, 3), (2, 1), (3, 4), (4, 1)] {
+            let _sent = tx.send(Message::single_key(key, value));
+        }
+        let mut heap = BinaryHeap::new();
+        while let Ok(msg) = rx.try_recv() {
+            heap.push(msg);
+        }
+        // the greatest value first, the key breaks the tie
+        let popped: Vec<_> = std::iter::from_fn(|| heap.pop())
+            .map(|msg| (msg.get_single_key().copied(), *msg))
+            .collect();
+        assert_eq!(popped, vec![(Some(3), 4), (Some(1), 3), (Some(4), 1), (Some(2), 1)]);
+        let single: super::Message<u32, u32> = Message::single_key(9, 1);
+        let multiple: super::Message<u32, u32> = Message::multiple_keys(vec![1, 2], 1);
+        assert!(single < multiple);
+        let multiple2: super::Message<u32, u32> = Message::multiple_keys(vec![1, 3], 1);
+        assert!(multiple < multiple2);
+    }
+
+    #[test]
+    #[allow(clippy::mutable_key_type)] // the key lock inside is never hashed
+    fn test_message_hash() {
+        use std::collections::hash_map::DefaultHasher;
+        use std::hash::{Hash, Hasher};
+        fn hash_of<T: Hash>(value: &T) -> u64 {
+            let mut hasher = DefaultHasher::new();
+            value.hash(&mut hasher);
+            hasher.finish()
+        }
+        let (tx, rx) = bounded(10);
+        let _sent = tx.send(Message::single_key(1, 1));
+        let _sent2 = tx.send(Message::multiple_keys(vec![2, 3, 4], 2));
+        let mut set = HashSet::new();
+        while let Ok(msg) = rx.try_recv() {
+            assert!(set.insert(msg));
+        }
+        // equal messages detached from the channel are deduplicated
+        assert!(!set.insert(Message::single_key(1, 1)));
+        assert!(!set.insert(Message::multiple_keys(vec![4, 3, 2], 2)));
+        assert!(set.insert(Message::multiple_keys(vec![2, 3], 2)));
+        assert_eq!(set.len(), 3);
+        // the keys of a set hash the same in any order
+        let keys: Vec<u32> = (0..64).collect();
+        let forward: super::Message<u32, ()> = Message::keys_only(keys.clone());
+        let backward: super::Message<u32, ()> =
+            Message::keys_only(keys.into_iter().rev());
+        assert_eq!(hash_of(&forward), hash_of(&backward));
+        // a single key differs from a set of it
+        let single: super::Message<u32, ()> = Message::key_only(1);
+        let multiple: super::Message<u32, ()> = Message::keys_only(vec![1]);
+        assert_ne!(single, multiple);
+        assert_ne!(hash_of(&single), hash_of(&multiple));
+    }
+
+    #[test]
+    fn test_clone_detached() {
+        let (tx, rx) = bounded(10);
+        let completions = rx.completions(8);
+        let _sent = tx.send(Message::single_key(1, 1));
+        let _sent2 = tx.send(Message::single_key(1, 2));
+        let msg = unwrap_ok_or!(rx.recv(), err, panic!("{:?}", err));
+        let copy = msg.clone_detached();
+        assert_eq!(copy, msg);
+        drop(copy);
+        // the original still holds the key
+        assert_eq!(rx.recv(), Err(RecvError::AllConflict));
+        assert_eq!(completions.try_next(), None);
+        drop(msg);
+        assert_eq!(rx.recv(), Ok(Message::single_key(1, 2)));
+        assert_eq!(completions.try_next(), Some((vec![1], CompletionKind::Dropped)));
+    }
+
+    #[test]
+    fn test_map() {
+        let (tx, rx) = bounded(10);
+        let _sent = tx.send(Message::single_key(1, String::from("1")));
+        let _sent2 = tx.send(Message::single_key(1, String::from("x")));
+        let msg = unwrap_ok_or!(rx.recv(), err, panic!("{:?}", err));
+        let mapped = msg.map(|value| value.parse::<u32>());
+        assert_eq!(mapped.get_value(), &Ok(1));
+        // the mapped message holds the key
+        assert_eq!(rx.recv(), Err(RecvError::AllConflict));
+        drop(mapped);
+        let msg2 = unwrap_ok_or!(rx.recv(), err, panic!("{:?}", err));
+        let tried = msg2.try_map(|value| value.parse::<u32>());
+        let (unmapped, _err) = unwrap_some_or!(tried.err(), panic!("it's not a number"));
+        assert_eq!(unmapped, Message::single_key(1, String::from("x")));
+        let _sent3 = tx.send(Message::single_key(1, String::from("2")));
+        assert_eq!(rx.recv(), Err(RecvError::AllConflict));
+        drop(unmapped);
+        let msg3 = unwrap_ok_or!(rx.recv(), err, panic!("{:?}", err));
+        let mapped3 = unwrap_ok_or!(
+            msg3.try_map(|value| value.parse::<u32>()),
+            _,
+            panic!("it's a number")
+        );
+        assert_eq!(*mapped3.get_value(), 2);
+        assert_eq!(mapped3.get_single_key(), Some(&1));
+    }
+
+    #[test]
+    fn test_release_keys() {
+        let (tx, rx) = bounded(10);
+        let completions = rx.completions(8);
+        let _sent = tx.send(Message::single_key(1, 1));
+        let _sent2 = tx.send(Message::single_key(1, 2));
+        let mut msg = unwrap_ok_or!(rx.recv(), err, panic!("{:?}", err));
+        assert_eq!(rx.recv(), Err(RecvError::AllConflict));
+        msg.release_keys();
+        let msg2 = unwrap_ok_or!(rx.recv(), err, panic!("{:?}", err));
+        assert_eq!(msg2, Message::single_key(1, 2));
+        // released once, neither a second call nor the drop releases the key
+        // held by the second message
+        msg.release_keys();
+        assert_eq!(*msg.get_value(), 1);
+        drop(msg);
+        let _sent3 = tx.send(Message::single_key(1, 3));
+        assert_eq!(rx.recv(), Err(RecvError::AllConflict));
+        assert_eq!(completions.try_next(), Some((vec![1], CompletionKind::Released)));
+        assert_eq!(completions.try_next(), None);
+        // a message not received from a channel has nothing to release
+        let mut detached: super::Message<u32, u32> = Message::single_key(1, 4);
+        detached.release_keys();
+        drop(msg2);
+        assert_eq!(rx.recv(), Ok(Message::single_key(1, 3)));
+        // dropping is told apart from the explicit release
+        assert_eq!(completions.try_next(), Some((vec![1], CompletionKind::Dropped)));
+    }
+
+    #[test]
+    fn test_into_parts() {
+        let (tx, rx) = bounded(10);
+        let completions = rx.completions(8);
+        let _sent = tx.send(Message::single_key(1, 1));
+        let _sent2 = tx.send(Message::multiple_keys(vec![1, 2], 2));
+        let msg = unwrap_ok_or!(rx.recv(), err, panic!("{:?}", err));
+        let (keys, value) = msg.into_parts();
+        // released exactly once
+        assert_eq!(completions.try_next(), Some((vec![1], CompletionKind::Dropped)));
+        assert_eq!(completions.try_next(), None);
+        assert_eq!((keys, value), (KeySet::Single(1), 1));
+        let msg2 = unwrap_ok_or!(rx.recv(), err, panic!("{:?}", err));
+        let (keys2, value2) = msg2.into_parts();
+        assert_eq!(keys2, KeySet::multiple(vec![1, 2]));
+        // requeue an identical message
+        assert!(tx
+            .send(Message::from_key_set(keys2, value2))
+            .is_ok());
+        assert_eq!(rx.recv(), Ok(Message::multiple_keys(vec![1, 2], 2)));
+    }
+
+    #[test]
+    fn test_key_throttle() {
+        let (tx, rx) = bounded(10);
+        let throttled = tx.with_key_throttle(1);
+        assert_eq!(throttled.send(Message::single_key(1, 1)), Ok(()));
+        let sent = Arc::new(AtomicBool::new(false));
+        let throttled_sent = Arc::<AtomicBool>::clone(&sent);
+        let handle = thread::spawn(move || {
+            let res = throttled.send(Message::single_key(1, 2));
+            throttled_sent.store(true, SeqCst);
+            res
+        });
+        // the unthrottled sender proceeds freely
+        assert_eq!(tx.send(Message::single_key(1, 3)), Ok(()));
+        assert_eq!(tx.send(Message::single_key(1, 4)), Ok(()));
+        thread::sleep(Duration::from_millis(50));
+        assert!(!sent.load(SeqCst));
+        let msg = unwrap_ok_or!(rx.recv(), err, panic!("{:?}", err));
+        assert_eq!(msg, Message::single_key(1, 1));
+        // the first message is still held by receiver
+        thread::sleep(Duration::from_millis(50));
+        assert!(!sent.load(SeqCst));
+        drop(msg);
+        assert_eq!(unwrap_ok_or!(handle.join(), err, panic!("{:?}", err)), Ok(()));
+        assert!(sent.load(SeqCst));
+    }
 }
@@ -1,81 +1,529 @@
 //! A FIFO queue shared by sender and receiver
 
 use super::Message;
-use crate::buff::State;
-use crate::err::{RecvError, SendError};
+use crate::active::{ActiveKeys, SHARDS};
+use crate::buff::{contain, KeyedBuff, SenderCount, State};
+use crate::completions::CompletionKind;
+use crate::err::{
+    RecvError, RecvFilterError, SendError, SendTimeoutError, TryRecvError, TrySendError,
+};
+use crate::lock::{lock, lock_or_recover, Mutex, MutexGuard};
 use crate::message::{DeactivateKeys, Key};
-use crate::unwrap_ok_or;
+use crate::waiter::{SyncWaiter, Waiter};
+use crate::{unwrap_ok_or, unwrap_some_or};
 use std::fmt::Debug;
-use std::sync::{Condvar, Mutex, MutexGuard};
-
-// it's safe here because all operations on rc will
-// protect by the Mutex
-#[allow(unsafe_code)]
-unsafe impl<K: Key, V> Send for Shared<K, V> {}
-#[allow(unsafe_code)]
-unsafe impl<K: Key, V> Sync for Shared<K, V> {}
+use std::panic::RefUnwindSafe;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
 
 /// shared state between senders and receiver
 #[derive(Debug)]
 pub struct Shared<K: Key, V> {
     /// the queue state
     pub(crate) state: Mutex<State<Message<K, V>>>,
-    /// cond var that representes fill a new message into queue
-    pub(crate) fill: Condvar,
-    /// cond var that representes consume a message from queue
-    pub(crate) empty: Condvar,
+    /// keys held by received messages and key locks, locked after the state
+    pub(crate) active: Arc<ActiveKeys<K>>,
+    /// number of live senders
+    pub(crate) senders: SenderCount,
+    /// waiter that representes fill a new message into queue
+    pub(crate) fill: SyncWaiter,
+    /// waiter that representes consume a message from queue
+    pub(crate) empty: SyncWaiter,
+    /// waiters that represente release keys of received messages, one for
+    /// each shard of active keys, and it waits with the shard locked
+    pub(crate) released: Box<[SyncWaiter]>,
+    /// sequence number of the next message, only taken under the state lock
+    pub(crate) next_seq: AtomicU64,
 }
 
+// user code under the lock is contained, so the state is consistent after
+// a panic, a `parking_lot` mutex just doesn't say so as std's does
+impl<K: Key + RefUnwindSafe, V: RefUnwindSafe> RefUnwindSafe for Shared<K, V> {}
+
 impl<K: Key, V> DeactivateKeys for Shared<K, V> {
     type Key = K;
     /// release all keys
-    fn release_key<'a, I: IntoIterator<Item = &'a Self::Key>>(&'a self, keys: I) {
-        let mut state = unwrap_ok_or!(self.state.lock(), err, panic!("{:?}", err));
-        for k in keys {
-            state.buff.deactivate_key(k);
+    fn release_key(&self, keys: &[&K], kind: CompletionKind) {
+        // only the shards of the keys are locked, one at a time, so releasing
+        // never contends with sending, nor with releasing keys of other shards
+        let released = contain(|| {
+            let mut waited_any = false;
+            let mut feed = None;
+            for k in keys {
+                let shard = self.active.shard_of(*k);
+                let (waited, listened) = self.active.deactivate(shard, *k);
+                waited_any |= waited;
+                feed = listened;
+                self.released_waiter(shard).notify_all();
+            }
+            if keys.is_empty() {
+                feed = self.active.feed();
+            }
+            (waited_any, feed)
+        });
+        // wake up the receiver if a parked message waits for the keys, the
+        // receiver parks messages and waits with the state locked, locking it
+        // here makes sure the receiver is waiting, or will see the freed keys
+        if matches!(released, Ok((true, _))) {
+            // called in drop, never panic, the state is consistent even if poisoned
+            drop(lock_or_recover(&self.state));
+            self.fill.notify_one();
+        }
+        // emitted without the lock, cloning keys may panic too
+        let emitted = released.and_then(|(_, feed)| {
+            contain(|| {
+                if let Some(feed) = feed {
+                    feed.push((keys.iter().map(|k| (*k).clone()).collect(), kind));
+                }
+            })
+        });
+        if let Err(payload) = emitted {
+            if !std::thread::panicking() {
+                std::panic::resume_unwind(payload);
+            }
         }
     }
 }
 
 impl<K: Key, V> Shared<K, V> {
-    /// wait for an empty buff slot to put a message
-    fn acquire_send_slot(&self) -> MutexGuard<'_, State<Message<K, V>>> {
-        let mut state = unwrap_ok_or!(self.state.lock(), err, panic!("{:?}", err));
+    /// new a shared queue with capacity, a prioritized one receives the
+    /// highest priority message first
+    pub(crate) fn new(cap: usize, prioritized: bool) -> Self {
+        let active = Arc::new(ActiveKeys::new(cap));
+        Shared {
+            state: Mutex::new(State::new(cap, prioritized, Arc::clone(&active))),
+            active,
+            senders: SenderCount::new(1),
+            fill: SyncWaiter::default(),
+            empty: SyncWaiter::default(),
+            released: (0..SHARDS)
+                .map(|_| SyncWaiter::default())
+                .collect(),
+            next_seq: AtomicU64::new(0),
+        }
+    }
+
+    /// number of expired messages dropped instead of received
+    pub(crate) fn expired_count(&self) -> u64 {
+        let state = lock(&self.state);
+        state.buff.expired_count()
+    }
+
+    /// number of queued messages if the queue is disconnected
+    pub(crate) fn remaining_after_disconnect(&self) -> Option<usize> {
+        let state = lock(&self.state);
+        state.remaining_after_disconnect()
+    }
+
+    /// active keys that queued messages wait for
+    pub(crate) fn blocking_keys(&self) -> Vec<K> {
+        let state = lock(&self.state);
+        let keys = contain(|| state.buff.blocking_keys());
+        drop(state);
+        unwrap_ok_or!(keys, payload, std::panic::resume_unwind(payload))
+    }
+
+    /// activate a key without any message, a queued message with the key
+    /// is parked when it's found ready
+    pub(crate) fn lock_key(&self, key: K) {
+        self.active.activate(key, false);
+    }
+
+    /// waiter of released keys in `shard`
+    fn released_waiter(&self, shard: usize) -> &SyncWaiter {
+        unwrap_some_or!(self.released.get(shard), panic!("fatal error"))
+    }
+
+    /// wait until `key` is not active, or `deadline` is reached,
+    /// return whether it's released
+    pub(crate) fn wait_key_released(&self, key: &K, deadline: Option<Instant>) -> bool {
+        let shard = self.active.shard_of(key);
+        let mutex = unwrap_some_or!(self.active.shards.get(shard), panic!("fatal error"));
+        let mut table = lock(mutex);
+        loop {
+            // rechecked after every wakeup, the key may be activated again
+            let active = contain(|| table.is_active(key));
+            let active = unwrap_ok_or!(active, payload, {
+                drop(table);
+                std::panic::resume_unwind(payload)
+            });
+            if !active {
+                return true;
+            }
+            table = match deadline {
+                Some(deadline) if Instant::now() >= deadline => return false,
+                _ => self
+                    .released_waiter(shard)
+                    .wait_until(mutex, table, deadline),
+            };
+        }
+    }
+
+    /// wait for an empty buff slot to put a message, until `deadline`,
+    /// the buff is still full if the deadline is reached
+    fn acquire_send_slot(
+        &self, deadline: Option<Instant>,
+    ) -> MutexGuard<'_, State<Message<K, V>>> {
+        let mut state = lock(&self.state);
         loop {
             if !state.buff.is_full() || state.disconnected {
                 return state;
             }
-            state = unwrap_ok_or!(self.empty.wait(state), err, panic!("{:?}", err));
+            state = match deadline {
+                Some(deadline) if Instant::now() >= deadline => return state,
+                _ => self
+                    .empty
+                    .wait_until(&self.state, state, deadline),
+            };
         }
     }
     /// send a message
     pub(crate) fn send(
         &self, message: Message<K, V>,
     ) -> Result<(), SendError<Message<K, V>>> {
-        let mut state = self.acquire_send_slot();
+        let state = self.acquire_send_slot(None);
         if state.disconnected {
             return Err(SendError(message));
         }
-        state.buff.push_back(message);
+        if state.buff.cap() == 0 {
+            return self
+                .hand_over(state, message, None)
+                .map_err(|err| match err {
+                    SendTimeoutError::Disconnected(unsent) => SendError(unsent),
+                    SendTimeoutError::Timeout(_) => {
+                        unreachable!("send without deadline never times out")
+                    }
+                });
+        }
+        self.push(state, message);
+        Ok(())
+    }
+
+    /// send a message, wait until `deadline` for an empty buff slot
+    pub(crate) fn send_deadline(
+        &self, message: Message<K, V>, deadline: Option<Instant>,
+    ) -> Result<(), SendTimeoutError<Message<K, V>>> {
+        let state = self.acquire_send_slot(deadline);
+        if state.disconnected {
+            return Err(SendTimeoutError::Disconnected(message));
+        }
+        if state.buff.is_full() {
+            return Err(SendTimeoutError::Timeout(message));
+        }
+        if state.buff.cap() == 0 {
+            return self.hand_over(state, message, deadline);
+        }
+        self.push(state, message);
+        Ok(())
+    }
+
+    /// send a message without waiting for an empty buff slot
+    pub(crate) fn try_send(
+        &self, message: Message<K, V>,
+    ) -> Result<(), TrySendError<Message<K, V>>> {
+        let state = lock(&self.state);
+        if state.disconnected {
+            return Err(TrySendError::Disconnected(message));
+        }
+        // nothing is buffered in a rendezvous channel
+        if state.buff.is_full() || state.buff.cap() == 0 {
+            return Err(TrySendError::Full(message));
+        }
+        self.push(state, message);
+        Ok(())
+    }
+
+    /// put a message into the single slot of a rendezvous channel, and wait
+    /// until `deadline` for the receiver to take it, the message is taken back
+    /// if the deadline is reached or the channel is disconnected before that
+    fn hand_over<'a>(
+        &'a self, mut state: MutexGuard<'a, State<Message<K, V>>>,
+        mut message: Message<K, V>, deadline: Option<Instant>,
+    ) -> Result<(), SendTimeoutError<Message<K, V>>> {
+        let id = state.buff.next_id();
+        message.stamp_enqueued(self.next_seq.fetch_add(1, Ordering::Relaxed));
+        if let Err((unsent, payload)) = state.buff.push_back(message) {
+            drop(state);
+            drop(unsent);
+            std::panic::resume_unwind(payload);
+        }
+        self.fill.notify_one();
+        loop {
+            if !state.buff.is_queued(id) {
+                return Ok(());
+            }
+            let disconnected = state.disconnected;
+            if disconnected || deadline.is_some_and(|d| Instant::now() >= d) {
+                let taken = contain(|| state.buff.remove(id));
+                drop(state);
+                // the slot is free for other senders
+                self.empty.notify_all();
+                let unsent =
+                    unwrap_ok_or!(taken, payload, std::panic::resume_unwind(payload));
+                return Err(if disconnected {
+                    SendTimeoutError::Disconnected(unsent)
+                } else {
+                    SendTimeoutError::Timeout(unsent)
+                });
+            }
+            state = self
+                .empty
+                .wait_until(&self.state, state, deadline);
+        }
+    }
+
+    /// wake up senders after a message is taken, in a rendezvous channel the
+    /// sender of the message and the senders waiting for the slot wait together,
+    /// so do senders waiting for several slots freed at once
+    fn wake_senders(&self, all: bool) {
+        if all {
+            self.empty.notify_all();
+        } else {
+            self.empty.notify_one();
+        }
+    }
+
+    /// push a message into a buff with an empty slot and wake the receiver
+    fn push(
+        &self, mut state: MutexGuard<'_, State<Message<K, V>>>,
+        mut message: Message<K, V>,
+    ) {
+        message.stamp_enqueued(self.next_seq.fetch_add(1, Ordering::Relaxed));
+        let pushed = state.buff.push_back(message);
         drop(state);
+        if let Err((unsent, payload)) = pushed {
+            // the message may run user code in drop too
+            drop(unsent);
+            std::panic::resume_unwind(payload);
+        }
         self.fill.notify_one();
-        Ok(())
     }
 
     /// recv a message
     pub(crate) fn recv(&self) -> Result<Message<K, V>, RecvError> {
-        let mut state = unwrap_ok_or!(self.state.lock(), err, panic!("{:?}", err));
-        if state.buff.is_empty() && !state.disconnected {
-            state = unwrap_ok_or!(self.fill.wait(state), err, panic!("{:?}", err));
+        let msg = self.recv_deadline(None)?;
+        Ok(unwrap_some_or!(msg, unreachable!("recv without deadline never times out")))
+    }
+
+    /// recv a message, wait until `deadline` when buffer is empty,
+    /// return `None` if the deadline is reached
+    pub(crate) fn recv_deadline(
+        &self, deadline: Option<Instant>,
+    ) -> Result<Option<Message<K, V>>, RecvError> {
+        self.recv_with(deadline, None, KeyedBuff::pop_unconflict_front)
+    }
+
+    /// recv a message without waiting
+    pub(crate) fn try_recv(&self) -> Result<Message<K, V>, TryRecvError> {
+        // a deadline already reached never waits on `fill`
+        let msg = self.recv_deadline(Some(Instant::now()))?;
+        msg.ok_or(TryRecvError::Empty)
+    }
+
+    /// recv a message, wait when buffer is empty or all messages conflict,
+    /// return `Err` only if the channel is disconnected and buffer is empty
+    pub(crate) fn recv_ready(&self) -> Result<Message<K, V>, RecvError> {
+        // dropped after unlocking
+        let mut expired = Vec::new();
+        let mut state = lock(&self.state);
+        loop {
+            while !state.buff.has_ready()
+                && (!state.disconnected || !state.buff.is_empty())
+            {
+                state = self.fill.wait_until(&self.state, state, None);
+            }
+            if !state.buff.has_ready() {
+                return Err(RecvError::Disconnected);
+            }
+            let value = contain(|| state.buff.pop_unconflict_front());
+            expired.append(&mut state.buff.take_expired());
+            // the ready messages are all expired
+            if matches!(value, Ok(Err(RecvError::AllConflict))) {
+                self.empty.notify_all();
+                continue;
+            }
+            let rendezvous = state.buff.cap() == 0;
+            drop(state);
+            self.wake_senders(rendezvous || !expired.is_empty());
+            return unwrap_ok_or!(value, payload, std::panic::resume_unwind(payload));
         }
-        if state.buff.is_empty() && state.disconnected {
+    }
+
+    /// recv the front-most message without activating its keys
+    pub(crate) fn recv_unguarded(&self) -> Result<Message<K, V>, RecvError> {
+        let msg = self.recv_with(None, None, KeyedBuff::pop_front_unguarded)?;
+        Ok(unwrap_some_or!(msg, unreachable!("recv without deadline never times out")))
+    }
+
+    /// recv the front-most message regardless of conflict, and activate its keys
+    pub(crate) fn recv_any(&self) -> Result<Message<K, V>, RecvError> {
+        let msg = self.recv_with(None, None, KeyedBuff::pop_front_any)?;
+        Ok(unwrap_some_or!(msg, unreachable!("recv without deadline never times out")))
+    }
+
+    /// wait for a receivable message like `recv`, and keep the state locked,
+    /// so the message stays at the front of buff while the guard is alive
+    pub(crate) fn peek(&self) -> Result<MutexGuard<'_, State<Message<K, V>>>, RecvError> {
+        let mut state = lock(&self.state);
+        loop {
+            while state.buff.is_empty() && !state.disconnected {
+                state = self.fill.wait_until(&self.state, state, None);
+            }
+            let purged = contain(|| state.buff.purge_expired());
+            let expired = state.buff.take_expired();
+            if purged.is_ok() && expired.is_empty() {
+                break;
+            }
+            // the guard is returned locked, so expired messages are dropped
+            // before that, their slots are free for senders
+            drop(state);
+            drop(expired);
+            self.empty.notify_all();
+            if let Err(payload) = purged {
+                std::panic::resume_unwind(payload);
+            }
+            state = lock(&self.state);
+        }
+        if state.buff.is_empty() {
             return Err(RecvError::Disconnected);
         }
-        let value = state.buff.pop_unconflict_front();
-        // notify the blocked sender corrospend to this message
+        if !state.buff.has_ready() {
+            return Err(RecvError::AllConflict);
+        }
+        Ok(state)
+    }
+
+    /// recv the front-most message that matches `pred` without conflict,
+    /// wait when buffer is empty
+    pub(crate) fn recv_filter<P>(
+        &self, mut pred: P,
+    ) -> Result<Message<K, V>, RecvFilterError>
+    where
+        P: FnMut(&Message<K, V>) -> bool,
+    {
+        let msg = self.recv_with(None, None, |buff: &mut KeyedBuff<_>| {
+            buff.pop_unconflict_where(&mut pred)
+        })?;
+        Ok(unwrap_some_or!(msg, unreachable!("recv without deadline never times out")))
+    }
+
+    /// recv the front-most message with `key`, wait until it's receivable,
+    /// i.e. until it's sent and the key is released
+    pub(crate) fn recv_key(&self, key: &K) -> Result<Message<K, V>, RecvError> {
+        // dropped after unlocking
+        let mut expired = Vec::new();
+        let mut state = lock(&self.state);
+        loop {
+            let popped = contain(|| {
+                state
+                    .buff
+                    .pop_unconflict_where(|msg| msg.key().contains(key))
+            });
+            expired.append(&mut state.buff.take_expired());
+            match popped {
+                Ok(Ok(msg)) => {
+                    let rendezvous = state.buff.cap() == 0;
+                    drop(state);
+                    self.wake_senders(rendezvous || !expired.is_empty());
+                    return Ok(msg);
+                }
+                Ok(Err(RecvFilterError::NoMatch)) if state.disconnected => {
+                    return Err(RecvError::Disconnected);
+                }
+                // released keys wake up the receiver too
+                Ok(Err(_)) => {
+                    if !expired.is_empty() {
+                        self.empty.notify_all();
+                    }
+                    state = self.fill.wait_until(&self.state, state, None);
+                }
+                Err(payload) => {
+                    drop(state);
+                    std::panic::resume_unwind(payload);
+                }
+            }
+        }
+    }
+
+    /// take all queued messages in FIFO order without activating their keys
+    pub(crate) fn drain(&self) -> Vec<Message<K, V>> {
+        let mut state = lock(&self.state);
+        let mut drained = Vec::with_capacity(state.buff.len());
+        let popped = contain(|| {
+            while let Ok(msg) = state.buff.pop_front_unguarded() {
+                drained.push(msg);
+            }
+        });
+        let expired = state.buff.take_expired();
+        drop(state);
+        drop(expired);
+        self.empty.notify_all();
+        if let Err(payload) = popped {
+            drop(drained);
+            std::panic::resume_unwind(payload);
+        }
+        drained
+    }
+
+    /// recv a message, wait when buffer is empty,
+    /// return `None` if `cancelled` is set
+    pub(crate) fn recv_or(
+        &self, cancelled: &AtomicBool,
+    ) -> Result<Option<Message<K, V>>, RecvError> {
+        self.recv_with(None, Some(cancelled), KeyedBuff::pop_unconflict_front)
+    }
+
+    /// wake up the receiver waiting in `recv_or` after `cancelled` is set
+    pub(crate) fn wake_receiver(&self) {
+        // the receiver checks `cancelled` under the lock before waiting,
+        // lock here so the notification is not lost in between
+        drop(lock(&self.state));
+        self.fill.notify_all();
+    }
+
+    /// recv a message taken by `pop`, wait until `deadline` when buffer is empty,
+    /// return `None` if the deadline is reached or `cancelled` is set
+    fn recv_with<E, F>(
+        &self, deadline: Option<Instant>, cancelled: Option<&AtomicBool>, mut pop: F,
+    ) -> Result<Option<Message<K, V>>, E>
+    where
+        E: From<RecvError>,
+        F: FnMut(&mut KeyedBuff<Message<K, V>>) -> Result<Message<K, V>, E>,
+    {
+        // dropped after unlocking
+        let mut expired = Vec::new();
+        let mut state = lock(&self.state);
+        let value = loop {
+            while state.buff.is_empty() && !state.disconnected {
+                if cancelled.is_some_and(|c| c.load(Ordering::SeqCst)) {
+                    return Ok(None);
+                }
+                state = match deadline {
+                    Some(deadline) if Instant::now() >= deadline => return Ok(None),
+                    _ => self
+                        .fill
+                        .wait_until(&self.state, state, deadline),
+                };
+            }
+            if state.buff.is_empty() {
+                return Err(RecvError::Disconnected.into());
+            }
+            let value = contain(|| pop(&mut state.buff));
+            expired.append(&mut state.buff.take_expired());
+            // the queued messages are all expired, wait for the next one
+            if matches!(value, Ok(Err(_))) && state.buff.is_empty() && !expired.is_empty()
+            {
+                self.empty.notify_all();
+                continue;
+            }
+            break value;
+        };
+        let rendezvous = state.buff.cap() == 0;
         drop(state);
-        // notify other blocked sender
-        self.empty.notify_one();
-        value
+        // an expired message frees a slot too
+        self.wake_senders(rendezvous || !expired.is_empty());
+        let value = unwrap_ok_or!(value, payload, std::panic::resume_unwind(payload));
+        value.map(Some)
     }
 }
@@ -0,0 +1,150 @@
+//! Per-key in-flight limit used by throttled senders
+
+use crate::buff::contain;
+use crate::message::{CompletionHook, Key};
+use crate::unwrap_ok_or;
+use std::any::Any;
+use std::collections::HashMap;
+use std::panic::{resume_unwind, RefUnwindSafe};
+use std::sync::{Arc, Condvar, Mutex};
+#[cfg(feature = "async")]
+use tokio::sync::Notify;
+
+/// count of a sender's in-flight messages on each key
+#[derive(Debug)]
+pub(crate) struct KeyThrottle<K: Key> {
+    /// in-flight messages count of each key, zero counts are removed
+    in_flight: Mutex<HashMap<K, usize>>,
+    /// max in-flight messages of each key
+    limit: usize,
+    /// cond var that representes a key's count decreased
+    released: Condvar,
+    /// notify async senders when a key's count decreased
+    #[cfg(feature = "async")]
+    notify: Notify,
+}
+
+impl<K: Key> KeyThrottle<K> {
+    /// new a throttle with limit per key
+    pub(crate) fn new(limit: usize) -> Self {
+        assert!(limit > 0, "The in-flight limit per key must be greater than 0");
+        KeyThrottle {
+            in_flight: Mutex::new(HashMap::new()),
+            limit,
+            released: Condvar::new(),
+            #[cfg(feature = "async")]
+            notify: Notify::new(),
+        }
+    }
+
+    /// count all keys in if none of them reaches the limit,
+    /// a panic in the key's `Clone`/`Hash`/`Eq` is returned after the counted keys
+    /// are rolled back
+    fn try_count_in(
+        &self, in_flight: &mut HashMap<K, usize>, keys: &[K],
+    ) -> Result<bool, Box<dyn Any + Send>> {
+        let mut counted = 0_usize;
+        let res = contain(|| {
+            if keys.iter().any(|k| {
+                in_flight
+                    .get(k)
+                    .is_some_and(|n| *n >= self.limit)
+            }) {
+                return false;
+            }
+            for k in keys {
+                let n = in_flight.entry(k.clone()).or_insert(0);
+                *n = n.saturating_add(1);
+                counted = counted.saturating_add(1);
+            }
+            true
+        });
+        if res.is_err() {
+            Self::count_out(in_flight, keys.iter().take(counted));
+        }
+        res
+    }
+
+    /// decrease the count of keys, zero counts are removed
+    fn count_out<'a, I: IntoIterator<Item = &'a K>>(
+        in_flight: &mut HashMap<K, usize>, keys: I,
+    ) where
+        K: 'a,
+    {
+        for k in keys {
+            if let Some(n) = in_flight.get_mut(k) {
+                *n = n.saturating_sub(1);
+                if *n == 0 {
+                    let _drop = in_flight.remove(k);
+                }
+            }
+        }
+    }
+
+    /// block until all keys are below the limit, then count them in
+    pub(crate) fn acquire_blocking(&self, keys: &[K]) {
+        let mut in_flight =
+            unwrap_ok_or!(self.in_flight.lock(), err, panic!("{:?}", err));
+        loop {
+            match self.try_count_in(&mut in_flight, keys) {
+                Ok(true) => return,
+                Ok(false) => {}
+                Err(payload) => {
+                    drop(in_flight);
+                    resume_unwind(payload);
+                }
+            }
+            in_flight =
+                unwrap_ok_or!(self.released.wait(in_flight), err, panic!("{:?}", err));
+        }
+    }
+
+    /// wait until all keys are below the limit, then count them in
+    #[cfg(feature = "async")]
+    pub(crate) async fn acquire(&self, keys: &[K]) {
+        loop {
+            // created before checking, so `notify_waiters` in between is not lost
+            let released = self.notify.notified();
+            {
+                let mut in_flight =
+                    unwrap_ok_or!(self.in_flight.lock(), err, panic!("{:?}", err));
+                match self.try_count_in(&mut in_flight, keys) {
+                    Ok(true) => return,
+                    Ok(false) => {}
+                    Err(payload) => {
+                        drop(in_flight);
+                        resume_unwind(payload);
+                    }
+                }
+            }
+            released.await;
+        }
+    }
+
+    /// count all keys out, and wake up waiting senders
+    pub(crate) fn release(&self, keys: &[K]) {
+        // called in drop, must not panic
+        let mut in_flight = unwrap_ok_or!(self.in_flight.lock(), _, return);
+        let released = contain(|| Self::count_out(&mut in_flight, keys));
+        drop(in_flight);
+        self.released.notify_all();
+        #[cfg(feature = "async")]
+        self.notify.notify_waiters();
+        if let Err(payload) = released {
+            if !std::thread::panicking() {
+                resume_unwind(payload);
+            }
+        }
+    }
+}
+
+// tokio primitives never run user code, so they are consistent after a panic
+impl<K: Key + RefUnwindSafe> RefUnwindSafe for KeyThrottle<K> {}
+
+impl<K: Key + Send + Sync + 'static> KeyThrottle<K> {
+    /// a hook that counts the keys out when the message is dropped
+    pub(crate) fn release_hook(self: &Arc<Self>, keys: Vec<K>) -> CompletionHook {
+        let throttle = Arc::clone(self);
+        Box::new(move || throttle.release(&keys))
+    }
+}
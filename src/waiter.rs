@@ -0,0 +1,125 @@
+//! Blocking wait/notify primitives used by the sync channel
+
+#[cfg(feature = "event_listener")]
+use crate::lock::lock;
+#[cfg(not(feature = "event_listener"))]
+use crate::lock::{notify_all, notify_one, wait, wait_timeout, Condvar};
+use crate::lock::{Mutex, MutexGuard};
+#[cfg(feature = "event_listener")]
+use event_listener::Event;
+#[cfg(not(feature = "event_listener"))]
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+
+/// A wait list that threads block on while holding nothing,
+/// the condition is always rechecked under the mutex by callers
+pub(crate) trait Waiter: Default {
+    /// release `guard`, block until notified or `deadline` is reached,
+    /// then lock `mutex` again
+    ///
+    /// It may return spuriously, callers must recheck their condition in a loop.
+    fn wait_until<'a, T>(
+        &self, mutex: &'a Mutex<T>, guard: MutexGuard<'a, T>, deadline: Option<Instant>,
+    ) -> MutexGuard<'a, T>;
+
+    /// wake up one blocked thread
+    fn notify_one(&self);
+
+    /// wake up all blocked threads
+    fn notify_all(&self);
+}
+
+/// A waiter based on [`Condvar`]
+///
+/// Notifying a condvar is a syscall even if nobody waits on it, so the parked
+/// threads are counted and a notification without any is skipped. It's what
+/// a single producer and a single consumer mostly do, as they rarely wait
+/// for each other.
+#[cfg(not(feature = "event_listener"))]
+#[derive(Debug, Default)]
+pub(crate) struct CondvarWaiter {
+    /// the blocked threads
+    cond: Condvar,
+    /// number of threads blocked or about to block,
+    /// it's only increased while holding the mutex
+    parked: AtomicUsize,
+}
+
+#[cfg(not(feature = "event_listener"))]
+impl Waiter for CondvarWaiter {
+    fn wait_until<'a, T>(
+        &self, _mutex: &'a Mutex<T>, guard: MutexGuard<'a, T>, deadline: Option<Instant>,
+    ) -> MutexGuard<'a, T> {
+        // counted before the mutex is released, a notifier changes the condition
+        // under the mutex before loading the count, so it never misses this thread
+        let _parked = self.parked.fetch_add(1, Ordering::SeqCst);
+        let guard = match deadline {
+            Some(deadline) => {
+                let timeout = deadline.saturating_duration_since(Instant::now());
+                wait_timeout(&self.cond, guard, timeout)
+            }
+            None => wait(&self.cond, guard),
+        };
+        let _woken = self.parked.fetch_sub(1, Ordering::SeqCst);
+        guard
+    }
+
+    fn notify_one(&self) {
+        if self.parked.load(Ordering::SeqCst) > 0 {
+            notify_one(&self.cond);
+        }
+    }
+
+    fn notify_all(&self) {
+        if self.parked.load(Ordering::SeqCst) > 0 {
+            notify_all(&self.cond);
+        }
+    }
+}
+
+/// A waiter based on [`Event`]
+#[cfg(feature = "event_listener")]
+#[derive(Debug, Default)]
+pub(crate) struct EventWaiter(Event);
+
+#[cfg(feature = "event_listener")]
+impl Waiter for EventWaiter {
+    fn wait_until<'a, T>(
+        &self, mutex: &'a Mutex<T>, guard: MutexGuard<'a, T>, deadline: Option<Instant>,
+    ) -> MutexGuard<'a, T> {
+        // listen before unlock, so a notification in between is not lost
+        let listener = self.0.listen();
+        drop(guard);
+        match deadline {
+            Some(deadline) => {
+                let _notified = listener.wait_deadline(deadline);
+            }
+            None => listener.wait(),
+        }
+        lock(mutex)
+    }
+
+    fn notify_one(&self) {
+        // `notify` skips threads that are notified but not yet woken up,
+        // which may leave a second free slot unnoticed
+        self.0.notify_additional(1);
+    }
+
+    fn notify_all(&self) {
+        self.0.notify(usize::MAX);
+    }
+}
+
+/// the waiter selected by features
+#[cfg(not(feature = "event_listener"))]
+type BaseWaiter = CondvarWaiter;
+/// the waiter selected by features
+#[cfg(feature = "event_listener")]
+type BaseWaiter = EventWaiter;
+
+/// the waiter used by sync channel
+#[cfg(not(feature = "fault-injection"))]
+pub(crate) type SyncWaiter = BaseWaiter;
+/// the waiter used by sync channel
+#[cfg(feature = "fault-injection")]
+pub(crate) type SyncWaiter = crate::fault::Faulty<BaseWaiter>;
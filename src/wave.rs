@@ -0,0 +1,39 @@
+//! Partition messages into conflict-free waves
+
+use crate::message::{DeactivateKeys, Key, Message};
+use crate::unwrap_some_or;
+use std::collections::HashMap;
+
+/// partition messages into waves, messages in a wave have no conflict with
+/// each other, so a whole wave can be received concurrently without any
+/// [`crate::RecvError::AllConflict`]
+///
+/// A message goes to the first wave after the latest wave that holds any of
+/// its keys, so messages sharing a key keep their relative order across waves,
+/// and messages in a wave keep their relative order in `msgs`.
+#[inline]
+#[must_use]
+pub fn plan_waves<K, V, T>(msgs: Vec<Message<K, V, T>>) -> Vec<Vec<Message<K, V, T>>>
+where
+    K: Key,
+    T: DeactivateKeys<Key = K>,
+{
+    let mut waves: Vec<Vec<Message<K, V, T>>> = Vec::new();
+    // index of the latest wave that holds the key
+    let mut latest: HashMap<K, usize> = HashMap::new();
+    for msg in msgs {
+        let wave = msg
+            .keys()
+            .filter_map(|k| latest.get(k))
+            .max()
+            .map_or(0, |w| unwrap_some_or!(w.checked_add(1), panic!("fatal error")));
+        for k in msg.keys() {
+            let _drop = latest.insert(k.clone(), wave);
+        }
+        if wave == waves.len() {
+            waves.push(Vec::new());
+        }
+        unwrap_some_or!(waves.get_mut(wave), panic!("fatal error")).push(msg);
+    }
+    waves
+}
@@ -0,0 +1,107 @@
+//! Static assertions of the auto traits and bounds of the public API,
+//! a failure here is a breaking change
+//!
+//! Things that must not compile are `compile_fail` doctests on `Receiver`.
+
+use kv_mpsc::{
+    sync_channel, Completion, CompletionKind, Completions, KeySet, Message,
+    MessageBuilder, RecvError, RecvFilterError, RecvOr, RecvTimeoutError, RedactedKey,
+    SendError, SendTimeoutError, TryRecvError, TryReserveError, TrySendError,
+};
+use std::fmt::Debug;
+
+fn assert_send_sync_unpin<T: Send + Sync + Unpin>() {}
+fn assert_send_unpin<T: Send + Unpin>() {}
+#[cfg(feature = "async")]
+fn assert_unpin<T: Unpin>() {}
+fn assert_value<T: Send + Sync + Unpin>(_: &T) {}
+fn assert_error<T: Debug + Clone + Copy + PartialEq + Eq + Send + Sync + Unpin>() {}
+
+/// a value type without `Debug`, no method may require it
+struct Opaque;
+
+#[test]
+fn test_sync_channel() {
+    use sync_channel::{
+        BoundedSender, CancelToken, Receiver, ThrottledSender, WeakSender,
+    };
+    assert_send_sync_unpin::<BoundedSender<u32, String>>();
+    assert_send_sync_unpin::<WeakSender<u32, String>>();
+    assert_send_sync_unpin::<ThrottledSender<u32, String>>();
+    assert_send_sync_unpin::<CancelToken<u32, String>>();
+    // single consumer, see the `compile_fail` doctest on `Receiver`
+    assert_send_unpin::<Receiver<u32, String>>();
+
+    let (tx, rx) = sync_channel::bounded::<u32, Opaque>(1);
+    assert!(tx.send(Message::single_key(1, Opaque)).is_ok());
+    let msg = rx.recv().unwrap_or_else(|_| unreachable!());
+    assert_value(&msg);
+    let lock = rx.lock_key(2);
+    assert_value(&lock);
+    assert_eq!(lock.get_single_key(), Some(&2));
+    drop(lock);
+    drop(msg);
+}
+
+#[cfg(feature = "async")]
+#[tokio::test]
+async fn test_async_channel() {
+    use kv_mpsc::async_channel::{
+        self, BoundedSender, KeyReleasedFuture, OwnedSendPermit, Receiver, RecvFuture,
+        RecvStats, SendPermit, ThrottledSender, UnboundedSender,
+    };
+    assert_send_sync_unpin::<BoundedSender<u32, String>>();
+    assert_send_sync_unpin::<SendPermit<'_, u32, String>>();
+    assert_send_sync_unpin::<OwnedSendPermit<u32, String>>();
+    assert_send_sync_unpin::<ThrottledSender<u32, String>>();
+    assert_send_sync_unpin::<UnboundedSender<u32, String>>();
+    assert_send_unpin::<Receiver<u32, String>>();
+    // borrows a receiver, which is not `Sync`
+    assert_unpin::<RecvFuture<'_, u32, String>>();
+    assert_send_unpin::<KeyReleasedFuture<'_, u32, String>>();
+    assert_send_sync_unpin::<RecvStats>();
+
+    let (tx, rx) = async_channel::bounded::<u32, Opaque>(1);
+    // a sending task can be spawned
+    let send = async move {
+        tx.send(Message::single_key(1, Opaque))
+            .await
+            .is_ok()
+    };
+    assert!(tokio::spawn(send).await.unwrap_or(false));
+    let msg = rx
+        .recv()
+        .await
+        .unwrap_or_else(|_| unreachable!());
+    assert_value(&msg);
+    let lock = rx.lock_key(2);
+    assert_value(&lock);
+    drop(lock);
+    drop(msg);
+}
+
+#[cfg(feature = "stream")]
+#[test]
+fn test_grouped_stream() {
+    use kv_mpsc::async_channel::GroupedStream;
+    assert_send_unpin::<GroupedStream<u32, String>>();
+}
+
+#[test]
+fn test_other_types() {
+    assert_error::<RecvError>();
+    assert_error::<RecvOr>();
+    assert_error::<RecvFilterError>();
+    assert_error::<TryRecvError>();
+    assert_error::<RecvTimeoutError>();
+    assert_error::<TryReserveError>();
+    assert_send_sync_unpin::<SendError<u32>>();
+    assert_send_sync_unpin::<TrySendError<u32>>();
+    assert_send_sync_unpin::<SendTimeoutError<u32>>();
+    assert_send_sync_unpin::<Completions<u32>>();
+    assert_send_sync_unpin::<Completion<u32>>();
+    assert_send_sync_unpin::<CompletionKind>();
+    assert_send_sync_unpin::<RedactedKey<u32>>();
+    assert_send_sync_unpin::<KeySet<u32>>();
+    assert_send_sync_unpin::<MessageBuilder<u32, String>>();
+}